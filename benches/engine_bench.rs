@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{Config, KvStore, KvsEngine, SledKvsEngine};
 use rand::prelude::*;
 use tempfile::TempDir;
 
@@ -76,5 +76,32 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+/// Compare reading values out of segment files through a memory map with
+/// opening and seeking the file for every lookup. The block cache is kept
+/// tiny so nearly every lookup reaches the segment file.
+fn mmap_get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mmap_get_bench");
+    for mmap in &[false, true] {
+        group.bench_with_input(format!("mmap_{}", mmap), mmap, |b, mmap| {
+            let temp_dir = TempDir::new().unwrap();
+            let config = Config::builder().block_cache_size(0).mmap(*mmap).build();
+            let store = KvStore::with_config(temp_dir.path(), config).unwrap();
+            for key_i in 1..(1 << 12) {
+                store
+                    .set(format!("key{}", key_i).into_bytes(), b"value".to_vec())
+                    .unwrap();
+            }
+            store.flush_memtable().unwrap();
+            let mut rng = SmallRng::from_seed([0; 32]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1..1 << 12)).as_bytes())
+                    .unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set_bench, get_bench, mmap_get_bench);
 criterion_main!(benches);
@@ -0,0 +1,152 @@
+use crate::common::{
+    decode_frame, CompactResponse, FindResponse, GetResponse, RemoveResponse, Request, SetResponse,
+    StatsResponse,
+};
+use crate::{KvError, Result, StoreStats, WireCodec};
+use serde_json::Deserializer;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// Key value store client for applications running inside of a tokio
+/// runtime. It speaks the same protocol as `KvClient` but never blocks the
+/// calling thread while waiting on the server.
+pub struct AsyncKvClient {
+    stream: TcpStream,
+    codec: WireCodec,
+    buffer: Vec<u8>,
+}
+
+impl AsyncKvClient {
+    /// Connect to `addr` to access `KvsServer`
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(AsyncKvClient {
+            stream,
+            codec: WireCodec::default(),
+            buffer: vec![],
+        })
+    }
+
+    /// Encode requests and responses with `codec` instead of JSON. The
+    /// server must be using the same codec.
+    pub fn with_codec(mut self, codec: WireCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Get the value of a given key from the server.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = key.into_bytes();
+        match self.write(&Request::Get { key }).await? {
+            GetResponse::Ok(Some(value)) => Ok(Some(String::from_utf8(value)?)),
+            GetResponse::Ok(None) => Ok(None),
+            GetResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
+    /// Set the value of a string key in the server.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        let (key, value) = (key.into_bytes(), value.into_bytes());
+        match self.write(&Request::Set { key, value }).await? {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
+    /// Find a list of keys given a pattern from the server. Set `ignore_case`
+    /// to match ASCII letters without regard to case.
+    pub async fn find(&mut self, pattern: String, ignore_case: bool) -> Result<Vec<String>> {
+        let request = Request::Find {
+            pattern: pattern.into_bytes(),
+            ignore_case,
+            offset: 0,
+            limit: None,
+        };
+        match self.write(&request).await? {
+            FindResponse::Ok(mut list) => Ok(list
+                .drain(..)
+                .map(|b| {
+                    String::from_utf8(b).unwrap_or_else(|err| format!("<from_utf8_error> {}", err))
+                })
+                .collect::<Vec<_>>()),
+            FindResponse::Err(err) => Err(KvError::StringError(err.into())),
+        }
+    }
+
+    /// Remove a value from the key value store
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        let key = key.into_bytes();
+        match self.write(&Request::Remove { key }).await? {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
+    /// Get statistics about the number of keys and disk usage of the server.
+    pub async fn stats(&mut self) -> Result<StoreStats> {
+        match self.write(&Request::Stats).await? {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
+    /// Ask the server to reclaim the space used by overwritten and removed keys.
+    pub async fn compact(&mut self) -> Result<()> {
+        match self.write(&Request::Compact).await? {
+            CompactResponse::Ok(()) => Ok(()),
+            CompactResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
+    async fn write<T, R>(&mut self, t: &T) -> Result<R>
+    where
+        T: ?Sized + serde::Serialize,
+        R: serde::de::DeserializeOwned,
+    {
+        let mut request = vec![];
+        self.codec.write(&mut request, t)?;
+        self.stream.write_all(&request).await?;
+        self.stream.flush().await?;
+        self.read().await
+    }
+
+    /// Read the next response. Bytes are buffered until they hold a
+    /// complete JSON value or bincode frame.
+    async fn read<R>(&mut self) -> Result<R>
+    where
+        R: serde::de::DeserializeOwned,
+    {
+        loop {
+            if let Some(response) = self.decode()? {
+                return Ok(response);
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Server closed the connection",
+                )
+                .into());
+            }
+        }
+    }
+
+    fn decode<R>(&mut self) -> Result<Option<R>>
+    where
+        R: serde::de::DeserializeOwned,
+    {
+        if self.codec == WireCodec::Bincode {
+            return decode_frame(&mut self.buffer);
+        }
+        let mut responses = Deserializer::from_slice(&self.buffer).into_iter::<R>();
+        match responses.next() {
+            Some(Ok(response)) => {
+                let consumed = responses.byte_offset();
+                self.buffer.drain(..consumed);
+                Ok(Some(response))
+            }
+            Some(Err(e)) if !e.is_eof() => Err(e.into()),
+            _ => Ok(None),
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use clap_v3::{App, Arg, ArgMatches};
 use kvs::{KvClient, KvError, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
 use std::net::{IpAddr, SocketAddr};
 use std::process::exit;
 use std::str::FromStr;
@@ -23,6 +25,12 @@ fn main() {
                 .default_value("4000")
                 .help("Set the servers port number"),
         )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .help("Password of a server that requires authentication"),
+        )
         .subcommand(
             App::new("get")
                 .about("Get the string value of a given string key")
@@ -45,6 +53,44 @@ fn main() {
                     Arg::with_name("pattern")
                         .help("A string that matches a pattern")
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("ignore-case")
+                        .short('i')
+                        .long("ignore-case")
+                        .help("Match letters without regard to case"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .help("Show at most this many keys"),
+                )
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("Number of sorted keys to skip before the first one shown"),
+                ),
+        )
+        .subcommand(
+            App::new("count")
+                .about("Count the keys that match a pattern")
+                .arg(
+                    Arg::with_name("pattern")
+                        .help("A string that matches a pattern")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            App::new("append")
+                .about("Append a string to the value of a string key")
+                .arg(Arg::with_name("key").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("suffix")
+                        .help("The string to add to the end of the value")
+                        .required(true),
                 ),
         )
         .subcommand(
@@ -52,6 +98,27 @@ fn main() {
                 .about("Remove a given string key")
                 .arg(Arg::with_name("key").help("A string key").required(true)),
         )
+        .subcommand(App::new("stats").about("Show key count and disk usage statistics"))
+        .subcommand(App::new("compact").about("Reclaim space used by overwritten and removed keys"))
+        .subcommand(App::new("verify").about("Check every stored record against its checksum"))
+        .subcommand(
+            App::new("dump")
+                .about("Write every key and value to a file")
+                .arg(
+                    Arg::with_name("file")
+                        .help("File to write the dump to")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            App::new("load")
+                .about("Set every key and value of a dump file")
+                .arg(
+                    Arg::with_name("file")
+                        .help("Dump file to load")
+                        .required(true),
+                ),
+        )
         .subcommand(
             App::new("test")
                 .about("Test the key value store")
@@ -70,7 +137,10 @@ fn run(opt: ArgMatches) -> Result<()> {
     let addr = opt.value_of("addr").unwrap();
     let port = opt.value_of("port").unwrap();
     let ip = SocketAddr::new(IpAddr::from_str(addr).unwrap(), port.parse().unwrap());
-    let mut client = KvClient::connect(ip)?;
+    let mut client = match opt.value_of("token") {
+        Some(token) => KvClient::connect_with_auth(ip, token)?,
+        None => KvClient::connect(ip)?,
+    };
     match opt.subcommand() {
         ("get", Some(sub)) => {
             if let Some(value) = client.get(sub.value_of("key").unwrap().to_string())? {
@@ -85,6 +155,12 @@ fn run(opt: ArgMatches) -> Result<()> {
             client.set(key.clone(), value.clone())?;
             println!("Set {} to {}", key, value);
         }
+        ("append", Some(sub)) => {
+            let key = sub.value_of("key").unwrap().to_string();
+            let suffix = sub.value_of("suffix").unwrap().to_string();
+            let len = client.append(key.clone(), suffix)?;
+            println!("Value of {} is now {} bytes long", key, len);
+        }
         ("rm", Some(sub)) => {
             let key = sub.value_of("key").unwrap().to_string();
             client.remove(key.clone())?;
@@ -92,12 +168,62 @@ fn run(opt: ArgMatches) -> Result<()> {
         }
         ("find", Some(sub)) => {
             let pattern = sub.value_of("pattern").unwrap().to_string();
-            let keys = client.find(pattern.clone())?;
+            let offset = sub
+                .value_of("offset")
+                .unwrap()
+                .parse::<usize>()
+                .map_err(|_| KvError::Parse("The offset was not a valid number".into()))?;
+            let limit = match sub.value_of("limit") {
+                Some(limit) => Some(
+                    limit
+                        .parse::<usize>()
+                        .map_err(|_| KvError::Parse("The limit was not a valid number".into()))?,
+                ),
+                None => None,
+            };
+            let keys = client.find_page(
+                pattern.clone(),
+                sub.is_present("ignore-case"),
+                offset,
+                limit,
+            )?;
             println!("For Pattern {}, Found:", pattern);
             for key in keys {
                 println!("{}", key);
             }
         }
+        ("count", Some(sub)) => {
+            let pattern = sub.value_of("pattern").unwrap().to_string();
+            println!("{}", client.count(pattern)?);
+        }
+        ("stats", Some(_)) => {
+            let stats = client.stats()?;
+            println!("Keys: {}", stats.keys);
+            for (level, segments) in stats.segments_per_level.iter().enumerate() {
+                println!("Level {} segments: {}", level + 1, segments);
+            }
+            println!("Disk size: {} bytes", stats.disk_size);
+            println!("WAL size: {} bytes", stats.wal_size);
+        }
+        ("compact", Some(_)) => client.compact()?,
+        ("verify", Some(_)) => {
+            let report = client.verify()?;
+            println!("Good records: {}", report.good);
+            println!("Corrupt records: {}", report.corrupt.len());
+            for key in report.corrupt {
+                println!("{}", String::from_utf8_lossy(&key));
+            }
+        }
+        ("dump", Some(sub)) => {
+            let path = sub.value_of("file").unwrap();
+            let dumped = client.dump(BufWriter::new(File::create(path)?))?;
+            println!("Dumped {} keys to {}", dumped, path);
+        }
+        ("load", Some(sub)) => {
+            let path = sub.value_of("file").unwrap();
+            let loaded = client.load(BufReader::new(File::open(path)?))?;
+            println!("Loaded {} keys from {}", loaded, path);
+        }
         ("test", Some(sub)) => {
             let operation = match sub.value_of("operation") {
                 Some("get") => "get",
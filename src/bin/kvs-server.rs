@@ -1,15 +1,21 @@
 use clap_v3::{App, Arg};
 use kvs::*;
 use log::LevelFilter;
-use log::{error, info};
+use log::{error, info, warn};
 use std::env::current_dir;
 use std::fs;
+use std::io;
 use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1";
 
+#[derive(Clone, Copy)]
 enum Engine {
     Kvs,
     Sled,
@@ -40,6 +46,26 @@ impl std::fmt::Display for Engine {
     }
 }
 
+/// A single engine served on its own port, parsed from `<engine>:<port>`
+struct Binding {
+    engine: Engine,
+    port: u16,
+}
+
+impl FromStr for Binding {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (engine, port) = s.split_once(':').ok_or("binding must be <engine>:<port>")?;
+        Ok(Binding {
+            engine: engine.parse()?,
+            port: port
+                .parse()
+                .map_err(|_| "binding port is not a valid number")?,
+        })
+    }
+}
+
 fn main() {
     env_logger::init();
     let opt = App::new("kvs-server")
@@ -62,7 +88,26 @@ fn main() {
             Arg::from("<engine> 'The type of engine to use'")
                 .short('e')
                 .default_value("kvs")
-                .possible_values(&["kvs", "sled"]),
+                .possible_values(&["kvs", "sled", "memory"]),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .short('b')
+                .long("bind")
+                .takes_value(true)
+                .multiple(true)
+                .help("Serve an engine on its own port as <engine>:<port>. May be repeated"),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("Start even if the data was written by a different engine"),
+        )
+        .arg(
+            Arg::with_name("password")
+                .long("password")
+                .takes_value(true)
+                .help("Require clients to authenticate with this password"),
         )
         .get_matches();
 
@@ -70,30 +115,131 @@ fn main() {
     let engine: Engine = engine_str.parse().unwrap();
     let address = opt.value_of("addr").unwrap();
     let port = opt.value_of("port").unwrap();
+    let force = opt.is_present("force");
+    let password = opt.value_of("password");
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
-    info!("Storage engine: {}", engine_str);
-    info!("Listening on {}", address);
 
-    if let Err(e) = run(engine, address, port) {
+    let result = match opt.values_of("bind") {
+        Some(bindings) => bindings
+            .map(Binding::from_str)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| KvError::Parse(e.into()))
+            .and_then(|bindings| run_many(bindings, address, force, password)),
+        None => {
+            info!("Storage engine: {}", engine_str);
+            info!("Listening on {}:{}", address, port);
+            current_dir()
+                .map_err(KvError::from)
+                .and_then(|dir| run(engine, &dir, address, port, force, password))
+        }
+    };
+
+    if let Err(e) = result {
         error!("{}", e);
         exit(1);
     }
 }
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: impl Into<SocketAddr>) -> Result<()> {
-    let server = KvServer::new(engine);
+fn run_with_engine<E: KvsEngine>(
+    engine: E,
+    addr: impl Into<SocketAddr>,
+    password: Option<&str>,
+) -> Result<()> {
+    let mut server = KvServer::new(engine);
+    if let Some(password) = password {
+        server = server.with_password(password);
+    }
     server.run(addr.into())
 }
 
-fn run(engine: Engine, address: &str, port: &str) -> Result<()> {
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+/// Run every binding on its own thread. Each engine keeps its data in a
+/// `<engine>-<port>` folder so that bindings never share state. Returns the
+/// first error of any binding, even while the other bindings keep serving.
+fn run_many(
+    bindings: Vec<Binding>,
+    address: &str,
+    force: bool,
+    password: Option<&str>,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    for binding in bindings {
+        let dir = current_dir()?.join(format!("{}-{}", binding.engine, binding.port));
+        fs::create_dir_all(&dir)?;
+        let address = address.to_string();
+        let password = password.map(str::to_string);
+        let sender = sender.clone();
+        info!(
+            "Storage engine {} listening on {}:{}",
+            binding.engine, address, binding.port
+        );
+        thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                run(
+                    binding.engine,
+                    &dir,
+                    &address,
+                    &binding.port.to_string(),
+                    force,
+                    password.as_deref(),
+                )
+            }))
+            .unwrap_or_else(|_| Err(KvError::StringError("Engine thread panicked".into())));
+            let _ = sender.send(result);
+        });
+    }
+    drop(sender);
+
+    // report the first binding that fails, no matter which binding it is
+    for result in receiver {
+        result?;
+    }
+    Ok(())
+}
+
+/// Make sure the data in `dir` was written by `engine`, so one engine never
+/// opens another engine's files. A directory without an `engine` file is new.
+fn check_engine(engine: Engine, dir: &Path, force: bool) -> Result<()> {
+    let found = match fs::read_to_string(dir.join("engine")) {
+        Ok(found) => found.trim().to_string(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if found == engine.to_string() {
+        return Ok(());
+    }
+    let mismatch = KvError::EngineMismatch {
+        found,
+        requested: engine.to_string(),
+    };
+    if force {
+        warn!("{}, starting anyway", mismatch);
+        Ok(())
+    } else {
+        Err(mismatch)
+    }
+}
+
+fn run(
+    engine: Engine,
+    dir: &Path,
+    address: &str,
+    port: &str,
+    force: bool,
+    password: Option<&str>,
+) -> Result<()> {
+    // the memory engine never reads or writes the directory, so it can run
+    // anywhere and leaves the marker of the engine that owns the data alone
+    if !matches!(engine, Engine::Memory) {
+        check_engine(engine, dir, force)?;
+        fs::write(dir.join("engine"), format!("{}", engine))?;
+    }
     let ip = SocketAddr::new(IpAddr::from_str(address).unwrap(), port.parse().unwrap());
 
     match engine {
-        Engine::Kvs => run_with_engine(KvStore::restore("./.temp")?, ip)?,
-        Engine::Sled => run_with_engine(SledKvsEngine::restore(current_dir()?.as_path())?, ip)?,
-        Engine::Memory => run_with_engine(KvInMemoryStore::restore("").unwrap(), ip)?,
+        Engine::Kvs => run_with_engine(KvStore::restore(dir.join(".temp"))?, ip, password)?,
+        Engine::Sled => run_with_engine(SledKvsEngine::restore(PathBuf::from(dir))?, ip, password)?,
+        Engine::Memory => run_with_engine(KvInMemoryStore::restore("").unwrap(), ip, password)?,
     };
 
     Ok(())
@@ -1,29 +1,199 @@
-use crate::common::{FindResponse, GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvError, Result};
-use serde_json::de::IoRead;
+use crate::common::{
+    read_frame, read_whole_entry, write_entry, AppendResponse, AuthResponse, CompactResponse,
+    CountResponse, DumpResponse, FindResponse, GetResponse, LoadResponse, RemoveResponse, Request,
+    SetResponse, StatsResponse, SubscribeResponse, VerifyResponse,
+};
+use crate::{KvError, Notification, Result, StoreStats, VerifyReport, WireCodec};
 use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+/// Number of dump bytes sent to the server in a single load request
+const LOAD_BATCH_SIZE: usize = 64 * 1024;
+
+/// How a `KvClient` reconnects after its connection to the server fails.
+/// The delay between attempts starts at `backoff` and doubles after every
+/// failed attempt.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: usize,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Retry a failed request up to `max_retries` times, waiting `backoff`
+    /// before the first reconnect.
+    pub fn new(max_retries: usize, backoff: Duration) -> Self {
+        Self {
+            max_retries,
+            backoff,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(5, Duration::from_millis(100))
+    }
+}
 
 /// Key value store client
 pub struct KvClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    addrs: Vec<SocketAddr>,
+    retry: Option<RetryPolicy>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    codec: WireCodec,
+    token: Option<String>,
+    authenticated: bool,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
 }
 
 impl KvClient {
     /// Connect to `addr` to access `KvsServer`
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+        Self::open(addr.to_socket_addrs()?.collect(), None, None, None)
+    }
+
+    /// Connect to `addr` to access `KvsServer`, giving up with
+    /// `KvError::Timeout` if the server doesn't accept the connection within
+    /// `connect_timeout` or doesn't answer a request within
+    /// `request_timeout`. After a request times out the connection may
+    /// still receive the late response, so create a new client.
+    pub fn connect_with_timeout<A: ToSocketAddrs>(
+        addr: A,
+        connect_timeout: Duration,
+        request_timeout: Duration,
+    ) -> Result<Self> {
+        Self::open(
+            addr.to_socket_addrs()?.collect(),
+            None,
+            Some(connect_timeout),
+            Some(request_timeout),
+        )
+    }
+
+    /// Connect to `addr` to access `KvsServer`. If the connection breaks,
+    /// for example because the server restarted, the client reconnects
+    /// following `retry` and sends the failed request again. A request
+    /// that reached the server before the connection broke may be applied
+    /// twice.
+    pub fn connect_with_retry<A: ToSocketAddrs>(addr: A, retry: RetryPolicy) -> Result<Self> {
+        Self::open(addr.to_socket_addrs()?.collect(), Some(retry), None, None)
+    }
+
+    /// Connect to `addr` to access a `KvsServer` that requires a password.
+    /// `token` is sent before the first request on every connection, so a
+    /// wrong token is reported as the error of that request and the server
+    /// closes the connection.
+    pub fn connect_with_auth<A: ToSocketAddrs>(addr: A, token: impl Into<String>) -> Result<Self> {
+        let mut client = Self::connect(addr)?;
+        client.token = Some(token.into());
+        client.authenticated = false;
+        Ok(client)
+    }
+
+    fn open(
+        addrs: Vec<SocketAddr>,
+        retry: Option<RetryPolicy>,
+        connect_timeout: Option<Duration>,
+        request_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let (reader, writer) = Self::open_stream(&addrs, connect_timeout, request_timeout)?;
         Ok(KvClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
+            addrs,
+            retry,
+            connect_timeout,
+            request_timeout,
+            codec: WireCodec::default(),
+            token: None,
+            authenticated: true,
+            reader,
+            writer,
         })
     }
 
-    /// Get the value of a given key from the server.
+    /// Encode requests and responses with `codec` instead of JSON. The
+    /// server must be using the same codec.
+    pub fn with_codec(mut self, codec: WireCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    fn connect_stream(addrs: &[SocketAddr], timeout: Option<Duration>) -> Result<TcpStream> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return TcpStream::connect(addrs).map_err(classify_io),
+        };
+        let mut last_error = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(classify_io(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to")
+        })))
+    }
+
+    fn open_stream(
+        addrs: &[SocketAddr],
+        connect_timeout: Option<Duration>,
+        request_timeout: Option<Duration>,
+    ) -> Result<(BufReader<TcpStream>, BufWriter<TcpStream>)> {
+        let tcp_reader = Self::connect_stream(addrs, connect_timeout)?;
+        tcp_reader.set_read_timeout(request_timeout)?;
+        tcp_reader.set_write_timeout(request_timeout)?;
+        let tcp_writer = tcp_reader.try_clone()?;
+        Ok((BufReader::new(tcp_reader), BufWriter::new(tcp_writer)))
+    }
+
+    /// Replace the broken connection with a new one, waiting between
+    /// attempts as described by the retry policy.
+    fn reconnect(&mut self, retry: &RetryPolicy) -> Result<()> {
+        let mut backoff = retry.backoff;
+        let mut attempt = 0;
+        loop {
+            thread::sleep(backoff);
+            attempt += 1;
+            match Self::open_stream(&self.addrs, self.connect_timeout, self.request_timeout) {
+                Ok((reader, writer)) => {
+                    self.reader = reader;
+                    self.writer = writer;
+                    self.authenticated = self.token.is_none();
+                    return Ok(());
+                }
+                Err(e) if attempt >= retry.max_retries => return Err(e),
+                Err(e) => {
+                    warn!("Reconnect attempt {} failed: {}", attempt, e);
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+
+    /// Get the value of a given key from the server. Fails if the value
+    /// isn't valid UTF-8, use `get_bytes` to read binary values.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the value of a given key from the server, or `default` if the key
+    /// doesn't exist. Errors reading the key or talking to the server are
+    /// still returned.
+    pub fn get_or(&mut self, key: String, default: String) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// Get the raw bytes of the value of a given key from the server.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         match self.write(&Request::Get { key })? {
             GetResponse::Ok(value) => Ok(value),
             GetResponse::Err(msg) => Err(KvError::StringError(msg.into())),
@@ -32,41 +202,395 @@ impl KvClient {
 
     /// Set the value of a string key in the server.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set the value of a key in the server. Neither the key nor the value
+    /// has to be valid UTF-8.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         match self.write(&Request::Set { key, value })? {
             SetResponse::Ok(_) => Ok(()),
             SetResponse::Err(msg) => Err(KvError::StringError(msg.into())),
         }
     }
 
-    /// Find a list of keys given a pattern from the server.
-    pub fn find(&mut self, pattern: String) -> Result<Vec<String>> {
-        match self.write(&Request::Find { pattern })? {
-            FindResponse::Ok(mut list) => Ok(list
-                .drain(..)
-                .map(|b| {
-                    String::from_utf8(b).unwrap_or_else(|err| format!("<from_utf8_error> {}", err))
-                })
-                .collect::<Vec<_>>()),
+    /// Find a list of keys given a pattern from the server. Set `ignore_case`
+    /// to match ASCII letters without regard to case. Keys that aren't valid
+    /// UTF-8 are replaced with a description of the error, use `find_bytes`
+    /// to get binary keys.
+    pub fn find(&mut self, pattern: String, ignore_case: bool) -> Result<Vec<String>> {
+        self.find_page(pattern, ignore_case, 0, None)
+    }
+
+    /// Find one page of the keys matching a pattern from the server. The
+    /// sorted keys are skipped up to `offset` and at most `limit` of them
+    /// are returned.
+    pub fn find_page(
+        &mut self,
+        pattern: String,
+        ignore_case: bool,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .find_page_bytes(pattern.into_bytes(), ignore_case, offset, limit)?
+            .drain(..)
+            .map(|b| {
+                String::from_utf8(b).unwrap_or_else(|err| format!("<from_utf8_error> {}", err))
+            })
+            .collect::<Vec<_>>())
+    }
+
+    /// Find the raw bytes of the keys matching a pattern from the server.
+    pub fn find_bytes(&mut self, pattern: Vec<u8>, ignore_case: bool) -> Result<Vec<Vec<u8>>> {
+        self.find_page_bytes(pattern, ignore_case, 0, None)
+    }
+
+    /// Find one page of the raw bytes of the keys matching a pattern from
+    /// the server.
+    pub fn find_page_bytes(
+        &mut self,
+        pattern: Vec<u8>,
+        ignore_case: bool,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Vec<Vec<u8>>> {
+        match self.write(&Request::Find {
+            pattern,
+            ignore_case,
+            offset,
+            limit,
+        })? {
+            FindResponse::Ok(list) => Ok(list),
             FindResponse::Err(err) => Err(KvError::StringError(err.into())),
         }
     }
 
+    /// Count the keys matching a pattern on the server, without sending the
+    /// keys themselves.
+    pub fn count(&mut self, pattern: String) -> Result<usize> {
+        self.count_bytes(pattern.into_bytes())
+    }
+
+    /// Count the keys matching a pattern that may not be valid UTF-8
+    pub fn count_bytes(&mut self, pattern: Vec<u8>) -> Result<usize> {
+        match self.write(&Request::Count { pattern })? {
+            CountResponse::Ok(count) => Ok(count),
+            CountResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
     /// Remove a value from the key value store
     pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Remove the value of a key that may not be valid UTF-8
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
         match self.write(&Request::Remove { key })? {
             RemoveResponse::Ok(_) => Ok(()),
             RemoveResponse::Err(msg) => Err(KvError::StringError(msg.into())),
         }
     }
 
+    /// Append a string to the end of the value of a key in the server.
+    /// Returns the length of the value after the append.
+    pub fn append(&mut self, key: String, suffix: String) -> Result<usize> {
+        self.append_bytes(key.into_bytes(), suffix.into_bytes())
+    }
+
+    /// Append raw bytes to the end of the value of a key in the server,
+    /// creating the key if it doesn't exist. An append is never retried,
+    /// since the server may have applied it before the connection was lost.
+    pub fn append_bytes(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize> {
+        match self.send(&Request::Append { key, suffix })? {
+            AppendResponse::Ok(len) => Ok(len),
+            AppendResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
+    /// Get statistics about the number of keys and disk usage of the server.
+    pub fn stats(&mut self) -> Result<StoreStats> {
+        match self.write(&Request::Stats)? {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
+    /// Ask the server to reclaim the space used by overwritten and removed keys.
+    pub fn compact(&mut self) -> Result<()> {
+        match self.write(&Request::Compact)? {
+            CompactResponse::Ok(()) => Ok(()),
+            CompactResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
+    /// Ask the server to check every record it keeps on disk against its
+    /// checksum.
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        match self.write(&Request::Verify)? {
+            VerifyResponse::Ok(report) => Ok(report),
+            VerifyResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
+    /// Write every key and value held by the server to `writer`, in the
+    /// format read by `KvsEngine::import`. Returns the number of keys that
+    /// were written. A dump is never retried, since part of it may already
+    /// have been written.
+    pub fn dump(&mut self, mut writer: impl Write) -> Result<usize> {
+        let mut dumped = 0;
+        let mut response = self.send(&Request::Dump)?;
+        loop {
+            match response {
+                DumpResponse::Entry { key, value } => {
+                    write_entry(&mut writer, &key, &value)?;
+                    dumped += 1;
+                }
+                DumpResponse::Done => break,
+                DumpResponse::Err(msg) => return Err(KvError::StringError(msg.into())),
+            }
+            response = self.read()?;
+        }
+        writer.flush()?;
+        Ok(dumped)
+    }
+
+    /// Set every key and value of a dump written by `dump` or
+    /// `KvsEngine::export`. The dump is sent to the server in batches.
+    /// A truncated entry at the end of the dump is skipped. Returns the
+    /// number of keys that were loaded.
+    pub fn load(&mut self, mut reader: impl Read) -> Result<usize> {
+        let mut loaded = 0;
+        let mut batch = vec![];
+        loop {
+            let entry = read_whole_entry(&mut reader)?;
+            let done = entry.is_none();
+            if let Some((key, value)) = entry {
+                write_entry(&mut batch, &key, &value)?;
+            }
+            if !batch.is_empty() && (done || batch.len() >= LOAD_BATCH_SIZE) {
+                let dump = std::mem::take(&mut batch);
+                match self.write(&Request::Load { dump })? {
+                    LoadResponse::Ok(count) => loaded += count,
+                    LoadResponse::Err(msg) => return Err(KvError::StringError(msg.into())),
+                }
+            }
+            if done {
+                return Ok(loaded);
+            }
+        }
+    }
+
+    /// Watch every key matching a pattern. The connection is handed over to
+    /// the returned `Subscription`, which yields a `Notification` each time
+    /// a matching key is set or removed on the server. Waiting for the next
+    /// notification never times out.
+    pub fn subscribe(mut self, pattern: Vec<u8>) -> Result<Subscription> {
+        match self.write(&Request::Subscribe { pattern })? {
+            SubscribeResponse::Ok(()) => {
+                self.reader.get_ref().set_read_timeout(None)?;
+                Ok(Subscription {
+                    client: self,
+                    closed: false,
+                })
+            }
+            SubscribeResponse::Notification { .. } => Err(KvError::StringError(
+                "Server sent a notification before the subscription started".into(),
+            )),
+            SubscribeResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
     fn write<T, R>(&mut self, t: &T) -> Result<R>
     where
         T: ?Sized + serde::Serialize,
         R: serde::de::DeserializeOwned,
     {
-        serde_json::to_writer(&mut self.writer, &t)?;
-        self.writer.flush()?;
-        let resp = R::deserialize(&mut self.reader)?;
-        Ok(resp)
+        let retry = match self.retry.clone() {
+            Some(retry) => retry,
+            None => return self.send(t),
+        };
+        let mut retries = 0;
+        loop {
+            match self.send(t) {
+                Err(e) if retries < retry.max_retries && is_connection_error(&e) => {
+                    warn!("Lost connection to the server: {}", e);
+                    retries += 1;
+                    self.reconnect(&retry)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn send<T, R>(&mut self, t: &T) -> Result<R>
+    where
+        T: ?Sized + serde::Serialize,
+        R: serde::de::DeserializeOwned,
+    {
+        if !self.authenticated {
+            self.authenticate()?;
+        }
+        self.codec.write(&mut self.writer, t).map_err(classify)?;
+        self.writer.flush().map_err(classify_io)?;
+        self.read()
+    }
+
+    /// Send the token of the client to the server
+    fn authenticate(&mut self) -> Result<()> {
+        let token = self.token.clone().unwrap_or_default();
+        self.codec
+            .write(&mut self.writer, &Request::Auth { token })
+            .map_err(classify)?;
+        self.writer.flush().map_err(classify_io)?;
+        match self.read()? {
+            AuthResponse::Ok(()) => {
+                self.authenticated = true;
+                Ok(())
+            }
+            AuthResponse::Err(msg) => Err(KvError::StringError(msg.into())),
+        }
+    }
+
+    fn read<R>(&mut self) -> Result<R>
+    where
+        R: serde::de::DeserializeOwned,
+    {
+        match self.codec {
+            WireCodec::Json => R::deserialize(&mut Deserializer::from_reader(&mut self.reader))
+                .map_err(KvError::from),
+            WireCodec::Bincode => read_frame(&mut self.reader).and_then(|resp| {
+                resp.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "Server closed the connection")
+                        .into()
+                })
+            }),
+        }
+        .map_err(classify)
+    }
+}
+
+/// Changes streamed by the server to a client that called
+/// [`KvClient::subscribe`]. The iterator ends with an error once the
+/// connection to the server is lost.
+pub struct Subscription {
+    client: KvClient,
+    closed: bool,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Notification>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.closed {
+            return None;
+        }
+        let error = match self.client.read() {
+            Ok(SubscribeResponse::Notification { key, value }) => {
+                return Some(Ok(Notification { key, value }))
+            }
+            Ok(SubscribeResponse::Ok(())) => {
+                KvError::StringError("Server started the subscription twice".into())
+            }
+            Ok(SubscribeResponse::Err(msg)) => KvError::StringError(msg.into()),
+            Err(e) => e,
+        };
+        self.closed = true;
+        Some(Err(error))
+    }
+}
+
+/// Turn an IO error caused by a socket timeout into `KvError::Timeout`, and
+/// one caused by the server refusing or dropping the connection into
+/// `KvError::ConnectionRefused` or `KvError::ConnectionReset`
+fn classify_io(err: io::Error) -> KvError {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => KvError::Timeout(err),
+        io::ErrorKind::ConnectionRefused => KvError::ConnectionRefused(err),
+        io::ErrorKind::ConnectionReset => KvError::ConnectionReset(err),
+        _ => KvError::Io(err),
+    }
+}
+
+/// Report IO errors hidden inside of other errors as plain IO errors so
+/// timeouts and broken connections can be told apart from bad responses.
+fn classify(err: KvError) -> KvError {
+    match err {
+        KvError::Io(err) => classify_io(err),
+        KvError::Json(err) if err.is_io() => classify_io(err.into()),
+        err => err,
+    }
+}
+
+/// Whether the error means the connection to the server is broken, rather
+/// than the server answering with an error.
+fn is_connection_error(err: &KvError) -> bool {
+    match err {
+        KvError::Io(_) | KvError::ConnectionRefused(_) | KvError::ConnectionReset(_) => true,
+        KvError::Json(e) => e.is_io() || e.is_eof(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use serde::de::Error;
+
+    use super::{classify, classify_io, is_connection_error};
+    use crate::KvError;
+
+    fn io_error(kind: io::ErrorKind) -> io::Error {
+        io::Error::new(kind, "socket failed")
+    }
+
+    #[test]
+    fn io_errors_are_classified_by_kind() {
+        let classified = |kind| classify_io(io_error(kind));
+        assert!(matches!(
+            classified(io::ErrorKind::TimedOut),
+            KvError::Timeout(_)
+        ));
+        assert!(matches!(
+            classified(io::ErrorKind::WouldBlock),
+            KvError::Timeout(_)
+        ));
+        assert!(matches!(
+            classified(io::ErrorKind::ConnectionRefused),
+            KvError::ConnectionRefused(_)
+        ));
+        assert!(matches!(
+            classified(io::ErrorKind::ConnectionReset),
+            KvError::ConnectionReset(_)
+        ));
+        assert!(matches!(
+            classified(io::ErrorKind::BrokenPipe),
+            KvError::Io(_)
+        ));
+    }
+
+    #[test]
+    fn wrapped_io_errors_are_classified() {
+        let reset = KvError::Io(io_error(io::ErrorKind::ConnectionReset));
+        assert!(matches!(classify(reset), KvError::ConnectionReset(_)));
+        let json = serde_json::Error::io(io_error(io::ErrorKind::ConnectionRefused));
+        assert!(matches!(
+            classify(KvError::Json(json)),
+            KvError::ConnectionRefused(_)
+        ));
+        let bad_response = KvError::Json(serde_json::Error::custom("bad response"));
+        assert!(matches!(classify(bad_response), KvError::Json(_)));
+    }
+
+    #[test]
+    fn refused_and_reset_connections_are_retried() {
+        let refused = classify_io(io_error(io::ErrorKind::ConnectionRefused));
+        let reset = classify_io(io_error(io::ErrorKind::ConnectionReset));
+        let timeout = classify_io(io_error(io::ErrorKind::TimedOut));
+        assert!(is_connection_error(&refused));
+        assert!(is_connection_error(&reset));
+        assert!(!is_connection_error(&timeout));
     }
 }
@@ -1,18 +1,282 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::SystemTime;
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
+use crate::{KvError, Result, StoreStats, VerifyReport};
+
+/// Largest timestamp handed out by `next_timestamp` so far
+static LAST_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+/// Size of the big endian length written before every bincode frame
+const FRAME_HEADER_SIZE: usize = 4;
+
+/// How requests and responses are encoded on the wire. The client and the
+/// server must be configured with the same codec.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WireCodec {
+    /// Self delimiting JSON values, readable by any JSON client
+    #[default]
+    Json,
+    /// Bincode messages, each preceded by its length as a 4 byte big endian
+    /// integer. Cheaper to parse and keeps binary data compact.
+    Bincode,
+}
+
+impl WireCodec {
+    /// Write a single message to `writer`
+    pub(crate) fn write<W: Write, T: ?Sized + Serialize>(
+        self,
+        writer: &mut W,
+        message: &T,
+    ) -> Result<()> {
+        match self {
+            WireCodec::Json => serde_json::to_writer(writer, message)?,
+            WireCodec::Bincode => write_bytes(writer, &bincode::serialize(message)?)?,
+        }
+        Ok(())
+    }
+}
+
+/// Serde adapters that write bytes to human readable formats, like the JSON
+/// codec, as base64 strings instead of arrays of numbers. Other formats, like
+/// bincode, still write them as a length followed by the raw bytes.
+pub(crate) mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(bytes))
+        } else {
+            bytes.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            STANDARD.decode(encoded).map_err(de::Error::custom)
+        } else {
+            Vec::deserialize(deserializer)
+        }
+    }
+
+    /// Bytes nested inside of another type, such as an `Option` or a `Vec`
+    struct Bytes<'a>(&'a [u8]);
+
+    impl Serialize for Bytes<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(self.0, serializer)
+        }
+    }
+
+    struct ByteBuf(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for ByteBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize(deserializer).map(ByteBuf)
+        }
+    }
+
+    pub mod option {
+        use super::{ByteBuf, Bytes};
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            bytes: &Option<Vec<u8>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            bytes.as_deref().map(Bytes).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<u8>>, D::Error> {
+            Ok(Option::<ByteBuf>::deserialize(deserializer)?.map(|bytes| bytes.0))
+        }
+    }
+
+    pub mod list {
+        use super::{ByteBuf, Bytes};
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            list: &[Vec<u8>],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(list.iter().map(|bytes| Bytes(bytes)))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<Vec<u8>>, D::Error> {
+            let list = Vec::<ByteBuf>::deserialize(deserializer)?;
+            Ok(list.into_iter().map(|bytes| bytes.0).collect())
+        }
+    }
+}
+
+/// Read the 4 byte big endian length that starts a frame. Returns `None` if
+/// the stream ended cleanly before the length started.
+fn read_length<R: Read>(reader: &mut R) -> Result<Option<usize>> {
+    let mut header = [0; FRAME_HEADER_SIZE];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(u32::from_be_bytes(header) as usize))
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    let length = u32::try_from(bytes.len())
+        .map_err(|_| KvError::Parse("Message is too large to fit in a frame".into()))?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+/// Read exactly `length` bytes. The length comes from the peer or the dump,
+/// so the buffer only grows as the bytes arrive instead of being allocated
+/// up front, and a bogus length can't make us allocate gigabytes.
+fn read_bytes<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    reader
+        .by_ref()
+        .take(length as u64)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() < length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+/// Read a single bincode frame. Returns `None` if the stream ended cleanly
+/// before the frame started.
+pub(crate) fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    match read_length(reader)? {
+        Some(length) => Ok(Some(bincode::deserialize(&read_bytes(reader, length)?)?)),
+        None => Ok(None),
+    }
+}
+
+/// Write a key and its value to a dump. Both are preceded by their length
+/// as a 4 byte big endian integer.
+pub(crate) fn write_entry<W: Write>(writer: &mut W, key: &[u8], value: &[u8]) -> Result<()> {
+    write_bytes(writer, key)?;
+    write_bytes(writer, value)
+}
+
+/// Read a key and its value written by `write_entry`. Returns `None` once
+/// the dump has ended.
+pub(crate) fn read_entry<R: Read>(reader: &mut R) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let key = match read_length(reader)? {
+        Some(length) => read_bytes(reader, length)?,
+        None => return Ok(None),
+    };
+    let value = match read_length(reader)? {
+        Some(length) => read_bytes(reader, length)?,
+        None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    };
+    Ok(Some((key, value)))
+}
+
+/// Read a key and its value like `read_entry`, except a dump that stops part
+/// way through its last entry, for example because the export was cut short,
+/// ends before that entry instead of failing.
+pub(crate) fn read_whole_entry<R: Read>(reader: &mut R) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match read_entry(reader) {
+        Err(KvError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            warn!("Dump ends with a truncated entry, it was skipped");
+            Ok(None)
+        }
+        result => result,
+    }
+}
+
+/// Decode a bincode frame from the front of `buffer`, removing its bytes.
+/// Returns `None` if the buffer doesn't hold a whole frame yet.
+pub(crate) fn decode_frame<T: DeserializeOwned>(buffer: &mut Vec<u8>) -> Result<Option<T>> {
+    if buffer.len() < FRAME_HEADER_SIZE {
+        return Ok(None);
+    }
+    let mut header = [0; FRAME_HEADER_SIZE];
+    header.copy_from_slice(&buffer[..FRAME_HEADER_SIZE]);
+    let end = FRAME_HEADER_SIZE + u32::from_be_bytes(header) as usize;
+    if buffer.len() < end {
+        return Ok(None);
+    }
+    let message = bincode::deserialize(&buffer[FRAME_HEADER_SIZE..end])?;
+    buffer.drain(..end);
+    Ok(Some(message))
+}
+
+/// Keys, values and patterns are sent as raw bytes, so they don't have to
+/// be valid UTF-8. JSON carries them as base64 strings.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Find { pattern: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+    Find {
+        #[serde(with = "base64_bytes")]
+        pattern: Vec<u8>,
+        #[serde(default)]
+        ignore_case: bool,
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    Set {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+    Append {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "base64_bytes")]
+        suffix: Vec<u8>,
+    },
+    Count {
+        #[serde(with = "base64_bytes")]
+        pattern: Vec<u8>,
+    },
+    Stats,
+    Compact,
+    Verify,
+    Dump,
+    Load {
+        #[serde(with = "base64_bytes")]
+        dump: Vec<u8>,
+    },
+    Subscribe {
+        #[serde(with = "base64_bytes")]
+        pattern: Vec<u8>,
+    },
+    Auth {
+        token: String,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
-    Ok(Option<String>),
+    Ok(#[serde(with = "base64_bytes::option")] Option<Vec<u8>>),
     Err(String),
 }
 
@@ -28,9 +292,82 @@ pub enum RemoveResponse {
     Err(String),
 }
 
+/// Length of the value after the suffix was appended
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AppendResponse {
+    Ok(usize),
+    Err(String),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum FindResponse {
-    Ok(Vec<Vec<u8>>),
+    Ok(#[serde(with = "base64_bytes::list")] Vec<Vec<u8>>),
+    Err(String),
+}
+
+/// Number of keys matching the pattern
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CountResponse {
+    Ok(usize),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(StoreStats),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CompactResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum VerifyResponse {
+    Ok(VerifyReport),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LoadResponse {
+    Ok(usize),
+    Err(String),
+}
+
+/// A dump is streamed as one `Entry` for every live key, followed by `Done`
+/// or `Err` if the dump failed part way through
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DumpResponse {
+    Entry {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+    },
+    Done,
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// A subscription is answered with `Ok` once the server is watching the
+/// pattern, then a `Notification` is streamed for every matching change.
+/// The connection can't be used for other requests afterwards.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SubscribeResponse {
+    Ok(()),
+    Notification {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "base64_bytes::option")]
+        value: Option<Vec<u8>>,
+    },
     Err(String),
 }
 
@@ -40,3 +377,190 @@ pub fn now() -> u128 {
         .unwrap()
         .as_nanos()
 }
+
+/// Timestamp in nanoseconds since the unix epoch that is larger than every
+/// timestamp returned before it, so writes are ordered even when they land
+/// in the same nanosecond or the clock steps back. It follows the wall clock
+/// whenever the clock is ahead of the last timestamp.
+pub fn next_timestamp() -> u128 {
+    let wall = now() as u64;
+    let mut last = LAST_TIMESTAMP.load(Ordering::SeqCst);
+    loop {
+        let next = wall.max(last + 1);
+        match LAST_TIMESTAMP.compare_exchange_weak(last, next, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return next as u128,
+            Err(actual) => last = actual,
+        }
+    }
+}
+
+/// Make sure every timestamp handed out from now on is larger than
+/// `timestamp`, which was read back from disk
+pub fn observe_timestamp(timestamp: u128) {
+    LAST_TIMESTAMP.fetch_max(timestamp as u64, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_frame, next_timestamp, observe_timestamp, read_entry, read_frame, write_entry,
+        FindResponse, GetResponse, Request, WireCodec,
+    };
+
+    fn request() -> Request {
+        Request::Set {
+            key: b"key\x001".to_vec(),
+            value: b"value\xff".to_vec(),
+        }
+    }
+
+    #[test]
+    fn bincode_frame_round_trip() {
+        let mut bytes = vec![];
+        WireCodec::Bincode.write(&mut bytes, &request()).unwrap();
+        WireCodec::Bincode
+            .write(&mut bytes, &Request::Stats)
+            .unwrap();
+
+        let mut reader = &bytes[..];
+        let first: Request = read_frame(&mut reader).unwrap().unwrap();
+        assert!(matches!(first, Request::Set { ref key, .. } if key == b"key\x001"));
+        let second: Request = read_frame(&mut reader).unwrap().unwrap();
+        assert!(matches!(second, Request::Stats));
+        assert!(read_frame::<_, Request>(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn json_carries_bytes_as_base64() {
+        let mut bytes = vec![];
+        WireCodec::Json.write(&mut bytes, &request()).unwrap();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            r#"{"Set":{"key":"a2V5ADE=","value":"dmFsdWX/"}}"#
+        );
+
+        let json = serde_json::to_string(&GetResponse::Ok(Some(b"\xff\x00".to_vec()))).unwrap();
+        assert_eq!(json, r#"{"Ok":"/wA="}"#);
+        let json = serde_json::to_string(&GetResponse::Ok(None)).unwrap();
+        assert_eq!(json, r#"{"Ok":null}"#);
+        let found = FindResponse::Ok(vec![b"a".to_vec(), b"\xfe".to_vec()]);
+        let json = serde_json::to_string(&found).unwrap();
+        assert_eq!(json, r#"{"Ok":["YQ==","/g=="]}"#);
+        match serde_json::from_str(&json).unwrap() {
+            FindResponse::Ok(keys) => assert_eq!(keys, vec![b"a".to_vec(), b"\xfe".to_vec()]),
+            FindResponse::Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn bincode_keeps_writing_raw_bytes() {
+        let set = bincode::serialize(&request()).unwrap();
+        let key = b"key\x001".to_vec();
+        let value = b"value\xff".to_vec();
+        // the variant index followed by the fields as plain byte vectors
+        let mut expected = bincode::serialize(&2u32).unwrap();
+        expected.extend(bincode::serialize(&key).unwrap());
+        expected.extend(bincode::serialize(&value).unwrap());
+        assert_eq!(set, expected);
+    }
+
+    #[test]
+    fn frame_longer_than_its_bytes_is_refused() {
+        // a header claiming a 4 GiB frame followed by only a few bytes
+        let mut bytes = u32::MAX.to_be_bytes().to_vec();
+        bytes.extend_from_slice(b"abc");
+        let mut reader = &bytes[..];
+        match read_frame::<_, Request>(&mut reader) {
+            Err(crate::KvError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+            other => panic!("expected an unexpected eof, got {:?}", other.map(|_| ())),
+        }
+        assert!(read_entry(&mut &bytes[..]).is_err());
+    }
+
+    #[test]
+    fn decode_frame_split_across_reads() {
+        let mut bytes = vec![];
+        WireCodec::Bincode.write(&mut bytes, &request()).unwrap();
+
+        // the header and part of the body arrive in the first fill
+        let mut buffer = bytes[..6].to_vec();
+        assert!(decode_frame::<Request>(&mut buffer).unwrap().is_none());
+        assert_eq!(buffer.len(), 6);
+
+        buffer.extend_from_slice(&bytes[6..]);
+        buffer.extend_from_slice(&bytes[..2]);
+        let decoded: Request = decode_frame(&mut buffer).unwrap().unwrap();
+        assert!(matches!(decoded, Request::Set { ref value, .. } if value == b"value\xff"));
+        assert_eq!(buffer, bytes[..2].to_vec());
+    }
+
+    #[test]
+    fn decode_frame_byte_by_byte_keeps_its_buffer() {
+        let mut bytes = vec![];
+        WireCodec::Bincode.write(&mut bytes, &request()).unwrap();
+
+        // an incomplete frame is left where it is, so the buffer is never
+        // copied or reallocated while the frame trickles in
+        let mut buffer = Vec::with_capacity(bytes.len());
+        let start = buffer.as_ptr();
+        for (received, byte) in bytes.iter().enumerate().take(bytes.len() - 1) {
+            buffer.push(*byte);
+            assert!(decode_frame::<Request>(&mut buffer).unwrap().is_none());
+            assert_eq!(buffer.len(), received + 1);
+            assert_eq!(buffer.as_ptr(), start);
+        }
+        buffer.push(*bytes.last().unwrap());
+        let decoded: Request = decode_frame(&mut buffer).unwrap().unwrap();
+        assert!(matches!(decoded, Request::Set { ref key, .. } if key == b"key\x001"));
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), start);
+    }
+
+    #[test]
+    fn dump_entry_round_trip() {
+        let mut bytes = vec![];
+        write_entry(&mut bytes, b"key1", b"value1").unwrap();
+        write_entry(&mut bytes, b"", b"").unwrap();
+
+        let mut reader = &bytes[..];
+        let first = read_entry(&mut reader).unwrap().unwrap();
+        assert_eq!(first, (b"key1".to_vec(), b"value1".to_vec()));
+        assert_eq!(read_entry(&mut reader).unwrap().unwrap(), (vec![], vec![]));
+        assert!(read_entry(&mut reader).unwrap().is_none());
+
+        // a dump that stops between a key and its value is an error
+        let mut reader = &bytes[..8];
+        assert!(read_entry(&mut reader).is_err());
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        let mut bytes = vec![];
+        WireCodec::Bincode.write(&mut bytes, &request()).unwrap();
+        let mut reader = &bytes[..bytes.len() - 1];
+        assert!(read_frame::<_, Request>(&mut reader).is_err());
+    }
+
+    #[test]
+    fn timestamps_always_increase() {
+        let threads = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| (0..10_000).map(|_| next_timestamp()).collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        let mut all = vec![];
+        for thread in threads {
+            let timestamps = thread.join().unwrap();
+            assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+            all.extend(timestamps);
+        }
+        all.sort_unstable();
+        all.dedup();
+        assert_eq!(all.len(), 40_000);
+
+        // a timestamp read back from the future moves the clock ahead
+        let future = next_timestamp() + 1_000_000_000;
+        observe_timestamp(future);
+        assert!(next_timestamp() > future);
+    }
+}
@@ -1,6 +1,9 @@
 use bit_vec::BitVec;
-use std::collections::hash_map::{DefaultHasher, RandomState};
-use std::hash::{BuildHasher, Hash, Hasher};
+use serde::{Deserialize, Serialize};
+
+/// Seeds used by every new filter. They are fixed so that two filters built
+/// from the same items are identical, no matter which process built them.
+const DEFAULT_SEEDS: [u64; 2] = [0x9e37_79b9_7f4a_7c15, 0xc2b2_ae3d_27d4_eb4f];
 
 /// A BloomFilter is a space effeint way to store the likely hood a given value
 /// is contained inside of a set. A Bloom filter is good for telling you if a
@@ -11,7 +14,9 @@ use std::hash::{BuildHasher, Hash, Hasher};
 /// https://richardstartin.github.io/posts/building-a-bloom-filter-from-scratch
 ///
 /// A fast standard Bloom Filter implementation that requires only two
-/// hash functions, generated by `std::collections::hash_map::DefaultHasher`.
+/// hash functions. Each hash function is a seeded FNV-1a hash, which unlike
+/// `std::collections::hash_map::DefaultHasher` is stable across rust releases
+/// so that a filter can be saved to disk and loaded again.
 ///
 /// If an item is not present in the filter then `contains` is guaranteed
 /// to return `false` for the queried item.
@@ -24,8 +29,17 @@ pub struct BloomFilter {
     optimal_m: usize,
     /// Number of hash functions.
     optimal_k: u32,
-    /// Two hash functions from which k number of hashes are derived.
-    hashers: [DefaultHasher; 2],
+    /// Seeds of the two hash functions from which k number of hashes are derived.
+    seeds: [u64; 2],
+}
+
+/// The on disk representation of a `BloomFilter`
+#[derive(Serialize, Deserialize)]
+struct PersistedBloomFilter {
+    bitmap: Vec<u8>,
+    optimal_m: usize,
+    optimal_k: u32,
+    seeds: [u64; 2],
 }
 
 impl BloomFilter {
@@ -34,20 +48,53 @@ impl BloomFilter {
     pub fn new(items_count: usize, fp_rate: f64) -> Self {
         let optimal_m = Self::bitmap_size(items_count, fp_rate);
         let optimal_k = Self::optimal_k(fp_rate);
-        let hashers = [
-            RandomState::new().build_hasher(),
-            RandomState::new().build_hasher(),
-        ];
         BloomFilter {
             bitmap: BitVec::from_elem(optimal_m, false),
             optimal_m,
             optimal_k,
-            hashers,
+            seeds: DEFAULT_SEEDS,
+        }
+    }
+
+    /// Number of bits in the bitmap of the filter
+    #[cfg(test)]
+    pub fn optimal_m(&self) -> usize {
+        self.optimal_m
+    }
+
+    /// Serialize the filter so it can be saved to disk. The seeds are saved
+    /// with the bitmap so a filter written by an older version, which used
+    /// random seeds, can still be loaded.
+    pub fn to_bytes(&self) -> crate::Result<Vec<u8>> {
+        let persisted = PersistedBloomFilter {
+            bitmap: self.bitmap.to_bytes(),
+            optimal_m: self.optimal_m,
+            optimal_k: self.optimal_k,
+            seeds: self.seeds,
+        };
+        Ok(bincode::serialize(&persisted)?)
+    }
+
+    /// Restore a filter that was serialized with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> crate::Result<Self> {
+        let persisted: PersistedBloomFilter = bincode::deserialize(bytes)?;
+        let mut bitmap = BitVec::from_bytes(&persisted.bitmap);
+        if bitmap.len() < persisted.optimal_m {
+            return Err(crate::KvError::Parse(
+                "Bloom filter bitmap is smaller than its size".into(),
+            ));
         }
+        bitmap.truncate(persisted.optimal_m);
+        Ok(BloomFilter {
+            bitmap,
+            optimal_m: persisted.optimal_m,
+            optimal_k: persisted.optimal_k,
+            seeds: persisted.seeds,
+        })
     }
 
     /// Insert item to the set.
-    pub fn insert(&mut self, item: &str) {
+    pub fn insert(&mut self, item: &[u8]) {
         let (h1, h2) = self.hash_kernel(item);
 
         for k_i in 0..self.optimal_k {
@@ -59,7 +106,7 @@ impl BloomFilter {
 
     /// Check if an item is present in the set.
     /// There can be false positives, but no false negatives.
-    pub fn contains(&self, item: &str) -> bool {
+    pub fn contains(&self, item: &[u8]) -> bool {
         let (h1, h2) = self.hash_kernel(item);
 
         for k_i in 0..self.optimal_k {
@@ -94,16 +141,250 @@ impl BloomFilter {
     }
 
     /// Calculate two hash values from which the k hashes are derived.
-    fn hash_kernel(&self, item: &str) -> (u64, u64) {
-        let hasher1 = &mut self.hashers[0].clone();
-        let hasher2 = &mut self.hashers[1].clone();
+    fn hash_kernel(&self, item: &[u8]) -> (u64, u64) {
+        let hash1 = Self::seeded_hash(self.seeds[0], item);
+        let hash2 = Self::seeded_hash(self.seeds[1], item);
 
-        item.hash(hasher1);
-        item.hash(hasher2);
+        (hash1, hash2)
+    }
 
-        let hash1 = hasher1.finish();
-        let hash2 = hasher2.finish();
+    /// FNV-1a hash starting from the given seed. The result is mixed at the
+    /// end so that similar items are spread over the whole bitmap.
+    fn seeded_hash(seed: u64, bytes: &[u8]) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ (hash >> 33)
+    }
+}
 
-        (hash1, hash2)
+/// A CountingBloomFilter is a `BloomFilter` that keeps a small counter in
+/// every slot instead of a single bit, so items can be removed again. It
+/// sizes itself and hashes items exactly like `BloomFilter`, which makes it
+/// fit level indexes that change incrementally instead of being rebuilt.
+///
+/// A counter saturates at `u8::MAX`. Once a counter is saturated it can no
+/// longer tell how many items share it, so it is never decremented again.
+/// This keeps the guarantee that `contains` never returns `false` for an
+/// item that was inserted, at the cost of the slot staying set forever.
+// Not used by the level indexes yet, only by its tests
+#[allow(dead_code)]
+pub struct CountingBloomFilter {
+    counters: Vec<u8>,
+    /// Number of counters.
+    optimal_m: usize,
+    /// Number of hash functions.
+    optimal_k: u32,
+    /// Seeds of the two hash functions from which k number of hashes are derived.
+    seeds: [u64; 2],
+}
+
+#[allow(dead_code)]
+impl CountingBloomFilter {
+    /// Create a new CountingBloomFilter that expects to store `items_count`
+    /// membership with a false positive rate of the value specified in `fp_rate`.
+    pub fn new(items_count: usize, fp_rate: f64) -> Self {
+        let optimal_m = BloomFilter::bitmap_size(items_count, fp_rate);
+        CountingBloomFilter {
+            counters: vec![0; optimal_m],
+            optimal_m,
+            optimal_k: BloomFilter::optimal_k(fp_rate),
+            seeds: DEFAULT_SEEDS,
+        }
+    }
+
+    /// Insert item to the set.
+    pub fn insert(&mut self, item: &[u8]) {
+        for index in self.indexes(item) {
+            let counter = &mut self.counters[index];
+            *counter = counter.saturating_add(1);
+        }
+    }
+
+    /// Remove an item that was inserted before. An item the filter doesn't
+    /// contain is left alone and `false` is returned, since decrementing its
+    /// counters could remove the items that share them. Removing an item that
+    /// was never inserted, but is a false positive, has the same effect, so
+    /// only items known to be in the set should be removed.
+    pub fn remove(&mut self, item: &[u8]) -> bool {
+        if !self.contains(item) {
+            return false;
+        }
+        for index in self.indexes(item) {
+            let counter = &mut self.counters[index];
+            if *counter != u8::MAX {
+                *counter -= 1;
+            }
+        }
+        true
+    }
+
+    /// Check if an item is present in the set.
+    /// There can be false positives, but no false negatives.
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.indexes(item).all(|index| self.counters[index] > 0)
+    }
+
+    /// The k counters an item maps to. An index can appear more than once.
+    fn indexes(&self, item: &[u8]) -> impl Iterator<Item = usize> {
+        let h1 = BloomFilter::seeded_hash(self.seeds[0], item);
+        let h2 = BloomFilter::seeded_hash(self.seeds[1], item);
+        let optimal_m = self.optimal_m;
+        (0..self.optimal_k as u64)
+            .map(move |k_i| h1.wrapping_add(k_i.wrapping_mul(h2)) as usize % optimal_m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BloomFilter, CountingBloomFilter};
+
+    #[test]
+    fn serialize_round_trip() {
+        let mut filter = BloomFilter::new(100, 0.001);
+        for i in 0..100 {
+            filter.insert(format!("key{}", i).as_bytes());
+        }
+        let bytes = filter.to_bytes().unwrap();
+        let loaded = BloomFilter::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.optimal_m, filter.optimal_m);
+        assert_eq!(loaded.optimal_k, filter.optimal_k);
+        assert_eq!(loaded.seeds, filter.seeds);
+        assert_eq!(loaded.bitmap, filter.bitmap);
+        assert_eq!(loaded.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn loaded_filter_gives_same_answers() {
+        let mut filter = BloomFilter::new(500, 0.01);
+        for i in 0..500 {
+            filter.insert(format!("key{}", i).as_bytes());
+        }
+        let loaded = BloomFilter::from_bytes(&filter.to_bytes().unwrap()).unwrap();
+        for i in 0..1000 {
+            let key = format!("key{}", i);
+            assert_eq!(
+                loaded.contains(key.as_bytes()),
+                filter.contains(key.as_bytes())
+            );
+            if i < 500 {
+                assert!(loaded.contains(key.as_bytes()));
+            }
+        }
+    }
+
+    #[test]
+    fn reloaded_filter_reports_identical_membership() {
+        let mut filter = BloomFilter::new(1000, 0.001);
+        for i in 0..1000 {
+            filter.insert(format!("present{}", i).as_bytes());
+        }
+        let loaded = BloomFilter::from_bytes(&filter.to_bytes().unwrap()).unwrap();
+        for i in 0..1000 {
+            let present = format!("present{}", i);
+            assert!(loaded.contains(present.as_bytes()));
+            let absent = format!("absent{}", i);
+            assert_eq!(
+                loaded.contains(absent.as_bytes()),
+                filter.contains(absent.as_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn filters_with_same_items_are_identical() {
+        let mut first = BloomFilter::new(100, 0.01);
+        let mut second = BloomFilter::new(100, 0.01);
+        for i in 0..100 {
+            first.insert(format!("key{}", i).as_bytes());
+            second.insert(format!("key{}", i).as_bytes());
+        }
+        assert_eq!(first.to_bytes().unwrap(), second.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn binary_keys_do_not_alias() {
+        // both keys become "key\u{FFFD}" when converted lossily to a string
+        let first: &[u8] = b"key\xff";
+        let second: &[u8] = b"key\xfe";
+        assert_eq!(
+            String::from_utf8_lossy(first),
+            String::from_utf8_lossy(second)
+        );
+
+        let mut filter = BloomFilter::new(100, 0.001);
+        filter.insert(first);
+        assert!(filter.contains(first));
+        assert!(!filter.contains(second));
+
+        // every one of these keys is "k\u{FFFD}" as a lossy string
+        let mut filter = BloomFilter::new(64, 0.001);
+        for byte in 0x80..0xc0u8 {
+            filter.insert(&[b'k', byte]);
+        }
+        let aliased = (0xc0..=0xffu8)
+            .filter(|byte| filter.contains(&[b'k', *byte]))
+            .count();
+        assert!(aliased < 3);
+    }
+
+    #[test]
+    fn counting_filter_forgets_removed_keys() {
+        let mut filter = CountingBloomFilter::new(100, 0.001);
+        for i in 0..100 {
+            filter.insert(format!("key{}", i).as_bytes());
+        }
+        for i in 0..50 {
+            assert!(filter.remove(format!("key{}", i).as_bytes()));
+        }
+        let forgotten = (0..50)
+            .filter(|i| filter.contains(format!("key{}", i).as_bytes()))
+            .count();
+        assert!(forgotten < 3);
+        for i in 50..100 {
+            assert!(filter.contains(format!("key{}", i).as_bytes()));
+        }
+        // a key that was never inserted is left alone
+        assert!(!filter.remove(b"missing"));
+        assert!(!filter.contains(b"missing"));
+    }
+
+    #[test]
+    fn counting_filter_matches_standard_filter() {
+        let mut counting = CountingBloomFilter::new(200, 0.01);
+        let mut standard = BloomFilter::new(200, 0.01);
+        for i in 0..200 {
+            counting.insert(format!("key{}", i).as_bytes());
+            standard.insert(format!("key{}", i).as_bytes());
+        }
+        for i in 0..1000 {
+            let key = format!("key{}", i);
+            assert_eq!(
+                counting.contains(key.as_bytes()),
+                standard.contains(key.as_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn saturated_counters_are_never_decremented() {
+        let mut filter = CountingBloomFilter::new(10, 0.01);
+        for _ in 0..300 {
+            filter.insert(b"hot");
+        }
+        filter.insert(b"cold");
+        // more removes than the counters could count
+        for _ in 0..300 {
+            filter.remove(b"hot");
+        }
+        assert!(filter.contains(b"hot"));
+        assert!(filter.remove(b"cold"));
+        assert!(filter.contains(b"hot"));
     }
 }
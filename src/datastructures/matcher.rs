@@ -1,29 +1,130 @@
+use serde::{Deserialize, Serialize};
+
+/// Options that change how a find pattern is matched against keys
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchOptions {
+    /// Compare ASCII letters without regard to case
+    pub ignore_case: bool,
+}
+
 #[derive(Debug)]
 enum Test {
     Exact(u8),
     Wildcard,
     Until(Option<u8>),
+    Class(CharSet),
+}
+
+/// Set of bytes matched by a `[...]` character class
+#[derive(Debug)]
+struct CharSet {
+    ranges: Vec<(u8, u8)>,
+    negated: bool,
+}
+
+impl CharSet {
+    fn contains(&self, byte: u8) -> bool {
+        let found = self
+            .ranges
+            .iter()
+            .any(|(start, end)| *start <= byte && byte <= *end);
+        found != self.negated
+    }
+
+    /// Parse the class body that follows an opening `[`. `]` is taken
+    /// literally when it is the first byte of the class and `-` is taken
+    /// literally at either edge. Returns the set and the number of bytes
+    /// consumed including the closing `]`, or `None` if the class is never
+    /// closed.
+    fn parse(pattern: &[u8]) -> Option<(CharSet, usize)> {
+        let mut index = 0;
+        let negated = pattern.first() == Some(&b'^');
+        if negated {
+            index += 1;
+        }
+        let start = index;
+        let mut ranges = vec![];
+        loop {
+            let byte = *pattern.get(index)?;
+            if byte == b']' && index != start {
+                return Some((CharSet { ranges, negated }, index + 1));
+            }
+            match (pattern.get(index + 1), pattern.get(index + 2)) {
+                (Some(b'-'), Some(end)) if *end != b']' => {
+                    ranges.push((byte, *end));
+                    index += 3;
+                }
+                _ => {
+                    ranges.push((byte, byte));
+                    index += 1;
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct PreparedPattern {
     tests: Vec<Test>,
+    options: MatchOptions,
 }
 
 impl PreparedPattern {
+    fn eq(&self, a: u8, b: u8) -> bool {
+        if self.options.ignore_case {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    }
+
+    fn in_class(&self, set: &CharSet, byte: u8) -> bool {
+        if self.options.ignore_case && byte.is_ascii_alphabetic() {
+            // a negated class must exclude both cases, a plain class may
+            // include either of them
+            let (lower, upper) = (byte.to_ascii_lowercase(), byte.to_ascii_uppercase());
+            if set.negated {
+                set.contains(lower) && set.contains(upper)
+            } else {
+                set.contains(lower) || set.contains(upper)
+            }
+        } else {
+            set.contains(byte)
+        }
+    }
+
+    /// Bytes every matching key must start with. Empty when the pattern
+    /// starts with a wildcard or ignores case.
+    pub fn literal_prefix(&self) -> Vec<u8> {
+        if self.options.ignore_case {
+            return vec![];
+        }
+        self.tests
+            .iter()
+            .map_while(|test| match test {
+                Test::Exact(byte) => Some(*byte),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn test(&self, input: &[u8]) -> bool {
         let mut iter = input.iter();
         for test in self.tests.iter() {
             let result = match test {
-                Test::Exact(byte) => iter.next().map(|by| by == byte).unwrap_or(false),
+                Test::Exact(byte) => iter.next().map(|by| self.eq(*by, *byte)).unwrap_or(false),
                 Test::Wildcard => iter.next().is_some(),
+                Test::Class(set) => iter
+                    .next()
+                    .map(|by| self.in_class(set, *by))
+                    .unwrap_or(false),
                 Test::Until(None) => {
                     for _ in iter.by_ref() {}
                     true
                 }
                 Test::Until(Some(until)) => {
                     for byte in iter.by_ref() {
-                        if *byte == *until {
+                        if self.eq(*byte, *until) {
                             break;
                         }
                     }
@@ -38,22 +139,59 @@ impl PreparedPattern {
     }
 }
 
+/// Build a find pattern that matches `literal` exactly followed by `pattern`
+pub fn escape(literal: &[u8], pattern: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(literal.len() * 2 + pattern.len());
+    for byte in literal {
+        escaped.push(b'\\');
+        escaped.push(*byte);
+    }
+    escaped.extend_from_slice(pattern);
+    escaped
+}
+
+#[cfg(test)]
 pub fn prepare(like: Vec<u8>) -> PreparedPattern {
+    prepare_with(like, MatchOptions::default())
+}
+
+pub fn prepare_with(like: Vec<u8>, options: MatchOptions) -> PreparedPattern {
     let mut tests = vec![];
-    let mut iter = like.into_iter();
-    while let Some(byte) = iter.next() {
+    let mut index = 0;
+    while let Some(byte) = like.get(index) {
+        index += 1;
         match byte {
-            b'*' => tests.push(Test::Until(iter.next())),
+            b'*' => {
+                let mut until = like.get(index).copied();
+                if until == Some(b'\\') && index + 1 < like.len() {
+                    index += 1;
+                    until = like.get(index).copied();
+                }
+                tests.push(Test::Until(until));
+                index += 1;
+            }
+            b'\\' => {
+                // A trailing backslash has nothing to escape, keep it literal
+                tests.push(Test::Exact(*like.get(index).unwrap_or(&b'\\')));
+                index += 1;
+            }
             b'_' => tests.push(Test::Wildcard),
-            by => tests.push(Test::Exact(by)),
+            b'[' => match CharSet::parse(&like[index..]) {
+                Some((set, consumed)) => {
+                    tests.push(Test::Class(set));
+                    index += consumed;
+                }
+                None => tests.push(Test::Exact(b'[')),
+            },
+            by => tests.push(Test::Exact(*by)),
         }
     }
-    PreparedPattern { tests }
+    PreparedPattern { tests, options }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::prepare;
+    use super::{prepare, prepare_with, MatchOptions};
 
     #[test]
     fn match_all_exact() {
@@ -65,6 +203,21 @@ mod tests {
         assert!(!prepare.test(b"bananas"));
     }
 
+    #[test]
+    fn literal_prefix_stops_at_first_wildcard() {
+        assert_eq!(
+            prepare(b"user*".to_vec()).literal_prefix(),
+            b"user".to_vec()
+        );
+        assert_eq!(prepare(b"a\\*b".to_vec()).literal_prefix(), b"a*b".to_vec());
+        assert_eq!(prepare(b"ab_c".to_vec()).literal_prefix(), b"ab".to_vec());
+        assert!(prepare(b"*".to_vec()).literal_prefix().is_empty());
+        let options = MatchOptions { ignore_case: true };
+        assert!(prepare_with(b"user*".to_vec(), options)
+            .literal_prefix()
+            .is_empty());
+    }
+
     #[test]
     fn match_all_wildcard() {
         let prepare = prepare(b"___".to_vec());
@@ -107,4 +260,96 @@ mod tests {
         let prepare = prepare(b"*82__".to_vec());
         assert!(prepare.test(b"Key8200"));
     }
+
+    #[test]
+    fn match_class_range() {
+        let pattern = prepare(b"user[0-9]".to_vec());
+        assert!(pattern.test(b"user0"));
+        assert!(pattern.test(b"user9"));
+        assert!(!pattern.test(b"usera"));
+        assert!(!pattern.test(b"user10"));
+        assert!(!pattern.test(b"user"));
+    }
+
+    #[test]
+    fn match_class_single_chars() {
+        let pattern = prepare(b"[abc]at".to_vec());
+        assert!(pattern.test(b"aat"));
+        assert!(pattern.test(b"cat"));
+        assert!(!pattern.test(b"dat"));
+
+        let pattern = prepare(b"x[]-]".to_vec());
+        assert!(pattern.test(b"x]"));
+        assert!(pattern.test(b"x-"));
+        assert!(!pattern.test(b"xa"));
+
+        let pattern = prepare(b"[a-cx-z_]*".to_vec());
+        assert!(pattern.test(b"b"));
+        assert!(pattern.test(b"y"));
+        assert!(pattern.test(b"_"));
+        assert!(!pattern.test(b"m"));
+    }
+
+    #[test]
+    fn match_negated_class() {
+        let pattern = prepare(b"key[^0-9]".to_vec());
+        assert!(pattern.test(b"keya"));
+        assert!(!pattern.test(b"key5"));
+        assert!(!pattern.test(b"key"));
+    }
+
+    #[test]
+    fn unterminated_class_is_literal() {
+        let pattern = prepare(b"a[bc".to_vec());
+        assert!(pattern.test(b"a[bc"));
+        assert!(!pattern.test(b"ab"));
+    }
+
+    #[test]
+    fn match_escaped_wildcards() {
+        let pattern = prepare(b"a\\*b".to_vec());
+        assert!(pattern.test(b"a*b"));
+        assert!(!pattern.test(b"axb"));
+
+        let pattern = prepare(b"a\\_b".to_vec());
+        assert!(pattern.test(b"a_b"));
+        assert!(!pattern.test(b"axb"));
+
+        let pattern = prepare(b"a\\\\b".to_vec());
+        assert!(pattern.test(b"a\\b"));
+        assert!(!pattern.test(b"a\\\\b"));
+
+        let pattern = prepare(b"a\\".to_vec());
+        assert!(pattern.test(b"a\\"));
+    }
+
+    #[test]
+    fn match_escaped_and_unescaped_specials() {
+        let pattern = prepare(b"\\*_\\_*\\*".to_vec());
+        assert!(pattern.test(b"*a_key*"));
+        assert!(pattern.test(b"*b_*"));
+        assert!(!pattern.test(b"xa_key*"));
+        assert!(!pattern.test(b"*ab"));
+
+        let pattern = prepare(b"key\\[1]".to_vec());
+        assert!(pattern.test(b"key[1]"));
+        assert!(!pattern.test(b"key1"));
+    }
+
+    #[test]
+    fn match_ignoring_case() {
+        let options = MatchOptions { ignore_case: true };
+        let pattern = prepare_with(b"CAT".to_vec(), options);
+        assert!(pattern.test(b"cat"));
+        assert!(pattern.test(b"CaT"));
+        assert!(!prepare(b"CAT".to_vec()).test(b"cat"));
+
+        let pattern = prepare_with(b"*Y[A-C]".to_vec(), options);
+        assert!(pattern.test(b"keyb"));
+        assert!(!pattern.test(b"keyd"));
+
+        let pattern = prepare_with(b"key[^a]".to_vec(), options);
+        assert!(!pattern.test(b"keyA"));
+        assert!(pattern.test(b"keyB"));
+    }
 }
@@ -0,0 +1,199 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::sstable::Record;
+
+/// A block is found by the path of its segment and where it starts inside of
+/// the segment file
+type BlockKey = (PathBuf, u64);
+
+/// BlockCache keeps the decoded records of recently read segment blocks in
+/// memory, so reading a hot key doesn't open and decode its block every time.
+/// Once the cached blocks take up more than the capacity in bytes, the least
+/// recently used blocks are evicted. A capacity of 0 turns the cache off.
+pub struct BlockCache {
+    capacity: usize,
+    inner: Mutex<Blocks>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+struct CachedBlock {
+    records: Arc<Vec<Record>>,
+    size: usize,
+    tick: u64,
+}
+
+/// Cached blocks along with their access order. Every access is stamped with
+/// an increasing tick, so the smallest tick is the least recently used block.
+#[derive(Default)]
+struct Blocks {
+    tick: u64,
+    used: usize,
+    entries: HashMap<BlockKey, CachedBlock>,
+    order: BTreeMap<u64, BlockKey>,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Blocks::default()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the records of a block, marking it as the most recently used block
+    pub fn get(&self, segment_path: &Path, block_start: u64) -> Option<Arc<Vec<Record>>> {
+        let key = (segment_path.to_path_buf(), block_start);
+        let mut blocks = self.inner.lock().unwrap();
+        blocks.tick += 1;
+        let tick = blocks.tick;
+        let found = blocks.entries.get_mut(&key).map(|block| {
+            let old = std::mem::replace(&mut block.tick, tick);
+            (old, block.records.clone())
+        });
+        match found {
+            Some((old, records)) => {
+                blocks.order.remove(&old);
+                blocks.order.insert(tick, key);
+                self.hits.fetch_add(1, Ordering::SeqCst);
+                Some(records)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::SeqCst);
+                None
+            }
+        }
+    }
+
+    /// Cache the records of a block that take up `size` bytes in memory.
+    /// Blocks larger than the whole cache are not kept.
+    pub fn insert(
+        &self,
+        segment_path: &Path,
+        block_start: u64,
+        records: Arc<Vec<Record>>,
+        size: usize,
+    ) {
+        if size > self.capacity {
+            return;
+        }
+        let key = (segment_path.to_path_buf(), block_start);
+        let mut blocks = self.inner.lock().unwrap();
+        blocks.remove(&key);
+        blocks.tick += 1;
+        let tick = blocks.tick;
+        blocks.used += size;
+        blocks.order.insert(tick, key.clone());
+        blocks.entries.insert(
+            key,
+            CachedBlock {
+                records,
+                size,
+                tick,
+            },
+        );
+        while blocks.used > self.capacity {
+            let oldest = match blocks.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            blocks.remove(&oldest);
+        }
+    }
+
+    /// Forget every block of a segment. Called when the segment file is
+    /// deleted or replaced.
+    pub fn remove_segment(&self, segment_path: &Path) {
+        let mut blocks = self.inner.lock().unwrap();
+        let keys = blocks
+            .entries
+            .keys()
+            .filter(|(path, _)| path == segment_path)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            blocks.remove(&key);
+        }
+    }
+
+    /// Number of reads that were answered from the cache
+    #[cfg(test)]
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    /// Number of reads that had to go to the segment file
+    #[cfg(test)]
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::SeqCst)
+    }
+}
+
+impl Blocks {
+    fn remove(&mut self, key: &BlockKey) {
+        if let Some(block) = self.entries.remove(key) {
+            self.order.remove(&block.tick);
+            self.used -= block.size;
+        }
+    }
+}
+
+impl std::fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity)
+            .field("used", &self.inner.lock().unwrap().used)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use super::BlockCache;
+    use crate::engines::kvs::sstable::Record;
+
+    fn block() -> Arc<Vec<Record>> {
+        Arc::new(vec![Record::new(b"key".to_vec(), Some(b"value".to_vec()))])
+    }
+
+    #[test]
+    fn evicts_least_recently_used_block() {
+        let cache = BlockCache::new(30);
+        let path = Path::new("0.log");
+        for block_start in 0..3 {
+            cache.insert(path, block_start, block(), 10);
+        }
+        assert!(cache.get(path, 0).is_some());
+        cache.insert(path, 3, block(), 10);
+        assert!(cache.get(path, 1).is_none());
+        for block_start in [0, 2, 3].iter() {
+            assert!(cache.get(path, *block_start).is_some());
+        }
+        assert_eq!((cache.hits(), cache.misses()), (4, 1));
+    }
+
+    #[test]
+    fn remove_segment_forgets_its_blocks() {
+        let cache = BlockCache::new(100);
+        cache.insert(Path::new("0.log"), 0, block(), 10);
+        cache.insert(Path::new("1.log"), 0, block(), 10);
+        cache.remove_segment(Path::new("0.log"));
+        assert!(cache.get(Path::new("0.log"), 0).is_none());
+        assert!(cache.get(Path::new("1.log"), 0).is_some());
+        // a capacity of 0 keeps nothing
+        let cache = BlockCache::new(0);
+        cache.insert(Path::new("0.log"), 0, block(), 10);
+        assert!(cache.get(Path::new("0.log"), 0).is_none());
+    }
+}
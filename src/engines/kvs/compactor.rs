@@ -0,0 +1,133 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+/// Compactor owns the single background thread that merges levels. Merge
+/// requests that come in while a merge is already queued are coalesced into
+/// it, so a burst of WAL rotations only runs as many merges as the worker can
+/// keep up with and merges never overlap.
+pub struct Compactor {
+    sender: Option<Mutex<Sender<()>>>,
+    pending: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// Start the background thread, running `job` every time a merge is
+    /// requested.
+    pub fn new(job: impl Fn() + Send + 'static) -> crate::Result<Self> {
+        let (sender, receiver) = channel::<()>();
+        let pending = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let pending = pending.clone();
+            std::thread::Builder::new()
+                .name("kvs-compactor".into())
+                .spawn(move || {
+                    while receiver.recv().is_ok() {
+                        pending.store(false, Ordering::SeqCst);
+                        job();
+                    }
+                    debug!("Compactor is shutting down");
+                })?
+        };
+        Ok(Self {
+            sender: Some(Mutex::new(sender)),
+            pending,
+            worker: Some(worker),
+        })
+    }
+
+    /// A compactor without a background thread that ignores every request.
+    /// Used by stores that must never change their files.
+    pub fn disabled() -> Self {
+        Self {
+            sender: None,
+            pending: Arc::new(AtomicBool::new(false)),
+            worker: None,
+        }
+    }
+
+    /// Ask the background thread to run a merge. Nothing is queued if a merge
+    /// that hasn't started yet is already waiting.
+    pub fn request(&self) {
+        if self.pending.swap(true, Ordering::SeqCst) {
+            trace!("Merge is already queued, coalescing request");
+            return;
+        }
+        if let Some(sender) = self.sender.as_ref() {
+            if sender.lock().unwrap().send(()).is_err() {
+                error!("Compactor has stopped, merge request was dropped");
+            }
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // closing the channel lets the worker finish its queued merge and exit
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("Compactor thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::Compactor;
+
+    #[test]
+    fn merges_never_overlap() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let most_active = Arc::new(AtomicUsize::new(0));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let compactor = {
+            let (active, most_active, runs) = (active.clone(), most_active.clone(), runs.clone());
+            Compactor::new(move || {
+                let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                most_active.fetch_max(now_active, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(5));
+                runs.fetch_add(1, Ordering::SeqCst);
+                active.fetch_sub(1, Ordering::SeqCst);
+            })
+            .unwrap()
+        };
+
+        let compactor = Arc::new(compactor);
+        let requesters = (0..4)
+            .map(|_| {
+                let compactor = compactor.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        compactor.request();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for requester in requesters {
+            requester.join().unwrap();
+        }
+        // dropping the compactor waits for the queued merge to finish
+        drop(Arc::try_unwrap(compactor).ok().unwrap());
+
+        assert_eq!(most_active.load(Ordering::SeqCst), 1);
+        let runs = runs.load(Ordering::SeqCst);
+        assert!(runs >= 1);
+        assert!(runs < 200, "{} requests were not coalesced", runs);
+    }
+}
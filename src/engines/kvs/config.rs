@@ -1,80 +1,768 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::KvError;
 
-use super::{level::Levels, sstable::SSTable};
+use super::{
+    cache::BlockCache,
+    format::SerializationFormat,
+    level::Levels,
+    manifest::{check_setting, Manifest},
+    sstable::{move_file, sync_dir, SSTable},
+};
 
 const DEFAULT_WAL_SIZE: usize = 256 * 1000 * 1000;
+const DEFAULT_COMPACTION_FANOUT: usize = 10;
+const DEFAULT_BLOCK_SIZE: u64 = 4096;
+pub(crate) const DEFAULT_BLOOM_FP_RATE: f64 = 0.001;
+const DEFAULT_BLOCK_CACHE_SIZE: usize = 8 * 1000 * 1000;
+
+/// FsyncPolicy decides when writes to the write-ahead-log are forced onto
+/// the disk. Syncing is slow, so every policy trades write throughput for the
+/// number of acknowledged writes that can be lost if the machine crashes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Every write is synced to disk before it is acknowledged. No
+    /// acknowledged write is lost, but every write waits on the disk.
+    Always,
+    /// The log is synced once every N writes. Up to N - 1 acknowledged writes
+    /// can be lost.
+    EveryN(usize),
+    /// The log is synced by a background thread on the given interval.
+    /// Writes acknowledged since the last sync can be lost.
+    Interval(Duration),
+    /// Writes are handed to the operating system which decides when they
+    /// reach the disk. The fastest option, a crash of the machine can lose
+    /// any write the operating system hasn't written out yet.
+    #[default]
+    Never,
+}
+
+/// Compression decides how the blocks of a segment file are stored on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// Records are written as is
+    #[default]
+    None,
+    /// Every block of records is compressed with zstd at the given level
+    Zstd {
+        /// zstd compression level, higher levels trade speed for size
+        level: i32,
+    },
+}
 
+/// Config holds the settings used to tune a `KvStore`. Build one using
+/// `Config::builder()`.
+#[derive(Clone, Debug)]
 pub struct Config {
     folder: PathBuf,
+    wal_dir: Option<PathBuf>,
+    segment_dir: Option<PathBuf>,
     max_wal_size: usize,
+    write_buffer_size: usize,
+    compaction_fanout: usize,
+    compaction_max_level_bytes: Option<u64>,
+    level_size_ratio: Option<usize>,
+    max_levels: Option<usize>,
+    block_size: Option<u64>,
+    bloom_fp_rate: Option<f64>,
+    fsync_policy: FsyncPolicy,
+    compression: Option<Compression>,
+    serialization_format: Option<SerializationFormat>,
+    max_value_size: Option<usize>,
+    expiration_sweep_interval: Option<Duration>,
+    wal_max_age: Option<Duration>,
+    block_cache: Arc<BlockCache>,
+    read_only: bool,
+    strict: bool,
+    single_writer: bool,
+    compress_min_bytes: Option<usize>,
+    mmap: bool,
+}
+
+/// ConfigBuilder creates a `Config` for a `KvStore`.
+///
+/// A setting is decided in the following order:
+///
+/// 1. The value given to the builder
+/// 2. The environment variable for the setting, if it has one
+/// 3. The default value
+///
+/// | Setting             | Environment variable | Default     |
+/// |---------------------|----------------------|-------------|
+/// | `max_wal_size`      | `KV_MAX_LOG_SIZE`    | 256 MB      |
+/// | `write_buffer_size` |                      | `max_wal_size` |
+/// | `compaction_fanout` |                      | 10          |
+/// | `compaction_max_level_bytes` |             | unlimited   |
+/// | `level_size_ratio`  |                      | unset       |
+/// | `max_levels`        |                      | unlimited   |
+/// | `block_size`        |                      | 4096 bytes  |
+/// | `bloom_fp_rate`     |                      | 0.001       |
+/// | `fsync_policy`      |                      | `Never`     |
+/// | `compression`       |                      | `None`      |
+/// | `serialization_format` |                   | `Bincode`   |
+/// | `max_value_size`    |                      | unlimited   |
+/// | `expiration_sweep_interval` |              | off         |
+/// | `wal_max_age`       |                      | off         |
+/// | `block_cache_size`  |                      | 8 MB        |
+/// | `strict`            |                      | `false`     |
+/// | `single_writer`     |                      | `false`     |
+/// | `compress_min_bytes` |                     | off         |
+/// | `mmap`              |                      | `false`     |
+/// | `wal_dir`           |                      | the store's folder |
+/// | `segment_dir`       |                      | the store's folder |
+#[derive(Clone, Debug, Default)]
+pub struct ConfigBuilder {
+    max_wal_size: Option<usize>,
+    write_buffer_size: Option<usize>,
+    compaction_fanout: Option<usize>,
+    compaction_max_level_bytes: Option<u64>,
+    level_size_ratio: Option<usize>,
+    max_levels: Option<usize>,
+    block_size: Option<u64>,
+    bloom_fp_rate: Option<f64>,
+    fsync_policy: Option<FsyncPolicy>,
+    compression: Option<Compression>,
+    serialization_format: Option<SerializationFormat>,
+    max_value_size: Option<usize>,
+    expiration_sweep_interval: Option<Duration>,
+    wal_max_age: Option<Duration>,
+    block_cache_size: Option<usize>,
+    strict: Option<bool>,
+    single_writer: Option<bool>,
+    compress_min_bytes: Option<usize>,
+    mmap: Option<bool>,
+    wal_dir: Option<PathBuf>,
+    segment_dir: Option<PathBuf>,
+}
+
+impl ConfigBuilder {
+    /// Create a builder where every setting falls back to the environment or
+    /// its default value
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of key and value bytes the write-ahead-log can grow to before
+    /// the memory table is written to a segment and a new write-ahead-log is
+    /// started
+    pub fn max_wal_size(mut self, size: usize) -> Self {
+        self.max_wal_size = Some(size);
+        self
+    }
+
+    /// Size in bytes of the keys and values the memory table can hold before
+    /// it is written to a segment. Lower it to bound the memory used by the
+    /// store. Overwriting a key only grows the write-ahead-log, so the log
+    /// is bounded separately by `max_wal_size`.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = Some(size);
+        self
+    }
+
+    /// Number of segments a level may hold, multiplied by the level number,
+    /// before the level is merged into the next one
+    pub fn compaction_fanout(mut self, fanout: usize) -> Self {
+        self.compaction_fanout = Some(fanout);
+        self
+    }
+
+    /// Number of bytes the segments of the first level may take up before
+    /// the level is merged into the next one, however few segments it holds.
+    /// Deeper levels may hold more bytes, growing the same way their number
+    /// of segments does. A level of a single segment is never merged for its
+    /// size.
+    pub fn compaction_max_level_bytes(mut self, bytes: u64) -> Self {
+        self.compaction_max_level_bytes = Some(bytes);
+        self
+    }
+
+    /// Let every level hold `ratio` times as many segments as the level
+    /// before it, instead of growing linearly with the level number. The
+    /// first level still holds `compaction_fanout` segments.
+    pub fn level_size_ratio(mut self, ratio: usize) -> Self {
+        self.level_size_ratio = Some(ratio);
+        self
+    }
+
+    /// Largest number of levels the store creates. The deepest level is
+    /// merged into a single segment inside of itself instead of into a new
+    /// level. Deeper levels that already exist on disk are still read.
+    pub fn max_levels(mut self, levels: usize) -> Self {
+        self.max_levels = Some(levels.max(1));
+        self
+    }
+
+    /// Size in bytes of the blocks a segment file is indexed by. Only a new
+    /// store takes it, a store that already exists refuses to open with a
+    /// block size other than the one recorded in its manifest.
+    pub fn block_size(mut self, size: u64) -> Self {
+        self.block_size = Some(size);
+        self
+    }
+
+    /// Rate of false positives the bloom filter of a segment is sized for.
+    /// A higher rate makes smaller filters at the cost of reading more
+    /// segments that don't hold the key. A store refuses to open with a rate
+    /// that isn't between 0 and 1. Only a new store takes it, a store that
+    /// already exists refuses to open with a rate other than the one
+    /// recorded in its manifest.
+    pub fn bloom_fp_rate(mut self, rate: f64) -> Self {
+        self.bloom_fp_rate = Some(rate);
+        self
+    }
+
+    /// When writes to the write-ahead-log should be synced to disk
+    pub fn fsync_policy(mut self, policy: FsyncPolicy) -> Self {
+        self.fsync_policy = Some(policy);
+        self
+    }
+
+    /// How segment files compress their blocks. Only a new store takes it, a
+    /// store that already exists refuses to open with a compression other
+    /// than the one recorded in its manifest.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// How records, segment footers and the write-ahead-log are encoded on
+    /// disk. Only a new store takes it, a store that already exists refuses to
+    /// open with a format other than the one recorded in its manifest.
+    pub fn serialization_format(mut self, format: SerializationFormat) -> Self {
+        self.serialization_format = Some(format);
+        self
+    }
+
+    /// Largest value in bytes that can be written to the store. Larger values
+    /// are rejected with `KvError::ValueTooLarge`.
+    pub fn max_value_size(mut self, limit: usize) -> Self {
+        self.max_value_size = Some(limit);
+        self
+    }
+
+    /// Run a background thread that looks for expired keys on the given
+    /// interval and removes them, so the space they use is reclaimed by the
+    /// next compaction even if they are never read again
+    pub fn expiration_sweep_interval(mut self, interval: Duration) -> Self {
+        self.expiration_sweep_interval = Some(interval);
+        self
+    }
+
+    /// Run a background thread that writes the memory table to a segment
+    /// once its write-ahead-log is older than `age`, even if it is nowhere
+    /// near full. It bounds how old a log a restart may have to replay.
+    pub fn wal_max_age(mut self, age: Duration) -> Self {
+        self.wal_max_age = Some(age);
+        self
+    }
+
+    /// Size in bytes of the cache that keeps recently read segment blocks in
+    /// memory. A size of 0 turns the cache off.
+    pub fn block_cache_size(mut self, size: usize) -> Self {
+        self.block_cache_size = Some(size);
+        self
+    }
+
+    /// Return `KvError::Corruption` when a record read while restoring,
+    /// merging or finding keys doesn't match its checksum. By default the
+    /// record is logged and skipped, so the rest of the data stays readable.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = Some(strict);
+        self
+    }
+
+    /// Commit every `set` and `remove` on a single writer thread. Writes
+    /// made while the thread is busy are queued and committed together with
+    /// one write to the write-ahead-log, and each write returns once its
+    /// batch is committed. With `FsyncPolicy::Always` a batch is synced once
+    /// for all of its writes.
+    pub fn single_writer(mut self, enabled: bool) -> Self {
+        self.single_writer = Some(enabled);
+        self
+    }
+
+    /// Compress every value longer than `bytes` on its own before it is
+    /// written. Smaller values are stored as they are, since compressing
+    /// them costs time and can even make them larger. Values that were
+    /// already written keep the way they were stored.
+    pub fn compress_min_bytes(mut self, bytes: usize) -> Self {
+        self.compress_min_bytes = Some(bytes);
+        self
+    }
+
+    /// Read the blocks of segment files through a memory map of the file
+    /// instead of opening and seeking the file for every lookup. Each
+    /// segment is mapped once, on its first read.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = Some(mmap);
+        self
+    }
+
+    /// Directory the write-ahead-logs are kept in, such as one on a faster
+    /// disk than the segments. Only a new store takes it, a store that
+    /// already exists keeps the directory recorded in its manifest and
+    /// refuses to open with another one.
+    pub fn wal_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.wal_dir = Some(path.into());
+        self
+    }
+
+    /// Directory the segment files of every level are kept in, which can be
+    /// on another filesystem than the write-ahead-logs. Only a new store
+    /// takes it, like `wal_dir`. The manifest stays in the store's folder.
+    pub fn segment_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.segment_dir = Some(path.into());
+        self
+    }
+
+    /// Build the config
+    pub fn build(self) -> Config {
+        let max_wal_size = self.max_wal_size.unwrap_or_else(|| {
+            std::env::var("KV_MAX_LOG_SIZE")
+                .map(|v| v.parse::<usize>().unwrap_or(DEFAULT_WAL_SIZE))
+                .unwrap_or(DEFAULT_WAL_SIZE)
+        });
+        trace!("KV_MAX_WAL_SIZE set to {}", max_wal_size);
+        Config {
+            folder: PathBuf::new(),
+            wal_dir: self.wal_dir,
+            segment_dir: self.segment_dir,
+            max_wal_size,
+            write_buffer_size: self.write_buffer_size.unwrap_or(max_wal_size),
+            compaction_fanout: self.compaction_fanout.unwrap_or(DEFAULT_COMPACTION_FANOUT),
+            compaction_max_level_bytes: self.compaction_max_level_bytes,
+            level_size_ratio: self.level_size_ratio,
+            max_levels: self.max_levels,
+            block_size: self.block_size,
+            bloom_fp_rate: self.bloom_fp_rate,
+            fsync_policy: self.fsync_policy.unwrap_or_default(),
+            compression: self.compression,
+            serialization_format: self.serialization_format,
+            max_value_size: self.max_value_size,
+            expiration_sweep_interval: self.expiration_sweep_interval,
+            wal_max_age: self.wal_max_age,
+            block_cache: Arc::new(BlockCache::new(
+                self.block_cache_size.unwrap_or(DEFAULT_BLOCK_CACHE_SIZE),
+            )),
+            read_only: false,
+            strict: self.strict.unwrap_or(false),
+            single_writer: self.single_writer.unwrap_or(false),
+            compress_min_bytes: self.compress_min_bytes,
+            mmap: self.mmap.unwrap_or(false),
+        }
+    }
 }
 
 impl Config {
     /// Create a new config for the key value store
     pub fn new(folder: impl Into<PathBuf>) -> Self {
-        let max_wal_size = std::env::var("KV_MAX_LOG_SIZE")
-            .map(|v| v.parse::<usize>().unwrap_or(DEFAULT_WAL_SIZE))
-            .unwrap_or(DEFAULT_WAL_SIZE);
-        trace!("KV_MAX_WAL_SIZE set to {}", max_wal_size);
-        Self {
-            folder: folder.into(),
-            max_wal_size,
+        Self::builder().build().with_folder(folder)
+    }
+
+    /// Create a builder to tune the settings of the key value store
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+
+    pub(crate) fn with_folder(mut self, folder: impl Into<PathBuf>) -> Self {
+        self.folder = folder.into();
+        self
+    }
+
+    /// Open the store without writing to, creating or deleting any file
+    pub(crate) fn with_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Directory the store keeps its files in
+    pub(crate) fn folder(&self) -> &Path {
+        &self.folder
+    }
+
+    /// Directory the store keeps its write-ahead-logs in
+    pub(crate) fn wal_dir(&self) -> &Path {
+        self.wal_dir.as_deref().unwrap_or(&self.folder)
+    }
+
+    /// Directory the store keeps the segments of its levels in
+    pub(crate) fn segment_dir(&self) -> &Path {
+        self.segment_dir.as_deref().unwrap_or(&self.folder)
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether corrupt records are returned as errors instead of skipped
+    pub(crate) fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Whether writes are committed by a single writer thread
+    pub(crate) fn has_single_writer(&self) -> bool {
+        self.single_writer && !self.read_only
+    }
+
+    /// Make sure the store was not opened in read-only mode
+    pub(crate) fn check_writable(&self) -> crate::Result<()> {
+        if self.read_only {
+            Err(KvError::ReadOnly)
+        } else {
+            Ok(())
         }
     }
 
-    /// Create directory for database to execute in
-    pub fn init(&self) -> crate::Result<()> {
-        if !self.folder.exists() {
-            debug!("Failed to find {:?}; creating it", self.folder);
-            std::fs::create_dir_all(&self.folder)?;
-        } else if !self.folder.is_dir() {
-            debug!("Linked directory {:?} is a file", self.folder);
-            return Err(KvError::Parse(
-                format!("{:?} is not a directory", self.folder).into(),
-            ));
+    /// Number of segments `level` can hold before it is merged. A level
+    /// always holds at least 2 segments.
+    pub(crate) fn merge_threshold(&self, level: usize) -> usize {
+        self.compaction_fanout
+            .saturating_mul(self.level_growth(level))
+            .max(2)
+    }
+
+    /// Number of bytes the segments of `level` can take up before it is
+    /// merged, if the store limits it
+    pub(crate) fn max_level_bytes(&self, level: usize) -> Option<u64> {
+        let growth = self.level_growth(level) as u64;
+        self.compaction_max_level_bytes
+            .map(|bytes| bytes.saturating_mul(growth))
+    }
+
+    /// How many times more `level` holds than the first level
+    fn level_growth(&self, level: usize) -> usize {
+        match self.level_size_ratio {
+            Some(ratio) => ratio.saturating_pow(level.saturating_sub(1) as u32),
+            None => level,
         }
+    }
 
+    /// Whether `level` is the deepest level the store may create
+    pub(crate) fn is_deepest_level(&self, level: usize) -> bool {
+        matches!(self.max_levels, Some(max) if level >= max)
+    }
+
+    pub(crate) fn block_size(&self) -> u64 {
+        self.block_size.unwrap_or(DEFAULT_BLOCK_SIZE)
+    }
+
+    /// False positive rate the bloom filters of new segments are sized for
+    pub(crate) fn bloom_fp_rate(&self) -> f64 {
+        self.bloom_fp_rate.unwrap_or(DEFAULT_BLOOM_FP_RATE)
+    }
+
+    pub(crate) fn fsync_policy(&self) -> FsyncPolicy {
+        self.fsync_policy
+    }
+
+    pub(crate) fn compression(&self) -> Compression {
+        self.compression.unwrap_or_default()
+    }
+
+    /// Format the files of the store are encoded in
+    pub(crate) fn serialization_format(&self) -> SerializationFormat {
+        self.serialization_format.unwrap_or_default()
+    }
+
+    pub(crate) fn expiration_sweep_interval(&self) -> Option<Duration> {
+        self.expiration_sweep_interval
+    }
+
+    pub(crate) fn wal_max_age(&self) -> Option<Duration> {
+        self.wal_max_age
+    }
+
+    /// Values longer than it are compressed before they are written
+    pub(crate) fn compress_min_bytes(&self) -> Option<usize> {
+        self.compress_min_bytes
+    }
+
+    /// Whether segment files are read through a memory map
+    pub(crate) fn mmap(&self) -> bool {
+        self.mmap
+    }
+
+    /// Cache of segment blocks shared by every segment of the store
+    pub(crate) fn block_cache(&self) -> &Arc<BlockCache> {
+        &self.block_cache
+    }
+
+    /// Make sure a value isn't larger than the configured limit
+    pub(crate) fn check_value_size(&self, value: &[u8]) -> crate::Result<()> {
+        match self.max_value_size {
+            Some(limit) if value.len() > limit => Err(KvError::ValueTooLarge {
+                size: value.len(),
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Create directory for database to execute in and load the format the
+    /// store was written in from its manifest. A read-only store is never
+    /// given a new directory or manifest.
+    pub(crate) fn init(mut self) -> crate::Result<Self> {
+        if let Some(rate) = self.bloom_fp_rate {
+            if !(rate > 0.0 && rate < 1.0) {
+                return Err(KvError::Parse(
+                    format!("bloom_fp_rate must be between 0 and 1, got {}", rate).into(),
+                ));
+            }
+        }
+        prepare_dir(&self.folder, self.read_only)?;
+
+        let mut manifest = match Manifest::read(&self.folder)? {
+            Some(manifest) if manifest.records_settings() => manifest,
+            recorded => {
+                // a store written before manifests existed was always
+                // written with bincode, and one written before the manifest
+                // recorded every setting only knows its format
+                let existing = std::fs::read_dir(&self.folder)?.next().is_some();
+                let serialization_format = match (recorded, existing) {
+                    (Some(manifest), _) => manifest.serialization_format,
+                    (None, true) => SerializationFormat::Bincode,
+                    (None, false) => self.serialization_format(),
+                };
+                let manifest = Manifest::new(
+                    self.block_size(),
+                    self.compression(),
+                    self.bloom_fp_rate(),
+                    serialization_format,
+                    self.wal_dir.clone(),
+                    self.segment_dir.clone(),
+                );
+                if !self.read_only {
+                    manifest.write(&self.folder)?;
+                }
+                manifest
+            }
+        };
+        manifest.check_version()?;
+        if !manifest.records_dirs() {
+            // a store whose manifest doesn't know its directories kept every
+            // file in its folder. They are moved into the directories it is
+            // opened with, which it keeps from now on.
+            if !self.read_only {
+                self.move_into_dirs()?;
+            }
+            manifest.record_dirs(self.wal_dir.clone(), self.segment_dir.clone());
+            if !self.read_only {
+                manifest.write(&self.folder)?;
+            }
+        }
+        check_setting("block_size", manifest.block_size, self.block_size)?;
+        check_setting("compression", manifest.compression, self.compression)?;
+        check_setting("bloom_fp_rate", manifest.bloom_fp_rate, self.bloom_fp_rate)?;
+        check_setting(
+            "serialization_format",
+            manifest.serialization_format,
+            self.serialization_format,
+        )?;
+        check_setting(
+            "wal_dir",
+            manifest.wal_dir.as_ref(),
+            self.wal_dir.as_ref().map(Some),
+        )?;
+        check_setting(
+            "segment_dir",
+            manifest.segment_dir.as_ref(),
+            self.segment_dir.as_ref().map(Some),
+        )?;
+        self.block_size = Some(manifest.block_size);
+        self.compression = Some(manifest.compression);
+        self.bloom_fp_rate = Some(manifest.bloom_fp_rate);
+        self.serialization_format = Some(manifest.serialization_format);
+        self.wal_dir = manifest.wal_dir;
+        self.segment_dir = manifest.segment_dir;
+        // created after the manifest, so a directory inside of the folder
+        // doesn't make a new store look like one written before manifests
+        prepare_dir(self.wal_dir(), self.read_only)?;
+        prepare_dir(self.segment_dir(), self.read_only)?;
+        Ok(self)
+    }
+
+    /// Move the redo logs and segments of a store that kept them in its
+    /// folder into the WAL and segment directories. The moves are synced
+    /// before the manifest records the directories, and a move cut short by
+    /// a crash carries on the next time the store is opened.
+    fn move_into_dirs(&self) -> crate::Result<()> {
+        let (wal_dir, segment_dir) = (self.wal_dir(), self.segment_dir());
+        if wal_dir != self.folder {
+            move_files(&self.folder, wal_dir, &["redo"])?;
+        }
+        if segment_dir != self.folder {
+            // the first level keeps its segments in the folder itself, the
+            // deeper levels each have a folder of their own
+            move_files(&self.folder, segment_dir, &["log", "bloom"])?;
+            for entry in std::fs::read_dir(&self.folder)? {
+                let level_dir = entry?.path();
+                if !is_level_dir(&level_dir) || segment_dir.starts_with(&level_dir) {
+                    continue;
+                }
+                move_files(
+                    &level_dir,
+                    &segment_dir.join(level_dir.file_name().unwrap()),
+                    &["log", "bloom"],
+                )?;
+                std::fs::remove_dir(&level_dir)?;
+            }
+        }
+        sync_dir(&self.folder)?;
         Ok(())
     }
 
-    /// Find a redo log in the database directory and return the path to it
-    pub fn restore_wal(&self) -> crate::Result<SSTable> {
-        let path = self.find_redo_log()?;
-        match path {
-            Some(file) => SSTable::from_write_ahead_log(file),
-            None => SSTable::new(&self.folder),
+    /// Find a redo log in the WAL directory and return the path to it
+    pub(crate) fn restore_wal(&self) -> crate::Result<SSTable> {
+        let paths = self.find_redo_logs()?;
+        if self.read_only {
+            SSTable::read_only(&paths, self.serialization_format(), self.strict)
+        } else if paths.is_empty() {
+            SSTable::with_format(
+                self.wal_dir(),
+                self.fsync_policy,
+                self.serialization_format(),
+            )
+        } else {
+            SSTable::from_write_ahead_logs(
+                self.wal_dir(),
+                &paths,
+                self.fsync_policy,
+                self.serialization_format(),
+                self.strict,
+            )
         }
     }
 
-    pub fn restore_levels(&self) -> crate::Result<Levels> {
-        Levels::new(self.folder.as_path())
+    pub(crate) fn restore_levels(self: &Arc<Self>) -> crate::Result<Levels> {
+        Levels::new(self.segment_dir(), self.clone())
     }
 
-    pub fn replace_wal_inplace(&self, dest: &mut SSTable) -> crate::Result<SSTable> {
-        let new = SSTable::new(&self.folder)?;
+    pub(crate) fn replace_wal_inplace(&self, dest: &mut SSTable) -> crate::Result<SSTable> {
+        let new = SSTable::with_format(
+            self.wal_dir(),
+            self.fsync_policy,
+            self.serialization_format(),
+        )?;
         Ok(std::mem::replace(dest, new))
     }
 
-    pub fn should_rotate_wal(&self, size: usize) -> bool {
-        size > self.max_wal_size
+    /// Check if the memory table holds too much data or its write-ahead-log
+    /// has grown too large
+    pub(crate) fn should_rotate_wal(&self, memory_size: usize, log_size: usize) -> bool {
+        memory_size > self.write_buffer_size || log_size > self.max_wal_size
+    }
+
+    /// Remove every redo log inside of the WAL directory
+    pub(crate) fn remove_redo_logs(&self) -> crate::Result<()> {
+        for entry in std::fs::read_dir(self.wal_dir())? {
+            let path = entry?.path();
+            if path.extension().map(|e| e == "redo").unwrap_or(false) {
+                trace!("Removing redo log {:?}", path);
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Walk the database, WAL and segment directories and return the total
+    /// number of bytes on disk along with the number of bytes used by redo
+    /// logs.
+    pub(crate) fn disk_usage(&self) -> crate::Result<(u64, u64)> {
+        fn walk(dir: &Path, total: &mut u64, wal: &mut u64) -> crate::Result<()> {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    walk(&path, total, wal)?;
+                    continue;
+                }
+                let len = std::fs::metadata(&path)?.len();
+                *total += len;
+                if path.extension().map(|e| e == "redo").unwrap_or(false) {
+                    *wal += len;
+                }
+            }
+            Ok(())
+        }
+
+        let (mut total, mut wal) = (0, 0);
+        let mut walked: Vec<&Path> = vec![];
+        for dir in [self.folder(), self.wal_dir(), self.segment_dir()] {
+            // a directory inside of one already walked was counted with it
+            if walked.iter().any(|parent| dir.starts_with(parent)) {
+                continue;
+            }
+            walked.retain(|child| !child.starts_with(dir));
+            walked.push(dir);
+        }
+        for dir in walked {
+            walk(dir, &mut total, &mut wal)?;
+        }
+        Ok((total, wal))
     }
 
-    fn find_redo_log(&self) -> crate::Result<Option<PathBuf>> {
-        let dir = std::fs::read_dir(&self.folder)?;
+    /// Paths of every redo log inside of the WAL directory
+    pub(crate) fn find_redo_logs(&self) -> crate::Result<Vec<PathBuf>> {
+        let dir = std::fs::read_dir(self.wal_dir())?;
+        let mut paths = vec![];
         for entry in dir {
             let entry = entry?;
             if let Some(s) = entry.path().extension() {
                 if s == "redo" {
                     trace!("Found redo log: {:?}", entry.path());
-                    // TODO: If we find multiple redo logs on startup, we should
-                    // just compress them right now. At least we should include
-                    // an option for the user to submit.
-                    return Ok(Some(entry.path()));
+                    paths.push(entry.path());
                 }
             }
         }
-        Ok(None)
+        Ok(paths)
+    }
+}
+
+/// Move the files of `from` with one of the `extensions` into `to`
+fn move_files(from: &Path, to: &Path, extensions: &[&str]) -> crate::Result<()> {
+    prepare_dir(to, false)?;
+    for entry in std::fs::read_dir(from)? {
+        let path = entry?.path();
+        let extension = path.extension().and_then(|e| e.to_str());
+        if path.is_file() && extension.map(|e| extensions.contains(&e)).unwrap_or(false) {
+            debug!("Moving {:?} into {:?}", path, to);
+            move_file(&path, &to.join(path.file_name().unwrap()))?;
+        }
+    }
+    sync_dir(to)?;
+    Ok(())
+}
+
+/// Whether `path` is the folder of a level, named `lv` and its number
+fn is_level_dir(path: &Path) -> bool {
+    let name = path.file_name().and_then(|name| name.to_str());
+    path.is_dir()
+        && name
+            .and_then(|name| name.strip_prefix("lv"))
+            .map(|level| level.parse::<usize>().is_ok())
+            .unwrap_or(false)
+}
+
+/// Make sure `dir` is a directory, creating it unless the store is read-only
+fn prepare_dir(dir: &Path, read_only: bool) -> crate::Result<()> {
+    if !dir.exists() && read_only {
+        return Err(KvError::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{:?} does not exist", dir),
+        )));
+    } else if !dir.exists() {
+        debug!("Failed to find {:?}; creating it", dir);
+        std::fs::create_dir_all(dir)?;
+    } else if !dir.is_dir() {
+        debug!("Linked directory {:?} is a file", dir);
+        return Err(KvError::Parse(
+            format!("{:?} is not a directory", dir).into(),
+        ));
     }
+    Ok(())
 }
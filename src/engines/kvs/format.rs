@@ -0,0 +1,99 @@
+use std::io::Read;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// SerializationFormat decides how records, the footers of segment files and
+/// the write-ahead-log are encoded on disk. A store keeps the format it was
+/// created with in its manifest, so it is always read back the same way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SerializationFormat {
+    /// Compact binary encoding used by every store written before the format
+    /// could be chosen
+    #[default]
+    Bincode,
+    /// MessagePack, which other tools and languages can read
+    MessagePack,
+}
+
+/// Format serializes the values a store writes to disk
+pub(crate) trait Format {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> crate::Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> crate::Result<T>;
+
+    /// Read one value from the reader, leaving every byte after it unread
+    fn decode_from<T: DeserializeOwned>(&self, reader: &mut impl Read) -> crate::Result<T>;
+
+    /// Number of bytes the value takes up once serialized
+    fn encoded_size<T: Serialize + ?Sized>(&self, value: &T) -> crate::Result<u64> {
+        Ok(self.encode(value)?.len() as u64)
+    }
+}
+
+struct Bincode;
+
+impl Format for Bincode {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> crate::Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> crate::Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    fn decode_from<T: DeserializeOwned>(&self, reader: &mut impl Read) -> crate::Result<T> {
+        Ok(bincode::deserialize_from(reader)?)
+    }
+
+    fn encoded_size<T: Serialize + ?Sized>(&self, value: &T) -> crate::Result<u64> {
+        Ok(bincode::serialized_size(value)?)
+    }
+}
+
+struct MessagePack;
+
+impl Format for MessagePack {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> crate::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        rmp_serde::encode::write(&mut bytes, value)?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> crate::Result<T> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+
+    fn decode_from<T: DeserializeOwned>(&self, reader: &mut impl Read) -> crate::Result<T> {
+        Ok(rmp_serde::from_read(reader)?)
+    }
+}
+
+impl Format for SerializationFormat {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> crate::Result<Vec<u8>> {
+        match self {
+            SerializationFormat::Bincode => Bincode.encode(value),
+            SerializationFormat::MessagePack => MessagePack.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> crate::Result<T> {
+        match self {
+            SerializationFormat::Bincode => Bincode.decode(bytes),
+            SerializationFormat::MessagePack => MessagePack.decode(bytes),
+        }
+    }
+
+    fn decode_from<T: DeserializeOwned>(&self, reader: &mut impl Read) -> crate::Result<T> {
+        match self {
+            SerializationFormat::Bincode => Bincode.decode_from(reader),
+            SerializationFormat::MessagePack => MessagePack.decode_from(reader),
+        }
+    }
+
+    fn encoded_size<T: Serialize + ?Sized>(&self, value: &T) -> crate::Result<u64> {
+        match self {
+            SerializationFormat::Bincode => Bincode.encoded_size(value),
+            SerializationFormat::MessagePack => MessagePack.encoded_size(value),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+};
+
+/// GroupCommit lets writers appending to a write-ahead-log at the same time
+/// share a single sync of it. Every write is numbered once its bytes are
+/// handed to the log. A writer that needs its write synced either becomes
+/// the leader and syncs every write numbered so far, or waits for the
+/// leader's sync to finish and returns if that sync covered its write.
+#[derive(Debug, Default)]
+pub struct GroupCommit {
+    written: AtomicU64,
+    state: Mutex<SyncState>,
+    finished: Condvar,
+    syncs: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    /// Every write up to this number is on disk
+    synced: u64,
+    /// Whether a leader is syncing right now
+    syncing: bool,
+}
+
+impl GroupCommit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number the write whose bytes were just handed to the log. Call it
+    /// while still holding the log's lock, so the numbers follow the order
+    /// of the bytes in the log.
+    pub fn written(&self) -> u64 {
+        self.written.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Number of the newest write handed to the log
+    pub fn last_written(&self) -> u64 {
+        self.written.load(Ordering::SeqCst)
+    }
+
+    /// Wait until every write up to `sequence` is synced. If no sync is
+    /// running, this writer becomes the leader and runs `sync` for every
+    /// write made so far. A leader's error is only returned to the leader,
+    /// the writers waiting on it try again.
+    pub fn sync_through(&self, sequence: u64, sync: impl Fn() -> io::Result<()>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= sequence {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.finished.wait(state).unwrap();
+                continue;
+            }
+            state.syncing = true;
+            let through = self.last_written();
+            drop(state);
+
+            let result = sync();
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = state.synced.max(through);
+            }
+            self.finished.notify_all();
+            result?;
+        }
+    }
+
+    /// Number of syncs run so far
+    #[cfg(test)]
+    pub fn syncs(&self) -> usize {
+        self.syncs.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::GroupCommit;
+
+    #[test]
+    fn waiting_writers_share_a_sync() {
+        let group = Arc::new(GroupCommit::new());
+        let log = Arc::new(Mutex::new(()));
+        let writers = (0..16)
+            .map(|_| {
+                let (group, log) = (group.clone(), log.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        let lock = log.lock().unwrap();
+                        let sequence = group.written();
+                        drop(lock);
+                        group
+                            .sync_through(sequence, || {
+                                std::thread::sleep(Duration::from_millis(1));
+                                Ok(())
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(group.last_written(), 320);
+        assert!(group.syncs() < 320 / 4, "{} syncs", group.syncs());
+    }
+
+    #[test]
+    fn failed_sync_is_retried_by_the_next_writer() {
+        let group = GroupCommit::new();
+        let sequence = group.written();
+        let failed = group.sync_through(sequence, || Err(std::io::Error::other("disk went away")));
+        assert!(failed.is_err());
+        group.sync_through(sequence, || Ok(())).unwrap();
+        assert_eq!(group.syncs(), 2);
+        // a write that is already synced doesn't sync again
+        group.sync_through(sequence, || unreachable!()).unwrap();
+        assert_eq!(group.syncs(), 2);
+    }
+}
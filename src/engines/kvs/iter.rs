@@ -0,0 +1,217 @@
+use std::{collections::BinaryHeap, sync::Arc};
+
+use crate::datastructures::matcher::PreparedPattern;
+
+use super::{
+    sstable::{KeyMatch, MergeEntry, ReverseSegmentReader, Segment, SegmentReader},
+    Snapshot,
+};
+
+/// A sorted stream of the keys matching a pattern inside of one table or
+/// segment
+pub enum Source {
+    /// Matches of a table that is held in memory
+    Memory(std::vec::IntoIter<KeyMatch>),
+    /// Matches read from a segment file as they are needed. The segment is
+    /// kept alive so its file isn't deleted while it is being read.
+    Segment {
+        reader: Box<SegmentReader>,
+        _segment: Arc<Segment>,
+    },
+    /// Matches read from a segment file from its largest key to its
+    /// smallest
+    Reversed {
+        reader: Box<ReverseSegmentReader>,
+        _segment: Arc<Segment>,
+    },
+}
+
+impl Source {
+    fn next(&mut self, pattern: &PreparedPattern) -> crate::Result<Option<KeyMatch>> {
+        match self {
+            Source::Memory(matches) => Ok(matches.next()),
+            Source::Segment { reader, .. } => reader.next_match(pattern),
+            Source::Reversed { reader, .. } => reader.next_match(pattern),
+        }
+    }
+}
+
+/// FindIter lazily merges the sources of a store into the keys matching a
+/// pattern, in sorted order. Sources must be ordered from oldest to newest.
+/// Only the newest record of every key is looked at, and keys whose newest
+/// record was removed or has expired are skipped.
+pub struct FindIter {
+    pattern: PreparedPattern,
+    sources: Vec<Source>,
+    pending: Vec<Option<KeyMatch>>,
+    heap: BinaryHeap<MergeEntry>,
+    descending: bool,
+}
+
+impl FindIter {
+    pub fn new(pattern: PreparedPattern, sources: Vec<Source>) -> crate::Result<Self> {
+        Self::with_order(pattern, sources, false)
+    }
+
+    /// Merge sources that hand out their keys from largest to smallest, so
+    /// the keys are found in descending order
+    pub fn descending(pattern: PreparedPattern, sources: Vec<Source>) -> crate::Result<Self> {
+        Self::with_order(pattern, sources, true)
+    }
+
+    fn with_order(
+        pattern: PreparedPattern,
+        sources: Vec<Source>,
+        descending: bool,
+    ) -> crate::Result<Self> {
+        let mut iter = Self {
+            pattern,
+            pending: sources.iter().map(|_| None).collect(),
+            heap: BinaryHeap::with_capacity(sources.len()),
+            sources,
+            descending,
+        };
+        for source in 0..iter.sources.len() {
+            iter.advance(source)?;
+        }
+        Ok(iter)
+    }
+
+    /// Read the next match of a source and push it onto the heap
+    fn advance(&mut self, source: usize) -> crate::Result<()> {
+        if let Some(found) = self.sources[source].next(&self.pattern)? {
+            let (key, timestamp) = (found.key().to_vec(), found.timestamp());
+            let entry = match self.descending {
+                false => MergeEntry::new(key, timestamp, source),
+                true => MergeEntry::descending(key, timestamp, source),
+            };
+            self.heap.push(entry);
+            self.pending[source] = Some(found);
+        }
+        Ok(())
+    }
+
+    fn next_key(&mut self) -> crate::Result<Option<Vec<u8>>> {
+        while let Some(top) = self.heap.pop() {
+            // the first entry of a key is its newest record
+            let found = self.pending[top.reader()].take().unwrap();
+            self.advance(top.reader())?;
+            while self
+                .heap
+                .peek()
+                .map(|next| next.key() == found.key())
+                .unwrap_or(false)
+            {
+                let older = self.heap.pop().unwrap();
+                self.pending[older.reader()].take();
+                self.advance(older.reader())?;
+            }
+            if found.is_live() {
+                return Ok(Some(found.into_key()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for FindIter {
+    type Item = crate::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_key().transpose()
+    }
+}
+
+/// StoreIter walks every live key of a `KvStore` along with its value, in
+/// sorted key order, or in descending key order when it was created by
+/// `KvStore::scan_rev`. It reads from a snapshot taken when it was created,
+/// so writes made to the store afterwards are never seen. The memory table
+/// and the segments of every level are merged lazily, and only the newest
+/// record of every key is handed out.
+pub struct StoreIter {
+    snapshot: Snapshot,
+    keys: Option<FindIter>,
+    /// Smallest key that is handed out
+    start: Vec<u8>,
+    /// Keys greater or equal to it are never handed out
+    end: Option<Vec<u8>>,
+    descending: bool,
+}
+
+impl StoreIter {
+    pub(crate) fn new(snapshot: Snapshot) -> Self {
+        Self {
+            snapshot,
+            keys: None,
+            start: Vec::new(),
+            end: None,
+            descending: false,
+        }
+    }
+
+    /// Walk the keys from `start` up to but not including `end`, from the
+    /// largest to the smallest
+    pub(crate) fn rev(snapshot: Snapshot, start: &[u8], end: &[u8]) -> Self {
+        Self {
+            start: start.to_vec(),
+            end: Some(end.to_vec()),
+            descending: true,
+            ..Self::new(snapshot)
+        }
+    }
+
+    /// Move the iterator to the first key that is greater or equal to
+    /// `key`, or to the last key that is lower or equal to `key` when
+    /// walking in descending order. The segments are only read again once
+    /// the next key is asked for, and only from the block that could hold
+    /// `key`.
+    pub fn seek(&mut self, key: &[u8]) {
+        match self.descending {
+            false => self.start = key.to_vec(),
+            true => {
+                // the smallest key greater than `key`
+                let mut end = key.to_vec();
+                end.push(0);
+                self.end = Some(end);
+            }
+        }
+        self.keys = None;
+    }
+
+    fn next_entry(&mut self) -> crate::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.keys.is_none() {
+            let keys = match self.descending {
+                false => self.snapshot.keys_from(&self.start)?,
+                true => self.snapshot.keys_before(self.end.as_deref())?,
+            };
+            self.keys = Some(keys);
+        }
+        let keys = self.keys.as_mut().unwrap();
+        while let Some(key) = keys.next().transpose()? {
+            let below = key < self.start;
+            let above = matches!(&self.end, Some(end) if key >= *end);
+            // keys past the far end of the walk mean every key after them is
+            // as well, while keys before the near end are left over from the
+            // block the walk started in
+            if (below && self.descending) || (above && !self.descending) {
+                return Ok(None);
+            }
+            if below || above {
+                continue;
+            }
+            // the key may have expired after it was found
+            if let Some(value) = self.snapshot.get(&key)? {
+                return Ok(Some((key, value)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for StoreIter {
+    type Item = crate::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
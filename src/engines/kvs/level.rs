@@ -1,18 +1,29 @@
 use std::{
-    collections::HashSet,
     ffi::OsStr,
     path::{Path, PathBuf},
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, MutexGuard, RwLock},
 };
 
-use crate::{common::now, datastructures::matcher::PreparedPattern};
+use crate::{
+    common::{next_timestamp, observe_timestamp},
+    datastructures::matcher::PreparedPattern,
+};
 
-use super::sstable::{SSTable, Segment, SegmentReader};
+use super::{
+    config::Config,
+    iter::Source,
+    sstable::{
+        KeyMatch, Record, ReverseSegmentReader, SSTable, Segment, SegmentReader, SEGMENT_VERSION,
+    },
+};
 
-#[derive(Debug)]
+/// Storage is shared behind an `Arc` so that readers can take a cheap snapshot
+/// of a level and release its lock before touching the disk. A segment's file
+/// is only deleted once the last snapshot holding it is dropped.
+#[derive(Clone, Debug)]
 pub enum Storage {
-    SSTable(SSTable),
-    Segment(Segment),
+    SSTable(Arc<SSTable>),
+    Segment(Arc<Segment>),
 }
 
 impl Storage {
@@ -23,7 +34,7 @@ impl Storage {
         }
     }
 
-    pub fn sstable(&self) -> Option<&SSTable> {
+    pub fn sstable(&self) -> Option<&Arc<SSTable>> {
         match self {
             Storage::SSTable(s) => Some(s),
             Storage::Segment(_) => None,
@@ -49,10 +60,15 @@ struct Lvl {
     level: usize,
     dir: PathBuf,
     segments: Vec<Storage>,
+    config: Arc<Config>,
 }
 
 impl Level {
-    pub fn new(directory: impl Into<PathBuf>, level: usize) -> crate::Result<Self> {
+    pub fn new(
+        directory: impl Into<PathBuf>,
+        level: usize,
+        config: Arc<Config>,
+    ) -> crate::Result<Self> {
         debug!("Finding all files being added to level {}", level);
         let directory = directory.into();
         let dirs = std::fs::read_dir(&directory)?;
@@ -70,19 +86,17 @@ impl Level {
             log_paths.push(entry.path());
         }
         // sort log paths by their file stem number
-        log_paths.sort_by_key(|f| {
-            f.file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .parse::<u128>()
-                .unwrap()
-        });
+        log_paths.sort_by_key(|f| file_number(f));
 
         trace!("Logs are sorted {:?}", log_paths);
         let mut segments = vec![];
         for path in log_paths {
-            segments.push(Storage::Segment(Segment::from_log(path)?));
+            let segment = Segment::from_log(path, &config)?;
+            if segment.len() == 0 {
+                debug!("Skipping {} as it holds no records", segment);
+                continue;
+            }
+            segments.push(Storage::Segment(Arc::new(segment)));
         }
 
         debug!("Level {} indices set {:?}", level, segments);
@@ -91,44 +105,124 @@ impl Level {
                 dir: directory,
                 level,
                 segments,
+                config,
             })),
         })
     }
 
-    /// Update level mainly does 2 operations. The first is to find any SSTable
-    /// and convert it into a Segment with an index. After which, it will resave
-    /// it to the level as a segment.
+    /// Update level mainly does 2 operations. The first is to find every
+    /// SSTable and convert it into a Segment with an index. After which, it
+    /// will resave it to the level as a segment.
     ///
     /// With the level having the correct state, it then tries to merge it's file
     /// if, and only if, it reaches the given threshold.
     pub fn update_level(&self, next_path: impl AsRef<Path>) -> crate::Result<Option<Segment>> {
+        Ok(if self.needs_merge()? {
+            self.merge(next_path, false)?
+        } else {
+            None
+        })
+    }
+
+    /// Like `update_level`, but the segments of the level are merged into a
+    /// segment that stays inside of the level. The deepest level a store may
+    /// have is merged this way instead of into a new level.
+    pub fn update_level_in_place(&self) -> crate::Result<()> {
+        if self.needs_merge()? {
+            let dir = self.inner.read().unwrap().dir.clone();
+            self.merge(dir, true)?;
+        }
+        Ok(())
+    }
+
+    /// Save every table of the level, then check if the level holds more
+    /// segments than its merge threshold, or more than one segment taking up
+    /// more bytes than the level may hold
+    fn needs_merge(&self) -> crate::Result<bool> {
+        self.save_tables()?;
+
         let lock = self.inner.read().unwrap();
         let length = lock.segments.len();
-        let level = lock.level;
-
-        if let Some((index, table)) = lock
+        let bytes = lock
             .segments
             .iter()
-            .enumerate()
-            .find_map(|(u, s)| s.sstable().map(|t| (u, t)))
-        {
-            let new_segment = table.save(lock.dir.join(format!("{}.log", now())))?;
-            trace!("Created new {} from {}", new_segment, table);
-            let length = lock.segments.len();
-            drop(lock);
-            self.inner.write().unwrap().segments[index] = Storage::Segment(new_segment);
-            trace!("Level {} segments have been updated to {}", level, length);
-        } else {
+            .filter_map(Storage::segment)
+            .map(Segment::byte_size)
+            .sum::<u64>();
+        trace!(
+            "Level {}: Segments before merge {} ({} bytes)",
+            lock.level,
+            length,
+            bytes
+        );
+        let too_large = matches!(lock.config.max_level_bytes(lock.level), Some(max) if bytes > max);
+        Ok(length > lock.config.merge_threshold(lock.level) || (length > 1 && too_large))
+    }
+
+    /// Convert every SSTable inside of the level into a segment
+    fn save_tables(&self) -> crate::Result<()> {
+        loop {
+            let lock = self.inner.read().unwrap();
+            let table = match lock.segments.iter().find_map(|s| s.sstable().cloned()) {
+                Some(table) => table,
+                None => break,
+            };
+            let path = lock.dir.join(format!("{}.log", next_timestamp()));
+            let config = lock.config.clone();
             drop(lock);
+
+            let new_segment = table.save(path, &config)?;
+            trace!("Created new {} from {}", new_segment, table);
+            let mut lock = self.inner.write().unwrap();
+            let position = lock.segments.iter().position(|s| match s {
+                Storage::SSTable(s) => Arc::ptr_eq(s, &table),
+                Storage::Segment(_) => false,
+            });
+            match position {
+                Some(index) => lock.segments[index] = Storage::Segment(Arc::new(new_segment)),
+                // the level was cleared while the table was being saved
+                None => new_segment.mark_for_removal(),
+            }
+            trace!(
+                "Level {} segments have been updated to {}",
+                lock.level,
+                lock.segments.len()
+            );
         }
+        Ok(())
+    }
 
-        trace!("Level {}: Segments before merge {}", level, length);
-        Ok(if length > clamp(10 * level, 2) {
-            let merge = self.merge(next_path)?;
-            Some(merge)
-        } else {
-            None
-        })
+    /// Segments currently inside of the level, from oldest to newest
+    fn segments(&self) -> Vec<Arc<Segment>> {
+        self.inner
+            .read()
+            .unwrap()
+            .segments
+            .iter()
+            .filter_map(|s| match s {
+                Storage::Segment(s) => Some(s.clone()),
+                Storage::SSTable(_) => None,
+            })
+            .collect()
+    }
+
+    /// Remove the given segments from the level and delete their files
+    fn remove_segments(&self, segments: &[Arc<Segment>]) {
+        self.forget_segments(segments, true)
+    }
+
+    /// Remove the given segments from the level, only deleting their files
+    /// if `delete_files` is set
+    fn forget_segments(&self, segments: &[Arc<Segment>], delete_files: bool) {
+        self.inner.write().unwrap().segments.retain(|s| match s {
+            Storage::Segment(s) if segments.iter().any(|r| Arc::ptr_eq(r, s)) => {
+                if delete_files {
+                    s.mark_for_removal();
+                }
+                false
+            }
+            _ => true,
+        });
     }
 
     pub fn add(&self, storage: Storage) -> crate::Result<()> {
@@ -141,75 +235,135 @@ impl Level {
         Ok(())
     }
 
-    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
-        for level in self.inner.read().unwrap().segments.iter().rev() {
-            if let Some(value) = match level {
-                Storage::SSTable(s) => s.get(key),
-                Storage::Segment(s) => s.get(key)?,
-            } {
-                return Ok(Some(value));
+    /// Remove every segment from the level and delete their files
+    pub fn clear(&self) {
+        let mut lock = self.inner.write().unwrap();
+        for storage in lock.segments.iter() {
+            if let Storage::Segment(segment) = storage {
+                segment.mark_for_removal();
             }
         }
-        Ok(None)
+        lock.segments.clear();
     }
 
-    pub fn find(&self, pattern: &PreparedPattern) -> crate::Result<Vec<Vec<u8>>> {
-        let mut keys = std::collections::HashSet::new();
-        for level in self.inner.read().unwrap().segments.iter().rev() {
-            let new_keys = match level {
-                Storage::SSTable(s) => s.find(pattern),
-                Storage::Segment(s) => s.find(pattern)?,
-            };
-            for key in new_keys {
-                keys.insert(key);
+    /// Return the number of segments in the level and the number of keys they hold
+    pub fn stats(&self) -> (usize, usize) {
+        let lock = self.inner.read().unwrap();
+        let keys = lock.segments.iter().fold(0, |o, s| {
+            o + match s {
+                Storage::SSTable(s) => s.len(),
+                Storage::Segment(s) => s.len(),
             }
+        });
+        (lock.segments.len(), keys)
+    }
+
+    /// Get the newest record of a key inside of the level. The record may
+    /// be a tombstone or expired, which hides older records of the key. The
+    /// level is not locked while segments are read from disk, and every read
+    /// opens its own handle to the segment file.
+    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Record>> {
+        newest_in(&self.snapshot(), key)
+    }
+
+    /// Take a snapshot of the storage inside of the level. The level lock is
+    /// only held long enough to clone the handles.
+    fn snapshot(&self) -> Vec<Storage> {
+        self.inner.read().unwrap().segments.clone()
+    }
+
+    /// Find all keys matching the pattern. The level is not locked while the
+    /// segments are read and the thread yields between each segment so that
+    /// writers and compaction can make progress during a long scan.
+    pub fn find(&self, pattern: &PreparedPattern) -> crate::Result<Vec<KeyMatch>> {
+        let mut keys = vec![];
+        for level in self.snapshot().iter().rev() {
+            match level {
+                Storage::SSTable(s) => keys.extend(s.find(pattern)),
+                Storage::Segment(s) => keys.extend(s.find(pattern)?),
+            };
+            std::thread::yield_now();
         }
-        let keys = keys.into_iter().collect::<Vec<_>>();
         Ok(keys)
     }
 
-    fn merge(&self, path: impl AsRef<Path>) -> crate::Result<Segment> {
-        let segment_path = path.as_ref().join(format!("{}.log", now()));
+    /// Rewrite every segment that was written with an older format version
+    /// into the newest one. The rewritten segment goes to a new file with the
+    /// same file number, so its order within the level doesn't change, and
+    /// the old file is removed once the last reader of it is done. Returns
+    /// the number of rewritten segments.
+    ///
+    /// Callers have to hold the merge lock, so no merge replaces the
+    /// segments while they are rewritten.
+    pub fn upgrade(&self) -> crate::Result<usize> {
+        let outdated = self
+            .segments()
+            .into_iter()
+            .filter(|s| s.version() < SEGMENT_VERSION)
+            .collect::<Vec<_>>();
+        let config = self.inner.read().unwrap().config.clone();
+
+        let mut upgraded = 0;
+        for old in outdated {
+            debug!(
+                "Upgrading segment {:?} to version {}",
+                old.path(),
+                SEGMENT_VERSION
+            );
+            let reader = SegmentReader::new(&old)?;
+            let segment = Segment::from_segments(upgraded_path(old.path()), vec![reader], &config)?;
+            let mut lock = self.inner.write().unwrap();
+            match lock
+                .segments
+                .iter_mut()
+                .find(|s| matches!(s, Storage::Segment(s) if Arc::ptr_eq(s, &old)))
+            {
+                Some(storage) => {
+                    *storage = Storage::Segment(Arc::new(segment));
+                    old.mark_for_removal();
+                    upgraded += 1;
+                }
+                None => segment.mark_for_removal(),
+            }
+        }
+        Ok(upgraded)
+    }
+
+    /// Merge every segment of the level into a new segment inside of `path`.
+    /// Returns the new segment, unless it was put back into the level
+    /// `in_place`, taking the place of the segments it merged.
+    fn merge(&self, path: impl AsRef<Path>, in_place: bool) -> crate::Result<Option<Segment>> {
+        let segment_path = path.as_ref().join(format!("{}.log", next_timestamp()));
         // get all of the relavent segments
-        let lock = self.inner.read().unwrap();
-        let storage_segments = lock
-            .segments
-            .iter()
-            .enumerate()
-            .filter(|(_, s)| s.segment().is_some())
-            .collect::<Vec<(usize, &Storage)>>();
-        // partition the segments and indexies
-        let segment_readers: Vec<SegmentReader> = storage_segments
+        let segments = self.segments();
+        let segment_readers: Vec<SegmentReader> = segments
             .iter()
-            .filter_map(|(_, s)| s.segment())
             .filter_map(|s| SegmentReader::new(s).ok())
             .collect();
-        let mut indexies = storage_segments.iter().map(|i| i.0).collect::<Vec<usize>>();
-        indexies.sort();
-        drop(lock);
+        let config = self.inner.read().unwrap().config.clone();
 
         // attempt the merging processes
-        let segment = Segment::from_segments(segment_path, segment_readers)?;
+        let segment = Segment::from_segments(segment_path, segment_readers, &config)?;
 
-        // on successful compaction, remove the segments touched
+        // on successful compaction, remove the segments touched. The level
+        // may have changed while merging, so they are looked up by identity
+        // rather than by their position.
         let mut lock = self.inner.write().unwrap();
-        for index in indexies.iter().rev() {
-            if let Storage::Segment(segment) = lock.segments.get_mut(*index).unwrap() {
-                segment.mark_for_removal();
-                lock.segments.remove(*index);
+        lock.segments.retain(|s| match s {
+            Storage::Segment(s) if segments.iter().any(|m| Arc::ptr_eq(m, s)) => {
+                s.mark_for_removal();
+                false
             }
+            _ => true,
+        });
+        if in_place {
+            // everything added to the level since the merge started is newer
+            lock.segments.insert(0, Storage::Segment(Arc::new(segment)));
+            return Ok(None);
         }
         drop(lock);
 
-        Ok(segment)
-    }
-}
-
-fn clamp(level: usize, min: usize) -> usize {
-    if level < min {
-        min
-    } else {
-        level
+        Ok(Some(segment))
     }
 }
 
@@ -217,29 +371,227 @@ fn clamp(level: usize, min: usize) -> usize {
 pub struct Levels {
     inner: Arc<RwLock<Vec<Level>>>,
     directory: Arc<RwLock<PathBuf>>,
+    config: Arc<Config>,
+    merge_lock: Arc<Mutex<()>>,
+}
+
+/// Newest record of a key inside of the storage of a level, which is held
+/// from oldest to newest
+fn newest_in(storage: &[Storage], key: &[u8]) -> crate::Result<Option<Record>> {
+    for storage in storage.iter().rev() {
+        if let Some(record) = match storage {
+            Storage::SSTable(s) => s.get(key),
+            Storage::Segment(s) => s.get(key)?,
+        } {
+            return Ok(Some(record));
+        }
+    }
+    Ok(None)
+}
+
+/// Newest record of a key inside of a snapshot of every level
+pub fn get_pinned(levels: &[Vec<Storage>], key: &[u8]) -> crate::Result<Option<Record>> {
+    for storage in levels.iter() {
+        if let Some(record) = newest_in(storage, key)? {
+            return Ok(Some(record));
+        }
+    }
+    Ok(None)
+}
+
+/// Sorted streams of the keys matching the pattern in every table and
+/// segment of a snapshot of every level, from oldest to newest. Segments
+/// whose keys can't match the pattern are left out.
+pub fn sources_pinned(
+    levels: &[Vec<Storage>],
+    pattern: &PreparedPattern,
+) -> crate::Result<Vec<Source>> {
+    sources_from(levels, pattern, &[])
+}
+
+/// Like `sources_pinned`, but the streams start at the block holding
+/// `start`. Tables held in memory leave out every key lower than `start` and
+/// so do segments that end before it, while other segments may still hand
+/// out a few lower keys from the start of their first block.
+pub fn sources_from(
+    levels: &[Vec<Storage>],
+    pattern: &PreparedPattern,
+    start: &[u8],
+) -> crate::Result<Vec<Source>> {
+    let mut sources = vec![];
+    for level in levels.iter().rev() {
+        for storage in level.iter() {
+            match storage {
+                Storage::SSTable(s) => sources.push(memory_from(s, pattern, start)),
+                Storage::Segment(s) if s.may_match(pattern) && !s.ends_before(start) => {
+                    sources.push(Source::Segment {
+                        reader: Box::new(SegmentReader::starting_at(s, start)?),
+                        _segment: s.clone(),
+                    });
+                }
+                Storage::Segment(_) => {}
+            }
+        }
+    }
+    Ok(sources)
+}
+
+/// Stream the keys of a table held in memory that match the pattern and
+/// aren't lower than `start`
+pub fn memory_from(table: &SSTable, pattern: &PreparedPattern, start: &[u8]) -> Source {
+    let mut found = table.find(pattern);
+    found.retain(|found| found.key() >= start);
+    Source::Memory(found.into_iter())
+}
+
+/// Like `sources_pinned`, but every stream hands out its keys from largest
+/// to smallest, starting at the block holding the last key lower than `end`.
+/// Tables held in memory leave out every key that isn't lower than `end`,
+/// while segments may still hand out a few of them from the end of their
+/// first block.
+pub fn sources_before(
+    levels: &[Vec<Storage>],
+    pattern: &PreparedPattern,
+    end: Option<&[u8]>,
+) -> crate::Result<Vec<Source>> {
+    let mut sources = vec![];
+    for level in levels.iter().rev() {
+        for storage in level.iter() {
+            match storage {
+                Storage::SSTable(s) => sources.push(memory_before(s, pattern, end)),
+                Storage::Segment(s) if s.may_match(pattern) => {
+                    sources.push(Source::Reversed {
+                        reader: Box::new(ReverseSegmentReader::ending_before(s, end)),
+                        _segment: s.clone(),
+                    });
+                }
+                Storage::Segment(_) => {}
+            }
+        }
+    }
+    Ok(sources)
+}
+
+/// Stream the keys of a table held in memory that match the pattern and are
+/// lower than `end`, from largest to smallest
+pub fn memory_before(table: &SSTable, pattern: &PreparedPattern, end: Option<&[u8]>) -> Source {
+    let mut found = table.find(pattern);
+    if let Some(end) = end {
+        found.retain(|found| found.key() < end);
+    }
+    found.reverse();
+    Source::Memory(found.into_iter())
+}
+
+/// Segment files are named after the time they were created, so a larger
+/// number is a newer segment
+fn file_number(path: &Path) -> u128 {
+    path.file_stem()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse::<u128>()
+        .unwrap()
+}
+
+/// Path of the upgraded copy of a segment. A leading zero keeps the file
+/// number of the segment, so the copy takes its place when the level is
+/// loaded, without reusing a path that readers of the old segment still open.
+fn upgraded_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap().to_str().unwrap();
+    path.with_file_name(format!("0{}.log", stem))
+}
+
+/// Delete segments that were still being written when the store last shut
+/// down. They were either never renamed to a `.log` or never written to, so
+/// they hold no records.
+fn remove_unfinished_files(directory: &Path) -> crate::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let unfinished = match path.extension().and_then(OsStr::to_str) {
+            Some("tmp") => true,
+            Some("log") => std::fs::metadata(&path)?.len() == 0,
+            _ => false,
+        };
+        if unfinished {
+            warn!("Removing unfinished segment {:?}", path);
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove segments left behind by a merge that stopped after writing its
+/// output but before deleting its inputs. Every record of such a segment is
+/// also held by a newer segment in the same or a deeper level, and leaving it
+/// in place would let its older values shadow newer ones. A read-only store
+/// only leaves them out of its levels.
+fn remove_superseded(levels: &[Level], delete_files: bool) {
+    let segments = levels
+        .iter()
+        .enumerate()
+        .flat_map(|(depth, level)| level.segments().into_iter().map(move |s| (depth, s)))
+        .collect::<Vec<_>>();
+    let mut removed: Vec<Arc<Segment>> = vec![];
+    for (depth, stale) in segments.iter() {
+        let superseded = segments
+            .iter()
+            .filter(|(newer_depth, newer)| {
+                newer_depth >= depth
+                    // an upgraded copy of a segment has the same file number
+                    && (file_number(newer.path()), newer.version())
+                        > (file_number(stale.path()), stale.version())
+                    && !removed.iter().any(|r| Arc::ptr_eq(r, newer))
+            })
+            .any(|(_, newer)| stale.superseded_by(newer));
+        if superseded {
+            warn!("Removing {} as a newer segment holds its records", stale);
+            levels[*depth].forget_segments(std::slice::from_ref(stale), delete_files);
+            removed.push(stale.clone());
+        }
+    }
 }
 
 impl Levels {
-    pub fn new(directory: impl Into<PathBuf>) -> crate::Result<Self> {
+    pub fn new(directory: impl Into<PathBuf>, config: Arc<Config>) -> crate::Result<Self> {
         let directory = directory.into(); // parent directory;
         let mut level = 2;
-        let mut levels = vec![Level::new(&directory, 1)?];
+        let read_only = config.is_read_only();
+        if !read_only {
+            remove_unfinished_files(&directory)?;
+        }
+        let mut levels = vec![Level::new(&directory, 1, config.clone())?];
         loop {
             let lvl_dir = directory.join(format!("lv{}", level));
             if !lvl_dir.exists() {
                 break;
             }
-            levels.push(Level::new(lvl_dir, level)?);
+            if !read_only {
+                remove_unfinished_files(&lvl_dir)?;
+            }
+            levels.push(Level::new(lvl_dir, level, config.clone())?);
             level += 1;
         }
+        remove_superseded(&levels, !read_only);
+        // new writes have to be newer than every segment, even if the clock
+        // stepped back since the segments were written
+        for segment in levels.iter().flat_map(Level::segments) {
+            observe_timestamp(segment.max_timestamp()?);
+        }
 
         Ok(Self {
             inner: Arc::new(RwLock::new(levels)),
             directory: Arc::new(RwLock::new(directory)),
+            config,
+            merge_lock: Arc::new(Mutex::new(())),
         })
     }
 
     pub fn try_merge(&self) -> crate::Result<()> {
+        let _merging = self.merge_lock.lock().unwrap();
         let directory = (self.directory.read().unwrap()).clone();
         let mut index = 0;
         let mut level_index = 2;
@@ -247,24 +599,40 @@ impl Levels {
 
         loop {
             let next_path = (*directory).join(format!("lv{}", level_index));
+            let deepest = self.config.is_deepest_level(level_index - 1);
 
-            if !next_path.exists() {
+            if !deepest && !next_path.exists() {
                 trace!("level folder does not exist. Creating {:?}", &next_path);
                 std::fs::create_dir(&next_path)?;
             }
             let inner = self.inner.read().unwrap();
             let level = match inner.get(index) {
-                Some(level) => level.clone(),
+                Some(level) => {
+                    let level = level.clone();
+                    drop(inner);
+                    if let Some(segment) = new_segment_file.take() {
+                        trace!("Attempting to merge index level {}", index);
+                        level.add(Storage::Segment(Arc::new(segment)))?;
+                    }
+                    level
+                }
                 None => {
                     drop(inner);
-                    let level = Level::new(&*directory, level_index)?;
+                    // the new level reads its folder, which already holds the
+                    // segment that was just merged into it
+                    drop(new_segment_file.take());
+                    let lvl_dir = directory.join(format!("lv{}", level_index - 1));
+                    let level = Level::new(lvl_dir, level_index - 1, self.config.clone())?;
                     self.inner.write().unwrap().push(level.clone());
                     level
                 }
             };
-            if let Some(segment) = new_segment_file.take() {
-                trace!("Attempting to merge index level {}", index);
-                level.add(Storage::Segment(segment))?;
+            if deepest {
+                info!(
+                    "Merging index level {} in place as it is the deepest",
+                    index
+                );
+                return level.update_level_in_place();
             }
             new_segment_file = level.update_level(next_path)?;
             if new_segment_file.is_none() {
@@ -285,29 +653,489 @@ impl Levels {
         }
     }
 
-    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
-        let levels = self.inner.read().unwrap();
+    /// Merge every segment of every level into a single segment inside of
+    /// the deepest level. Keys that were removed are left out of the new
+    /// segment, so their space is reclaimed.
+    pub fn compact(&self) -> crate::Result<()> {
+        let _merging = self.merge_lock.lock().unwrap();
+        let levels = self.inner.read().unwrap().clone();
         for level in levels.iter() {
-            if let Some(value) = level.get(key)? {
-                return Ok(Some(value));
+            level.save_tables()?;
+        }
+
+        let segments = levels.iter().map(Level::segments).collect::<Vec<_>>();
+        let readers = segments
+            .iter()
+            .flatten()
+            .map(|s| SegmentReader::new(s))
+            .collect::<crate::Result<Vec<_>>>()?;
+        if readers.is_empty() {
+            return Ok(());
+        }
+
+        let target = levels.last().unwrap();
+        let path = target
+            .inner
+            .read()
+            .unwrap()
+            .dir
+            .join(format!("{}.log", next_timestamp()));
+        let merged = readers.len();
+        let segment = Segment::compact_segments(path, readers, &self.config)?;
+        info!("Compacted {} segments into {}", merged, segment);
+
+        for (level, merged) in levels.iter().zip(segments.iter()) {
+            level.remove_segments(merged);
+        }
+        if segment.len() == 0 {
+            segment.mark_for_removal();
+        } else {
+            // everything written since the compaction started is newer
+            let mut lock = target.inner.write().unwrap();
+            lock.segments.insert(0, Storage::Segment(Arc::new(segment)));
+        }
+        Ok(())
+    }
+
+    /// Write every table waiting in the first level to a segment
+    pub fn save_tables(&self) -> crate::Result<()> {
+        let first = self.inner.read().unwrap()[0].clone();
+        first.save_tables()
+    }
+
+    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Record>> {
+        let levels = self.inner.read().unwrap().clone();
+        for level in levels.iter() {
+            if let Some(record) = level.get(key)? {
+                return Ok(Some(record));
             }
         }
         Ok(None)
     }
 
-    pub fn find(&self, pattern: &PreparedPattern) -> crate::Result<HashSet<Vec<u8>>> {
-        let mut keys = HashSet::new();
-        let levels = self.inner.read().unwrap();
+    pub fn find(&self, pattern: &PreparedPattern) -> crate::Result<Vec<KeyMatch>> {
+        let mut keys = vec![];
+        let levels = self.inner.read().unwrap().clone();
         for level in levels.iter() {
-            for new_key in level.find(pattern)? {
-                keys.insert(new_key);
-            }
+            keys.extend(level.find(pattern)?);
         }
         Ok(keys)
     }
 
+    /// Sorted streams of the keys matching the pattern in every table and
+    /// segment, from oldest to newest. Segments whose keys can't match the
+    /// pattern are left out.
+    pub fn sources(&self, pattern: &PreparedPattern) -> crate::Result<Vec<Source>> {
+        sources_pinned(&self.snapshot(), pattern)
+    }
+
+    /// Take a snapshot of the storage inside of every level, from the first
+    /// level to the last. Holding it keeps the segment files from being
+    /// deleted.
+    pub fn snapshot(&self) -> Vec<Vec<Storage>> {
+        let levels = self.inner.read().unwrap().clone();
+        levels.iter().map(Level::snapshot).collect()
+    }
+
+    /// Hold off merges and compactions until the guard is dropped. A merge
+    /// moves segments between levels, so a snapshot taken in the middle of
+    /// one could find a segment in two levels or in none.
+    pub fn pause_merges(&self) -> MutexGuard<'_, ()> {
+        self.merge_lock.lock().unwrap()
+    }
+
+    /// Remove all data from every level. Only the first level is kept and
+    /// every deeper level folder is deleted. Callers have to pause merges
+    /// first, as a merge could be writing into one of those folders.
+    pub fn clear(&self) -> crate::Result<()> {
+        let mut levels = self.inner.write().unwrap();
+        for level in levels.iter() {
+            level.clear();
+        }
+        levels.truncate(1);
+
+        let directory = self.directory.read().unwrap();
+        let mut level_index = 2;
+        loop {
+            let lvl_dir = directory.join(format!("lv{}", level_index));
+            if !lvl_dir.exists() {
+                break;
+            }
+            trace!("Removing level folder {:?}", lvl_dir);
+            std::fs::remove_dir_all(lvl_dir)?;
+            level_index += 1;
+        }
+        Ok(())
+    }
+
+    /// Rewrite every segment in every level to the newest format version
+    pub fn upgrade(&self) -> crate::Result<usize> {
+        let _merging = self.merge_lock.lock().unwrap();
+        let levels = self.inner.read().unwrap().clone();
+        let mut upgraded = 0;
+        for level in levels {
+            upgraded += level.upgrade()?;
+        }
+        Ok(upgraded)
+    }
+
+    pub fn stats(&self) -> Vec<(usize, usize)> {
+        self.inner
+            .read()
+            .unwrap()
+            .iter()
+            .map(Level::stats)
+            .collect()
+    }
+
     pub fn add_table(&self, sstable: SSTable) -> crate::Result<()> {
-        self.inner.read().unwrap()[0].add(Storage::SSTable(sstable))?;
+        self.inner.read().unwrap()[0].add(Storage::SSTable(Arc::new(sstable)))?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Barrier,
+        },
+        time::{Duration, Instant},
+    };
+
+    use tempfile::TempDir;
+
+    use super::{newest_in, Level, Levels, Storage};
+    use crate::datastructures::matcher::prepare;
+    use crate::engines::kvs::{
+        config::{Config, FsyncPolicy},
+        sstable::{Record, SSTable, SEGMENT_VERSION},
+    };
+
+    /// Write a segment in the legacy layout, which only has the element count
+    /// as its header.
+    fn write_legacy_segment(path: impl AsRef<std::path::Path>, records: &[Record]) {
+        let mut file = std::fs::File::create(path).unwrap();
+        file.write_all(&records.len().to_be_bytes()).unwrap();
+        for record in records {
+            file.write_all(&bincode::serialize(record).unwrap())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn upgrade_legacy_segments() {
+        let temp_dir = TempDir::new().unwrap();
+        for segment in 0..3 {
+            let records = (0..10)
+                .map(|i| {
+                    Record::new(
+                        format!("key{}", i).into_bytes(),
+                        Some(format!("value{}-{}", segment, i).into_bytes()),
+                    )
+                })
+                .collect::<Vec<_>>();
+            write_legacy_segment(temp_dir.path().join(format!("{}.log", segment)), &records);
+        }
+
+        let level = Level::new(temp_dir.path(), 1, Arc::new(Config::new(temp_dir.path()))).unwrap();
+        let snapshot = level.snapshot();
+        assert_eq!(level.upgrade().unwrap(), 3);
+        assert_eq!(level.upgrade().unwrap(), 0);
+        // readers that started before the upgrade still read the old files
+        assert_eq!(
+            newest_in(&snapshot, b"key3")
+                .unwrap()
+                .and_then(Record::into_live_value),
+            Some(b"value2-3".to_vec())
+        );
+        drop(snapshot);
+        for segment in 0..3 {
+            assert!(!temp_dir.path().join(format!("{}.log", segment)).exists());
+            assert!(temp_dir.path().join(format!("0{}.log", segment)).exists());
+        }
+        for segment in level.inner.read().unwrap().segments.iter() {
+            assert_eq!(segment.segment().unwrap().version(), SEGMENT_VERSION);
+        }
+        assert_eq!(
+            level
+                .get(b"key3")
+                .unwrap()
+                .and_then(Record::into_live_value),
+            Some(b"value2-3".to_vec()),
+            "newest segment should still win after the upgrade"
+        );
+
+        // the upgraded files are read back in the newest format
+        drop(level);
+        let level = Level::new(temp_dir.path(), 1, Arc::new(Config::new(temp_dir.path()))).unwrap();
+        for segment in level.inner.read().unwrap().segments.iter() {
+            assert_eq!(segment.segment().unwrap().version(), SEGMENT_VERSION);
+        }
+        for i in 0..10 {
+            let key = format!("key{}", i).into_bytes();
+            assert_eq!(
+                level.get(&key).unwrap().and_then(Record::into_live_value),
+                Some(format!("value2-{}", i).into_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn outdated_copies_of_upgraded_segments_are_removed() {
+        let temp_dir = TempDir::new().unwrap();
+        let records = vec![Record::new(b"key".to_vec(), Some(b"value".to_vec()))];
+        write_legacy_segment(temp_dir.path().join("1.log"), &records);
+        let config = Arc::new(Config::new(temp_dir.path()));
+        let levels = Levels::new(temp_dir.path(), config.clone()).unwrap();
+        assert_eq!(levels.upgrade().unwrap(), 1);
+        drop(levels);
+
+        // the store stopped before the old file was removed
+        write_legacy_segment(temp_dir.path().join("1.log"), &records);
+        let levels = Levels::new(temp_dir.path(), config).unwrap();
+        assert!(!temp_dir.path().join("1.log").exists());
+        assert_eq!(levels.stats(), vec![(1, 1)]);
+        assert_eq!(
+            levels
+                .get(b"key")
+                .unwrap()
+                .and_then(Record::into_live_value),
+            Some(b"value".to_vec())
+        );
+    }
+
+    #[test]
+    fn empty_segment_files_are_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        let records = vec![Record::new(b"key".to_vec(), Some(b"value".to_vec()))];
+        write_legacy_segment(temp_dir.path().join("0.log"), &records);
+        std::fs::File::create(temp_dir.path().join("1.log")).unwrap();
+        std::fs::write(temp_dir.path().join("2.log"), [0, 0, 0]).unwrap();
+
+        let level = Level::new(temp_dir.path(), 1, Arc::new(Config::new(temp_dir.path()))).unwrap();
+        assert_eq!(level.stats(), (1, 1));
+        assert_eq!(
+            level.get(b"key").unwrap().and_then(Record::into_live_value),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(level.find(&prepare(b"*".to_vec())).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn compaction_fanout_controls_merges() {
+        let merge_with_fanout = |fanout: Option<usize>| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut builder = Config::builder();
+            if let Some(fanout) = fanout {
+                builder = builder.compaction_fanout(fanout);
+            }
+            let config = Arc::new(builder.build().with_folder(temp_dir.path()));
+            let levels = Levels::new(temp_dir.path(), config).unwrap();
+            for table in 0..3 {
+                let sstable = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+                for i in 0..10 {
+                    let value = format!("value{}-{}", table, i).into_bytes();
+                    sstable
+                        .append(format!("key{}", i).into_bytes(), Some(value))
+                        .unwrap();
+                }
+                levels.add_table(sstable).unwrap();
+                levels.try_merge().unwrap();
+            }
+            let stats = levels.stats();
+            let newest = levels
+                .get(b"key4")
+                .unwrap()
+                .and_then(Record::into_live_value);
+            (stats, newest)
+        };
+
+        // the default fanout lets level 1 hold all three segments
+        let (stats, newest) = merge_with_fanout(None);
+        assert_eq!(stats, vec![(3, 30)]);
+        assert_eq!(newest, Some(b"value2-4".to_vec()));
+
+        // a fanout of 1 merges level 1 once it holds more than 2 segments
+        let (stats, newest) = merge_with_fanout(Some(1));
+        assert_eq!(stats, vec![(0, 0), (1, 10)]);
+        assert_eq!(newest, Some(b"value2-4".to_vec()));
+    }
+
+    #[test]
+    fn max_levels_stops_creating_levels() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = Config::builder()
+            .compaction_fanout(1)
+            .max_levels(2)
+            .build()
+            .with_folder(temp_dir.path());
+        let levels = Levels::new(temp_dir.path(), Arc::new(config)).unwrap();
+        for table in 0..30 {
+            let sstable = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+            for i in table..table + 10 {
+                let value = format!("value{}-{}", table, i).into_bytes();
+                sstable
+                    .append(format!("key{:02}", i).into_bytes(), Some(value))
+                    .unwrap();
+            }
+            levels.add_table(sstable).unwrap();
+            levels.try_merge().unwrap();
+            assert!(!temp_dir.path().join("lv3").exists());
+        }
+
+        let stats = levels.stats();
+        assert_eq!(stats.len(), 2);
+        // the second level was merged into itself instead of growing past 2
+        assert!(stats[1].0 <= 2);
+        for i in 0..39 {
+            let newest = i.min(29);
+            let value = levels
+                .get(format!("key{:02}", i).as_bytes())
+                .unwrap()
+                .and_then(Record::into_live_value);
+            assert_eq!(value, Some(format!("value{}-{}", newest, i).into_bytes()));
+        }
+
+        // the levels still read back the same after a restart
+        let config = Config::builder()
+            .max_levels(2)
+            .build()
+            .with_folder(temp_dir.path());
+        let levels = Levels::new(temp_dir.path(), Arc::new(config)).unwrap();
+        assert_eq!(levels.stats().len(), 2);
+        let value = levels
+            .get(b"key15")
+            .unwrap()
+            .and_then(Record::into_live_value);
+        assert_eq!(value, Some(b"value15-15".to_vec()));
+    }
+
+    #[test]
+    fn level_size_ratio_sets_merge_thresholds() {
+        let config = Config::builder().compaction_fanout(4).build();
+        let thresholds = (1..=3)
+            .map(|l| config.merge_threshold(l))
+            .collect::<Vec<_>>();
+        assert_eq!(thresholds, vec![4, 8, 12]);
+
+        let config = Config::builder()
+            .compaction_fanout(4)
+            .level_size_ratio(10)
+            .build();
+        let thresholds = (1..=3)
+            .map(|l| config.merge_threshold(l))
+            .collect::<Vec<_>>();
+        assert_eq!(thresholds, vec![4, 40, 400]);
+
+        // a level always holds at least 2 segments before merging
+        let config = Config::builder().compaction_fanout(0).build();
+        assert_eq!(config.merge_threshold(1), 2);
+    }
+
+    #[test]
+    fn level_bytes_trigger_a_merge() {
+        let merges = |max_level_bytes: Option<u64>| {
+            let temp_dir = TempDir::new().unwrap();
+            let next_dir = TempDir::new().unwrap();
+            let mut builder = Config::builder();
+            if let Some(bytes) = max_level_bytes {
+                builder = builder.compaction_max_level_bytes(bytes);
+            }
+            let config = Arc::new(builder.build().with_folder(temp_dir.path()));
+            let level = Level::new(temp_dir.path(), 1, config).unwrap();
+            // one oversized segment and a few small ones, well under the
+            // number of segments the level may hold
+            for (table, value_size) in [4096, 10, 10, 10].iter().enumerate() {
+                let sstable = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+                sstable
+                    .append(
+                        format!("key{}", table).into_bytes(),
+                        Some(vec![0; *value_size]),
+                    )
+                    .unwrap();
+                level.add(Storage::SSTable(Arc::new(sstable))).unwrap();
+            }
+            level.update_level(next_dir.path()).unwrap().is_some()
+        };
+
+        assert!(!merges(None));
+        assert!(!merges(Some(8192)));
+        assert!(merges(Some(4096)));
+
+        // a level holding a single segment isn't merged for its size
+        let config = Config::builder().compaction_max_level_bytes(1).build();
+        let temp_dir = TempDir::new().unwrap();
+        let level = Level::new(temp_dir.path(), 1, Arc::new(config)).unwrap();
+        let sstable = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        sstable
+            .append(b"key".to_vec(), Some(vec![0; 4096]))
+            .unwrap();
+        level.add(Storage::SSTable(Arc::new(sstable))).unwrap();
+        assert!(level.update_level(temp_dir.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn max_level_bytes_grow_with_the_level() {
+        let config = Config::builder().compaction_max_level_bytes(100).build();
+        assert_eq!(config.max_level_bytes(1), Some(100));
+        assert_eq!(config.max_level_bytes(3), Some(300));
+        let config = Config::builder()
+            .compaction_max_level_bytes(100)
+            .level_size_ratio(10)
+            .build();
+        assert_eq!(config.max_level_bytes(3), Some(10_000));
+        assert_eq!(Config::builder().build().max_level_bytes(1), None);
+    }
+
+    #[test]
+    fn find_does_not_block_writers() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal_dir = TempDir::new().unwrap();
+        for segment in 0..200 {
+            let records = (0..50)
+                .map(|i| Record::new(format!("key{}", i).into_bytes(), Some(b"value".to_vec())))
+                .collect::<Vec<_>>();
+            write_legacy_segment(temp_dir.path().join(format!("{}.log", segment)), &records);
+        }
+        let level = Level::new(temp_dir.path(), 1, Arc::new(Config::new(temp_dir.path()))).unwrap();
+
+        let barrier = Arc::new(Barrier::new(2));
+        let done = Arc::new(AtomicBool::new(false));
+        let scanner = {
+            let (level, barrier, done) = (level.clone(), barrier.clone(), done.clone());
+            std::thread::spawn(move || {
+                barrier.wait();
+                let start = Instant::now();
+                let keys = level.find(&prepare(b"*".to_vec())).unwrap();
+                done.store(true, Ordering::SeqCst);
+                (start.elapsed(), keys.len())
+            })
+        };
+
+        // every write adds the same table, so the writer never opens more
+        // than one write-ahead-log however long the scan takes
+        let table = Arc::new(SSTable::new(wal_dir.path(), FsyncPolicy::Never).unwrap());
+        barrier.wait();
+        let mut slowest_write = Duration::default();
+        for _ in 0..100_000 {
+            if done.load(Ordering::SeqCst) {
+                break;
+            }
+            let start = Instant::now();
+            level.add(Storage::SSTable(table.clone())).unwrap();
+            slowest_write = slowest_write.max(start.elapsed());
+        }
+        let (scan_duration, keys) = scanner.join().unwrap();
+
+        assert!(keys > 0);
+        assert!(
+            slowest_write < scan_duration,
+            "a write waited {:?} on a scan that took {:?}",
+            slowest_write,
+            scan_duration
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    fmt::Debug,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    config::{Compression, DEFAULT_BLOOM_FP_RATE},
+    format::SerializationFormat,
+};
+use crate::KvError;
+
+/// Name of the file inside of a store's folder that holds its manifest
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// The newest manifest version. Version 0 manifests only recorded the
+/// serialization format, version 1 added the block size and compression. The
+/// bloom filter false positive rate was added later, manifests without it
+/// belong to stores that always used the default rate. Version 2 added the
+/// directories the write-ahead-logs and segments are kept in.
+const MANIFEST_VERSION: u32 = 2;
+
+/// Manifest records the settings a store was created with, so it is never
+/// opened with settings that would misread its files. It is kept as JSON in
+/// the `MANIFEST` file of the store's folder.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub block_size: u64,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default = "default_bloom_fp_rate")]
+    pub bloom_fp_rate: f64,
+    pub serialization_format: SerializationFormat,
+    /// `None` when the write-ahead-logs are kept in the store's folder
+    #[serde(default)]
+    pub wal_dir: Option<PathBuf>,
+    /// `None` when the segments are kept in the store's folder
+    #[serde(default)]
+    pub segment_dir: Option<PathBuf>,
+}
+
+impl Manifest {
+    /// Create a manifest of the newest version for the given settings
+    pub fn new(
+        block_size: u64,
+        compression: Compression,
+        bloom_fp_rate: f64,
+        serialization_format: SerializationFormat,
+        wal_dir: Option<PathBuf>,
+        segment_dir: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            block_size,
+            compression,
+            bloom_fp_rate,
+            serialization_format,
+            wal_dir,
+            segment_dir,
+        }
+    }
+
+    /// Read the manifest of the store in `folder`, or `None` if the store
+    /// doesn't have one
+    pub fn read(folder: &Path) -> crate::Result<Option<Self>> {
+        match std::fs::read(folder.join(MANIFEST_FILE_NAME)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the manifest into `folder`. It is written to a temporary file
+    /// first and then renamed, so a crash never leaves half of it behind.
+    pub fn write(&self, folder: &Path) -> crate::Result<()> {
+        let path = folder.join(MANIFEST_FILE_NAME);
+        let temp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&temp)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &path)?;
+        Ok(())
+    }
+
+    /// Whether the manifest records every setting a store is created with
+    pub fn records_settings(&self) -> bool {
+        self.version >= 1
+    }
+
+    /// Whether the manifest records the directories of the store. Older
+    /// manifests don't know where the store kept its files.
+    pub fn records_dirs(&self) -> bool {
+        self.version >= 2
+    }
+
+    /// Record the directories of a store whose manifest didn't know them,
+    /// upgrading the manifest to the newest version
+    pub fn record_dirs(&mut self, wal_dir: Option<PathBuf>, segment_dir: Option<PathBuf>) {
+        self.version = MANIFEST_VERSION;
+        self.wal_dir = wal_dir;
+        self.segment_dir = segment_dir;
+    }
+
+    /// Refuse a manifest written by a newer version of the store
+    pub fn check_version(&self) -> crate::Result<()> {
+        if self.version > MANIFEST_VERSION {
+            return Err(KvError::ManifestMismatch {
+                setting: "manifest version".to_owned(),
+                found: self.version.to_string(),
+                requested: MANIFEST_VERSION.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn default_bloom_fp_rate() -> f64 {
+    DEFAULT_BLOOM_FP_RATE
+}
+
+/// Refuse a setting that was asked for but doesn't match the value recorded
+/// in the manifest. A setting that wasn't asked for takes the recorded value.
+pub fn check_setting<T: PartialEq + Debug>(
+    setting: &str,
+    found: T,
+    requested: Option<T>,
+) -> crate::Result<()> {
+    match requested {
+        Some(requested) if requested != found => Err(KvError::ManifestMismatch {
+            setting: setting.to_owned(),
+            found: format!("{:?}", found),
+            requested: format!("{:?}", requested),
+        }),
+        _ => Ok(()),
+    }
+}
@@ -1,15 +1,45 @@
 use std::{
-    path::PathBuf,
-    sync::{Arc, RwLock},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{mpsc::Receiver, Arc, Mutex, RwLock, RwLockWriteGuard, Weak},
+    time::Duration,
 };
 
-use crate::{datastructures::matcher::prepare, KvError, KvsEngine};
+use crate::{
+    common::{now, read_whole_entry},
+    datastructures::matcher::prepare_with,
+    engines::{keys_in_range, prefix_successor, Subscribers},
+    KeyIter, KvError, KvsEngine, MatchOptions, Notification, RecordMeta, StoreStats, VerifyReport,
+};
+
+use self::{
+    compactor::Compactor,
+    iter::{FindIter, Source},
+    level::{Levels, Storage},
+    sstable::{verify_write_ahead_log, KeyMatch, Record, SSTable},
+    writer::Writer,
+};
 
-use self::{config::Config, level::Levels, sstable::SSTable};
+pub use self::config::{Compression, Config, ConfigBuilder, FsyncPolicy};
+pub use self::format::SerializationFormat;
+pub use self::iter::StoreIter;
+pub use self::snapshot::Snapshot;
 
+mod cache;
+mod compactor;
 mod config;
+mod format;
+mod group_commit;
+mod iter;
 mod level;
+mod manifest;
+mod snapshot;
 mod sstable;
+mod writer;
+
+/// Number of key and value bytes read from a dump before they are written
+/// to the store together
+const IMPORT_BATCH_SIZE: usize = 64 * 1024;
 
 /// KvStore stores all the data for the kvstore
 #[derive(Clone)]
@@ -17,55 +47,620 @@ pub struct KvStore {
     config: Arc<Config>,
     sstable: Arc<RwLock<SSTable>>,
     levels: Levels,
+    compactor: Arc<Compactor>,
+    subscribers: Subscribers,
+    /// Thread that commits `set` and `remove` when the store has a single
+    /// writer
+    writer: Option<Arc<Writer>>,
+    /// Held by read-modify-write operations from the lookup to the write,
+    /// so they take turns without locking the memory table
+    updates: Arc<Mutex<()>>,
+}
+
+/// Handle to a store held by background threads that doesn't keep the store
+/// alive once every `KvStore` has been dropped. An upgraded handle commits
+/// its writes itself, even if the store has a single writer thread, which
+/// is how that thread commits them.
+struct WeakKvStore {
+    config: Arc<Config>,
+    sstable: Weak<RwLock<SSTable>>,
+    levels: Levels,
+    compactor: Weak<Compactor>,
+    subscribers: Subscribers,
+    updates: Arc<Mutex<()>>,
+}
+
+impl WeakKvStore {
+    fn upgrade(&self) -> Option<KvStore> {
+        Some(KvStore {
+            config: self.config.clone(),
+            sstable: self.sstable.upgrade()?,
+            levels: self.levels.clone(),
+            compactor: self.compactor.upgrade()?,
+            subscribers: self.subscribers.clone(),
+            writer: None,
+            updates: self.updates.clone(),
+        })
+    }
 }
 
 impl KvStore {
     /// Create or restore a key value store. Given a folder location.
     pub fn new(folder: impl Into<PathBuf>) -> crate::Result<Self> {
-        let config = Config::new(folder);
-        config.init()?;
+        Self::with_config(folder, Config::builder().build())
+    }
+
+    /// Create or restore a key value store in a folder using the given config.
+    pub fn with_config(folder: impl Into<PathBuf>, config: Config) -> crate::Result<Self> {
+        let config = Arc::new(config.with_folder(folder).init()?);
         let sstable = config.restore_wal()?;
         let levels = config.restore_levels()?;
+        let compactor = {
+            let levels = levels.clone();
+            Compactor::new(move || {
+                if let Err(e) = levels.try_merge() {
+                    error!("Failed to succesfully merge with error {}", e)
+                } else {
+                    info!("Successfully merged levels together");
+                }
+            })?
+        };
+
+        let sstable = Arc::new(RwLock::new(sstable));
+        if let FsyncPolicy::Interval(interval) = config.fsync_policy() {
+            Self::sync_on_interval(Arc::downgrade(&sstable), interval)?;
+        }
+
+        let mut store = Self {
+            config,
+            sstable,
+            levels,
+            compactor: Arc::new(compactor),
+            subscribers: Subscribers::default(),
+            writer: None,
+            updates: Arc::new(Mutex::new(())),
+        };
+        if store.config.has_single_writer() {
+            let handle = store.downgrade();
+            let writer = Writer::new(move |records, durable| match handle.upgrade() {
+                Some(store) => store.commit(records, durable),
+                None => Err(KvError::StringError("Store has been dropped".into())),
+            })?;
+            store.writer = Some(Arc::new(writer));
+        }
+        if let Some(interval) = store.config.expiration_sweep_interval() {
+            store.sweep_on_interval(interval)?;
+        }
+        if let Some(max_age) = store.config.wal_max_age() {
+            store.flush_on_age(max_age)?;
+        }
 
         info!("State read, application ready for requests");
+        Ok(store)
+    }
 
+    /// Open an existing store without changing any of its files. The newest
+    /// segments and write-ahead-logs are read, but no write-ahead-log is
+    /// created and no background thread is started to sync, sweep or merge.
+    /// Every write returns `KvError::ReadOnly`.
+    pub fn open_read_only(folder: impl Into<PathBuf>) -> crate::Result<Self> {
+        let config = Arc::new(
+            Config::builder()
+                .build()
+                .with_folder(folder)
+                .with_read_only()
+                .init()?,
+        );
+        let sstable = config.restore_wal()?;
+        let levels = config.restore_levels()?;
+        info!("State read, read-only application ready for requests");
         Ok(Self {
-            config: Arc::new(config),
+            config,
             sstable: Arc::new(RwLock::new(sstable)),
             levels,
+            compactor: Arc::new(Compactor::disabled()),
+            subscribers: Subscribers::default(),
+            writer: None,
+            updates: Arc::new(Mutex::new(())),
         })
     }
 
-    fn write(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> crate::Result<()> {
-        let new_size = self.sstable.read().unwrap().append(key, value)?;
+    /// Sync the write-ahead-log on a background thread until the store is dropped
+    fn sync_on_interval(sstable: Weak<RwLock<SSTable>>, interval: Duration) -> crate::Result<()> {
+        std::thread::Builder::new()
+            .name("kvs-fsync".into())
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                let sstable = match sstable.upgrade() {
+                    Some(sstable) => sstable,
+                    None => break,
+                };
+                let synced = sstable.read().unwrap().sync();
+                if let Err(e) = synced {
+                    error!("Failed to sync write-ahead-log with error {}", e);
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Remove expired keys on a background thread until the store is dropped
+    fn sweep_on_interval(&self, interval: Duration) -> crate::Result<()> {
+        let store = self.downgrade();
+        std::thread::Builder::new()
+            .name("kvs-sweeper".into())
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                let store = match store.upgrade() {
+                    Some(store) => store,
+                    None => break,
+                };
+                match store.sweep_expired() {
+                    Ok(0) => {}
+                    Ok(swept) => info!("Swept {} expired keys", swept),
+                    Err(e) => error!("Failed to sweep expired keys with error {}", e),
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Write the memory table to a segment on a background thread whenever
+    /// its write-ahead-log gets older than `max_age`, until the store is
+    /// dropped
+    fn flush_on_age(&self, max_age: Duration) -> crate::Result<()> {
+        let store = self.downgrade();
+        let tick = (max_age / 4).max(Duration::from_millis(1));
+        std::thread::Builder::new()
+            .name("kvs-wal-age".into())
+            .spawn(move || loop {
+                std::thread::sleep(tick);
+                let store = match store.upgrade() {
+                    Some(store) => store,
+                    None => break,
+                };
+                let expired = {
+                    let sstable = store.sstable.read().unwrap();
+                    sstable.len() > 0 && sstable.age() >= max_age
+                };
+                if !expired {
+                    continue;
+                }
+                match store.flush_memtable() {
+                    Ok(()) => info!("Flushed write-ahead-log older than {:?}", max_age),
+                    Err(e) => error!("Failed to flush old write-ahead-log with error {}", e),
+                }
+            })?;
+        Ok(())
+    }
+
+    fn downgrade(&self) -> WeakKvStore {
+        WeakKvStore {
+            config: self.config.clone(),
+            sstable: Arc::downgrade(&self.sstable),
+            levels: self.levels.clone(),
+            compactor: Arc::downgrade(&self.compactor),
+            subscribers: self.subscribers.clone(),
+            updates: self.updates.clone(),
+        }
+    }
+
+    /// Write a tombstone for every key whose newest record has expired, so the
+    /// next compaction drops the key from disk. Returns the number of keys
+    /// that were removed.
+    pub fn sweep_expired(&self) -> crate::Result<usize> {
+        self.config.check_writable()?;
+        let pattern = prepare_with(b"*".to_vec(), MatchOptions::default());
+        let recent_keys = self.sstable.read().unwrap().find(&pattern);
+        let keys = self.levels.find(&pattern)?;
+
+        let mut swept = 0;
+        for (key, timestamp) in KeyMatch::expired(recent_keys.into_iter().chain(keys)) {
+            // hold the write lock so the key can't be set again between
+            // checking its newest record and writing the tombstone
+            let (sstable, newest) = self.lock_newest_record(&key)?;
+            let still_expired = newest
+                .map(|record| record.timestamp() == timestamp && record.is_expired(now()))
+                .unwrap_or(false);
+            if !still_expired {
+                continue;
+            }
+            let memory_size = sstable.append_record(Record::new(key.clone(), None))?;
+            let log_size = sstable.log_size();
+            drop(sstable);
+            self.subscribers.notify(&key, None);
+            self.rotate_if_full(memory_size, log_size)?;
+            swept += 1;
+        }
+        Ok(swept)
+    }
+
+    /// The newest record of a key, which may be a tombstone or expired. It
+    /// decides what the key reads as, so older values of the key stay
+    /// hidden. The memory table's lock is released before the levels are
+    /// read from disk.
+    fn newest_record(&self, key: &[u8]) -> crate::Result<Option<Record>> {
+        let recent = self.sstable.read().unwrap().get(key);
+        match recent {
+            Some(record) => Ok(Some(record)),
+            None => self.levels.get(key),
+        }
+    }
+
+    /// The newest record of a key, along with the memory table's write lock
+    /// so nothing can be written in between. The levels are read before the
+    /// lock is taken. Plain writes can still land in between, so the memory
+    /// table is checked again under its write lock.
+    fn lock_newest_record(
+        &self,
+        key: &[u8],
+    ) -> crate::Result<(RwLockWriteGuard<'_, SSTable>, Option<Record>)> {
+        loop {
+            let (table, recent) = {
+                let sstable = self.sstable.read().unwrap();
+                (sstable.log_path().to_path_buf(), sstable.get(key))
+            };
+            let older = match recent {
+                Some(_) => None,
+                None => self.levels.get(key)?,
+            };
+            let sstable = self.sstable.write().unwrap();
+            // a rotated memory table may have handed the key to the levels
+            // after they were read, so they have to be read again
+            if sstable.log_path() != table {
+                continue;
+            }
+            let newest = sstable.get(key).or(older);
+            return Ok((sstable, newest));
+        }
+    }
 
-        if self.config.should_rotate_wal(new_size) {
+    /// Write a record, handing it to the writer thread when the store has
+    /// one
+    fn write(&self, record: Record) -> crate::Result<()> {
+        self.write_with(record, false)
+    }
+
+    /// Write a record, syncing it to disk before returning if it is
+    /// `durable`
+    fn write_with(&self, record: Record, durable: bool) -> crate::Result<()> {
+        self.config.check_writable()?;
+        let record = record.compress_above(self.config.compress_min_bytes());
+        match &self.writer {
+            Some(writer) => writer.write(record, durable),
+            None => self.commit(vec![record], durable),
+        }
+    }
+
+    /// Append records to the write-ahead-log with a single write and tell
+    /// the subscribers watching their keys. `durable` records are synced to
+    /// disk before this returns, whatever the fsync policy is.
+    fn commit(&self, records: Vec<Record>, durable: bool) -> crate::Result<()> {
+        // the records are only copied when someone is listening
+        let changes = if self.subscribers.is_empty() {
+            vec![]
+        } else {
+            records
+                .iter()
+                .map(|record| (record.key().to_vec(), record.value().cloned()))
+                .collect()
+        };
+        let sstable = self.sstable.read().unwrap();
+        let memory_size = match durable {
+            true => sstable.append_records_durable(records)?,
+            false => sstable.append_records(records)?,
+        };
+        let log_size = sstable.log_size();
+        drop(sstable);
+        for (key, value) in changes {
+            self.subscribers.notify(&key, value.as_deref());
+        }
+        self.rotate_if_full(memory_size, log_size)
+    }
+
+    fn rotate_if_full(&self, memory_size: usize, log_size: usize) -> crate::Result<()> {
+        if self.config.should_rotate_wal(memory_size, log_size) {
             // sstable is too large, rotate
-            let mut sstable = self.sstable.write().unwrap();
+            self.rotate()?;
+            self.compactor.request();
+        }
+        Ok(())
+    }
+
+    /// Start a new write-ahead-log and hand the current SSTable to the first
+    /// level. Nothing happens if the SSTable is empty.
+    fn rotate(&self) -> crate::Result<()> {
+        let mut sstable = self.sstable.write().unwrap();
+        if sstable.len() > 0 {
             let old_sstable = self.config.replace_wal_inplace(&mut sstable)?;
             drop(sstable);
-
             self.levels.add_table(old_sstable)?;
-            let levels = self.levels.clone();
-            std::thread::spawn(move || {
-                if let Err(e) = levels.try_merge() {
-                    error!("Failed to succesfully merge with error {}", e)
-                } else {
-                    info!("Successfully merged levels together");
-                }
-            });
         }
         Ok(())
     }
 
+    /// Write the memory table to a segment right away instead of waiting for
+    /// it to fill up
+    pub fn flush_memtable(&self) -> crate::Result<()> {
+        self.config.check_writable()?;
+        self.rotate()?;
+        self.levels.save_tables()?;
+        self.compactor.request();
+        Ok(())
+    }
+
     /// Add a value to our key value store
     pub fn add(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<()> {
-        self.write(key, Some(value))
+        self.config.check_value_size(&value)?;
+        self.write(Record::new(key, Some(value)))
+    }
+
+    /// Add a value and sync it to disk before returning, whatever the fsync
+    /// policy of the store is. Once it returns the value survives a crash,
+    /// while other writes keep following the policy.
+    pub fn set_durable(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<()> {
+        self.config.check_value_size(&value)?;
+        self.write_with(Record::new(key, Some(value)), true)
+    }
+
+    /// Add a value that reads as missing once `ttl` has passed
+    pub fn add_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> crate::Result<()> {
+        self.config.check_value_size(&value)?;
+        let expires_at = now() + ttl.as_nanos();
+        self.write(Record::expiring(key, Some(value), Some(expires_at)))
+    }
+
+    /// Add a value only if the key doesn't hold a live value yet. Only one
+    /// of many concurrent callers adds its value. Returns whether the value
+    /// was added.
+    pub fn add_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<bool> {
+        let previous = self.update(key, |previous| match previous {
+            Some(_) => None,
+            None => Some(value),
+        })?;
+        Ok(previous.is_none())
+    }
+
+    /// Add a value, returning the live value the key held before it. No
+    /// other write to the store lands between the two.
+    pub fn get_and_add(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<Option<Vec<u8>>> {
+        self.update(key, |_| Some(value))
+    }
+
+    /// Add `suffix` to the end of the value of a key, treating a missing key
+    /// as an empty value. Concurrent appends to a key never overwrite each
+    /// other. Returns the length of the new value.
+    pub fn append(&self, key: Vec<u8>, suffix: &[u8]) -> crate::Result<usize> {
+        let previous = self.update(key, |previous| {
+            let mut value = previous.map(<[u8]>::to_vec).unwrap_or_default();
+            value.extend_from_slice(suffix);
+            Some(value)
+        })?;
+        Ok(previous.map_or(0, |previous| previous.len()) + suffix.len())
+    }
+
+    /// Replace the value of a key with the one `update` makes out of the
+    /// live value the key holds. Nothing is written if `update` returns
+    /// `None`. Updates take turns, and the levels are read before the memory
+    /// table is locked for the write. Plain writes can still land in between,
+    /// so the memory table is checked again under its write lock and a newer
+    /// record found there is what `update` is given. Returns the live value
+    /// the key held.
+    fn update(
+        &self,
+        key: Vec<u8>,
+        update: impl FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> crate::Result<Option<Vec<u8>>> {
+        self.config.check_writable()?;
+        let _updating = self.updates.lock().unwrap();
+        let (sstable, newest) = self.lock_newest_record(&key)?;
+        let previous = newest.and_then(Record::into_live_value);
+        let value = match update(previous.as_deref()) {
+            Some(value) => value,
+            None => return Ok(previous),
+        };
+        self.config.check_value_size(&value)?;
+        let change = if self.subscribers.is_empty() {
+            None
+        } else {
+            Some((key.clone(), value.clone()))
+        };
+        let record = Record::new(key, Some(value)).compress_above(self.config.compress_min_bytes());
+        let memory_size = sstable.append_record(record)?;
+        let log_size = sstable.log_size();
+        drop(sstable);
+        if let Some((key, value)) = change {
+            self.subscribers.notify(&key, Some(&value));
+        }
+        self.rotate_if_full(memory_size, log_size)?;
+        Ok(previous)
     }
 
     /// remove a value from our key value store
     pub fn remove(&self, key: Vec<u8>) -> crate::Result<()> {
-        self.write(key, None)
+        self.write(Record::new(key, None))
+    }
+
+    /// Write a tombstone for every key with a single write to the
+    /// write-ahead-log. Returns the number of keys that were removed.
+    fn remove_all(&self, keys: Vec<Vec<u8>>) -> crate::Result<usize> {
+        self.config.check_writable()?;
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let removed = keys.len();
+        self.commit(
+            keys.into_iter().map(|key| Record::new(key, None)).collect(),
+            false,
+        )?;
+        Ok(removed)
+    }
+
+    /// Load a dump written by `KvsEngine::export`. Entries are written to the
+    /// store in batches, each with a single write to the write-ahead-log, and
+    /// subscribers are told about every loaded key. A truncated entry at the
+    /// end of the dump is skipped. Returns the number
+    /// of keys that were loaded.
+    pub fn import(&self, mut reader: impl Read) -> crate::Result<usize> {
+        self.config.check_writable()?;
+        let mut imported = 0;
+        let mut batch = vec![];
+        let mut batch_size = 0;
+        loop {
+            let entry = read_whole_entry(&mut reader)?;
+            let done = entry.is_none();
+            if let Some((key, value)) = entry {
+                self.config.check_value_size(&value)?;
+                let record =
+                    Record::new(key, Some(value)).compress_above(self.config.compress_min_bytes());
+                batch_size += record.payload_size();
+                batch.push(record);
+            }
+            if !batch.is_empty() && (done || batch_size >= IMPORT_BATCH_SIZE) {
+                imported += batch.len();
+                self.commit(std::mem::take(&mut batch), false)?;
+                batch_size = 0;
+            }
+            if done {
+                return Ok(imported);
+            }
+        }
+    }
+
+    /// Wipe every key from the store. The current memory table and every
+    /// segment are thrown away and a fresh write-ahead-log takes their place.
+    pub fn clear(&self) -> crate::Result<()> {
+        self.config.check_writable()?;
+        let _merging = self.levels.pause_merges();
+        let mut sstable = self.sstable.write().unwrap();
+        self.levels.clear()?;
+        self.config.remove_redo_logs()?;
+        let old_sstable = self.config.replace_wal_inplace(&mut sstable)?;
+        drop(sstable);
+        drop(old_sstable);
+        info!("Store has been cleared");
+        Ok(())
+    }
+
+    /// Merge every level together right away instead of waiting for levels
+    /// to fill up. Overwritten values and removed keys are dropped from disk.
+    pub fn compact(&self) -> crate::Result<()> {
+        self.config.check_writable()?;
+        self.rotate()?;
+        self.levels.compact()?;
+        info!("Store has been compacted");
+        Ok(())
+    }
+
+    /// Rewrite every segment written with an older file format into the newest
+    /// format. The store stays available for reads and writes while segments
+    /// are upgraded. Returns the number of segments that were rewritten.
+    pub fn upgrade_segments(&self) -> crate::Result<usize> {
+        self.config.check_writable()?;
+        let upgraded = self.levels.upgrade()?;
+        info!("Upgraded {} segments to the newest format", upgraded);
+        Ok(upgraded)
+    }
+
+    /// Rewrite the whole store in the newest file formats, so files written
+    /// by older versions of the store no longer have to be read. The memory
+    /// table is written out to a segment, every level is compacted and the
+    /// segments compaction didn't rewrite are upgraded.
+    pub fn migrate(&self) -> crate::Result<()> {
+        self.compact()?;
+        let upgraded = self.levels.upgrade()?;
+        info!(
+            "Store has been migrated, {} segments were upgraded after compacting",
+            upgraded
+        );
+        Ok(())
+    }
+
+    /// Take a read only view of the store as it is right now. Writes made
+    /// after the snapshot is taken are never seen by it, and the segments it
+    /// reads from aren't deleted until it is dropped.
+    pub fn snapshot(&self) -> crate::Result<Snapshot> {
+        Ok(self.take_snapshot())
+    }
+
+    /// Walk every key of the store along with its value, in sorted key
+    /// order. The iterator reads from a snapshot of the store, so it never
+    /// sees writes made after it was created.
+    pub fn iter(&self) -> StoreIter {
+        StoreIter::new(self.take_snapshot())
+    }
+
+    /// Walk every key from `start` up to but not including `end` along with
+    /// its value, from the largest key to the smallest. Like `iter`, it reads
+    /// from a snapshot of the store.
+    pub fn scan_rev(&self, start: &[u8], end: &[u8]) -> StoreIter {
+        StoreIter::rev(self.take_snapshot(), start, end)
+    }
+
+    fn take_snapshot(&self) -> Snapshot {
+        let _merging = self.levels.pause_merges();
+        // holding the table's lock keeps it from being rotated into the first
+        // level while the levels are copied, so no record is missed or seen
+        // twice
+        let sstable = self.sstable.read().unwrap();
+        let memory = sstable.freeze();
+        let levels = self.levels.snapshot();
+        drop(sstable);
+        Snapshot::new(
+            self.config.folder().to_path_buf(),
+            self.config.segment_dir().to_path_buf(),
+            memory,
+            levels,
+        )
+    }
+
+    /// Copy the store into the empty folder `dest` while it keeps taking
+    /// writes. The backup holds every write made before it was started and
+    /// none made after. Open it with `KvStore::new` like any other store.
+    pub fn backup(&self, dest: impl AsRef<Path>) -> crate::Result<()> {
+        self.snapshot()?.backup(dest)
+    }
+
+    /// Check every record of every segment and write-ahead-log against its
+    /// checksum. The store keeps taking reads and writes while it is
+    /// verified.
+    pub fn verify(&self) -> crate::Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        // the logs are listed before the levels are pinned, so the records
+        // of a log removed in the meantime are found in a pinned segment
+        let logs = self.config.find_redo_logs()?;
+        let levels = self.levels.snapshot();
+        for path in logs {
+            match verify_write_ahead_log(&path, self.config.serialization_format(), &mut report) {
+                Err(KvError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        for segment in levels.iter().flatten().filter_map(Storage::segment) {
+            segment.verify(&mut report)?;
+        }
+        info!(
+            "Verified store: {} good and {} corrupt records",
+            report.good,
+            report.corrupt.len()
+        );
+        Ok(report)
+    }
+
+    /// Collect the number of keys held in memory and inside of each level's
+    /// segments, along with the size of the store on disk.
+    pub fn stats(&self) -> crate::Result<StoreStats> {
+        let mut keys = self.sstable.read().unwrap().len();
+        let mut segments_per_level = vec![];
+        for (segments, level_keys) in self.levels.stats() {
+            segments_per_level.push(segments);
+            keys += level_keys;
+        }
+        let (disk_size, wal_size) = self.config.disk_usage()?;
+        Ok(StoreStats {
+            keys,
+            segments_per_level,
+            disk_size,
+            wal_size,
+        })
     }
 }
 
@@ -81,29 +676,85 @@ impl KvsEngine for KvStore {
         self.add(key, value)
     }
 
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> crate::Result<()> {
+        self.add_with_ttl(key, value, ttl)
+    }
+
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<bool> {
+        self.add_if_absent(key, value)
+    }
+
+    fn get_and_set(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<Option<Vec<u8>>> {
+        self.get_and_add(key, value)
+    }
+
+    fn append(&self, key: Vec<u8>, suffix: &[u8]) -> crate::Result<usize> {
+        self.append(key, suffix)
+    }
+
     fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
-        match self.sstable.read().unwrap().get(key) {
-            Some(value) => Ok(Some(value)),
-            None => match self.levels.get(key)? {
-                Some(value) => Ok(Some(value)),
-                None => Err(KvError::KeyNotFound(
-                    format!("Key {:?} could not be found", key).into(),
-                )),
-            },
-        }
+        Ok(self.newest_record(key)?.and_then(Record::into_live_value))
     }
 
-    fn find(&self, key: Vec<u8>) -> crate::Result<Vec<Vec<u8>>> {
-        let pattern = prepare(key);
-        let recent_keys = self.sstable.read().unwrap().find(&pattern);
-        let mut keys = self.levels.find(&pattern)?;
-        for key in recent_keys {
-            keys.insert(key);
-        }
-        Ok(keys.into_iter().collect::<Vec<_>>())
+    fn get_with_metadata(&self, key: &[u8]) -> crate::Result<Option<(Vec<u8>, RecordMeta)>> {
+        let record = match self.newest_record(key)? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let timestamp = record.timestamp();
+        Ok(record.into_live_value().map(|value| {
+            let meta = RecordMeta {
+                timestamp,
+                value_size: value.len(),
+            };
+            (value, meta)
+        }))
+    }
+
+    fn find_iter_with(&self, like: Vec<u8>, options: MatchOptions) -> crate::Result<KeyIter<'_>> {
+        let pattern = prepare_with(like, options);
+        // the memory table is read first, so a table rotated into the first
+        // level in the meantime is found twice instead of not at all
+        let recent = self.sstable.read().unwrap().find(&pattern);
+        let mut sources = self.levels.sources(&pattern)?;
+        sources.push(Source::Memory(recent.into_iter()));
+        Ok(Box::new(FindIter::new(pattern, sources)?))
     }
 
     fn remove(&self, key: Vec<u8>) -> crate::Result<()> {
         self.remove(key)
     }
+
+    fn delete_range(&self, start: &[u8], end: &[u8]) -> crate::Result<usize> {
+        self.remove_all(keys_in_range(self, start, Some(end))?)
+    }
+
+    fn delete_prefix(&self, prefix: &[u8]) -> crate::Result<usize> {
+        let end = prefix_successor(prefix);
+        self.remove_all(keys_in_range(self, prefix, end.as_deref())?)
+    }
+
+    fn import(&self, reader: impl Read) -> crate::Result<usize> {
+        self.import(reader)
+    }
+
+    fn clear(&self) -> crate::Result<()> {
+        self.clear()
+    }
+
+    fn stats(&self) -> crate::Result<StoreStats> {
+        self.stats()
+    }
+
+    fn compact(&self) -> crate::Result<()> {
+        self.compact()
+    }
+
+    fn verify(&self) -> crate::Result<VerifyReport> {
+        self.verify()
+    }
+
+    fn subscribe(&self, like: Vec<u8>) -> crate::Result<Receiver<Notification>> {
+        Ok(self.subscribers.subscribe(like))
+    }
 }
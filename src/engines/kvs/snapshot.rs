@@ -0,0 +1,165 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{datastructures::matcher::prepare_with, EntryIter, KeyIter, KvError, MatchOptions};
+
+use super::{
+    iter::{FindIter, Source},
+    level::{
+        get_pinned, memory_before, memory_from, sources_before, sources_from, sources_pinned,
+        Storage,
+    },
+    manifest::Manifest,
+    sstable::{Record, SSTable},
+};
+
+/// Snapshot is a read only view of a `KvStore` as it was when
+/// `KvStore::snapshot` was called. It holds a copy of the memory table and
+/// the segments of every level, so writes made to the store afterwards are
+/// never seen. The segments stay on disk until the snapshot is dropped, even
+/// if a merge or compaction replaces them in the meantime. Keys that were
+/// given a ttl still expire while the snapshot is held.
+pub struct Snapshot {
+    folder: PathBuf,
+    segment_dir: PathBuf,
+    memory: SSTable,
+    levels: Vec<Vec<Storage>>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        folder: PathBuf,
+        segment_dir: PathBuf,
+        memory: SSTable,
+        levels: Vec<Vec<Storage>>,
+    ) -> Self {
+        Self {
+            folder,
+            segment_dir,
+            memory,
+            levels,
+        }
+    }
+
+    fn newest_record(&self, key: &[u8]) -> crate::Result<Option<Record>> {
+        match self.memory.get(key) {
+            Some(record) => Ok(Some(record)),
+            None => get_pinned(&self.levels, key),
+        }
+    }
+
+    /// Get the value a key held when the snapshot was taken
+    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        Ok(self.newest_record(key)?.and_then(Record::into_live_value))
+    }
+
+    /// Find every key matching the pattern `like` when the snapshot was
+    /// taken, in sorted order
+    pub fn find(&self, like: Vec<u8>) -> crate::Result<Vec<Vec<u8>>> {
+        self.find_iter_with(like, MatchOptions::default())?
+            .collect()
+    }
+
+    /// Lazily walk the keys matching the pattern `like` when the snapshot
+    /// was taken, in sorted order
+    pub fn find_iter_with(
+        &self,
+        like: Vec<u8>,
+        options: MatchOptions,
+    ) -> crate::Result<KeyIter<'_>> {
+        let pattern = prepare_with(like, options);
+        let mut sources = sources_pinned(&self.levels, &pattern)?;
+        sources.push(Source::Memory(self.memory.find(&pattern).into_iter()));
+        Ok(Box::new(FindIter::new(pattern, sources)?))
+    }
+
+    /// Lazily walk every key of the snapshot in sorted order, starting at
+    /// the block of every segment that holds `start`. A few keys lower than
+    /// `start` may still be handed out.
+    pub(crate) fn keys_from(&self, start: &[u8]) -> crate::Result<FindIter> {
+        let pattern = prepare_with(b"*".to_vec(), MatchOptions::default());
+        let mut sources = sources_from(&self.levels, &pattern, start)?;
+        sources.push(memory_from(&self.memory, &pattern, start));
+        FindIter::new(pattern, sources)
+    }
+
+    /// Lazily walk every key of the snapshot in descending order, starting
+    /// at the block of every segment that holds the last key lower than
+    /// `end`. A few keys greater or equal to `end` may still be handed out.
+    pub(crate) fn keys_before(&self, end: Option<&[u8]>) -> crate::Result<FindIter> {
+        let pattern = prepare_with(b"*".to_vec(), MatchOptions::default());
+        let mut sources = sources_before(&self.levels, &pattern, end)?;
+        sources.push(memory_before(&self.memory, &pattern, end));
+        FindIter::descending(pattern, sources)
+    }
+
+    /// Lazily walk every key matching the pattern `like` along with the
+    /// value it held when the snapshot was taken, in sorted key order
+    pub fn scan(&self, like: Vec<u8>) -> crate::Result<EntryIter<'_>> {
+        let keys = self.find_iter_with(like, MatchOptions::default())?;
+        Ok(Box::new(keys.filter_map(move |key| {
+            let key = match key {
+                Ok(key) => key,
+                Err(e) => return Some(Err(e)),
+            };
+            // the key may have expired after it was found
+            self.get(&key)
+                .transpose()
+                .map(|value| value.map(|value| (key, value)))
+        })))
+    }
+
+    /// Write the snapshot into the empty folder `dest` as a store that
+    /// `KvStore::new` can restore. Segments are linked into `dest`, or
+    /// copied when they can't be, and the records held in memory are written
+    /// as a write-ahead-log.
+    pub fn backup(&self, dest: impl AsRef<Path>) -> crate::Result<()> {
+        let dest = dest.as_ref();
+        std::fs::create_dir_all(dest)?;
+        if std::fs::read_dir(dest)?.next().is_some() {
+            return Err(KvError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} is not empty", dest),
+            )));
+        }
+        // the copy has to be read in the format its records are written in,
+        // and keeps its logs and segments in its own folder
+        if let Some(mut manifest) = Manifest::read(&self.folder)? {
+            manifest.wal_dir = None;
+            manifest.segment_dir = None;
+            manifest.write(dest)?;
+        }
+        // a level is only restored if every level before it has a folder
+        for level in 2..=self.levels.len() {
+            std::fs::create_dir(dest.join(format!("lv{}", level)))?;
+        }
+
+        self.memory.write_log(dest)?;
+        for storage in self.levels.iter().flatten() {
+            match storage {
+                Storage::SSTable(table) => table.write_log(dest)?,
+                Storage::Segment(segment) => {
+                    let relative =
+                        segment
+                            .path()
+                            .strip_prefix(&self.segment_dir)
+                            .map_err(|_| {
+                                KvError::Parse(
+                                    format!(
+                                        "{:?} is outside of {:?}",
+                                        segment.path(),
+                                        self.segment_dir
+                                    )
+                                    .into(),
+                                )
+                            })?;
+                    segment.link_to(dest.join(relative))?;
+                }
+            }
+        }
+        info!("Backed up snapshot of {:?} to {:?}", self.folder, dest);
+        Ok(())
+    }
+}
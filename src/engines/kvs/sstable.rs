@@ -1,58 +1,680 @@
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, BinaryHeap, HashMap, VecDeque},
     fmt::Debug,
     fs::File,
-    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Take, Write},
     ops::Deref,
     path::{Path, PathBuf},
     pin::Pin,
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
 use crc::{Crc, CRC_32_ISCSI};
-use serde::{Deserialize, Serialize};
+use memmap2::Mmap;
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::{self, SerializeTuple},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use uuid::Uuid;
 
+use super::cache::BlockCache;
+use super::config::{Compression, Config, FsyncPolicy};
+use super::format::{Format, SerializationFormat};
+use super::group_commit::GroupCommit;
 use crate::datastructures::bloom::BloomFilter;
-use crate::{common::now, datastructures::matcher::PreparedPattern};
+use crate::{
+    common::{next_timestamp, now, observe_timestamp},
+    datastructures::matcher::PreparedPattern,
+    KvError, VerifyReport,
+};
+
+/// Magic bytes written at the start of every versioned segment file. Legacy
+/// segments begin directly with their big endian element count, whose first
+/// byte is always zero, so the two layouts can't be confused.
+const SEGMENT_MAGIC: &[u8; 4] = b"KVSG";
+
+/// The newest segment format version. Version 0 is the legacy layout that
+/// only contains the element count as a header. Version 2 added the codec tag
+/// and version 3 added a footer that holds the block index. Version 4 front
+/// codes the keys of every block. Version 5 records the newest timestamp of
+/// the segment in its footer.
+pub const SEGMENT_VERSION: u8 = 5;
+
+/// First segment format version whose keys are front coded
+const FRONT_CODED_VERSION: u8 = 4;
+
+/// First segment format version whose footer holds its newest timestamp
+const MAX_TIMESTAMP_VERSION: u8 = 5;
+
+/// Magic bytes at the very end of a segment file that has a footer
+const FOOTER_MAGIC: &[u8; 4] = b"KVSF";
+
+/// Size of the fixed trailer that ends a segment file. It holds the offset of
+/// the footer, the checksum of the footer and the footer magic bytes.
+const TRAILER_SIZE: u64 = 16;
+
+/// Magic bytes written at the start of every versioned write-ahead-log.
+/// Legacy logs begin directly with their first record.
+const WAL_MAGIC: &[u8; 4] = b"KVWL";
+
+/// The newest write-ahead-log format version. Version 0 is the legacy layout
+/// without a header. Version 1 starts the log with its magic bytes and
+/// version, so the layout of logged records can change without old logs
+/// being misread.
+const WAL_VERSION: u8 = 1;
+
+/// Version of the `.bloom` sidecar layout. Filters written before version 1
+/// hashed a lossy string of the key and start with a zero byte, so they are
+/// rebuilt from the segment instead of being trusted.
+const BLOOM_FILE_VERSION: u8 = 1;
+
+/// Codec the blocks of a segment file are stored with. Its tag is saved in
+/// the segment header, segments written before version 2 are uncompressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Zstd,
+}
+
+impl Codec {
+    fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> crate::Result<Self> {
+        match tag {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            tag => Err(KvError::Parse(
+                format!("Unknown segment codec {}", tag).into(),
+            )),
+        }
+    }
+}
+
+impl From<Compression> for Codec {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::None => Codec::None,
+            Compression::Zstd { .. } => Codec::Zstd,
+        }
+    }
+}
+
+/// Largest element count used to size the bloom filter of a new index. A
+/// segment holding more keys still works, its filter just gives more false
+/// positives. This keeps a garbage count from asking for a huge filter.
+const MAX_FILTER_ELEMENTS: usize = 1 << 24;
+
+/// SegmentHeader is written at the start of every segment file and describes
+/// how the rest of the file should be read.
+#[derive(Clone, Copy, Debug)]
+pub struct SegmentHeader {
+    version: u8,
+    codec: Codec,
+    elements: usize,
+}
+
+impl SegmentHeader {
+    pub fn new(elements: usize, codec: Codec) -> Self {
+        Self {
+            version: SEGMENT_VERSION,
+            codec,
+            elements,
+        }
+    }
+
+    /// Read a header from the start of a segment file. Returns the header and
+    /// the number of bytes that were read.
+    pub fn read(reader: &mut impl Read) -> crate::Result<(Self, usize)> {
+        let mut buffer = 0_usize.to_be_bytes();
+        reader.read_exact(&mut buffer[..SEGMENT_MAGIC.len()])?;
+        if buffer[..SEGMENT_MAGIC.len()] == SEGMENT_MAGIC[..] {
+            let mut version = [0_u8];
+            reader.read_exact(&mut version)?;
+            let mut codec = [Codec::None.tag()];
+            if version[0] >= 2 {
+                reader.read_exact(&mut codec)?;
+            }
+            reader.read_exact(&mut buffer)?;
+            let header = Self {
+                version: version[0],
+                codec: Codec::from_tag(codec[0])?,
+                elements: usize::from_be_bytes(buffer),
+            };
+            let codec_size = if version[0] >= 2 { codec.len() } else { 0 };
+            Ok((header, SEGMENT_MAGIC.len() + 1 + codec_size + buffer.len()))
+        } else {
+            reader.read_exact(&mut buffer[SEGMENT_MAGIC.len()..])?;
+            let header = Self {
+                version: 0,
+                codec: Codec::None,
+                elements: usize::from_be_bytes(buffer),
+            };
+            Ok((header, buffer.len()))
+        }
+    }
+
+    /// Write the header in the newest format. Returns the number of bytes written.
+    pub fn write(&self, writer: &mut impl Write) -> crate::Result<usize> {
+        writer.write_all(SEGMENT_MAGIC)?;
+        writer.write_all(&[self.version, self.codec.tag()])?;
+        writer.write_all(&self.elements.to_be_bytes())?;
+        Ok(SEGMENT_MAGIC.len() + 2 + self.elements.to_be_bytes().len())
+    }
+}
+
+/// Compress a block of serialized records and write it as a length prefixed
+/// frame. Returns the number of bytes written.
+fn write_frame(writer: &mut impl Write, block: &[u8], level: i32) -> crate::Result<u64> {
+    let compressed = zstd::bulk::compress(block, level)?;
+    writer.write_all(&(compressed.len() as u32).to_be_bytes())?;
+    writer.write_all(&compressed)?;
+    Ok(4 + compressed.len() as u64)
+}
+
+/// Read a frame written by `write_frame` and decode the records inside of it
+/// as they are stored by the segment format `version`. Returns the records
+/// and the size of the frame on disk. The length of the frame comes from
+/// disk, so the buffer only grows as its bytes are read and a frame longer
+/// than the rest of the file is an error.
+fn read_frame(
+    reader: &mut impl Read,
+    version: u8,
+    format: SerializationFormat,
+) -> crate::Result<(Vec<Record>, u64)> {
+    let mut length = [0_u8; 4];
+    reader.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length);
+    let mut compressed = vec![];
+    reader.take(length as u64).read_to_end(&mut compressed)?;
+    if compressed.len() < length as usize {
+        return Err(KvError::Corruption {
+            context: format!(
+                "frame of {} bytes is longer than the {} bytes left in the segment",
+                length,
+                compressed.len()
+            ),
+            key: None,
+        });
+    }
+    let block = zstd::stream::decode_all(&compressed[..])?;
+    let mut cursor = Cursor::new(&block[..]);
+    let mut coder = KeyCoder::new(version, format);
+    let mut records = vec![];
+    while (cursor.position() as usize) < block.len() {
+        records.push(coder.decode(&mut cursor)?.0);
+    }
+    Ok((records, 4 + length as u64))
+}
+
+/// SegmentFooter is written after the last block of a segment file so the
+/// index of the segment can be loaded without reading every record.
+#[derive(Debug, Serialize, Deserialize)]
+struct SegmentFooter {
+    elements: usize,
+    byte_size: u64,
+    hints: Vec<BlockHint>,
+    min_key: Option<Vec<u8>>,
+    max_key: Option<Vec<u8>>,
+    max_timestamp: Option<u128>,
+}
+
+/// Footer of the segment versions before the newest timestamp was recorded
+#[derive(Deserialize)]
+struct LegacySegmentFooter {
+    elements: usize,
+    byte_size: u64,
+    hints: Vec<BlockHint>,
+    min_key: Option<Vec<u8>>,
+    max_key: Option<Vec<u8>>,
+}
+
+impl From<LegacySegmentFooter> for SegmentFooter {
+    fn from(footer: LegacySegmentFooter) -> Self {
+        Self {
+            elements: footer.elements,
+            byte_size: footer.byte_size,
+            hints: footer.hints,
+            min_key: footer.min_key,
+            max_key: footer.max_key,
+            max_timestamp: None,
+        }
+    }
+}
+
+/// Borrowed form of a `SegmentFooter`. It is encoded exactly like the
+/// footer, so a segment's footer can be written straight from its index
+/// without copying every block hint first.
+#[derive(Serialize)]
+struct SegmentFooterRef<'a> {
+    elements: usize,
+    byte_size: u64,
+    hints: &'a [BlockHint],
+    min_key: &'a Option<Vec<u8>>,
+    max_key: &'a Option<Vec<u8>>,
+    max_timestamp: Option<u128>,
+}
+
+impl SegmentFooterRef<'_> {
+    /// Write the footer followed by the trailer. `offset` is the position in
+    /// the file the footer starts at.
+    fn write(
+        &self,
+        writer: &mut impl Write,
+        offset: u64,
+        format: SerializationFormat,
+    ) -> crate::Result<()> {
+        let bytes = format.encode(self)?;
+        let crc = Crc::<u32>::new(&CRC_32_ISCSI);
+        writer.write_all(&bytes)?;
+        writer.write_all(&offset.to_be_bytes())?;
+        writer.write_all(&crc.checksum(&bytes).to_be_bytes())?;
+        writer.write_all(FOOTER_MAGIC)?;
+        Ok(())
+    }
+}
+
+impl SegmentFooter {
+    /// Read the footer at the end of a segment file of `version` whose data
+    /// starts at `data_start`. Returns the footer and the offset it starts
+    /// at, which is also where the data of the segment ends. Returns `None`
+    /// if the footer is missing or corrupt.
+    fn read(
+        file: &mut File,
+        version: u8,
+        data_start: u64,
+        format: SerializationFormat,
+    ) -> crate::Result<Option<(Self, u64)>> {
+        let length = file.metadata()?.len();
+        if length < data_start + TRAILER_SIZE {
+            return Ok(None);
+        }
+        let mut trailer = [0_u8; TRAILER_SIZE as usize];
+        file.seek(SeekFrom::Start(length - TRAILER_SIZE))?;
+        file.read_exact(&mut trailer)?;
+        if trailer[12..] != FOOTER_MAGIC[..] {
+            return Ok(None);
+        }
+        let mut offset = 0_u64.to_be_bytes();
+        offset.copy_from_slice(&trailer[..8]);
+        let offset = u64::from_be_bytes(offset);
+        let mut checksum = 0_u32.to_be_bytes();
+        checksum.copy_from_slice(&trailer[8..12]);
+        if offset < data_start || offset > length - TRAILER_SIZE {
+            return Ok(None);
+        }
+
+        let mut bytes = vec![0; (length - TRAILER_SIZE - offset) as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut bytes)?;
+        let crc = Crc::<u32>::new(&CRC_32_ISCSI);
+        if crc.checksum(&bytes) != u32::from_be_bytes(checksum) {
+            return Ok(None);
+        }
+        let footer = if version >= MAX_TIMESTAMP_VERSION {
+            format.decode(&bytes).ok()
+        } else {
+            format
+                .decode::<LegacySegmentFooter>(&bytes)
+                .ok()
+                .map(SegmentFooter::from)
+        };
+        Ok(footer.map(|footer| (footer, offset)))
+    }
+}
+
+/// Tags written in front of a record's value. The first two are the tags
+/// bincode writes for `None` and `Some` of an `Option`, which is how records
+/// were stored before they could expire, so old records still load.
+const TOMBSTONE_TAG: u8 = 0;
+const VALUE_TAG: u8 = 1;
+const EXPIRING_VALUE_TAG: u8 = 2;
+const COMPRESSED_VALUE_TAG: u8 = 3;
+const COMPRESSED_EXPIRING_VALUE_TAG: u8 = 4;
 
-#[derive(Clone, Default, Deserialize, Serialize, Debug)]
+/// zstd level values are compressed with when they are stored on their own
+const VALUE_COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Clone, Default, Debug)]
 pub struct Record {
     crc: u32,
     timestamp: u128,
     key: Vec<u8>,
     value: Option<Vec<u8>>,
+    /// Time in nanoseconds since the unix epoch after which the value reads
+    /// as missing. Tombstones never expire.
+    expires_at: Option<u128>,
+    /// Set when the value is stored compressed. Holds the compressed value
+    /// once it has been worked out, so it is only compressed once.
+    compressed: Option<OnceLock<Vec<u8>>>,
 }
 
 impl Record {
     pub fn new(key: Vec<u8>, value: Option<Vec<u8>>) -> Self {
-        let timestamp = now();
+        Self::expiring(key, value, None)
+    }
+
+    pub fn expiring(key: Vec<u8>, value: Option<Vec<u8>>, expires_at: Option<u128>) -> Self {
+        let timestamp = next_timestamp();
+        let expires_at = value.as_ref().and(expires_at);
         let mut record = Self {
             crc: 0,
             timestamp,
             key,
             value,
+            expires_at,
+            compressed: None,
         };
         record.crc = record.calculate_crc();
         record
     }
 
+    /// Store the value compressed when it is longer than `min_bytes`. The
+    /// checksum still covers the value as it was written.
+    pub fn compress_above(mut self, min_bytes: Option<usize>) -> Self {
+        let large =
+            matches!((&self.value, min_bytes), (Some(value), Some(min)) if value.len() > min);
+        if large {
+            self.compressed = Some(OnceLock::new());
+        }
+        self
+    }
+
+    /// Whether the value is stored compressed
+    #[cfg(test)]
+    pub fn is_compressed(&self) -> bool {
+        self.compressed.is_some()
+    }
+
+    /// The value as it is stored compressed, or `None` if it is stored as
+    /// it is
+    fn compressed_value(&self) -> io::Result<Option<&[u8]>> {
+        let (value, compressed) = match (&self.value, &self.compressed) {
+            (Some(value), Some(compressed)) => (value, compressed),
+            _ => return Ok(None),
+        };
+        if let Some(bytes) = compressed.get() {
+            return Ok(Some(bytes));
+        }
+        let bytes = zstd::bulk::compress(value, VALUE_COMPRESSION_LEVEL)?;
+        Ok(Some(compressed.get_or_init(|| bytes)))
+    }
+
     pub fn calculate_crc(&self) -> u32 {
         let crc = Crc::<u32>::new(&CRC_32_ISCSI);
         let mut digest = crc.digest();
         digest.update(&self.timestamp.to_be_bytes());
         digest.update(&self.key);
         digest.update(self.value.as_ref().unwrap_or(&vec![]));
+        if let Some(expires_at) = self.expires_at {
+            digest.update(&expires_at.to_be_bytes());
+        }
         digest.finalize()
     }
 
+    /// Check the record against its checksum. A corrupt record is logged and
+    /// `false` is returned, unless `strict` is set, in which case it is
+    /// returned as `KvError::Corruption`.
+    pub fn verify(&self, strict: bool) -> crate::Result<bool> {
+        let actual = self.calculate_crc();
+        if self.crc == actual {
+            return Ok(true);
+        }
+        error!("{} is corrupt (Actual {})", self, actual);
+        if strict {
+            return Err(KvError::Corruption {
+                context: format!("expected crc {}, got {}", self.crc, actual),
+                key: Some(self.key.clone()),
+            });
+        }
+        Ok(false)
+    }
+
     pub fn key(&self) -> &[u8] {
         &self.key
     }
 
+    /// Number of bytes taken up by the key and value of the record
+    pub fn payload_size(&self) -> usize {
+        self.key.len() + self.value.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Rough number of bytes the record takes up in memory
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.payload_size()
+    }
+
     pub fn value(&self) -> Option<&Vec<u8>> {
         self.value.as_ref()
     }
+
+    pub fn timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn is_expired(&self, now: u128) -> bool {
+        self.expires_at.map(|at| at <= now).unwrap_or(false)
+    }
+
+    /// The value of the record, or `None` if it is a tombstone or has expired
+    pub fn into_live_value(self) -> Option<Vec<u8>> {
+        if self.is_expired(now()) {
+            return None;
+        }
+        self.value
+    }
+}
+
+impl Record {
+    /// Serialize the record with `key` written in place of its own key
+    fn serialize_with_key<S: Serializer>(
+        &self,
+        key: &[u8],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let compressed = self.compressed_value().map_err(ser::Error::custom)?;
+        // self describing formats check the length, so it has to be exact
+        let length = match (&self.value, self.expires_at) {
+            (None, _) => 4,
+            (Some(_), None) => 5,
+            (Some(_), Some(_)) => 6,
+        };
+        let mut tuple = serializer.serialize_tuple(length)?;
+        tuple.serialize_element(&self.crc)?;
+        tuple.serialize_element(&self.timestamp)?;
+        tuple.serialize_element(key)?;
+        match (&self.value, self.expires_at, compressed) {
+            (None, _, _) => tuple.serialize_element(&TOMBSTONE_TAG)?,
+            (Some(value), None, None) => {
+                tuple.serialize_element(&VALUE_TAG)?;
+                tuple.serialize_element(value)?;
+            }
+            (Some(value), Some(expires_at), None) => {
+                tuple.serialize_element(&EXPIRING_VALUE_TAG)?;
+                tuple.serialize_element(value)?;
+                tuple.serialize_element(&expires_at)?;
+            }
+            (Some(_), None, Some(compressed)) => {
+                tuple.serialize_element(&COMPRESSED_VALUE_TAG)?;
+                tuple.serialize_element(compressed)?;
+            }
+            (Some(_), Some(expires_at), Some(compressed)) => {
+                tuple.serialize_element(&COMPRESSED_EXPIRING_VALUE_TAG)?;
+                tuple.serialize_element(compressed)?;
+                tuple.serialize_element(&expires_at)?;
+            }
+        }
+        tuple.end()
+    }
+}
+
+impl Serialize for Record {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.serialize_with_key(&self.key, serializer)
+    }
+}
+
+/// A record serialized with only the part of its key after the first
+/// `shared` bytes
+struct KeySuffix<'a> {
+    record: &'a Record,
+    shared: usize,
+}
+
+impl Serialize for KeySuffix<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.record
+            .serialize_with_key(&self.record.key[self.shared..], serializer)
+    }
+}
+
+/// KeyCoder front codes the sorted keys of a segment's blocks. Every record
+/// is written after the length of the prefix its key shares with the key
+/// before it, and only the rest of its key is stored. The first record of a
+/// block shares nothing, so a block can be decoded on its own. Segments
+/// written before version 4 store every key in full.
+struct KeyCoder {
+    front_coded: bool,
+    format: SerializationFormat,
+    previous: Vec<u8>,
+}
+
+impl KeyCoder {
+    fn new(version: u8, format: SerializationFormat) -> Self {
+        Self {
+            front_coded: version >= FRONT_CODED_VERSION,
+            format,
+            previous: vec![],
+        }
+    }
+
+    /// Encode a record, storing its whole key if it starts a block
+    fn encode(&mut self, record: &Record, starts_block: bool) -> crate::Result<Vec<u8>> {
+        if !self.front_coded {
+            return self.format.encode(record);
+        }
+        let shared = match starts_block {
+            true => 0,
+            false => shared_prefix(&self.previous, &record.key).min(u16::MAX as usize),
+        };
+        self.previous.clear();
+        self.previous.extend_from_slice(&record.key);
+        self.format
+            .encode(&(shared as u16, KeySuffix { record, shared }))
+    }
+
+    /// Decode the next record, returning it along with the number of bytes
+    /// it took up
+    fn decode(&mut self, reader: &mut impl Read) -> crate::Result<(Record, u64)> {
+        if !self.front_coded {
+            let record: Record = self.format.decode_from(reader)?;
+            let size = self.format.encoded_size(&record)?;
+            return Ok((record, size));
+        }
+        let (shared, mut record): (u16, Record) = self.format.decode_from(reader)?;
+        let size = self.format.encoded_size(&(shared, &record))?;
+        let shared = shared as usize;
+        if shared > self.previous.len() {
+            return Err(KvError::Corruption {
+                context: format!(
+                    "key shares {} bytes with a key of {} bytes",
+                    shared,
+                    self.previous.len()
+                ),
+                key: None,
+            });
+        }
+        let mut key = Vec::with_capacity(shared + record.key.len());
+        key.extend_from_slice(&self.previous[..shared]);
+        key.extend_from_slice(&record.key);
+        record.key = key;
+        self.previous.clear();
+        self.previous.extend_from_slice(&record.key);
+        Ok((record, size))
+    }
+}
+
+/// Length of the prefix two keys have in common
+fn shared_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+impl<'de> Deserialize<'de> for Record {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RecordVisitor;
+
+        impl<'de> Visitor<'de> for RecordVisitor {
+            type Value = Record;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a record")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Record, A::Error> {
+                let crc = element(&mut seq, 0)?;
+                let timestamp = element(&mut seq, 1)?;
+                let key = element(&mut seq, 2)?;
+                let (value, expires_at, compressed) = match element(&mut seq, 3)? {
+                    TOMBSTONE_TAG => (None, None, None),
+                    VALUE_TAG => (Some(element(&mut seq, 4)?), None, None),
+                    EXPIRING_VALUE_TAG => {
+                        let value = element(&mut seq, 4)?;
+                        (Some(value), Some(element(&mut seq, 5)?), None)
+                    }
+                    COMPRESSED_VALUE_TAG => {
+                        let (value, compressed) = decompress_value(element(&mut seq, 4)?);
+                        (Some(value), None, Some(compressed))
+                    }
+                    COMPRESSED_EXPIRING_VALUE_TAG => {
+                        let (value, compressed) = decompress_value(element(&mut seq, 4)?);
+                        (Some(value), Some(element(&mut seq, 5)?), Some(compressed))
+                    }
+                    tag => {
+                        return Err(de::Error::custom(format!("unknown value tag {}", tag)));
+                    }
+                };
+                Ok(Record {
+                    crc,
+                    timestamp,
+                    key,
+                    value,
+                    expires_at,
+                    compressed,
+                })
+            }
+        }
+
+        fn element<'de, A, T>(seq: &mut A, index: usize) -> Result<T, A::Error>
+        where
+            A: SeqAccess<'de>,
+            T: Deserialize<'de>,
+        {
+            seq.next_element()?
+                .ok_or_else(|| de::Error::invalid_length(index, &"a record"))
+        }
+
+        deserializer.deserialize_tuple(6, RecordVisitor)
+    }
+}
+
+/// Decompress a value that was stored compressed, keeping the compressed
+/// bytes so the record can be written again without compressing it again. A
+/// value that can't be decompressed is kept as it is, so the record fails its
+/// checksum like any other corrupt record.
+fn decompress_value(compressed: Vec<u8>) -> (Vec<u8>, OnceLock<Vec<u8>>) {
+    match zstd::stream::decode_all(&compressed[..]) {
+        Ok(value) => (value, OnceLock::from(compressed)),
+        Err(_) => (compressed, OnceLock::new()),
+    }
 }
 
 impl std::fmt::Display for Record {
@@ -71,6 +693,179 @@ impl std::fmt::Display for Record {
     }
 }
 
+/// A key that matched a pattern. The timestamp and tombstone of the record
+/// are kept so that matches from every table and segment can be resolved to
+/// the newest record of each key.
+#[derive(Clone, Debug)]
+pub struct KeyMatch {
+    key: Vec<u8>,
+    timestamp: u128,
+    tombstone: bool,
+    expired: bool,
+}
+
+impl KeyMatch {
+    fn from_record(record: &Record) -> Self {
+        Self {
+            key: record.key.clone(),
+            timestamp: record.timestamp,
+            tombstone: record.value.is_none(),
+            expired: record.is_expired(now()),
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn into_key(self) -> Vec<u8> {
+        self.key
+    }
+
+    pub fn timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    /// Check if the record holds a value that can still be read
+    pub fn is_live(&self) -> bool {
+        !self.tombstone && !self.expired
+    }
+
+    /// Find the keys whose newest record has expired. The timestamp of that
+    /// record is returned with every key.
+    pub fn expired(matches: impl IntoIterator<Item = KeyMatch>) -> Vec<(Vec<u8>, u128)> {
+        Self::newest(matches)
+            .into_iter()
+            .filter(|(_, found)| !found.tombstone && found.expired)
+            .map(|(key, found)| (key, found.timestamp))
+            .collect()
+    }
+
+    fn newest(matches: impl IntoIterator<Item = KeyMatch>) -> HashMap<Vec<u8>, KeyMatch> {
+        let mut newest: HashMap<Vec<u8>, KeyMatch> = HashMap::new();
+        for found in matches {
+            match newest.get(&found.key) {
+                Some(current) if current.timestamp >= found.timestamp => {}
+                _ => {
+                    newest.insert(found.key.clone(), found);
+                }
+            }
+        }
+        newest
+    }
+}
+
+/// Create a write-ahead-log at `path` that starts with the header of the
+/// newest version
+fn create_write_ahead_log(path: &Path) -> crate::Result<BufWriter<File>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(WAL_MAGIC)?;
+    writer.write_all(&[WAL_VERSION])?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Read the header of a write-ahead-log and return its version. A legacy log
+/// has no header, so nothing is read from it and version 0 is returned.
+fn read_write_ahead_log_header(reader: &mut impl BufRead) -> crate::Result<u8> {
+    let header = reader.fill_buf()?;
+    if header.len() <= WAL_MAGIC.len() || header[..WAL_MAGIC.len()] != WAL_MAGIC[..] {
+        return Ok(0);
+    }
+    let version = header[WAL_MAGIC.len()];
+    if version > WAL_VERSION {
+        return Err(KvError::Parse(
+            format!(
+                "write-ahead-log version {} is newer than the supported version {}",
+                version, WAL_VERSION
+            )
+            .into(),
+        ));
+    }
+    reader.consume(WAL_MAGIC.len() + 1);
+    Ok(version)
+}
+
+/// Reader that remembers whether it ran out of bytes, so a record that can't
+/// be decoded because the file ended can be told apart from one that is
+/// corrupt
+struct EofReader<R> {
+    inner: R,
+    hit_eof: bool,
+}
+
+impl<R: Read> Read for EofReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            self.hit_eof = true;
+        }
+        Ok(read)
+    }
+}
+
+/// Call `f` with every record of a write-ahead-log, in the order they were
+/// written. A record cut short by the end of the file is the torn tail of a
+/// write that never finished, so it is treated as the end of the log. A
+/// record that can't be read before the end of the file is corrupt. It is
+/// returned as an error when `strict` is set, otherwise reading stops there
+/// and `false` is returned because the records after it are lost. Every
+/// version of the log stores its records the same way, older record layouts
+/// are upgraded when the record is deserialized.
+fn for_each_logged_record(
+    path: &Path,
+    format: SerializationFormat,
+    strict: bool,
+    mut f: impl FnMut(Record) -> crate::Result<()>,
+) -> crate::Result<bool> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let version = read_write_ahead_log_header(&mut reader)?;
+    trace!("Reading {:?} as write-ahead-log version {}", path, version);
+    let mut reader = EofReader {
+        inner: reader,
+        hit_eof: false,
+    };
+    while !reader.inner.fill_buf()?.is_empty() {
+        let record: Record = match format.decode_from(&mut reader) {
+            Ok(record) => record,
+            Err(e) if reader.hit_eof => {
+                warn!("Stopped reading {:?} at torn record: {}", path, e);
+                break;
+            }
+            Err(e) => {
+                let message = format!("{:?} has a corrupt record before its end: {}", path, e);
+                if strict {
+                    return Err(KvError::CorruptLog(message.into()));
+                }
+                warn!("{}, the records after it are lost", message);
+                return Ok(false);
+            }
+        };
+        f(record)?;
+    }
+    Ok(true)
+}
+
+/// Count a record as good or corrupt
+fn tally(report: &mut VerifyReport, record: Record) -> crate::Result<()> {
+    if record.verify(false)? {
+        report.good += 1;
+    } else {
+        report.corrupt.push(record.key);
+    }
+    Ok(())
+}
+
+/// Check every record of a write-ahead-log against its checksum
+pub fn verify_write_ahead_log(
+    path: &Path,
+    format: SerializationFormat,
+    report: &mut VerifyReport,
+) -> crate::Result<()> {
+    for_each_logged_record(path, format, true, |record| tally(report, record))?;
+    Ok(())
+}
+
 /// MemoryTable keeps a tree of key and values in sorted order. Once it reaches
 /// a certian size, the table is moved to disk and a new empty one would take
 /// its place.
@@ -81,7 +876,7 @@ struct MemoryTable {
 
 #[derive(Clone, Debug)]
 struct MemTable {
-    map: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    map: BTreeMap<Vec<u8>, Record>,
     size: usize,
 }
 
@@ -95,21 +890,70 @@ impl MemoryTable {
         }
     }
 
-    fn from_write_ahead_log(path: impl AsRef<Path>) -> crate::Result<Self> {
-        debug!("Building memory table from redo log {:?}", &path.as_ref());
-        let table = Self::new();
-        let mut reader = BufReader::new(File::open(path.as_ref())?);
-        while !reader.fill_buf().unwrap().is_empty() {
-            let record: Record = bincode::deserialize_from(&mut reader).unwrap();
-            if record.crc != record.calculate_crc() {
-                let actual_crc = record.calculate_crc();
-                trace!("{} is corrupt (Actual {})", record, actual_crc);
-                continue;
+    /// Build a memory table from every record inside of the redo logs. The
+    /// records are replayed in the order they were written, so the newest
+    /// write of a key wins no matter which log it is in. Corrupt records are
+    /// skipped unless `strict` is set. Also returns every log that had a
+    /// corrupt record before its end, whose later records couldn't be read.
+    fn from_write_ahead_logs(
+        paths: &[PathBuf],
+        format: SerializationFormat,
+        strict: bool,
+    ) -> crate::Result<(Self, Vec<PathBuf>)> {
+        let mut records = vec![];
+        let mut damaged = vec![];
+        for path in paths {
+            let (logged, complete) = Self::read_write_ahead_log(path, format, strict)?;
+            records.extend(logged);
+            if !complete {
+                damaged.push(path.clone());
             }
+        }
+        records.sort_by_key(|r| r.timestamp);
+        // writes made after a restart must be newer than the ones replayed,
+        // even if the clock went back in the meantime
+        if let Some(newest) = records.last() {
+            observe_timestamp(newest.timestamp);
+        }
+
+        let table = Self::new();
+        for record in records {
             table.append(record);
         }
+        Ok((table, damaged))
+    }
+
+    /// Read the records of a redo log. The flag is `false` if a corrupt
+    /// record stopped the log from being read to its end.
+    fn read_write_ahead_log(
+        path: impl AsRef<Path>,
+        format: SerializationFormat,
+        strict: bool,
+    ) -> crate::Result<(Vec<Record>, bool)> {
+        debug!("Reading records from redo log {:?}", &path.as_ref());
+        let mut records = vec![];
+        let complete = for_each_logged_record(path.as_ref(), format, strict, |record| {
+            if record.verify(strict)? {
+                records.push(record);
+            }
+            Ok(())
+        })?;
+        Ok((records, complete))
+    }
 
-        Ok(table)
+    /// Write every record in the table to the writer. Returns the number of
+    /// key and value bytes written.
+    fn write_records(
+        &self,
+        writer: &mut impl Write,
+        format: SerializationFormat,
+    ) -> crate::Result<usize> {
+        let mut written = 0;
+        for record in self.inner.read().unwrap().map.values() {
+            writer.write_all(&format.encode(record)?)?;
+            written += record.payload_size();
+        }
+        Ok(written)
     }
 
     fn append(&self, record: Record) -> usize {
@@ -119,8 +963,8 @@ impl MemoryTable {
 
         trace!("Memory Size {}: Appending {}", lock.size, &record);
 
-        lock.size = match lock.map.insert(record.key, record.value) {
-            Some(old_value) => lock.size - old_value.map(|v| v.len()).unwrap_or(0) + value_size,
+        lock.size = match lock.map.insert(record.key.clone(), record) {
+            Some(old) => lock.size - old.value().map(|v| v.len()).unwrap_or(0) + value_size,
             None => lock.size + key_size + value_size,
         };
         let size = lock.size;
@@ -128,45 +972,49 @@ impl MemoryTable {
         size
     }
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        match self.inner.read().unwrap().map.get(key) {
-            Some(value) => value.clone(),
-            None => None,
+    fn get(&self, key: &[u8]) -> Option<Record> {
+        self.inner.read().unwrap().map.get(key).cloned()
+    }
+
+    /// Copy the records of the table into a new table that doesn't share
+    /// them, so later writes to this table aren't seen by the copy
+    fn copy(&self) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(self.inner.read().unwrap().clone())),
         }
     }
 
-    fn find(&self, pattern: &PreparedPattern) -> Vec<Vec<u8>> {
+    fn len(&self) -> usize {
+        self.inner.read().unwrap().map.len()
+    }
+
+    /// Number of key and value bytes held by the table
+    fn size(&self) -> usize {
+        self.inner.read().unwrap().size
+    }
+
+    fn find(&self, pattern: &PreparedPattern) -> Vec<KeyMatch> {
         let mut keys = vec![];
-        for key in self.inner.read().unwrap().map.keys() {
+        for (key, record) in self.inner.read().unwrap().map.iter() {
             if pattern.test(key) {
-                keys.push(key.clone());
+                keys.push(KeyMatch::from_record(record));
             }
         }
         keys
     }
 
     /// Drain memory table to file and return it as a segment.
-    fn drain_to_segment(&self, path: impl AsRef<Path>) -> crate::Result<Segment> {
+    fn drain_to_segment(&self, path: impl AsRef<Path>, config: &Config) -> crate::Result<Segment> {
         debug!("Draining memory table to segment {:?}", path.as_ref());
 
-        let mut writer = BufWriter::new(File::create(path.as_ref())?);
-
         let table = self.inner.read().unwrap();
-        let number_of_records = table.map.len();
-        let mut index = Index::new(number_of_records);
-        let mut block_start = writer.write(&number_of_records.to_be_bytes())?;
-        let mut size = block_start;
-
-        for (key, value) in table.map.iter() {
-            let record = Record::new(key.clone(), value.clone());
-            let bytes = bincode::serialize(&record)?;
-            block_start += index.add(block_start, record)?;
-            size += writer.write(&bytes)?;
+        let mut writer = SegmentWriter::create(path.as_ref(), table.map.len(), config)?;
+        for record in table.map.values() {
+            writer.push(record)?;
         }
-
         drop(table);
 
-        Ok(Segment::new(index, path.as_ref(), size))
+        writer.finish()
     }
 }
 
@@ -188,73 +1036,315 @@ impl std::fmt::Display for MemoryTable {
 #[derive(Clone, Debug)]
 pub struct SSTable {
     inner: MemoryTable,
-    write_ahead_log: Arc<Mutex<BufWriter<File>>>,
+    /// `None` when the table was restored by a read-only store
+    write_ahead_log: Option<Arc<Mutex<BufWriter<File>>>>,
+    /// Handle to the write-ahead-log that syncs it without taking the
+    /// writers' lock
+    sync_handle: Option<Arc<File>>,
+    group_commit: Arc<GroupCommit>,
     write_ahead_log_path: PathBuf,
+    fsync_policy: FsyncPolicy,
+    /// Format the records of the write-ahead-log are written in
+    format: SerializationFormat,
+    unsynced_writes: Arc<AtomicUsize>,
+    log_size: Arc<AtomicUsize>,
+    /// Set once the table has been saved as a segment. Only then can the
+    /// write-ahead-log be removed when the table is dropped.
+    persisted: Arc<AtomicBool>,
+    /// When the write-ahead-log was started
+    created: Instant,
 }
 
 impl SSTable {
     /// Create a new SSTable and pass the directory in where a write-ahead-log
     /// should be created to save data on write.
-    pub fn new(directory: impl AsRef<Path>) -> crate::Result<Self> {
+    #[cfg(test)]
+    pub fn new(directory: impl AsRef<Path>, fsync_policy: FsyncPolicy) -> crate::Result<Self> {
+        Self::with_format(directory, fsync_policy, SerializationFormat::default())
+    }
+
+    /// Create a new SSTable like `new`, writing its write-ahead-log in
+    /// `format`
+    pub fn with_format(
+        directory: impl AsRef<Path>,
+        fsync_policy: FsyncPolicy,
+        format: SerializationFormat,
+    ) -> crate::Result<Self> {
         info!("Creating new SSTable: {:?}.redo", directory.as_ref());
         let path = directory.as_ref().join(format!("{}.redo", Uuid::new_v4()));
-        let writer = BufWriter::new(File::create(path)?);
+        let writer = create_write_ahead_log(&path)?;
         Ok(Self {
             inner: MemoryTable::new(),
-            write_ahead_log: Arc::new(Mutex::new(writer)),
-            write_ahead_log_path: directory.as_ref().to_path_buf(),
+            sync_handle: Some(Arc::new(writer.get_ref().try_clone()?)),
+            write_ahead_log: Some(Arc::new(Mutex::new(writer))),
+            group_commit: Arc::new(GroupCommit::new()),
+            write_ahead_log_path: path,
+            fsync_policy,
+            format,
+            unsynced_writes: Arc::new(AtomicUsize::new(0)),
+            log_size: Arc::new(AtomicUsize::new(0)),
+            persisted: Arc::new(AtomicBool::new(false)),
+            created: Instant::now(),
         })
     }
 
-    /// Restore an SSTable from it's write-ahead-log.
-    pub fn from_write_ahead_log(path: impl AsRef<Path>) -> crate::Result<Self> {
-        info!("Restoring SSTable from: {:?}", path.as_ref());
-        let inner = MemoryTable::from_write_ahead_log(path.as_ref())?;
-        let writer = BufWriter::new(File::create(path.as_ref())?);
+    /// Restore an SSTable from every write-ahead-log that was left in the
+    /// directory. The recovered records are written into a single new log and
+    /// the old logs are removed.
+    pub fn from_write_ahead_logs(
+        directory: impl AsRef<Path>,
+        paths: &[PathBuf],
+        fsync_policy: FsyncPolicy,
+        format: SerializationFormat,
+        strict: bool,
+    ) -> crate::Result<Self> {
+        info!("Restoring SSTable from: {:?}", paths);
+        let (inner, damaged) = MemoryTable::from_write_ahead_logs(paths, format, strict)?;
+
+        // rewrite the logs with only the records that were recovered, so new
+        // writes aren't appended after a torn or corrupt record
+        let path = directory.as_ref().join(format!("{}.redo", Uuid::new_v4()));
+        let restore_path = path.with_extension("restore");
+        let mut writer = create_write_ahead_log(&restore_path)?;
+        let log_size = inner.write_records(&mut writer, format)?;
+        writer.flush()?;
+        // the recovered log has to be on disk under its real name before the
+        // logs it was recovered from are deleted
+        writer.get_ref().sync_all()?;
+        std::fs::rename(&restore_path, &path)?;
+        sync_dir(directory.as_ref())?;
+        for old_path in paths {
+            if damaged.contains(old_path) {
+                // the records after the corrupt one may still be recovered
+                // by hand, so the log is moved aside instead of deleted
+                let aside = old_path.with_extension("corrupt");
+                warn!("Keeping damaged redo log {:?} as {:?}", old_path, aside);
+                std::fs::rename(old_path, aside)?;
+            } else {
+                trace!("Removing restored redo log {:?}", old_path);
+                std::fs::remove_file(old_path)?;
+            }
+        }
 
         Ok(Self {
             inner,
-            write_ahead_log: Arc::new(Mutex::new(writer)),
-            write_ahead_log_path: path.as_ref().to_path_buf(),
+            sync_handle: Some(Arc::new(writer.get_ref().try_clone()?)),
+            write_ahead_log: Some(Arc::new(Mutex::new(writer))),
+            group_commit: Arc::new(GroupCommit::new()),
+            write_ahead_log_path: path,
+            fsync_policy,
+            format,
+            unsynced_writes: Arc::new(AtomicUsize::new(0)),
+            log_size: Arc::new(AtomicUsize::new(log_size)),
+            persisted: Arc::new(AtomicBool::new(false)),
+            created: Instant::now(),
         })
     }
 
-    /// Append a key value to memory inside of SSTable and then write it to our log
-    pub fn append(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> crate::Result<usize> {
-        let record = Record::new(key, value);
-        let bytes = bincode::serialize(&record)?;
-        let mut lock = self.write_ahead_log.lock().unwrap();
-        lock.write_all(&bytes)?;
-        lock.flush()?;
-        drop(lock);
-        Ok(self.inner.append(record))
+    /// Restore an SSTable from every write-ahead-log left in the directory
+    /// without touching the logs. The table can't be written to.
+    pub fn read_only(
+        paths: &[PathBuf],
+        format: SerializationFormat,
+        strict: bool,
+    ) -> crate::Result<Self> {
+        info!("Restoring read-only SSTable from: {:?}", paths);
+        Ok(Self {
+            inner: MemoryTable::from_write_ahead_logs(paths, format, strict)?.0,
+            write_ahead_log: None,
+            sync_handle: None,
+            group_commit: Arc::new(GroupCommit::new()),
+            write_ahead_log_path: PathBuf::new(),
+            fsync_policy: FsyncPolicy::Never,
+            format,
+            unsynced_writes: Arc::new(AtomicUsize::new(0)),
+            log_size: Arc::new(AtomicUsize::new(0)),
+            persisted: Arc::new(AtomicBool::new(false)),
+            created: Instant::now(),
+        })
     }
 
-    /// Check to see if a key exists inside of the SSTable
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.inner.get(key)
+    /// Copy the records of the table into a table that can't be written to
+    /// and has no write-ahead-log of its own. Writes made to this table
+    /// afterwards aren't seen by the copy.
+    pub fn freeze(&self) -> Self {
+        Self {
+            inner: self.inner.copy(),
+            write_ahead_log: None,
+            sync_handle: None,
+            group_commit: Arc::new(GroupCommit::new()),
+            write_ahead_log_path: PathBuf::new(),
+            fsync_policy: FsyncPolicy::Never,
+            format: self.format,
+            unsynced_writes: Arc::new(AtomicUsize::new(0)),
+            log_size: Arc::new(AtomicUsize::new(0)),
+            persisted: Arc::new(AtomicBool::new(false)),
+            created: Instant::now(),
+        }
     }
 
-    pub fn find(&self, pattern: &PreparedPattern) -> Vec<Vec<u8>> {
-        self.inner.find(pattern)
+    /// Append a key value to memory inside of SSTable and then write it to our log
+    #[cfg(test)]
+    pub fn append(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> crate::Result<usize> {
+        self.append_record(Record::new(key, value))
     }
 
-    /// Save the SSTable from memory onto disk as segment file. Return the path
-    /// to the new segment file.
-    pub fn save(&self, segment_path: impl AsRef<Path>) -> crate::Result<Segment> {
-        self.inner.drain_to_segment(segment_path)
+    /// Append a record to memory inside of SSTable and then write it to our log
+    pub fn append_record(&self, record: Record) -> crate::Result<usize> {
+        self.append_records(vec![record])
     }
-}
 
-impl std::fmt::Display for SSTable {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "SSTable({})", self.inner)
+    /// Append many records, writing all of them to our log at once. Returns
+    /// the size of the memory table after the last record was added.
+    pub fn append_records(&self, records: Vec<Record>) -> crate::Result<usize> {
+        self.write_records(records, false)
     }
-}
 
-impl Drop for SSTable {
-    fn drop(&mut self) {
-        let path = self.write_ahead_log_path.as_path();
+    /// Append many records like `append_records`, but sync the log before
+    /// returning whatever the fsync policy is
+    pub fn append_records_durable(&self, records: Vec<Record>) -> crate::Result<usize> {
+        self.write_records(records, true)
+    }
+
+    fn write_records(&self, records: Vec<Record>, durable: bool) -> crate::Result<usize> {
+        let mut bytes = vec![];
+        let mut payload_size = 0;
+        for record in &records {
+            bytes.extend(self.format.encode(record)?);
+            payload_size += record.payload_size();
+        }
+        let log = self.write_ahead_log.as_ref().ok_or(KvError::ReadOnly)?;
+        let mut lock = log.lock().unwrap();
+        lock.write_all(&bytes)?;
+        lock.flush()?;
+        let sequence = self.group_commit.written();
+        drop(lock);
+        self.log_size.fetch_add(payload_size, Ordering::SeqCst);
+        let unsynced = self
+            .unsynced_writes
+            .fetch_add(records.len(), Ordering::SeqCst)
+            + records.len();
+        let should_sync = durable
+            || match self.fsync_policy {
+                FsyncPolicy::Always => true,
+                FsyncPolicy::EveryN(n) => unsynced >= n,
+                FsyncPolicy::Interval(_) | FsyncPolicy::Never => false,
+            };
+        if should_sync {
+            self.sync_through(sequence)?;
+        }
+        let mut memory_size = self.inner.size();
+        for record in records {
+            memory_size = self.inner.append(record);
+        }
+        Ok(memory_size)
+    }
+
+    /// Force every write made to the write-ahead-log onto the disk
+    pub fn sync(&self) -> crate::Result<()> {
+        if self.unsynced_writes.load(Ordering::SeqCst) == 0 {
+            return Ok(());
+        }
+        // every write is flushed to the file before it is numbered
+        self.sync_through(self.group_commit.last_written())
+    }
+
+    /// Sync the write-ahead-log up to at least the write numbered
+    /// `sequence`. Writers syncing at the same time share one sync.
+    fn sync_through(&self, sequence: u64) -> crate::Result<()> {
+        let file = match &self.sync_handle {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        self.group_commit
+            .sync_through(sequence, || file.sync_data())?;
+        self.unsynced_writes.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Number of writes made to the write-ahead-log since it was last synced
+    #[cfg(test)]
+    pub fn unsynced_writes(&self) -> usize {
+        self.unsynced_writes.load(Ordering::SeqCst)
+    }
+
+    /// Number of times the write-ahead-log was synced
+    #[cfg(test)]
+    pub fn syncs(&self) -> usize {
+        self.group_commit.syncs()
+    }
+
+    /// Path of the write-ahead-log of the table. Every writable table gets
+    /// a log of its own, so the path tells tables apart.
+    pub fn log_path(&self) -> &Path {
+        &self.write_ahead_log_path
+    }
+
+    /// Get the newest record of a key inside of the SSTable. The record may
+    /// be a tombstone.
+    pub fn get(&self, key: &[u8]) -> Option<Record> {
+        self.inner.get(key)
+    }
+
+    pub fn find(&self, pattern: &PreparedPattern) -> Vec<KeyMatch> {
+        self.inner.find(pattern)
+    }
+
+    /// Number of key and value bytes written to the write-ahead-log,
+    /// including the values of keys that were overwritten since
+    pub fn log_size(&self) -> usize {
+        self.log_size.load(Ordering::SeqCst)
+    }
+
+    /// Number of keys held inside of the SSTable
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// How long ago the write-ahead-log of the table was started
+    pub fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// Write every record of the table into a new write-ahead-log inside of
+    /// `directory`, which a store opened there restores. Nothing is written
+    /// for an empty table.
+    pub fn write_log(&self, directory: impl AsRef<Path>) -> crate::Result<()> {
+        if self.len() == 0 {
+            return Ok(());
+        }
+        let path = directory.as_ref().join(format!("{}.redo", Uuid::new_v4()));
+        let mut writer = create_write_ahead_log(&path)?;
+        self.inner.write_records(&mut writer, self.format)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Save the SSTable from memory onto disk as segment file. Return the path
+    /// to the new segment file. Once saved, the write-ahead-log is removed
+    /// when the SSTable is dropped.
+    pub fn save(&self, segment_path: impl AsRef<Path>, config: &Config) -> crate::Result<Segment> {
+        let segment = self.inner.drain_to_segment(segment_path, config)?;
+        self.persisted.store(true, Ordering::SeqCst);
+        Ok(segment)
+    }
+}
+
+impl std::fmt::Display for SSTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SSTable({})", self.inner)
+    }
+}
+
+impl Drop for SSTable {
+    fn drop(&mut self) {
+        let path = self.write_ahead_log_path.as_path();
+        if !self.persisted.load(Ordering::SeqCst) {
+            // the records only live in the log, keep it so they are restored
+            trace!("Keeping redo log {:?} as it was never saved", &path);
+            return;
+        }
         trace!("Attempting to remove redo log {:?}", &path);
         match std::fs::remove_file(path) {
             Ok(_) => info!("Successfully removed redo log {:?}", &path),
@@ -263,7 +1353,7 @@ impl Drop for SSTable {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BlockHint {
     key: Vec<u8>,
     number_of_elements: usize,
@@ -287,32 +1377,20 @@ impl BlockHint {
         }
     }
 
-    fn init_block(&mut self, record: Record, record_size: u64) {
+    fn init_block(&mut self, record: &Record, record_size: u64) {
         self.key = record.key().to_vec();
         self.block_size = record_size;
         self.number_of_elements = 1;
     }
 
-    pub fn add(&mut self, record: Record) -> crate::Result<(u64, Option<BlockHint>)> {
-        let record_size = bincode::serialized_size(&record)?;
-        let mut next_block = None;
-        if self.block_size == 0 {
-            // Adding the first block
-            self.init_block(record, record_size);
-        } else {
-            let new_block_size = self.block_size + record_size;
-            if new_block_size - self.block_start > 4096 {
-                // create a new block
-                let mut new_block = BlockHint::new(self.block_start + self.block_size);
-                new_block.init_block(record, record_size);
-                next_block = Some(new_block);
-            } else {
-                // add to the current block
-                self.number_of_elements += 1;
-                self.block_size = new_block_size;
-            }
-        }
-        Ok((record_size, next_block))
+    /// Add a record that takes up `record_size` bytes on disk to the block
+    pub fn add(&mut self, record_size: u64) -> crate::Result<()> {
+        self.block_size = self
+            .block_size
+            .checked_add(record_size)
+            .ok_or_else(|| overflow("block size"))?;
+        self.number_of_elements += 1;
+        Ok(())
     }
 
     pub fn compare(&self, key: &[u8]) -> Compare {
@@ -332,133 +1410,468 @@ impl BlockHint {
             + self.block_start.to_be_bytes().len()
     }
 
+    /// Find every record of the blocks of `segment` whose key matches the
+    /// pattern
     pub(crate) fn find_keys(
         blocks: &mut [&Self],
-        segment_path: Pin<PathBuf>,
+        segment: &Segment,
         pattern: &PreparedPattern,
-    ) -> crate::Result<Vec<Vec<u8>>> {
+    ) -> crate::Result<Vec<KeyMatch>> {
         if blocks.is_empty() {
             return Ok(vec![]);
         }
+        let (codec, version, format) = (segment.codec, segment.version, segment.format);
+        let map = segment.mapped()?;
         blocks.sort_by(|a, b| a.block_start.cmp(&b.block_start));
-        let mut reader = BufReader::new(File::open(segment_path.to_path_buf())?);
+        // a mapped segment is read straight from memory, otherwise the file
+        // is opened once and every block is read through it
+        let mut file = None;
         let mut keys = vec![];
 
         for block in blocks.iter() {
-            reader.seek(SeekFrom::Start(block.block_start))?;
-            if reader.fill_buf().unwrap().is_empty() {
-                return Ok(keys);
-            }
-            let record: Record = bincode::deserialize_from(&mut reader)?;
-            if pattern.test(&record.key) {
-                keys.push(record.key.clone());
+            let records = match map {
+                Some(map) => block.decode(&mut block.mapped(map)?, codec, version, format)?,
+                None => {
+                    let reader = match file.as_mut() {
+                        Some(reader) => reader,
+                        None => file.insert(BufReader::new(File::open(&*segment.segment_path)?)),
+                    };
+                    reader.seek(SeekFrom::Start(block.block_start))?;
+                    block.decode(reader, codec, version, format)?
+                }
+            };
+            for record in records {
+                if !record.verify(segment.strict)? {
+                    continue;
+                }
+                if pattern.test(&record.key) {
+                    keys.push(KeyMatch::from_record(&record));
+                }
             }
         }
 
         Ok(keys)
     }
 
+    /// Search the block of `segment` for a key. The block is taken from the
+    /// cache when it was read recently, otherwise it is read from the segment
+    /// file, or its memory map, and added to the cache.
     pub(crate) fn search_for(
         &self,
-        segment_path: Pin<PathBuf>,
+        segment: &Segment,
         key: &[u8],
-    ) -> crate::Result<Option<Vec<u8>>> {
-        let mut reader = BufReader::new(File::open(segment_path.to_path_buf())?);
-        reader.seek(SeekFrom::Start(self.block_start))?;
-
-        let mut counter = 0;
-        while counter <= self.number_of_elements {
-            if reader.fill_buf().unwrap().is_empty() {
-                return Ok(None);
+    ) -> crate::Result<Option<Record>> {
+        let (codec, version, format) = (segment.codec, segment.version, segment.format);
+        let segment_path = &segment.segment_path;
+        let cache = segment.cache.as_deref();
+        let records = match cache.and_then(|cache| cache.get(segment_path, self.block_start)) {
+            Some(records) => records,
+            None => {
+                let records = match segment.mapped()? {
+                    Some(map) => self.decode(&mut self.mapped(map)?, codec, version, format)?,
+                    None => self.read_block(segment_path, codec, version, format)?,
+                };
+                let records = Arc::new(records);
+                if let Some(cache) = cache {
+                    let size = records.iter().map(Record::memory_size).sum();
+                    cache.insert(segment_path, self.block_start, records.clone(), size);
+                }
+                records
             }
-            counter += 1;
-            let record: Record = bincode::deserialize_from(&mut reader)?;
-            if record.key == key {
-                return Ok(record.value);
+        };
+        let record = records.iter().find(|record| record.key == key).cloned();
+
+        match record {
+            Some(record) => {
+                // a corrupt value is never handed out as the key's value
+                record.verify(true)?;
+                Ok(Some(record))
             }
+            None => Ok(None),
+        }
+    }
+
+    /// Read and decode every record of the block
+    fn read_block(
+        &self,
+        segment_path: &Path,
+        codec: Codec,
+        version: u8,
+        format: SerializationFormat,
+    ) -> crate::Result<Vec<Record>> {
+        let mut reader = BufReader::new(File::open(segment_path)?);
+        reader.seek(SeekFrom::Start(self.block_start))?;
+        self.decode(&mut reader, codec, version, format)
+    }
+
+    /// The bytes of a memory mapped segment file from the start of the block
+    fn mapped<'a>(&self, map: &'a [u8]) -> crate::Result<&'a [u8]> {
+        map.get(self.block_start as usize..)
+            .ok_or_else(|| KvError::Corruption {
+                context: format!(
+                    "block starts at {} past the end of the {} byte segment",
+                    self.block_start,
+                    map.len()
+                ),
+                key: None,
+            })
+    }
+
+    /// Decode every record of the block from a reader positioned at its start
+    fn decode(
+        &self,
+        reader: &mut impl BufRead,
+        codec: Codec,
+        version: u8,
+        format: SerializationFormat,
+    ) -> crate::Result<Vec<Record>> {
+        match codec {
+            Codec::None => read_records(reader, self.number_of_elements, version, format),
+            Codec::Zstd => Ok(read_frame(reader, version, format)?.0),
+        }
+    }
+}
+
+/// Read up to `count` uncompressed records from the start of a block,
+/// stopping early if the reader runs out. Errors reading from the reader are
+/// returned, never treated as the end of the records. The count comes from
+/// disk, so it isn't trusted to size the buffer up front.
+fn read_records(
+    reader: &mut impl BufRead,
+    count: usize,
+    version: u8,
+    format: SerializationFormat,
+) -> crate::Result<Vec<Record>> {
+    let mut coder = KeyCoder::new(version, format);
+    let mut records = Vec::with_capacity(count.min(1024));
+    while records.len() < count {
+        if reader.fill_buf()?.is_empty() {
+            break;
         }
-        Ok(None)
+        records.push(coder.decode(reader)?.0);
+    }
+    Ok(records)
+}
+
+/// Error for an offset or size of a segment that overflowed, which only a
+/// corrupt segment can cause
+fn overflow(what: &str) -> KvError {
+    KvError::Corruption {
+        context: format!("{} of the segment overflowed", what),
+        key: None,
+    }
+}
+
+/// Path of the sidecar file that holds the bloom filter of a segment
+fn bloom_path(segment_path: &Path) -> PathBuf {
+    segment_path.with_extension("bloom")
+}
+
+/// Path a segment is written to before it is complete. Only a finished
+/// segment is renamed to its real path, so a crash part way through a write
+/// never leaves a partial segment behind.
+pub fn temp_path(segment_path: &Path) -> PathBuf {
+    let mut path = segment_path.as_os_str().to_owned();
+    path.push(".tmp");
+    PathBuf::from(path)
+}
+
+/// Sync a directory, so the files created, renamed or removed in it survive
+/// a crash
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Move a file to `to`. A rename can't cross filesystems, so a file moved
+/// onto another filesystem is copied instead, and the copy is synced before
+/// the original is removed so a crash never leaves neither behind.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    move_file_with(from, to, |from, to| std::fs::rename(from, to))
+}
+
+/// Move a file like `move_file`, trying `rename` first
+fn move_file_with(
+    from: &Path,
+    to: &Path,
+    rename: impl FnOnce(&Path, &Path) -> io::Result<()>,
+) -> io::Result<()> {
+    match rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            trace!("Copying {:?} to {:?} across filesystems", from, to);
+            std::fs::copy(from, to)?;
+            File::open(to)?.sync_all()?;
+            std::fs::remove_file(from)
+        }
+        result => result,
     }
 }
 
 pub struct Index {
     filter: BloomFilter,
+    filter_loaded: bool,
     hints: Vec<BlockHint>,
     element_size: usize,
     byte_size: u64,
+    block_size: u64,
+    min_key: Option<Vec<u8>>,
+    max_key: Option<Vec<u8>>,
+    /// Newest timestamp of the records, unknown for a segment whose footer
+    /// was written before it was recorded
+    max_timestamp: Option<u128>,
 }
 
 impl Index {
-    pub fn new(estimated_elements: usize) -> Self {
-        let filter = BloomFilter::new(estimated_elements, 0.001);
+    /// Create an empty index whose bloom filter is sized for
+    /// `estimated_elements` keys at the false positive rate `fp_rate`
+    pub fn new(estimated_elements: usize, block_size: u64, fp_rate: f64) -> Self {
+        let filter = BloomFilter::new(estimated_elements.min(MAX_FILTER_ELEMENTS), fp_rate);
+        Self::from_filter(filter, block_size)
+    }
+
+    fn from_filter(filter: BloomFilter, block_size: u64) -> Self {
         Self {
             filter,
+            filter_loaded: false,
             hints: Vec::new(),
             element_size: 0,
             byte_size: 0,
+            block_size,
+            min_key: None,
+            max_key: None,
+            max_timestamp: None,
+        }
+    }
+
+    /// Create an index using a bloom filter that was loaded from disk. Keys
+    /// added to the index won't be inserted into the filter again.
+    fn with_filter(filter: BloomFilter, block_size: u64) -> Self {
+        let mut index = Self::from_filter(filter, block_size);
+        index.filter_loaded = true;
+        index
+    }
+
+    /// Save the bloom filter next to the segment file. The size of the segment
+    /// is saved with it so a filter that doesn't belong to the segment anymore
+    /// can be detected.
+    fn save_filter(&self, segment_path: &Path) -> crate::Result<()> {
+        let segment_size = std::fs::metadata(segment_path)?.len();
+        let mut writer = BufWriter::new(File::create(bloom_path(segment_path))?);
+        writer.write_all(&[BLOOM_FILE_VERSION])?;
+        writer.write_all(&segment_size.to_be_bytes())?;
+        writer.write_all(&self.filter.to_bytes()?)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Load the bloom filter saved next to the segment file. Returns `None`
+    /// if the filter is missing or stale and must be rebuilt.
+    fn load_filter(segment_path: &Path) -> Option<BloomFilter> {
+        let bytes = std::fs::read(bloom_path(segment_path)).ok()?;
+        let segment_size = std::fs::metadata(segment_path).ok()?.len();
+        let mut size_buffer = 0_u64.to_be_bytes();
+        if bytes.len() < size_buffer.len() + 1 || bytes[0] != BLOOM_FILE_VERSION {
+            debug!("Bloom filter for {:?} has an old format", segment_path);
+            return None;
+        }
+        size_buffer.copy_from_slice(&bytes[1..9]);
+        if u64::from_be_bytes(size_buffer) != segment_size {
+            debug!("Bloom filter for {:?} is stale", segment_path);
+            return None;
+        }
+        match BloomFilter::from_bytes(&bytes[9..]) {
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                error!("Failed to load bloom filter for {:?}: {}", segment_path, e);
+                None
+            }
+        }
+    }
+
+    /// Create an index from a segment footer and the segment's bloom filter
+    fn from_footer(filter: BloomFilter, footer: SegmentFooter, block_size: u64) -> Self {
+        let mut index = Self::with_filter(filter, block_size);
+        index.hints = footer.hints;
+        index.element_size = footer.elements;
+        index.byte_size = footer.byte_size;
+        index.min_key = footer.min_key;
+        index.max_key = footer.max_key;
+        index.max_timestamp = footer.max_timestamp;
+        index
+    }
+
+    /// Footer holding the block hints of the index
+    fn footer(&self) -> SegmentFooterRef<'_> {
+        SegmentFooterRef {
+            elements: self.element_size,
+            byte_size: self.byte_size,
+            hints: &self.hints,
+            min_key: &self.min_key,
+            max_key: &self.max_key,
+            max_timestamp: self.max_timestamp,
+        }
+    }
+
+    /// Remember the timestamp of a record added to the index
+    fn track_timestamp(&mut self, timestamp: u128) {
+        self.max_timestamp = Some(self.max_timestamp.unwrap_or(0).max(timestamp));
+    }
+
+    /// Widen the range of keys held by the index to include `key`
+    fn track_key(&mut self, key: &[u8]) {
+        if self.min_key.as_deref().map(|min| key < min).unwrap_or(true) {
+            self.min_key = Some(key.to_vec());
+        }
+        if self.max_key.as_deref().map(|max| key > max).unwrap_or(true) {
+            self.max_key = Some(key.to_vec());
         }
     }
 
-    pub fn add(&mut self, block_start: usize, record: Record) -> crate::Result<usize> {
-        if record.crc != record.calculate_crc() {
-            let actual_crc = record.calculate_crc();
-            error!("{} is corrupt (Actual {})", record, actual_crc);
-            return Ok(bincode::serialized_size(&record)? as usize);
+    /// Check if `key` falls between the smallest and largest key of the index
+    fn in_range(&self, key: &[u8]) -> bool {
+        match (&self.min_key, &self.max_key) {
+            (Some(min), Some(max)) => min.as_slice() <= key && key <= max.as_slice(),
+            _ => false,
         }
-        self.filter.insert(&String::from_utf8_lossy(record.key()));
-        let block = match self.hints.last_mut() {
+    }
+
+    /// Check if every key of the index is lower than `key`
+    fn ends_before(&self, key: &[u8]) -> bool {
+        match &self.max_key {
+            Some(max) => max.as_slice() < key,
+            None => true,
+        }
+    }
+
+    /// Check if the keys of the index fall between the smallest and largest
+    /// key of `other`
+    fn range_within(&self, other: &Index) -> bool {
+        match (&self.min_key, &self.max_key) {
+            (Some(min), Some(max)) => other.in_range(min) && other.in_range(max),
+            _ => false,
+        }
+    }
+
+    /// Check if any key between the smallest and largest key of the index
+    /// could start with `prefix`
+    fn prefix_in_range(&self, prefix: &[u8]) -> bool {
+        match (&self.min_key, &self.max_key) {
+            (Some(min), Some(max)) => {
+                &min[..min.len().min(prefix.len())] <= prefix && prefix <= max.as_slice()
+            }
+            _ => false,
+        }
+    }
+
+    /// Check if a record starts a new block, which it does when the last
+    /// block would grow past the block size if the record was added to it in
+    /// full. A record larger than a block always ends up alone in its block,
+    /// since it doesn't fit next to the records before it and nothing fits
+    /// next to it afterwards.
+    pub fn starts_block(
+        &self,
+        record: &Record,
+        format: SerializationFormat,
+    ) -> crate::Result<bool> {
+        let block = match self.hints.last() {
             Some(block) => block,
-            None => {
-                let block = BlockHint::new(block_start as u64);
+            None => return Ok(true),
+        };
+        let block_size = block
+            .block_size
+            .checked_add(format.encoded_size(record)?)
+            .ok_or_else(|| overflow("block size"))?;
+        Ok(block_size > self.block_size)
+    }
+
+    /// Add a record that was written at `position` and takes up
+    /// `record_size` bytes on disk. The record is added to the last block
+    /// unless `starts_block` says it begins a new one. A corrupt record still
+    /// takes up room in its block, but its key is left out of the filter and
+    /// the key range.
+    pub fn add(
+        &mut self,
+        position: usize,
+        record: &Record,
+        record_size: u64,
+        starts_block: bool,
+    ) -> crate::Result<()> {
+        if record.verify(false)? {
+            if !self.filter_loaded {
+                self.filter.insert(record.key());
+            }
+            self.track_key(record.key());
+            self.track_timestamp(record.timestamp());
+        }
+        match self.hints.last_mut() {
+            Some(block) if !starts_block => block.add(record_size)?,
+            _ => {
+                let mut block = BlockHint::new(position as u64);
+                block.init_block(record, record_size);
                 self.hints.push(block);
-                self.hints.last_mut().unwrap()
             }
+        }
+        self.byte_size = self
+            .byte_size
+            .checked_add(record_size)
+            .ok_or_else(|| overflow("byte size"))?;
+        self.element_size += 1;
+        Ok(())
+    }
+
+    /// Add a compressed block that starts at `block_start` and takes up
+    /// `block_size` bytes on disk, given the keys of its records.
+    pub fn add_block(
+        &mut self,
+        block_start: usize,
+        keys: &[Vec<u8>],
+        block_size: u64,
+    ) -> crate::Result<()> {
+        let first = match keys.first() {
+            Some(key) => key,
+            None => return Ok(()),
         };
-        let (record_size, new_block) = block.add(record)?;
-        self.byte_size += record_size;
-        if let Some(block) = new_block {
-            self.hints.push(block);
+        let byte_size = self
+            .byte_size
+            .checked_add(block_size)
+            .ok_or_else(|| overflow("byte size"))?;
+        for key in keys.iter() {
+            if !self.filter_loaded {
+                self.filter.insert(key);
+            }
+            self.track_key(key);
         }
-        Ok(record_size as usize)
+        self.hints.push(BlockHint {
+            key: first.clone(),
+            number_of_elements: keys.len(),
+            block_size,
+            block_start: block_start as u64,
+        });
+        self.byte_size = byte_size;
+        self.element_size += keys.len();
+        Ok(())
     }
 
     pub fn get(&self, key: &[u8]) -> Option<&BlockHint> {
-        if !self.filter.contains(&String::from_utf8_lossy(key)) {
+        if !self.filter.contains(key) {
             None
         } else {
-            Some(self.search(key))
+            self.search(key)
         }
     }
 
-    fn find(&self, pattern: &PreparedPattern) -> Vec<&BlockHint> {
-        let mut hints = vec![];
-        for hint in self.hints.iter() {
-            if pattern.test(&hint.key) {
-                hints.push(hint);
-            }
-        }
-        hints
-    }
-
-    fn search(&self, key: &[u8]) -> &BlockHint {
-        let mut middle = self.hints.len() / 2;
-        let mut hints = &self.hints[..];
-        loop {
-            if hints.len() == 1 {
-                return &hints[0];
-            }
-            match hints[middle].compare(key) {
-                Compare::Higher => {
-                    hints = &hints[middle..self.hints.len()];
-                    middle /= 2;
-                }
-                Compare::Lower => {
-                    hints = &hints[0..middle];
-                    middle /= 2;
-                }
-                Compare::Equal => return &hints[middle],
+    /// Find the block the key would be stored in, which is the last block
+    /// that starts with a key lower or equal to it.
+    fn search(&self, key: &[u8]) -> Option<&BlockHint> {
+        let (mut low, mut high) = (0, self.hints.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.hints[middle].compare(key) {
+                Compare::Equal => return Some(&self.hints[middle]),
+                Compare::Higher => low = middle + 1,
+                Compare::Lower => high = middle,
             }
         }
+        low.checked_sub(1).map(|index| &self.hints[index])
     }
 }
 
@@ -483,11 +1896,159 @@ impl std::fmt::Display for Index {
     }
 }
 /// An index that maps records in a file a log file keys  
+/// SegmentWriter writes records, in sorted order, into a new segment file and
+/// builds the index of the segment along the way. When compression is turned
+/// on, records are gathered into blocks that are compressed as a whole.
+struct SegmentWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    index: Index,
+    compression: Compression,
+    block_size: u64,
+    /// Keys of the records in the block being compressed
+    block: Vec<Vec<u8>>,
+    block_bytes: Vec<u8>,
+    coder: KeyCoder,
+    format: SerializationFormat,
+    position: usize,
+    count: usize,
+    cache: Arc<BlockCache>,
+    strict: bool,
+    mmap: bool,
+}
+
+impl SegmentWriter {
+    fn create(
+        path: impl Into<PathBuf>,
+        estimated_elements: usize,
+        config: &Config,
+    ) -> crate::Result<Self> {
+        let path = path.into();
+        let compression = config.compression();
+        let mut writer = BufWriter::new(File::create(temp_path(&path))?);
+        let position =
+            SegmentHeader::new(estimated_elements, compression.into()).write(&mut writer)?;
+        Ok(Self {
+            path,
+            writer,
+            index: Index::new(
+                estimated_elements,
+                config.block_size(),
+                config.bloom_fp_rate(),
+            ),
+            compression,
+            block_size: config.block_size(),
+            block: vec![],
+            block_bytes: vec![],
+            coder: KeyCoder::new(SEGMENT_VERSION, config.serialization_format()),
+            format: config.serialization_format(),
+            position,
+            count: 0,
+            cache: config.block_cache().clone(),
+            strict: config.is_strict(),
+            mmap: config.mmap(),
+        })
+    }
+
+    /// Write a record to the segment. The record is only borrowed, so
+    /// records can be written straight out of a memory table or a merge
+    /// without being copied. A compressed block only keeps the keys of its
+    /// records for the index. In strict mode a corrupt record read by a merge
+    /// fails the merge instead of being left out of the index.
+    fn push(&mut self, record: &Record) -> crate::Result<()> {
+        if self.strict {
+            record.verify(true)?;
+        }
+        self.count += 1;
+        match self.compression {
+            Compression::None => {
+                let starts_block = self.index.starts_block(record, self.format)?;
+                let bytes = self.coder.encode(record, starts_block)?;
+                self.index
+                    .add(self.position, record, bytes.len() as u64, starts_block)?;
+                self.position = self
+                    .position
+                    .checked_add(bytes.len())
+                    .ok_or_else(|| overflow("position"))?;
+                self.writer.write_all(&bytes)?;
+            }
+            Compression::Zstd { level } => {
+                // a record larger than a block is compressed on its own
+                // instead of being added to the block before it
+                if self.format.encoded_size(record)? >= self.block_size {
+                    self.write_block(level)?;
+                }
+                let bytes = self.coder.encode(record, self.block.is_empty())?;
+                self.block_bytes.extend(bytes);
+                self.block.push(record.key.clone());
+                self.index.track_timestamp(record.timestamp());
+                if self.block_bytes.len() as u64 >= self.block_size {
+                    self.write_block(level)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, level: i32) -> crate::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let frame_size = write_frame(&mut self.writer, &self.block_bytes, level)?;
+        self.index
+            .add_block(self.position, &self.block, frame_size)?;
+        self.position = self
+            .position
+            .checked_add(frame_size as usize)
+            .ok_or_else(|| overflow("position"))?;
+        self.block.clear();
+        self.block_bytes.clear();
+        Ok(())
+    }
+
+    /// Write out the last block, the footer and the final element count, then
+    /// move the file to its real path, returning the finished segment.
+    fn finish(mut self) -> crate::Result<Segment> {
+        if let Compression::Zstd { level } = self.compression {
+            self.write_block(level)?;
+        }
+        self.index
+            .footer()
+            .write(&mut self.writer, self.position as u64, self.format)?;
+        // rewrite the header to have the correct count of elements in the file
+        self.writer.rewind()?;
+        SegmentHeader::new(self.count, self.compression.into()).write(&mut self.writer)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        move_file(&temp_path(&self.path), &self.path)?;
+        // a segment without its filter rebuilds it when opened
+        self.index.save_filter(&self.path)?;
+
+        let mut segment = Segment::new(self.index, self.path, self.position);
+        segment.codec = self.compression.into();
+        segment.format = self.format;
+        segment.cache = Some(self.cache);
+        segment.strict = self.strict;
+        segment.mmap = self.mmap;
+        Ok(segment)
+    }
+}
+
 pub struct Segment {
     index: Pin<Box<Index>>,
     segment_path: Pin<PathBuf>,
     size: Pin<Box<usize>>,
-    should_remove: Pin<Box<bool>>,
+    should_remove: AtomicBool,
+    version: u8,
+    codec: Codec,
+    format: SerializationFormat,
+    cache: Option<Arc<BlockCache>>,
+    /// Return corrupt records as errors instead of skipping them
+    strict: bool,
+    /// Read blocks through a memory map of the file
+    mmap: bool,
+    /// The memory map of the file, made on the first read that needs it
+    map: OnceLock<Mmap>,
 }
 
 impl Segment {
@@ -498,117 +2059,390 @@ impl Segment {
             index: Pin::new(Box::new(index)),
             segment_path: Pin::new(path),
             size: Pin::new(Box::new(size)),
-            should_remove: Pin::new(Box::new(false)),
+            should_remove: AtomicBool::new(false),
+            version: SEGMENT_VERSION,
+            codec: Codec::None,
+            format: SerializationFormat::default(),
+            cache: None,
+            strict: false,
+            mmap: false,
+            map: OnceLock::new(),
         }
     }
 
-    pub fn from_log(path: impl Into<PathBuf>) -> crate::Result<Segment> {
+    /// Open a segment file. The index is loaded from the footer of the file
+    /// when it has one, otherwise every record is read to rebuild it.
+    /// A file too short to hold a header, for example one that was created
+    /// but never written to before a crash, opens as an empty segment.
+    pub fn from_log(path: impl Into<PathBuf>, config: &Config) -> crate::Result<Segment> {
         let segment_path = path.into();
         debug!("Reading segment from log: {:?}", &segment_path);
+        let mut file = File::open(&segment_path)?;
+        let header = match SegmentHeader::read(&mut file) {
+            Err(KvError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                warn!("Segment {:?} has no header, opening it empty", segment_path);
+                let index = Index::new(0, config.block_size(), config.bloom_fp_rate());
+                let mut segment = Self::new(index, segment_path, 0);
+                segment.format = config.serialization_format();
+                segment.cache = Some(config.block_cache().clone());
+                segment.strict = config.is_strict();
+                segment.mmap = config.mmap();
+                return Ok(segment);
+            }
+            result => result?.0,
+        };
+        // every record takes up at least a byte, so a count larger than the
+        // file can only come from a corrupt header
+        let file_size = file.metadata()?.len();
+        if header.elements as u64 > file_size {
+            return Err(KvError::Corruption {
+                context: format!(
+                    "{:?} claims {} elements but is only {} bytes long",
+                    segment_path, header.elements, file_size
+                ),
+                key: None,
+            });
+        }
+        if let Some(segment) = Self::from_footer(&segment_path, config)? {
+            return Ok(segment);
+        }
+        Self::scan_log(segment_path, config)
+    }
+
+    /// Load the index of a segment from its footer and bloom filter. Returns
+    /// `None` if either of them is missing or can't be trusted.
+    fn from_footer(segment_path: &Path, config: &Config) -> crate::Result<Option<Segment>> {
+        let mut file = File::open(segment_path)?;
+        let (header, data_start) = SegmentHeader::read(&mut file)?;
+        if header.version < 3 {
+            return Ok(None);
+        }
+        let format = config.serialization_format();
+        let footer = SegmentFooter::read(&mut file, header.version, data_start as u64, format)?;
+        let (footer, data_end) = match footer {
+            Some(footer) => footer,
+            None => {
+                warn!("Segment {:?} has a corrupt footer", segment_path);
+                return Ok(None);
+            }
+        };
+        let filter = match Index::load_filter(segment_path) {
+            Some(filter) => filter,
+            None => return Ok(None),
+        };
+        let index = Index::from_footer(filter, footer, config.block_size());
+        let mut segment = Self::new(index, segment_path, data_end as usize);
+        segment.version = header.version;
+        segment.codec = header.codec;
+        segment.format = format;
+        segment.cache = Some(config.block_cache().clone());
+        segment.strict = config.is_strict();
+        segment.mmap = config.mmap();
+        Ok(Some(segment))
+    }
+
+    /// Rebuild the index of a segment by reading every record inside of it
+    fn scan_log(segment_path: PathBuf, config: &Config) -> crate::Result<Segment> {
         let mut reader = BufReader::new(File::open(&segment_path)?);
-        let mut size_buffer = 0_usize.to_be_bytes();
-        let mut block_start = reader.read(&mut size_buffer)?;
-        let elements = usize::from_be_bytes(size_buffer);
+        let (header, mut block_start) = SegmentHeader::read(&mut reader)?;
 
-        let mut index = Index::new(elements);
-        while !reader.fill_buf().unwrap().is_empty() {
-            let record: Record = bincode::deserialize_from(&mut reader).unwrap();
-            block_start += index.add(block_start, record)?;
+        let mut index = match Index::load_filter(&segment_path) {
+            Some(filter) => Index::with_filter(filter, config.block_size()),
+            None => Index::new(header.elements, config.block_size(), config.bloom_fp_rate()),
+        };
+        // a footer follows the records of newer segments, so stop once every
+        // record in the header's count has been read
+        let mut remaining = header.elements;
+        let has_footer = header.version >= 3;
+        let format = config.serialization_format();
+        let mut coder = KeyCoder::new(header.version, format);
+        while !reader.fill_buf()?.is_empty() && (!has_footer || remaining > 0) {
+            match header.codec {
+                Codec::None => {
+                    // a record cut short is the end of a segment that was
+                    // never finished, keep every record before it
+                    let (record, record_size) = match coder.decode(&mut reader) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            warn!("Stopped reading {:?} at torn record: {}", segment_path, e);
+                            break;
+                        }
+                    };
+                    if config.is_strict() {
+                        record.verify(true)?;
+                    }
+                    let starts_block = index.starts_block(&record, format)?;
+                    index.add(block_start, &record, record_size, starts_block)?;
+                    block_start = block_start
+                        .checked_add(record_size as usize)
+                        .ok_or_else(|| overflow("block start"))?;
+                    remaining = remaining.saturating_sub(1);
+                }
+                Codec::Zstd => {
+                    let (records, frame_size) = read_frame(&mut reader, header.version, format)?;
+                    for record in records.iter() {
+                        if config.is_strict() {
+                            record.verify(true)?;
+                        }
+                        index.track_timestamp(record.timestamp());
+                    }
+                    let keys = records.into_iter().map(|r| r.key).collect::<Vec<_>>();
+                    index.add_block(block_start, &keys, frame_size)?;
+                    block_start = block_start
+                        .checked_add(frame_size as usize)
+                        .ok_or_else(|| overflow("block start"))?;
+                    remaining = remaining.saturating_sub(keys.len());
+                }
+            }
+        }
+        if !index.filter_loaded && !config.is_read_only() {
+            index.save_filter(&segment_path)?;
         }
-        Ok(Self::new(index, segment_path, block_start))
+        let mut segment = Self::new(index, segment_path, block_start);
+        segment.version = header.version;
+        segment.codec = header.codec;
+        segment.format = format;
+        segment.cache = Some(config.block_cache().clone());
+        segment.strict = config.is_strict();
+        segment.mmap = config.mmap();
+        Ok(segment)
     }
 
     pub fn from_segments(
+        path: impl Into<PathBuf>,
+        readers: Vec<SegmentReader>,
+        config: &Config,
+    ) -> crate::Result<Segment> {
+        Self::write_segments(path, readers, config, false)
+    }
+
+    /// Merge segments like `from_segments`, but leave out every key whose
+    /// newest record is a tombstone. Only safe when the readers hold every
+    /// older record of the store, otherwise deleted keys would come back.
+    pub fn compact_segments(
+        path: impl Into<PathBuf>,
+        readers: Vec<SegmentReader>,
+        config: &Config,
+    ) -> crate::Result<Segment> {
+        Self::write_segments(path, readers, config, true)
+    }
+
+    /// Merge the readers into a new segment. Records stream from the readers
+    /// to the new file, so the memory used by a merge doesn't grow with the
+    /// size of the level: at any time it holds one record per reader, one
+    /// block of output, and the index of the new segment. The index keeps a
+    /// single hint per block plus the bloom filter, the same index the
+    /// finished segment keeps for lookups, and the footer is written from it
+    /// without a copy.
+    fn write_segments(
         path: impl Into<PathBuf>,
         mut readers: Vec<SegmentReader>,
+        config: &Config,
+        drop_tombstones: bool,
     ) -> crate::Result<Segment> {
         // initialize variables
         let segment_path = path.into();
-        let estimated_elements = readers.iter().fold(0, |o, r| o + r.elements);
-        let start: usize = 0;
-        let mut writer = BufWriter::new(File::create(&segment_path)?);
-        let mut block_start = writer.write(&start.to_be_bytes())?;
-        let mut index = Index::new(estimated_elements);
-        let mut size = 0;
-        let mut count: usize = 0;
-
-        loop {
-            // read the next record inside of the segment file
-            for reader in readers.iter_mut() {
-                reader.next()?;
-            }
-
-            // get all of the values from the readers
-            let mut records = readers
-                .iter()
-                .filter_map(|r| r.value.as_ref())
-                .collect::<Vec<_>>();
+        let estimated_elements = readers
+            .iter()
+            .fold(0_usize, |o, r| o.saturating_add(r.elements));
+        let mut writer = SegmentWriter::create(segment_path, estimated_elements, config)?;
+
+        // the heap holds the next record of every reader, so the smallest key
+        // is always on top
+        let mut heap = BinaryHeap::with_capacity(readers.len());
+        for index in 0..readers.len() {
+            MergeEntry::advance(&mut readers, index, &mut heap)?;
+        }
 
-            // however, if there was no records left, then leave the loop
-            if records.is_empty() {
-                break;
+        while let Some(top) = heap.pop() {
+            // the first entry of a key is its newest record, every other
+            // record of the key is older and left out
+            let writeable_record = readers[top.reader].value.take().unwrap();
+            MergeEntry::advance(&mut readers, top.reader, &mut heap)?;
+            while heap
+                .peek()
+                .map(|next| next.key == writeable_record.key)
+                .unwrap_or(false)
+            {
+                let older = heap.pop().unwrap();
+                readers[older.reader].value.take();
+                MergeEntry::advance(&mut readers, older.reader, &mut heap)?;
             }
-
-            // sort by key so we have an ordered list from largest to smallest
-            records.sort_by_key(|f| f.value.as_deref());
-            records.reverse();
-
-            // remove the first value and take all of the other keys that are equal to it
-            let mut groupped_records = vec![records.pop().unwrap()];
-            for record in records {
-                if record.key == groupped_records[0].key {
-                    groupped_records.push(record);
-                }
+            if drop_tombstones
+                && (writeable_record.value.is_none() || writeable_record.is_expired(now()))
+            {
+                continue;
             }
 
-            // again, sort by timestamp, take the newest one (highest timestamp)
-            groupped_records.sort_by_key(|r| r.timestamp);
-            let writeable_record = groupped_records.pop().unwrap();
-
             // write the record to our database
-            let bytes = bincode::serialize(&writeable_record)?;
-            block_start += index.add(block_start, writeable_record.clone())?;
-            size += writer.write(&bytes)?;
-            count += 1;
+            writer.push(&writeable_record)?;
         }
 
-        // rewrite first 8 bytes to have the correct count of elements in the file
-        writer.rewind()?;
-        writer.write_all(&count.to_be_bytes())?;
-
-        Ok(Segment::new(index, segment_path, size))
+        writer.finish()
     }
 
-    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+    /// Get the record of a key inside of the segment. The record may be a
+    /// tombstone.
+    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Record>> {
         debug!(
             "Searching for {} in {:?}",
             String::from_utf8_lossy(key),
             self.segment_path
         );
+        if !self.index.in_range(key) {
+            return Ok(None);
+        }
         if let Some(block_hint) = self.index.get(key) {
-            Ok(block_hint.search_for(self.segment_path.clone(), key)?)
+            Ok(block_hint.search_for(self, key)?)
         } else {
             Ok(None)
         }
     }
 
-    pub fn find(&self, pattern: &PreparedPattern) -> crate::Result<Vec<Vec<u8>>> {
+    /// Find every record whose key matches the pattern. A pattern can match
+    /// keys anywhere inside of a block, so every block of the segment is read
+    /// unless the pattern's literal prefix falls outside of the segment.
+    pub fn find(&self, pattern: &PreparedPattern) -> crate::Result<Vec<KeyMatch>> {
         debug!(
             "Finding keys that match {:?} in {:?}",
             pattern, self.segment_path
         );
-        let mut set = HashSet::new();
-        let mut hints = self.index.find(pattern);
-        let keys = BlockHint::find_keys(&mut hints, self.segment_path.clone(), pattern)?;
-        for key in keys {
-            set.insert(key);
+        if !self.may_match(pattern) {
+            return Ok(vec![]);
+        }
+        let mut hints = self.index.hints.iter().collect::<Vec<_>>();
+        BlockHint::find_keys(&mut hints, self, pattern)
+    }
+
+    /// The memory map of the segment file, or `None` when the segment is read
+    /// through the file. The file is mapped on the first call.
+    fn mapped(&self) -> crate::Result<Option<&[u8]>> {
+        if !self.mmap {
+            return Ok(None);
         }
-        let set = set.into_iter().collect::<Vec<_>>();
-        Ok(set)
+        if let Some(map) = self.map.get() {
+            return Ok(Some(map));
+        }
+        let file = File::open(&*self.segment_path)?;
+        // Safety: a segment file is never written to once it is finished.
+        // Merges and upgrades write their output to new paths and a replaced
+        // file is only removed once its segment is dropped, along with the
+        // map.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Some(self.map.get_or_init(|| map)))
+    }
+
+    /// Check if any key of the segment could match the pattern, judging by
+    /// the pattern's literal prefix and the range of keys in the segment
+    pub fn may_match(&self, pattern: &PreparedPattern) -> bool {
+        self.index.prefix_in_range(&pattern.literal_prefix())
     }
 
-    pub fn mark_for_removal(&mut self) {
-        *self.should_remove = true;
+    /// Number of bytes the records of the segment take up on disk
+    pub fn byte_size(&self) -> u64 {
+        *self.size as u64
+    }
+
+    /// Check if every key held by the segment is lower than `key`
+    pub fn ends_before(&self, key: &[u8]) -> bool {
+        self.index.ends_before(key)
+    }
+
+    /// Number of records held inside of the segment
+    pub fn len(&self) -> usize {
+        self.index.element_size
+    }
+
+    /// Format version the segment file was written with
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Newest timestamp of the records in the segment. A segment whose
+    /// footer was written before the timestamp was recorded falls back to
+    /// when its file was last modified, which is never older than its
+    /// records unless the clock stepped back before the file was written.
+    pub fn max_timestamp(&self) -> crate::Result<u128> {
+        if let Some(timestamp) = self.index.max_timestamp {
+            return Ok(timestamp);
+        }
+        let modified = std::fs::metadata(&*self.segment_path)?.modified()?;
+        Ok(modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|since| since.as_nanos())
+            .unwrap_or(0))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.segment_path
+    }
+
+    /// Check every record of the segment file against its checksum
+    pub fn verify(&self, report: &mut VerifyReport) -> crate::Result<()> {
+        if *self.size == 0 {
+            return Ok(());
+        }
+        let mut reader = SegmentReader::new(self)?;
+        loop {
+            reader.next()?;
+            match reader.value.take() {
+                Some(record) => tally(report, record)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Link the segment file and its bloom filter to `path`, copying them
+    /// instead when they can't be linked, for example because `path` is on
+    /// another disk. A segment file never changes once written, so the link
+    /// can be shared.
+    pub fn link_to(&self, path: impl AsRef<Path>) -> crate::Result<()> {
+        fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+            if std::fs::hard_link(from, to).is_err() {
+                std::fs::copy(from, to)?;
+            }
+            Ok(())
+        }
+
+        let path = path.as_ref();
+        link_or_copy(&self.segment_path, path)?;
+        let filter_path = bloom_path(&self.segment_path);
+        // a missing filter is rebuilt when the segment is opened
+        if filter_path.exists() {
+            link_or_copy(&filter_path, &bloom_path(path))?;
+        }
+        Ok(())
+    }
+
+    /// Check if `newer` holds every record of this segment, or a newer record
+    /// of the same key. A segment that can't be read is never superseded.
+    pub fn superseded_by(&self, newer: &Segment) -> bool {
+        if !self.index.range_within(&newer.index) {
+            return false;
+        }
+        let check = || -> crate::Result<bool> {
+            let mut reader = SegmentReader::new(self)?;
+            loop {
+                reader.next()?;
+                let record = match reader.value.take() {
+                    Some(record) => record,
+                    None => return Ok(true),
+                };
+                match newer.get(record.key())? {
+                    Some(found) if found.timestamp() >= record.timestamp() => {}
+                    _ => return Ok(false),
+                }
+            }
+        };
+        check().unwrap_or_else(|e| {
+            warn!("Failed to compare {} with {}: {}", self, newer, e);
+            false
+        })
+    }
+
+    /// Delete the segment file once the last handle to the segment is dropped
+    pub fn mark_for_removal(&self) {
+        self.should_remove.store(true, Ordering::SeqCst);
     }
 }
 
@@ -634,10 +2468,24 @@ impl Debug for Segment {
 
 impl Drop for Segment {
     fn drop(&mut self) {
-        if *self.should_remove {
+        // unmap the file before it is removed, some platforms refuse to
+        // remove a mapped file
+        drop(self.map.take());
+        if self.should_remove.load(Ordering::SeqCst) {
             trace!("Dropping segment {:?}. Deleting file.", &self.segment_path);
+            if let Some(cache) = &self.cache {
+                cache.remove_segment(&self.segment_path);
+            }
+            let filter_path = bloom_path(&self.segment_path);
+            if filter_path.exists() {
+                if let Err(e) = std::fs::remove_file(&filter_path) {
+                    error!("Failed to delete bloom filter {:?}: {}", filter_path, e);
+                }
+            }
             if self.segment_path.exists() {
-                std::fs::remove_file(&*self.segment_path).unwrap();
+                if let Err(e) = std::fs::remove_file(&*self.segment_path) {
+                    error!("Failed to delete segment {:?}: {}", self.segment_path, e);
+                }
             } else {
                 error!(
                     "Failed to delete segment {:?} as the file no longer exists",
@@ -648,39 +2496,1300 @@ impl Drop for Segment {
     }
 }
 
+/// The next record of a reader while segments are merged. Entries are
+/// ordered so the top of a `BinaryHeap` is the smallest key, or the largest
+/// key for descending entries, and for equal keys the newest record. Records
+/// with the same timestamp are won by the newer segment, which comes later in
+/// the list of readers.
+#[derive(PartialEq, Eq)]
+pub struct MergeEntry {
+    key: Vec<u8>,
+    timestamp: u128,
+    reader: usize,
+    descending: bool,
+}
+
+impl MergeEntry {
+    pub fn new(key: Vec<u8>, timestamp: u128, reader: usize) -> Self {
+        Self {
+            key,
+            timestamp,
+            reader,
+            descending: false,
+        }
+    }
+
+    /// Entry of a merge that hands out the largest key first
+    pub fn descending(key: Vec<u8>, timestamp: u128, reader: usize) -> Self {
+        Self {
+            descending: true,
+            ..Self::new(key, timestamp, reader)
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Index of the reader the entry was read from
+    pub fn reader(&self) -> usize {
+        self.reader
+    }
+
+    /// Read the next record of a reader and push it onto the heap
+    fn advance(
+        readers: &mut [SegmentReader],
+        reader: usize,
+        heap: &mut BinaryHeap<MergeEntry>,
+    ) -> crate::Result<()> {
+        readers[reader].next()?;
+        if let Some(record) = &readers[reader].value {
+            heap.push(MergeEntry::new(
+                record.key.clone(),
+                record.timestamp,
+                reader,
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Ord for MergeEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let keys = match self.descending {
+            false => other.key.cmp(&self.key),
+            true => self.key.cmp(&other.key),
+        };
+        keys.then(self.timestamp.cmp(&other.timestamp))
+            .then(self.reader.cmp(&other.reader))
+    }
+}
+
+impl PartialOrd for MergeEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 pub struct SegmentReader {
     path: PathBuf,
-    reader: BufReader<File>,
+    reader: BufReader<Take<File>>,
     elements: usize,
+    codec: Codec,
+    version: u8,
+    format: SerializationFormat,
+    coder: KeyCoder,
+    block: VecDeque<Record>,
     pub value: Option<Record>,
+    strict: bool,
 }
 
 impl SegmentReader {
     pub fn new(segment: &Segment) -> crate::Result<Self> {
         trace!("Creating segment reader from {}", segment);
         let path = PathBuf::from(&*segment.segment_path.clone());
-        let mut reader = BufReader::new(File::open(&path)?);
-        let mut size_buffer = 0_usize.to_be_bytes();
-        reader.read_exact(&mut size_buffer)?;
-        let elements = usize::from_be_bytes(size_buffer);
+        // only read up to the end of the records, leaving out the footer
+        let mut reader = BufReader::new(File::open(&path)?.take(*segment.size as u64));
+        // an empty segment has no header to read
+        let header = match *segment.size {
+            0 => SegmentHeader::new(0, Codec::None),
+            _ => SegmentHeader::read(&mut reader)?.0,
+        };
         Ok(Self {
             path,
             reader,
-            elements,
+            elements: header.elements,
+            codec: header.codec,
+            version: header.version,
+            format: segment.format,
+            coder: KeyCoder::new(header.version, segment.format),
+            block: VecDeque::new(),
             value: None,
+            strict: segment.strict,
         })
     }
 
+    /// Create a reader that skips every block before the one `key` would be
+    /// stored in. Records of that block lower than `key` are still read.
+    pub fn starting_at(segment: &Segment, key: &[u8]) -> crate::Result<Self> {
+        let mut reader = Self::new(segment)?;
+        if let Some(hint) = segment.index.search(key) {
+            let mut file = File::open(&reader.path)?;
+            file.seek(SeekFrom::Start(hint.block_start))?;
+            let remaining = (*segment.size as u64).saturating_sub(hint.block_start);
+            reader.reader = BufReader::new(file.take(remaining));
+            // the first key of a block is always written in full
+            reader.coder = KeyCoder::new(reader.version, reader.format);
+        }
+        Ok(reader)
+    }
+
     pub fn next(&mut self) -> crate::Result<()> {
-        if self.value.is_none() && !self.done() {
-            let record = bincode::deserialize_from(&mut self.reader)?;
+        if self.value.is_none() && !self.done()? {
+            let record = match self.codec {
+                Codec::None => self.coder.decode(&mut self.reader)?.0,
+                Codec::Zstd => {
+                    if self.block.is_empty() {
+                        let (records, _) = read_frame(&mut self.reader, self.version, self.format)?;
+                        self.block.extend(records);
+                    }
+                    match self.block.pop_front() {
+                        Some(record) => record,
+                        None => return Ok(()),
+                    }
+                }
+            };
             trace!("Found next {} in {:?}", record, self.path);
             let _ = self.value.insert(record);
         }
         Ok(())
     }
 
-    pub fn done(&mut self) -> bool {
-        self.reader.fill_buf().unwrap().is_empty() && self.value.is_none()
+    /// Read records until one matches the pattern. Corrupt records are
+    /// skipped. Returns `None` once the reader is out of records.
+    pub fn next_match(&mut self, pattern: &PreparedPattern) -> crate::Result<Option<KeyMatch>> {
+        loop {
+            self.next()?;
+            let record = match self.value.take() {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+            if !record.verify(self.strict)? {
+                continue;
+            }
+            if pattern.test(&record.key) {
+                return Ok(Some(KeyMatch::from_record(&record)));
+            }
+        }
+    }
+
+    pub fn done(&mut self) -> crate::Result<bool> {
+        Ok(self.reader.fill_buf()?.is_empty() && self.block.is_empty() && self.value.is_none())
+    }
+}
+
+/// Reads the records of a segment from its largest key to its smallest. The
+/// blocks are found through the hints of the index and read back to front,
+/// one at a time.
+pub struct ReverseSegmentReader {
+    path: PathBuf,
+    codec: Codec,
+    version: u8,
+    format: SerializationFormat,
+    strict: bool,
+    /// Blocks that are left to read, the next one being last
+    blocks: Vec<BlockHint>,
+    /// Records of the block being read, the next one being last
+    block: Vec<Record>,
+}
+
+impl ReverseSegmentReader {
+    /// Create a reader that starts at the last block holding keys lower than
+    /// `end`, or at the last block of the segment when there is no `end`.
+    /// Records of that block that aren't lower than `end` are still read.
+    pub fn ending_before(segment: &Segment, end: Option<&[u8]>) -> Self {
+        trace!("Creating reverse segment reader from {}", segment);
+        let blocks = segment
+            .index
+            .hints
+            .iter()
+            .take_while(|hint| match end {
+                Some(end) => hint.key.as_slice() < end,
+                None => true,
+            })
+            .cloned()
+            .collect();
+        Self {
+            path: PathBuf::from(&*segment.segment_path),
+            codec: segment.codec,
+            version: segment.version,
+            format: segment.format,
+            strict: segment.strict,
+            blocks,
+            block: vec![],
+        }
+    }
+
+    /// Read records until one matches the pattern. Corrupt records are
+    /// skipped. Returns `None` once the reader is out of records.
+    pub fn next_match(&mut self, pattern: &PreparedPattern) -> crate::Result<Option<KeyMatch>> {
+        loop {
+            let record = match self.block.pop() {
+                Some(record) => record,
+                None => match self.blocks.pop() {
+                    Some(hint) => {
+                        self.block =
+                            hint.read_block(&self.path, self.codec, self.version, self.format)?;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+            };
+            if !record.verify(self.strict)? {
+                continue;
+            }
+            if pattern.test(&record.key) {
+                return Ok(Some(KeyMatch::from_record(&record)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{self, BufRead, Read, Write};
+    use std::path::Path;
+
+    use tempfile::TempDir;
+
+    use serde::Serialize;
+
+    use super::{
+        move_file_with, read_frame, read_records, verify_write_ahead_log, BlockHint, Codec, Index,
+        KeyCoder, MemoryTable, Record, ReverseSegmentReader, SSTable, Segment, SegmentHeader,
+        SegmentReader, SEGMENT_VERSION, TRAILER_SIZE, WAL_MAGIC, WAL_VERSION,
+    };
+    use crate::common::now;
+    use crate::datastructures::matcher::prepare;
+    use crate::engines::kvs::config::{Compression, Config, FsyncPolicy};
+    use crate::engines::kvs::format::SerializationFormat;
+    use crate::{KvError, VerifyReport};
+
+    fn records() -> Vec<Record> {
+        (0..10)
+            .map(|i| Record::new(format!("key{:02}", i).into_bytes(), Some(vec![0; 50])))
+            .collect()
+    }
+
+    /// Add records to an index the way a segment writer lays them out
+    fn index_records<'a>(index: &mut Index, records: impl IntoIterator<Item = &'a Record>) {
+        let mut coder = KeyCoder::new(SEGMENT_VERSION, SerializationFormat::Bincode);
+        let mut position = 0;
+        for record in records {
+            let starts_block = index
+                .starts_block(record, SerializationFormat::Bincode)
+                .unwrap();
+            let size = coder.encode(record, starts_block).unwrap().len();
+            index
+                .add(position, record, size as u64, starts_block)
+                .unwrap();
+            position += size;
+        }
+    }
+
+    /// Reader of a disk that fails after handing out `good` bytes
+    struct FlakyReader {
+        good: Vec<u8>,
+        position: usize,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = {
+                let mut available = self.fill_buf()?;
+                available.read(buf)?
+            };
+            self.consume(read);
+            Ok(read)
+        }
+    }
+
+    impl BufRead for FlakyReader {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            if self.position < self.good.len() {
+                Ok(&self.good[self.position..])
+            } else {
+                Err(io::Error::other("disk went away"))
+            }
+        }
+
+        fn consume(&mut self, amount: usize) {
+            self.position += amount;
+        }
+    }
+
+    #[test]
+    fn read_errors_are_returned_not_unwrapped() {
+        let mut good = vec![];
+        for record in records().iter().take(2) {
+            good.extend(bincode::serialize(record).unwrap());
+        }
+        let mut reader = FlakyReader { good, position: 0 };
+        assert!(read_records(&mut reader, 10, 0, SerializationFormat::Bincode).is_err());
+
+        // an empty reader is the end of the records, not an error
+        assert!(read_records(
+            &mut &[][..],
+            10,
+            SEGMENT_VERSION,
+            SerializationFormat::Bincode
+        )
+        .unwrap()
+        .is_empty());
+
+        let mut reader = FlakyReader {
+            good: vec![],
+            position: 0,
+        };
+        assert!(SegmentHeader::read(&mut reader).is_err());
+    }
+
+    #[test]
+    fn block_count_follows_block_size() {
+        let record_size = bincode::serialized_size(&records()[0]).unwrap();
+        let block_count = |block_size: u64| {
+            let mut index = Index::new(10, block_size, 0.001);
+            index_records(&mut index, records().iter());
+            index.hints.len()
+        };
+
+        assert_eq!(block_count(4096), 1);
+        assert_eq!(block_count(record_size * 4), 3);
+        assert_eq!(block_count(record_size * 4 + 1), 3);
+        assert_eq!(block_count(record_size), 10);
+        assert_eq!(block_count(1), 10);
+    }
+
+    #[test]
+    fn oversized_record_gets_its_own_block() {
+        let block_size = bincode::serialized_size(&records()[0]).unwrap() * 3;
+        let large = Record::new(b"key05x".to_vec(), Some(vec![7; block_size as usize * 5]));
+        let large_size = bincode::serialized_size(&large).unwrap();
+        let mut sorted = records();
+        sorted.insert(6, large.clone());
+
+        let mut index = Index::new(11, block_size, 0.001);
+        index_records(&mut index, sorted.iter());
+        let hint = index.get(large.key()).unwrap();
+        assert_eq!(hint.key, large.key);
+        assert_eq!(hint.number_of_elements, 1);
+        // the first key of a block is stored whole after a zero prefix length
+        assert_eq!(hint.block_size, large_size + 2);
+        assert_eq!(index.get(b"key06").unwrap().key, b"key06".to_vec());
+
+        for compression in [Compression::None, Compression::Zstd { level: 3 }].iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let config = Config::builder()
+                .block_size(block_size)
+                .compression(*compression)
+                .build()
+                .with_folder(temp_dir.path());
+            let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+            for record in sorted.iter() {
+                table
+                    .append(record.key.clone(), record.value.clone())
+                    .unwrap();
+            }
+            let segment = table.save(temp_dir.path().join("0.log"), &config).unwrap();
+            let hint = segment.index.get(large.key()).unwrap();
+            assert_eq!((&hint.key, hint.number_of_elements), (&large.key, 1));
+            for record in sorted.iter() {
+                let found = segment.get(&record.key).unwrap().and_then(|r| r.value);
+                assert_eq!(found, record.value);
+            }
+        }
+    }
+
+    #[test]
+    fn absurd_element_count_is_a_clean_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = Config::builder().build().with_folder(temp_dir.path());
+        let path = temp_dir.path().join("0.log");
+        let mut file = File::create(&path).unwrap();
+        SegmentHeader::new(usize::MAX, Codec::None)
+            .write(&mut file)
+            .unwrap();
+        for record in records() {
+            file.write_all(&bincode::serialize(&record).unwrap())
+                .unwrap();
+        }
+        drop(file);
+        match Segment::from_log(&path, &config) {
+            Err(KvError::Corruption { key: None, .. }) => {}
+            other => panic!("expected a corruption error, got {:?}", other.map(|_| ())),
+        }
+
+        // a garbage count doesn't size the bloom filter of an index
+        Index::new(usize::MAX, 4096, 0.001);
+    }
+
+    #[test]
+    fn absurd_frame_length_is_a_clean_error() {
+        // a corrupt length would ask for 4 GiB, but only a few bytes follow
+        let mut bytes = u32::MAX.to_be_bytes().to_vec();
+        bytes.extend(b"not a frame");
+        match read_frame(
+            &mut &bytes[..],
+            SEGMENT_VERSION,
+            SerializationFormat::Bincode,
+        ) {
+            Err(KvError::Corruption { key: None, .. }) => {}
+            other => panic!("expected a corruption error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn block_offset_overflow_is_an_error() {
+        let mut block = BlockHint::new(0);
+        block.init_block(&records()[0], u64::MAX - 10);
+        block.add(10).unwrap();
+        // the block would grow past the end of a u64
+        match block.add(1) {
+            Err(KvError::Corruption { key: None, .. }) => {}
+            other => panic!("expected an overflow, got {:?}", other.map(|_| ())),
+        }
+
+        let mut index = Index::new(10, 4096, 0.001);
+        index.add(0, &records()[0], u64::MAX, true).unwrap();
+        match index.starts_block(&records()[1], SerializationFormat::Bincode) {
+            Err(KvError::Corruption { key: None, .. }) => {}
+            other => panic!("expected an overflow, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn records_decode_in_every_format() {
+        let later = now() + 1_000_000_000_000;
+        let records = [
+            Record::new(b"key1".to_vec(), None),
+            Record::new(b"key2".to_vec(), Some(b"value".to_vec())),
+            Record::expiring(b"key3".to_vec(), Some(vec![200; 20]), Some(later)),
+            Record::new(b"key4".to_vec(), Some(vec![7; 500])).compress_above(Some(0)),
+        ];
+        let mut encodings = vec![];
+        for format in [
+            SerializationFormat::Bincode,
+            SerializationFormat::MessagePack,
+        ]
+        .iter()
+        {
+            for version in [0, SEGMENT_VERSION].iter() {
+                let mut coder = KeyCoder::new(*version, *format);
+                let encoded = records
+                    .iter()
+                    .enumerate()
+                    .map(|(i, record)| coder.encode(record, i == 0).unwrap())
+                    .collect::<Vec<_>>();
+                let bytes = encoded.concat();
+                let mut reader = &bytes[..];
+                let mut coder = KeyCoder::new(*version, *format);
+                for (record, encoded) in records.iter().zip(encoded.iter()) {
+                    let (decoded, size) = coder.decode(&mut reader).unwrap();
+                    assert_eq!(size, encoded.len() as u64, "{:?}", format);
+                    assert_eq!(decoded.key, record.key);
+                    assert_eq!(decoded.value, record.value);
+                    assert_eq!(decoded.expires_at, record.expires_at);
+                    assert!(decoded.verify(true).unwrap());
+                }
+                assert!(reader.is_empty());
+                encodings.push(bytes);
+            }
+        }
+        assert_ne!(encodings[0], encodings[2]);
+    }
+
+    #[test]
+    fn front_coded_keys_round_trip() {
+        let long_prefix = vec![b'p'; u16::MAX as usize + 10];
+        let mut keys = vec![
+            b"".to_vec(),
+            b"a".to_vec(),
+            b"ab".to_vec(),
+            b"abc".to_vec(),
+            b"abd".to_vec(),
+            b"b".to_vec(),
+        ];
+        // keys sharing more than a u16 can count only share part of it
+        keys.push([&long_prefix[..], b"1"].concat());
+        keys.push([&long_prefix[..], b"2"].concat());
+        let sorted = keys
+            .iter()
+            .map(|key| Record::new(key.clone(), Some(key.clone())))
+            .collect::<Vec<_>>();
+
+        for compression in [Compression::None, Compression::Zstd { level: 3 }].iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let config = Config::builder()
+                .block_size(64)
+                .compression(*compression)
+                .build()
+                .with_folder(temp_dir.path());
+            let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+            for record in sorted.iter() {
+                table
+                    .append(record.key.clone(), record.value.clone())
+                    .unwrap();
+            }
+            let path = temp_dir.path().join("0.log");
+            let segment = table.save(&path, &config).unwrap();
+            assert_eq!(segment.version(), SEGMENT_VERSION);
+            for key in keys.iter() {
+                let found = segment.get(key).unwrap().and_then(|r| r.value);
+                assert_eq!(found.as_ref(), Some(key));
+            }
+            let found = segment.find(&prepare(b"*".to_vec())).unwrap();
+            let found = found.into_iter().map(|m| m.key).collect::<Vec<_>>();
+            assert_eq!(found, keys);
+
+            let mut reader = SegmentReader::new(&segment).unwrap();
+            for key in keys.iter() {
+                reader.next().unwrap();
+                assert_eq!(&reader.value.take().unwrap().key, key);
+            }
+            assert!(reader.done().unwrap());
+
+            let scanned = Segment::scan_log(path, &config).unwrap();
+            assert_eq!(
+                format!("{:?}", segment.index.hints),
+                format!("{:?}", scanned.index.hints)
+            );
+        }
+    }
+
+    #[test]
+    fn front_coding_shrinks_prefixed_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = Config::builder().build().with_folder(temp_dir.path());
+        let sorted = (0..100)
+            .map(|i| {
+                let key = format!("users:profile:settings:{:04}", i);
+                Record::new(key.into_bytes(), Some(vec![1; 4]))
+            })
+            .collect::<Vec<_>>();
+        let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        for record in sorted.iter() {
+            table
+                .append(record.key.clone(), record.value.clone())
+                .unwrap();
+        }
+        let segment = table.save(temp_dir.path().join("0.log"), &config).unwrap();
+
+        let plain_size = sorted
+            .iter()
+            .map(|record| bincode::serialized_size(record).unwrap())
+            .sum::<u64>();
+        let coded_size = segment.index.byte_size;
+        assert!(
+            coded_size * 10 < plain_size * 7,
+            "{} bytes front coded, {} bytes plain",
+            coded_size,
+            plain_size
+        );
+        let file_size = std::fs::metadata(segment.path()).unwrap().len();
+        assert!(file_size < plain_size);
+    }
+
+    #[test]
+    fn segments_without_front_coding_are_still_read() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = Config::builder().build().with_folder(temp_dir.path());
+        let path = temp_dir.path().join("0.log");
+        let mut file = File::create(&path).unwrap();
+        let header = SegmentHeader {
+            version: 3,
+            codec: Codec::None,
+            elements: 10,
+        };
+        header.write(&mut file).unwrap();
+        for record in records() {
+            file.write_all(&bincode::serialize(&record).unwrap())
+                .unwrap();
+        }
+        drop(file);
+
+        let segment = Segment::from_log(&path, &config).unwrap();
+        assert_eq!(segment.version(), 3);
+        assert_eq!(segment.len(), 10);
+        for record in records() {
+            let found = segment.get(&record.key).unwrap().and_then(|r| r.value);
+            assert_eq!(found, record.value);
+        }
+        let mut reader = SegmentReader::new(&segment).unwrap();
+        reader.next().unwrap();
+        assert_eq!(reader.value.take().unwrap().key, records()[0].key);
+    }
+
+    #[test]
+    fn get_from_every_block() {
+        let temp_dir = TempDir::new().unwrap();
+        let record_size = bincode::serialized_size(&records()[0]).unwrap();
+        let config = Config::builder()
+            .block_size(record_size * 3)
+            .build()
+            .with_folder(temp_dir.path());
+        let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        for record in records() {
+            table.append(record.key, record.value).unwrap();
+        }
+        let segment = table.save(temp_dir.path().join("0.log"), &config).unwrap();
+
+        assert_eq!(segment.index.hints.len(), 4);
+        for record in records() {
+            let found = segment.get(&record.key).unwrap().and_then(|r| r.value);
+            assert_eq!(found, record.value);
+        }
+        assert!(segment.get(b"key").unwrap().is_none());
+        assert!(segment.get(b"key99").unwrap().is_none());
+    }
+
+    #[test]
+    fn reader_starts_at_the_block_holding_the_key() {
+        for compression in [Compression::None, Compression::Zstd { level: 3 }].iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let record_size = bincode::serialized_size(&records()[0]).unwrap();
+            let config = Config::builder()
+                .block_size(record_size * 3)
+                .compression(*compression)
+                .build()
+                .with_folder(temp_dir.path());
+            let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+            for record in records() {
+                table.append(record.key, record.value).unwrap();
+            }
+            let segment = table.save(temp_dir.path().join("0.log"), &config).unwrap();
+
+            let keys_from = |key: &[u8]| {
+                let mut reader = SegmentReader::starting_at(&segment, key).unwrap();
+                let mut keys = vec![];
+                while let Some(found) = reader.next_match(&prepare(b"*".to_vec())).unwrap() {
+                    keys.push(String::from_utf8(found.key().to_vec()).unwrap());
+                }
+                keys
+            };
+            assert!(segment.index.hints.len() > 1);
+            let mut skipped = 0;
+            for hint in segment.index.hints.iter() {
+                // a key inside of the block reads from the start of the block
+                let mut inside = hint.key.clone();
+                inside.push(b'a');
+                let keys = keys_from(&inside);
+                assert_eq!(keys[0].as_bytes(), hint.key.as_slice());
+                assert_eq!(keys.len(), 10 - skipped);
+                skipped += hint.number_of_elements;
+            }
+            assert_eq!(keys_from(b"key").len(), 10);
+        }
+    }
+
+    #[test]
+    fn reverse_reader_reads_blocks_back_to_front() {
+        for compression in [Compression::None, Compression::Zstd { level: 3 }].iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let record_size = bincode::serialized_size(&records()[0]).unwrap();
+            let config = Config::builder()
+                .block_size(record_size * 3)
+                .compression(*compression)
+                .build()
+                .with_folder(temp_dir.path());
+            let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+            for record in records() {
+                table.append(record.key, record.value).unwrap();
+            }
+            let segment = table.save(temp_dir.path().join("0.log"), &config).unwrap();
+
+            let keys_before = |end: Option<&[u8]>| {
+                let mut reader = ReverseSegmentReader::ending_before(&segment, end);
+                let mut keys = vec![];
+                while let Some(found) = reader.next_match(&prepare(b"*".to_vec())).unwrap() {
+                    keys.push(found.key().to_vec());
+                }
+                keys
+            };
+            let mut expected = records().into_iter().map(|r| r.key).collect::<Vec<_>>();
+            expected.reverse();
+            assert_eq!(keys_before(None), expected);
+            assert!(keys_before(Some(b"key")).is_empty());
+            // the reader starts at the end of the block holding the last
+            // lower key
+            let last = segment.index.hints.last().unwrap();
+            let before_last = keys_before(Some(&last.key));
+            assert_eq!(before_last, expected[last.number_of_elements..]);
+        }
+    }
+
+    #[test]
+    fn footer_index_matches_scanned_index() {
+        for compression in [Compression::None, Compression::Zstd { level: 3 }].iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let record_size = bincode::serialized_size(&records()[0]).unwrap();
+            let config = Config::builder()
+                .block_size(record_size * 3)
+                .compression(*compression)
+                .build()
+                .with_folder(temp_dir.path());
+            let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+            for record in records() {
+                table.append(record.key, record.value).unwrap();
+            }
+            let path = temp_dir.path().join("0.log");
+            table.save(&path, &config).unwrap();
+
+            let loaded = Segment::from_footer(&path, &config).unwrap().unwrap();
+            let scanned = Segment::scan_log(path.clone(), &config).unwrap();
+            assert_eq!(loaded.len(), scanned.len());
+            assert_eq!(*loaded.size, *scanned.size);
+            assert_eq!(
+                format!("{:?}", loaded.index.hints),
+                format!("{:?}", scanned.index.hints)
+            );
+            assert!(loaded.index.max_timestamp.is_some());
+            assert_eq!(loaded.index.max_timestamp, scanned.index.max_timestamp);
+            let mut keys = records().into_iter().map(|r| r.key).collect::<Vec<_>>();
+            keys.extend(vec![b"key".to_vec(), b"key99".to_vec()]);
+            for key in keys {
+                let found = loaded.get(&key).unwrap().and_then(|r| r.value);
+                assert_eq!(found, scanned.get(&key).unwrap().and_then(|r| r.value));
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_lookups_skip_the_segment_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = Config::builder().build().with_folder(temp_dir.path());
+        let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        for record in records() {
+            table.append(record.key, record.value).unwrap();
+        }
+        let path = temp_dir.path().join("0.log");
+        table.save(&path, &config).unwrap();
+        let segment = Segment::from_log(&path, &config).unwrap();
+        assert_eq!(segment.index.min_key, Some(b"key00".to_vec()));
+        assert_eq!(segment.index.max_key, Some(b"key09".to_vec()));
+
+        // any lookup that reads the segment now fails to open its file
+        std::fs::remove_file(&path).unwrap();
+        assert!(segment.get(b"key10").unwrap().is_none());
+        assert!(segment.get(b"a").unwrap().is_none());
+        assert!(segment
+            .find(&prepare(b"value*".to_vec()))
+            .unwrap()
+            .is_empty());
+        assert!(segment
+            .find(&prepare(b"key1*".to_vec()))
+            .unwrap()
+            .is_empty());
+        assert!(segment.find(&prepare(b"ke*".to_vec())).is_err());
+        assert!(segment.find(&prepare(b"*".to_vec())).is_err());
+    }
+
+    #[test]
+    fn merge_keeps_newest_record_of_every_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = Config::builder().build().with_folder(temp_dir.path());
+        let mut segments = vec![];
+        for generation in 0..4 {
+            let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+            // every generation writes a different but overlapping set of keys
+            for i in (generation..20).step_by(generation + 1) {
+                let value = format!("{}-{}", i, generation).into_bytes();
+                table
+                    .append(format!("key{:02}", i).into_bytes(), Some(value))
+                    .unwrap();
+            }
+            let path = temp_dir.path().join(format!("{}.log", generation));
+            segments.push(table.save(path, &config).unwrap());
+        }
+
+        let readers = segments
+            .iter()
+            .map(|segment| SegmentReader::new(segment).unwrap())
+            .collect();
+        let merged =
+            Segment::from_segments(temp_dir.path().join("merged.log"), readers, &config).unwrap();
+        assert_eq!(merged.len(), 20);
+
+        let mut reader = SegmentReader::new(&merged).unwrap();
+        let mut keys = vec![];
+        loop {
+            reader.next().unwrap();
+            let record = match reader.value.take() {
+                Some(record) => record,
+                None => break,
+            };
+            let i = keys.len();
+            let newest = (0..4).rev().find(|g| i >= *g && (i - g) % (g + 1) == 0);
+            let expected = format!("{}-{}", i, newest.unwrap()).into_bytes();
+            assert_eq!(record.value, Some(expected));
+            keys.push(record.key);
+        }
+        let expected_keys = (0..20)
+            .map(|i| format!("key{:02}", i).into_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected_keys);
+    }
+
+    #[test]
+    fn merge_index_holds_one_hint_per_block() {
+        let temp_dir = TempDir::new().unwrap();
+        let record = Record::new(b"key00000".to_vec(), Some(vec![0; 100]));
+        let record_size = bincode::serialized_size(&record).unwrap();
+        let block_size = record_size * 16;
+        let config = Config::builder()
+            .block_size(block_size)
+            .build()
+            .with_folder(temp_dir.path());
+        let mut segments = vec![];
+        for generation in 0..4 {
+            let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+            for i in (generation..20_000).step_by(4) {
+                let key = format!("key{:05}", i).into_bytes();
+                table.append(key, Some(vec![0; 100])).unwrap();
+            }
+            let path = temp_dir.path().join(format!("{}.log", generation));
+            segments.push(table.save(path, &config).unwrap());
+        }
+
+        let readers = segments
+            .iter()
+            .map(|segment| SegmentReader::new(segment).unwrap())
+            .collect();
+        let merged =
+            Segment::from_segments(temp_dir.path().join("merged.log"), readers, &config).unwrap();
+        assert_eq!(merged.len(), 20_000);
+        // every block but the last is filled until the next record, written
+        // in full, wouldn't fit
+        let (_, full_blocks) = merged.index.hints.split_last().unwrap();
+        for hint in full_blocks {
+            assert!(hint.block_size <= block_size);
+            assert!(hint.block_size + record_size > block_size);
+        }
+        let index_size = merged.index.hints.iter().map(|h| h.size()).sum::<usize>();
+        assert!((index_size as u64) < merged.index.byte_size / 50);
+
+        // the footer written without copying the hints reads back the same
+        let loaded = Segment::from_footer(merged.path(), &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", loaded.index.hints),
+            format!("{:?}", merged.index.hints)
+        );
+    }
+
+    #[test]
+    fn merged_records_are_written_without_copies() {
+        for compression in [Compression::None, Compression::Zstd { level: 3 }].iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let record_size = bincode::serialized_size(&records()[0]).unwrap();
+            let config = Config::builder()
+                .block_size(record_size * 3)
+                .compression(*compression)
+                .build()
+                .with_folder(temp_dir.path());
+            let mut segments = vec![];
+            for generation in 0..2_u8 {
+                let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+                for record in records().into_iter().skip(generation as usize) {
+                    table
+                        .append(record.key, Some(vec![generation; 50]))
+                        .unwrap();
+                }
+                let path = temp_dir.path().join(format!("{}.log", generation));
+                segments.push(table.save(path, &config).unwrap());
+            }
+            let readers = segments
+                .iter()
+                .map(|segment| SegmentReader::new(segment).unwrap())
+                .collect();
+            let path = temp_dir.path().join("merged.log");
+            let merged = Segment::from_segments(&path, readers, &config).unwrap();
+
+            // the newest value of every key is kept, and the index built
+            // from borrowed records matches one rebuilt from the file
+            assert_eq!(merged.len(), 10);
+            for (i, record) in records().into_iter().enumerate() {
+                let newest = if i == 0 { 0 } else { 1 };
+                let found = merged.get(&record.key).unwrap().and_then(|r| r.value);
+                assert_eq!(found, Some(vec![newest; 50]));
+            }
+            let scanned = Segment::scan_log(path.clone(), &config).unwrap();
+            assert_eq!(scanned.len(), merged.len());
+            assert_eq!(
+                format!("{:?}", scanned.index.hints),
+                format!("{:?}", merged.index.hints)
+            );
+        }
+    }
+
+    #[test]
+    fn second_read_is_served_from_block_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = Config::builder().build().with_folder(temp_dir.path());
+        let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        for record in records() {
+            table.append(record.key, record.value).unwrap();
+        }
+        let path = temp_dir.path().join("0.log");
+        let segment = table.save(&path, &config).unwrap();
+        let cache = config.block_cache();
+
+        assert_eq!(segment.get(b"key03").unwrap().unwrap().key(), b"key03");
+        assert_eq!((cache.hits(), cache.misses()), (0, 1));
+
+        // the file can't be opened anymore, so the block must come from memory
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(segment.get(b"key03").unwrap().unwrap().key(), b"key03");
+        assert_eq!(segment.get(b"key05").unwrap().unwrap().key(), b"key05");
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+
+        // without a cache every read goes to the file
+        let config = Config::builder()
+            .block_cache_size(0)
+            .build()
+            .with_folder(temp_dir.path());
+        let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        table
+            .append(b"key".to_vec(), Some(b"value".to_vec()))
+            .unwrap();
+        let path = temp_dir.path().join("1.log");
+        let segment = table.save(&path, &config).unwrap();
+        assert!(segment.get(b"key").unwrap().is_some());
+        std::fs::remove_file(&path).unwrap();
+        assert!(segment.get(b"key").is_err());
+    }
+
+    #[test]
+    fn corrupt_footer_falls_back_to_scan() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = Config::builder().build().with_folder(temp_dir.path());
+        let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        for record in records() {
+            table.append(record.key, record.value).unwrap();
+        }
+        let path = temp_dir.path().join("0.log");
+        table.save(&path, &config).unwrap();
+
+        // flip a byte of the footer, leaving the size of the file unchanged
+        let mut bytes = std::fs::read(&path).unwrap();
+        let footer_byte = bytes.len() - TRAILER_SIZE as usize - 1;
+        bytes[footer_byte] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(Segment::from_footer(&path, &config).unwrap().is_none());
+        let segment = Segment::from_log(&path, &config).unwrap();
+        assert_eq!(segment.len(), records().len());
+        for record in records() {
+            let found = segment.get(&record.key).unwrap().and_then(|r| r.value);
+            assert_eq!(found, record.value);
+        }
+    }
+
+    #[test]
+    fn every_n_batches_syncs() {
+        let temp_dir = TempDir::new().unwrap();
+        let table = SSTable::new(temp_dir.path(), FsyncPolicy::EveryN(3)).unwrap();
+        for i in 0..2 {
+            table
+                .append(format!("key{}", i).into_bytes(), None)
+                .unwrap();
+        }
+        assert_eq!(table.unsynced_writes(), 2);
+        table.append(b"key2".to_vec(), None).unwrap();
+        assert_eq!(table.unsynced_writes(), 0);
+        table.append(b"key3".to_vec(), None).unwrap();
+        assert_eq!(table.unsynced_writes(), 1);
+    }
+
+    #[test]
+    fn always_and_never_syncs() {
+        let temp_dir = TempDir::new().unwrap();
+        let always = SSTable::new(temp_dir.path(), FsyncPolicy::Always).unwrap();
+        always.append(b"key".to_vec(), None).unwrap();
+        assert_eq!(always.unsynced_writes(), 0);
+
+        let never = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        never.append(b"key".to_vec(), None).unwrap();
+        assert_eq!(never.unsynced_writes(), 1);
+        never.sync().unwrap();
+        assert_eq!(never.unsynced_writes(), 0);
+    }
+
+    #[test]
+    fn concurrent_appends_share_syncs() {
+        let temp_dir = TempDir::new().unwrap();
+        let table = SSTable::new(temp_dir.path(), FsyncPolicy::Always).unwrap();
+        let appenders = (0..16)
+            .map(|thread| {
+                let table = table.clone();
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        let key = format!("key{}-{}", thread, i).into_bytes();
+                        table.append(key, Some(vec![0; 10])).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for appender in appenders {
+            appender.join().unwrap();
+        }
+
+        let appends = 16 * 25;
+        assert_eq!(table.len(), appends);
+        assert_eq!(table.unsynced_writes(), 0);
+        assert!(
+            table.syncs() < appends / 2,
+            "{} syncs for {} appends",
+            table.syncs(),
+            appends
+        );
+        // every append reached the log
+        let log = &table.write_ahead_log_path;
+        let (records, complete) =
+            MemoryTable::read_write_ahead_log(log, SerializationFormat::Bincode, true).unwrap();
+        assert!(complete);
+        assert_eq!(records.len(), appends);
+    }
+
+    #[test]
+    fn durable_appends_sync_whatever_the_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        table.append(b"key1".to_vec(), Some(vec![0; 10])).unwrap();
+        assert_eq!(table.syncs(), 0);
+        assert_eq!(table.unsynced_writes(), 1);
+
+        let record = Record::new(b"key2".to_vec(), Some(vec![0; 10]));
+        table.append_records_durable(vec![record]).unwrap();
+        assert_eq!(table.syncs(), 1);
+        assert_eq!(table.unsynced_writes(), 0);
+    }
+
+    fn redo_logs(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some("redo".as_ref()))
+            .count()
+    }
+
+    #[test]
+    fn higher_fp_rate_makes_smaller_filters() {
+        let save = |fp_rate: f64| {
+            let temp_dir = TempDir::new().unwrap();
+            let config = Config::builder()
+                .bloom_fp_rate(fp_rate)
+                .build()
+                .with_folder(temp_dir.path());
+            let table = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+            for i in 0..1000 {
+                table
+                    .append(format!("key{}", i).into_bytes(), Some(b"value".to_vec()))
+                    .unwrap();
+            }
+            let segment = table.save(temp_dir.path().join("0.log"), &config).unwrap();
+            // a bloom filter never turns away a key it holds
+            for i in 0..1000 {
+                let key = format!("key{}", i).into_bytes();
+                assert!(segment.index.filter.contains(&key));
+                assert!(segment.get(&key).unwrap().is_some(), "{} lost", i);
+            }
+            let bits = segment.index.filter.optimal_m();
+
+            // the rate is kept when the segment is opened again
+            drop(segment);
+            let segment = Segment::from_log(temp_dir.path().join("0.log"), &config).unwrap();
+            assert_eq!(segment.index.filter.optimal_m(), bits);
+            bits
+        };
+        let precise = save(0.001);
+        let small = save(0.1);
+        assert!(
+            small * 2 < precise,
+            "{} bits at 0.1, {} bits at 0.001",
+            small,
+            precise
+        );
+    }
+
+    #[test]
+    fn drop_removes_redo_log_once_saved() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = Config::builder().build().with_folder(temp_dir.path());
+
+        let saved = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        saved
+            .append(b"key".to_vec(), Some(b"value".to_vec()))
+            .unwrap();
+        let segment = saved.save(temp_dir.path().join("0.log"), &config).unwrap();
+        let unsaved = SSTable::new(temp_dir.path(), FsyncPolicy::Never).unwrap();
+        unsaved
+            .append(b"key".to_vec(), Some(b"new".to_vec()))
+            .unwrap();
+        assert_eq!(redo_logs(temp_dir.path()), 2);
+
+        drop(saved);
+        assert_eq!(redo_logs(temp_dir.path()), 1);
+        drop(unsaved);
+        assert_eq!(redo_logs(temp_dir.path()), 1);
+        let found = segment.get(b"key").unwrap().and_then(|r| r.value);
+        assert_eq!(found, Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn legacy_write_ahead_log_is_rewritten_with_a_header() {
+        let temp_dir = TempDir::new().unwrap();
+        let legacy = temp_dir.path().join("legacy.redo");
+        let mut file = File::create(&legacy).unwrap();
+        for i in 0..5 {
+            let record = Record::new(format!("key{}", i).into_bytes(), Some(b"value".to_vec()));
+            file.write_all(&bincode::serialize(&record).unwrap())
+                .unwrap();
+        }
+        drop(file);
+
+        let table = SSTable::from_write_ahead_logs(
+            temp_dir.path(),
+            &[legacy],
+            FsyncPolicy::Never,
+            SerializationFormat::Bincode,
+            false,
+        )
+        .unwrap();
+        assert_eq!(table.len(), 5);
+        // the rewritten log holds the key and value bytes of every record
+        assert_eq!(table.log_size(), 5 * b"key0value".len());
+        table
+            .append(b"key5".to_vec(), Some(b"value".to_vec()))
+            .unwrap();
+
+        let bytes = std::fs::read(&table.write_ahead_log_path).unwrap();
+        assert_eq!(&bytes[..4], WAL_MAGIC);
+        assert_eq!(bytes[4], WAL_VERSION);
+        let mut report = VerifyReport::default();
+        verify_write_ahead_log(
+            &table.write_ahead_log_path,
+            SerializationFormat::Bincode,
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(report.good, 6);
+
+        // a log written by a newer version is refused instead of misread
+        let newer = temp_dir.path().join("newer.redo");
+        std::fs::write(&newer, [&WAL_MAGIC[..], &[WAL_VERSION + 1]].concat()).unwrap();
+        let mut report = VerifyReport::default();
+        assert!(verify_write_ahead_log(&newer, SerializationFormat::Bincode, &mut report).is_err());
+    }
+
+    #[test]
+    fn records_without_expiry_keep_their_layout() {
+        /// How records were written before they could expire
+        #[derive(Serialize)]
+        struct LegacyRecord {
+            crc: u32,
+            timestamp: u128,
+            key: Vec<u8>,
+            value: Option<Vec<u8>>,
+        }
+
+        for value in [Some(b"value".to_vec()), None] {
+            let record = Record::new(b"key".to_vec(), value.clone());
+            let legacy = LegacyRecord {
+                crc: record.crc,
+                timestamp: record.timestamp,
+                key: b"key".to_vec(),
+                value,
+            };
+            let bytes = bincode::serialize(&legacy).unwrap();
+            assert_eq!(bincode::serialize(&record).unwrap(), bytes);
+
+            let loaded: Record = bincode::deserialize(&bytes).unwrap();
+            assert_eq!(loaded.crc, loaded.calculate_crc());
+            assert_eq!(loaded.value, record.value);
+            assert_eq!(loaded.expires_at, None);
+        }
+    }
+
+    #[test]
+    fn expiring_record_round_trip() {
+        let expires_at = now() + 1_000_000_000;
+        let record = Record::expiring(b"key".to_vec(), Some(b"value".to_vec()), Some(expires_at));
+        let mut bytes = bincode::serialize(&record).unwrap();
+        bytes.extend(bincode::serialize(&Record::new(b"next".to_vec(), None)).unwrap());
+
+        let mut reader = &bytes[..];
+        let loaded: Record = bincode::deserialize_from(&mut reader).unwrap();
+        assert_eq!(loaded.expires_at, Some(expires_at));
+        assert_eq!(loaded.crc, loaded.calculate_crc());
+        assert!(!loaded.is_expired(now()));
+        assert!(loaded.is_expired(expires_at));
+        let next: Record = bincode::deserialize_from(&mut reader).unwrap();
+        assert_eq!(next.key, b"next".to_vec());
+        assert!(reader.is_empty());
+
+        // changing the expiry must break the checksum
+        let mut tampered = loaded;
+        tampered.expires_at = Some(expires_at + 1);
+        assert_ne!(tampered.crc, tampered.calculate_crc());
+    }
+
+    #[test]
+    fn only_large_values_are_compressed() {
+        let expires_at = now() + 1_000_000_000;
+        let small = vec![b'a'; 64];
+        let large = vec![b'a'; 4096];
+        for expires_at in [None, Some(expires_at)] {
+            let record = |value: &[u8]| {
+                Record::expiring(b"key".to_vec(), Some(value.to_vec()), expires_at)
+                    .compress_above(Some(64))
+            };
+
+            let stored = record(&small);
+            assert!(!stored.is_compressed());
+            let bytes = bincode::serialize(&stored).unwrap();
+            assert!(bytes.len() > small.len());
+            let loaded: Record = bincode::deserialize(&bytes).unwrap();
+            assert!(!loaded.is_compressed());
+            assert_eq!(loaded.value, Some(small.clone()));
+
+            let stored = record(&large);
+            assert!(stored.is_compressed());
+            let bytes = bincode::serialize(&stored).unwrap();
+            assert!(bytes.len() < large.len() / 10, "{} bytes", bytes.len());
+            let loaded: Record = bincode::deserialize(&bytes).unwrap();
+            assert!(loaded.is_compressed());
+            assert!(loaded.verify(true).unwrap());
+            assert_eq!(loaded.value, Some(large.clone()));
+            assert_eq!(loaded.expires_at, expires_at);
+            // a record read back is written the same way again
+            assert_eq!(bincode::serialize(&loaded).unwrap(), bytes);
+        }
+        let tombstone = Record::new(b"key".to_vec(), None).compress_above(Some(0));
+        assert!(!tombstone.is_compressed());
+    }
+
+    #[test]
+    fn undecodable_compressed_value_fails_its_checksum() {
+        let record = Record::new(b"key".to_vec(), Some(vec![b'a'; 4096])).compress_above(Some(64));
+        let mut bytes = bincode::serialize(&record).unwrap();
+        // flip a byte of the zstd frame header
+        let header = bytes.len() - record.compressed_value().unwrap().unwrap().len();
+        bytes[header] ^= 0xff;
+        let loaded: Record = bincode::deserialize(&bytes).unwrap();
+        assert!(!loaded.verify(false).unwrap());
+        assert!(loaded.verify(true).is_err());
+    }
+
+    #[test]
+    fn move_across_filesystems_copies_the_file() {
+        let from_dir = TempDir::new().unwrap();
+        let to_dir = TempDir::new().unwrap();
+        let (from, to) = (from_dir.path().join("0.log"), to_dir.path().join("0.log"));
+        std::fs::write(&from, b"segment").unwrap();
+
+        move_file_with(&from, &to, |_, _| {
+            Err(io::Error::from(io::ErrorKind::CrossesDevices))
+        })
+        .unwrap();
+        assert!(!from.exists());
+        assert_eq!(std::fs::read(&to).unwrap(), b"segment");
+
+        // any other failure to rename is returned and the file stays put
+        let result = move_file_with(&to, &from, |_, _| {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(to.exists() && !from.exists());
     }
 }
@@ -0,0 +1,244 @@
+use std::{
+    sync::{
+        mpsc::{channel, Sender},
+        Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use crate::{GenericError, KvError};
+
+use super::sstable::Record;
+
+/// Largest number of records committed together as one batch
+const MAX_BATCH_SIZE: usize = 1024;
+
+/// A record waiting to be committed, along with the channel its result is
+/// sent back on
+struct Queued {
+    record: Record,
+    durable: bool,
+    ack: Sender<crate::Result<()>>,
+}
+
+/// Writer owns the single background thread that commits the writes of a
+/// store. Records queued while a batch is being committed are committed
+/// together as the next batch, so concurrent writers share one write, and
+/// one sync, of the write-ahead-log instead of each paying for their own.
+pub struct Writer {
+    sender: Option<Mutex<Sender<Queued>>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Writer {
+    /// Start the background thread, running `commit` on every batch of
+    /// queued records in the order they were queued. `commit` is told if any
+    /// record of the batch has to be synced to disk before it returns.
+    pub fn new(
+        commit: impl Fn(Vec<Record>, bool) -> crate::Result<()> + Send + 'static,
+    ) -> crate::Result<Self> {
+        let (sender, receiver) = channel::<Queued>();
+        let worker = std::thread::Builder::new()
+            .name("kvs-writer".into())
+            .spawn(move || {
+                while let Ok(first) = receiver.recv() {
+                    let mut batch = vec![first];
+                    while batch.len() < MAX_BATCH_SIZE {
+                        match receiver.try_recv() {
+                            Ok(queued) => batch.push(queued),
+                            Err(_) => break,
+                        }
+                    }
+                    trace!("Committing batch of {} records", batch.len());
+                    let durable = batch.iter().any(|queued| queued.durable);
+                    let (records, acks): (Vec<_>, Vec<_>) =
+                        batch.into_iter().map(|q| (q.record, q.ack)).unzip();
+                    let result = commit(records, durable);
+                    for ack in acks {
+                        let result = match &result {
+                            Ok(()) => Ok(()),
+                            Err(e) => Err(e.duplicate()),
+                        };
+                        // the caller only stops waiting if its thread is gone
+                        let _ = ack.send(result);
+                    }
+                }
+                debug!("Writer is shutting down");
+            })?;
+        Ok(Self {
+            sender: Some(Mutex::new(sender)),
+            worker: Some(worker),
+        })
+    }
+
+    /// Queue a record and wait until the batch holding it has been
+    /// committed. A `durable` record is synced to disk along with the rest
+    /// of its batch.
+    pub fn write(&self, record: Record, durable: bool) -> crate::Result<()> {
+        let (ack, result) = channel();
+        let queued = Queued {
+            record,
+            durable,
+            ack,
+        };
+        let sent = match self.sender.as_ref() {
+            Some(sender) => sender.lock().unwrap().send(queued).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(stopped());
+        }
+        result.recv().unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+fn stopped() -> KvError {
+    KvError::StringError(GenericError::new("Writer has stopped"))
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // closing the channel lets the worker commit what is queued and exit
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("Writer thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use super::Writer;
+    use crate::engines::kvs::sstable::Record;
+
+    const THREADS: usize = 8;
+    const WRITES_PER_THREAD: usize = 25;
+
+    /// Stand in for a write-ahead-log where every commit pays for a sync
+    fn slow_commit(commits: &AtomicUsize, records: &Mutex<Vec<Record>>, batch: Vec<Record>) {
+        std::thread::sleep(Duration::from_millis(2));
+        commits.fetch_add(1, Ordering::SeqCst);
+        records.lock().unwrap().extend(batch);
+    }
+
+    /// Write from many threads at once, returning how long it took
+    fn write_concurrently(write: impl Fn(Record) + Send + Sync + 'static) -> Duration {
+        let write = Arc::new(write);
+        let start = Instant::now();
+        let writers = (0..THREADS)
+            .map(|thread| {
+                let write = write.clone();
+                std::thread::spawn(move || {
+                    for i in 0..WRITES_PER_THREAD {
+                        let key = format!("key{}-{}", thread, i).into_bytes();
+                        write(Record::new(key, Some(vec![0; 10])));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        start.elapsed()
+    }
+
+    #[test]
+    fn grouped_commits_beat_commit_per_write() {
+        let writes = THREADS * WRITES_PER_THREAD;
+
+        // every write commits on its own, one after the other
+        let commits = Arc::new(AtomicUsize::new(0));
+        let records = Arc::new(Mutex::new(vec![]));
+        let log = Arc::new(Mutex::new(()));
+        let per_write = {
+            let (commits, records) = (commits.clone(), records.clone());
+            write_concurrently(move |record| {
+                let _log = log.lock().unwrap();
+                slow_commit(&commits, &records, vec![record]);
+            })
+        };
+        assert_eq!(commits.load(Ordering::SeqCst), writes);
+
+        // writes queued behind a commit are committed together
+        let commits = Arc::new(AtomicUsize::new(0));
+        let records = Arc::new(Mutex::new(vec![]));
+        let writer = {
+            let (commits, records) = (commits.clone(), records.clone());
+            Writer::new(move |batch, _| {
+                slow_commit(&commits, &records, batch);
+                Ok(())
+            })
+            .unwrap()
+        };
+        let writer = Arc::new(writer);
+        let grouped = {
+            let writer = writer.clone();
+            write_concurrently(move |record| writer.write(record, false).unwrap())
+        };
+        // every write was committed before it was acknowledged
+        assert_eq!(records.lock().unwrap().len(), writes);
+        let batches = commits.load(Ordering::SeqCst);
+        assert!(
+            batches < writes / 2,
+            "{} batches for {} writes",
+            batches,
+            writes
+        );
+        assert!(
+            grouped < per_write,
+            "grouped took {:?}, per write took {:?}",
+            grouped,
+            per_write
+        );
+    }
+
+    #[test]
+    fn failed_commit_is_returned_to_every_writer() {
+        let writer = Arc::new(Writer::new(|_, _| Err(crate::KvError::ReadOnly)).unwrap());
+        let writers = (0..THREADS)
+            .map(|thread| {
+                let writer = writer.clone();
+                std::thread::spawn(move || {
+                    let key = format!("key{}", thread).into_bytes();
+                    writer.write(Record::new(key, None), false)
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in writers {
+            match handle.join().unwrap() {
+                Err(crate::KvError::ReadOnly) => {}
+                other => panic!("expected a read only error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn durable_record_makes_its_batch_durable() {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let writer = {
+            let batches = batches.clone();
+            Writer::new(move |batch, durable| {
+                batches.lock().unwrap().push((batch.len(), durable));
+                Ok(())
+            })
+            .unwrap()
+        };
+        writer
+            .write(Record::new(b"key1".to_vec(), None), false)
+            .unwrap();
+        writer
+            .write(Record::new(b"key2".to_vec(), None), true)
+            .unwrap();
+        assert_eq!(*batches.lock().unwrap(), vec![(1, false), (1, true)]);
+    }
+}
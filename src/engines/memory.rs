@@ -1,14 +1,89 @@
 use std::{
-    collections::BTreeMap,
-    sync::{Arc, RwLock},
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{mpsc::Receiver, Arc, Mutex, RwLock},
+    time::Duration,
 };
 
-use crate::{datastructures::matcher::prepare, KvsEngine};
+use crate::{
+    common::now,
+    datastructures::matcher::{prepare_with, PreparedPattern},
+    engines::Subscribers,
+    GenericError, KeyIter, KvError, KvsEngine, MatchOptions, Notification, StoreStats,
+};
+
+const SNAPSHOT_FILE_NAME: &str = "memory.snapshot";
+
+/// When each key set with a time to live expires, in nanoseconds since the
+/// unix epoch
+type Expiries = HashMap<Vec<u8>, u128>;
+
+/// The keys and values loaded from a snapshot, along with their expiries
+type Snapshot = (BTreeMap<Vec<u8>, Vec<u8>>, Expiries);
 
 /// Key value store that keeps all data in memory
 #[derive(Clone)]
 pub struct KvInMemoryStore {
     map: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+    /// Always locked after `map`, so the two are never out of step
+    expiries: Arc<RwLock<Expiries>>,
+    lru: Option<Arc<Mutex<Lru>>>,
+    subscribers: Subscribers,
+}
+
+/// Access order of the keys in a bounded store. Every access is stamped
+/// with an increasing tick, so the smallest tick is the least recently
+/// used key.
+struct Lru {
+    capacity: usize,
+    tick: u64,
+    ticks: HashMap<Vec<u8>, u64>,
+    order: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Mark `key` as the most recently used key
+    fn touch(&mut self, key: &[u8]) {
+        self.tick += 1;
+        if let Some(old) = self.ticks.insert(key.to_vec(), self.tick) {
+            self.order.remove(&old);
+        }
+        self.order.insert(self.tick, key.to_vec());
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(old) = self.ticks.remove(key) {
+            self.order.remove(&old);
+        }
+    }
+
+    /// Pop the least recently used key if the store is over capacity
+    fn evict(&mut self) -> Option<Vec<u8>> {
+        if self.ticks.len() <= self.capacity {
+            return None;
+        }
+        let (&tick, _) = self.order.iter().next()?;
+        let key = self.order.remove(&tick)?;
+        self.ticks.remove(&key);
+        Some(key)
+    }
+
+    fn clear(&mut self) {
+        self.ticks.clear();
+        self.order.clear();
+    }
 }
 
 impl KvInMemoryStore {
@@ -16,8 +91,178 @@ impl KvInMemoryStore {
     pub fn new() -> Self {
         Self {
             map: Arc::new(RwLock::new(BTreeMap::new())),
+            expiries: Arc::new(RwLock::new(HashMap::new())),
+            lru: None,
+            subscribers: Subscribers::default(),
+        }
+    }
+
+    /// Create an in memory key value store that holds at most `max_entries`
+    /// keys. Once full, setting a new key evicts the least recently used one.
+    /// Both `get` and `set` count as a use. A store with a capacity of zero
+    /// keeps no keys at all.
+    pub fn with_capacity(max_entries: usize) -> Self {
+        Self {
+            map: Arc::new(RwLock::new(BTreeMap::new())),
+            expiries: Arc::new(RwLock::new(HashMap::new())),
+            lru: Some(Arc::new(Mutex::new(Lru::new(max_entries)))),
+            subscribers: Subscribers::default(),
+        }
+    }
+
+    /// Write every key and value in the store to a snapshot file inside
+    /// `folder`, followed by when the keys with a time to live expire. The
+    /// snapshot is written to a temporary file first and then renamed, so a
+    /// crash never leaves a half written snapshot behind.
+    /// [`KvsEngine::restore`] loads it back.
+    pub fn snapshot(&self, folder: impl Into<PathBuf>) -> crate::Result<()> {
+        let folder = folder.into();
+        if folder.as_os_str().is_empty() {
+            return Err(KvError::StringError(GenericError::new(
+                "Can not snapshot to an empty path",
+            )));
+        }
+        fs::create_dir_all(&folder)?;
+        let path = folder.join(SNAPSHOT_FILE_NAME);
+        let temp = path.with_extension("snapshot.tmp");
+        {
+            let map = self.map.read().unwrap();
+            let expiries = self.expiries.read().unwrap();
+            let mut writer = BufWriter::new(File::create(&temp)?);
+            bincode::serialize_into(&mut writer, &*map)?;
+            bincode::serialize_into(&mut writer, &*expiries)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&temp, &path)?;
+        Ok(())
+    }
+
+    /// Insert a value into the locked map, evicting the least recently used
+    /// keys if the store is over capacity. The key expires at `expires_at`,
+    /// or never if it is `None`. Returns the live value the key held.
+    fn insert(
+        &self,
+        map: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u128>,
+    ) -> Option<Vec<u8>> {
+        let mut expiries = self.expiries.write().unwrap();
+        self.subscribers.notify(&key, Some(&value));
+        let expired = is_expired(&expiries, &key, now());
+        match expires_at {
+            Some(expires_at) => expiries.insert(key.clone(), expires_at),
+            None => expiries.remove(&key),
+        };
+        let previous = map.insert(key.clone(), value).filter(|_| !expired);
+        // the key is in the map before it is touched, so a store with no
+        // capacity evicts it straight away instead of losing track of it
+        if let Some(lru) = &self.lru {
+            let mut lru = lru.lock().unwrap();
+            lru.touch(&key);
+            while let Some(evicted) = lru.evict() {
+                map.remove(&evicted);
+                expiries.remove(&evicted);
+            }
+        }
+        previous
+    }
+
+    /// The live value of a key in the locked map
+    fn live_value<'a>(
+        &self,
+        map: &'a BTreeMap<Vec<u8>, Vec<u8>>,
+        key: &[u8],
+    ) -> Option<&'a Vec<u8>> {
+        let expiries = self.expiries.read().unwrap();
+        map.get(key).filter(|_| !is_expired(&expiries, key, now()))
+    }
+
+    /// Remove the keys of the locked map whose time to live has passed
+    fn purge_expired(&self, map: &mut BTreeMap<Vec<u8>, Vec<u8>>) {
+        let mut expiries = self.expiries.write().unwrap();
+        let now = now();
+        let expired = expiries
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            self.forget(map, &mut expiries, &key);
+        }
+    }
+
+    /// Remove `key` if its time to live has passed by the time the map can
+    /// be locked for writing
+    fn purge_if_expired(&self, key: &[u8]) {
+        let mut map = self.map.write().unwrap();
+        let mut expiries = self.expiries.write().unwrap();
+        if is_expired(&expiries, key, now()) {
+            self.forget(&mut map, &mut expiries, key);
         }
     }
+
+    fn forget(&self, map: &mut BTreeMap<Vec<u8>, Vec<u8>>, expiries: &mut Expiries, key: &[u8]) {
+        map.remove(key);
+        expiries.remove(key);
+        if let Some(lru) = &self.lru {
+            lru.lock().unwrap().remove(key);
+        }
+    }
+
+    fn load_snapshot(folder: &Path) -> crate::Result<Snapshot> {
+        let path = folder.join(SNAPSHOT_FILE_NAME);
+        if folder.as_os_str().is_empty() || !path.is_file() {
+            return Ok((BTreeMap::new(), HashMap::new()));
+        }
+        let mut reader = BufReader::new(File::open(path)?);
+        let map = bincode::deserialize_from(&mut reader)?;
+        // snapshots taken before keys could expire end after the map
+        let expiries = match reader.fill_buf()? {
+            [] => HashMap::new(),
+            _ => bincode::deserialize_from(&mut reader)?,
+        };
+        Ok((map, expiries))
+    }
+}
+
+/// Whether `key` was set with a time to live that has passed by `now`
+fn is_expired(expiries: &Expiries, key: &[u8], now: u128) -> bool {
+    expiries.get(key).map(|at| *at <= now).unwrap_or(false)
+}
+
+/// Walks the keys of the store matching a pattern, skipping expired keys.
+/// The map is only locked while looking for the next key, so writers aren't
+/// blocked for as long as the iterator is alive.
+struct FindIter<'a> {
+    map: &'a RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+    expiries: &'a RwLock<Expiries>,
+    pattern: PreparedPattern,
+    prefix: Vec<u8>,
+    last: Option<Vec<u8>>,
+}
+
+impl<'a> Iterator for FindIter<'a> {
+    type Item = crate::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let map = self.map.read().unwrap();
+        let expiries = self.expiries.read().unwrap();
+        let now = now();
+        let start = match &self.last {
+            Some(last) => Bound::Excluded(last),
+            None => Bound::Included(&self.prefix),
+        };
+        let found = map
+            .range::<Vec<u8>, _>((start, Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(&self.prefix))
+            .find(|key| self.pattern.test(key) && !is_expired(&expiries, key, now))?
+            .clone();
+        self.last = Some(found.clone());
+        Some(Ok(found))
+    }
 }
 
 impl Default for KvInMemoryStore {
@@ -27,47 +272,207 @@ impl Default for KvInMemoryStore {
 }
 
 impl KvsEngine for KvInMemoryStore {
-    fn restore(_: impl Into<std::path::PathBuf>) -> crate::Result<Self>
+    fn restore(folder: impl Into<PathBuf>) -> crate::Result<Self>
     where
         Self: Sized,
     {
+        let (map, expiries) = Self::load_snapshot(&folder.into())?;
         Ok(Self {
-            map: Arc::new(RwLock::new(Default::default())),
+            map: Arc::new(RwLock::new(map)),
+            expiries: Arc::new(RwLock::new(expiries)),
+            lru: None,
+            subscribers: Subscribers::default(),
         })
     }
 
     fn set(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<()> {
-        self.map.write().unwrap().insert(key, value);
+        let mut map = self.map.write().unwrap();
+        self.insert(&mut map, key, value, None);
         Ok(())
     }
 
-    fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
-        Ok(self.map.read().unwrap().get(key).map(Clone::clone))
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> crate::Result<()> {
+        let mut map = self.map.write().unwrap();
+        let expires_at = now() + ttl.as_nanos();
+        self.insert(&mut map, key, value, Some(expires_at));
+        Ok(())
     }
 
-    fn find(&self, like: Vec<u8>) -> crate::Result<Vec<Vec<u8>>> {
-        let mut keys = vec![];
-        let tester = prepare(like);
-        let read = self.map.read().unwrap();
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<bool> {
+        let mut map = self.map.write().unwrap();
+        if self.live_value(&map, &key).is_some() {
+            return Ok(false);
+        }
+        self.insert(&mut map, key, value, None);
+        Ok(true)
+    }
 
-        for key in read.keys() {
-            if tester.test(key) {
-                keys.push(key.to_vec());
-            }
+    fn get_and_set(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<Option<Vec<u8>>> {
+        let mut map = self.map.write().unwrap();
+        Ok(self.insert(&mut map, key, value, None))
+    }
+
+    fn append(&self, key: Vec<u8>, suffix: &[u8]) -> crate::Result<usize> {
+        let mut map = self.map.write().unwrap();
+        let mut value = self.live_value(&map, &key).cloned().unwrap_or_default();
+        value.extend_from_slice(suffix);
+        let len = value.len();
+        self.insert(&mut map, key, value, None);
+        Ok(len)
+    }
+
+    fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        let map = self.map.read().unwrap();
+        let value = self.live_value(&map, key).cloned();
+        let expired = value.is_none() && map.contains_key(key);
+        // touched while the map is still locked, so a concurrent remove
+        // can't leave the key behind in the access order
+        if let (Some(lru), Some(_)) = (&self.lru, &value) {
+            lru.lock().unwrap().touch(key);
         }
+        drop(map);
+        if expired {
+            self.purge_if_expired(key);
+        }
+        Ok(value)
+    }
 
-        Ok(keys)
+    fn find_iter_with(&self, like: Vec<u8>, options: MatchOptions) -> crate::Result<KeyIter<'_>> {
+        let pattern = prepare_with(like, options);
+        Ok(Box::new(FindIter {
+            map: &self.map,
+            expiries: &self.expiries,
+            prefix: pattern.literal_prefix(),
+            pattern,
+            last: None,
+        }))
+    }
+
+    fn count(&self, like: Vec<u8>) -> crate::Result<usize> {
+        let pattern = prepare_with(like, MatchOptions::default());
+        let prefix = pattern.literal_prefix();
+        let map = self.map.read().unwrap();
+        let expiries = self.expiries.read().unwrap();
+        let now = now();
+        Ok(map
+            .range::<Vec<u8>, _>((Bound::Included(&prefix), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(&prefix))
+            .filter(|key| pattern.test(key) && !is_expired(&expiries, key, now))
+            .count())
     }
 
     fn remove(&self, key: Vec<u8>) -> crate::Result<()> {
-        let _ = self.map.write().unwrap().remove(&key);
+        let mut map = self.map.write().unwrap();
+        if let Some(lru) = &self.lru {
+            lru.lock().unwrap().remove(&key);
+        }
+        let _ = map.remove(&key);
+        self.expiries.write().unwrap().remove(&key);
+        self.subscribers.notify(&key, None);
+        Ok(())
+    }
+
+    fn clear(&self) -> crate::Result<()> {
+        let mut map = self.map.write().unwrap();
+        if let Some(lru) = &self.lru {
+            lru.lock().unwrap().clear();
+        }
+        map.clear();
+        self.expiries.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn stats(&self) -> crate::Result<StoreStats> {
+        let mut map = self.map.write().unwrap();
+        self.purge_expired(&mut map);
+        Ok(StoreStats {
+            keys: map.len(),
+            ..Default::default()
+        })
+    }
+
+    fn compact(&self) -> crate::Result<()> {
+        self.purge_expired(&mut self.map.write().unwrap());
         Ok(())
     }
+
+    fn subscribe(&self, like: Vec<u8>) -> crate::Result<Receiver<Notification>> {
+        Ok(self.subscribers.subscribe(like))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{KvInMemoryStore, KvsEngine};
+    use std::time::Duration;
+
+    use crate::{KvInMemoryStore, KvsEngine, MatchOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn count_matches_find() {
+        let kv = KvInMemoryStore::new();
+        for key in ["session:1", "session:2", "session:10", "sessions", "user:1"].iter() {
+            kv.set(key.as_bytes().to_vec(), b"value".to_vec()).unwrap();
+        }
+        kv.remove(b"session:2".to_vec()).unwrap();
+        for pattern in ["session:*", "*:1", "*", "user:?", "nothing*"].iter() {
+            let pattern = pattern.as_bytes().to_vec();
+            assert_eq!(
+                kv.count(pattern.clone()).unwrap(),
+                kv.find(pattern).unwrap().len()
+            );
+        }
+        assert_eq!(kv.count(b"session*".to_vec()).unwrap(), 3);
+    }
+
+    #[test]
+    fn expired_keys_are_not_found_or_counted() {
+        let kv = KvInMemoryStore::new();
+        kv.set(b"session:1".to_vec(), b"value".to_vec()).unwrap();
+        kv.set_with_ttl(
+            b"session:2".to_vec(),
+            b"value".to_vec(),
+            Duration::from_millis(50),
+        )
+        .unwrap();
+        assert_eq!(kv.count(b"session:*".to_vec()).unwrap(), 2);
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(kv.stats().unwrap().keys, 1);
+        assert!(kv.expiries.read().unwrap().is_empty());
+        assert_eq!(
+            kv.find(b"session:*".to_vec()).unwrap(),
+            vec![b"session:1".to_vec()]
+        );
+        assert_eq!(kv.count(b"session:*".to_vec()).unwrap(), 1);
+
+        // setting the key again without a ttl keeps it for good
+        assert!(kv
+            .set_if_absent(b"session:2".to_vec(), b"again".to_vec())
+            .unwrap());
+        assert_eq!(kv.count(b"session:*".to_vec()).unwrap(), 2);
+    }
+
+    #[test]
+    fn snapshot_keeps_expiry() {
+        let temp_dir = TempDir::new().unwrap();
+        let kv = KvInMemoryStore::new();
+        kv.set(b"kept".to_vec(), b"value".to_vec()).unwrap();
+        kv.set_with_ttl(b"gone".to_vec(), b"value".to_vec(), Duration::from_secs(0))
+            .unwrap();
+        kv.snapshot(temp_dir.path()).unwrap();
+
+        let restored = KvInMemoryStore::restore(temp_dir.path()).unwrap();
+        assert!(restored.map.read().unwrap().contains_key(b"gone".as_ref()));
+        // reading an expired key removes it
+        assert_eq!(restored.get(b"gone").unwrap(), None);
+        assert!(!restored.map.read().unwrap().contains_key(b"gone".as_ref()));
+        assert_eq!(
+            restored.find(b"*".to_vec()).unwrap(),
+            vec![b"kept".to_vec()]
+        );
+    }
 
     #[test]
     fn find_keys() {
@@ -86,4 +491,114 @@ mod tests {
         let keys = kv.find(b"th*".to_vec()).unwrap();
         assert_eq!(keys, test_keys);
     }
+
+    #[test]
+    fn find_keys_ignoring_case() {
+        let kv = KvInMemoryStore::new();
+        kv.set(b"cat".to_vec(), b"meow".to_vec()).unwrap();
+        assert!(kv.find(b"CAT".to_vec()).unwrap().is_empty());
+        let options = MatchOptions { ignore_case: true };
+        let keys = kv.find_with(b"CAT".to_vec(), options).unwrap();
+        assert_eq!(keys, vec![b"cat".to_vec()]);
+    }
+
+    #[test]
+    fn find_iter_reads_keys_lazily() {
+        let kv = KvInMemoryStore::new();
+        for index in 0..1000 {
+            kv.set(format!("key{:03}", index).into_bytes(), b"value".to_vec())
+                .unwrap();
+        }
+        let mut keys = kv.find_iter(b"key*".to_vec()).unwrap();
+        let first = keys.by_ref().take(5).collect::<crate::Result<Vec<_>>>();
+        let expected = (0..5)
+            .map(|index| format!("key{:03}", index).into_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(first.unwrap(), expected);
+
+        // the store can be written to while the iterator is alive, and keys
+        // ahead of the iterator are still found
+        kv.set(b"key0045".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(keys.next().unwrap().unwrap(), b"key0045".to_vec());
+        assert_eq!(keys.next().unwrap().unwrap(), b"key005".to_vec());
+    }
+
+    #[test]
+    fn evicts_least_recently_set_key() {
+        let kv = KvInMemoryStore::with_capacity(3);
+        for key in &["a", "b", "c", "d"] {
+            kv.set(key.as_bytes().to_vec(), b"value".to_vec()).unwrap();
+        }
+        assert_eq!(kv.get(b"a").unwrap(), None);
+        for key in &["b", "c", "d"] {
+            assert!(kv.get(key.as_bytes()).unwrap().is_some());
+        }
+        assert_eq!(kv.stats().unwrap().keys, 3);
+    }
+
+    #[test]
+    fn get_protects_key_from_eviction() {
+        let kv = KvInMemoryStore::with_capacity(3);
+        for key in &["a", "b", "c"] {
+            kv.set(key.as_bytes().to_vec(), b"value".to_vec()).unwrap();
+        }
+        assert!(kv.get(b"a").unwrap().is_some());
+        kv.set(b"d".to_vec(), b"value".to_vec()).unwrap();
+        assert!(kv.get(b"a").unwrap().is_some());
+        assert_eq!(kv.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_no_keys() {
+        let kv = KvInMemoryStore::with_capacity(0);
+        kv.set(b"a".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(kv.get(b"a").unwrap(), None);
+        assert_eq!(kv.stats().unwrap().keys, 0);
+        assert!(kv.lru.as_ref().unwrap().lock().unwrap().ticks.is_empty());
+    }
+
+    #[test]
+    fn removed_key_leaves_the_access_order() {
+        let kv = KvInMemoryStore::with_capacity(2);
+        kv.set(b"a".to_vec(), b"value".to_vec()).unwrap();
+        kv.set(b"b".to_vec(), b"value".to_vec()).unwrap();
+        assert!(kv.get(b"a").unwrap().is_some());
+        kv.remove(b"a".to_vec()).unwrap();
+        kv.set(b"c".to_vec(), b"value".to_vec()).unwrap();
+        // only two keys are live, so nothing is evicted
+        assert!(kv.get(b"b").unwrap().is_some());
+        assert!(kv.get(b"c").unwrap().is_some());
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let temp_dir = TempDir::new().unwrap();
+        let kv = KvInMemoryStore::new();
+        for index in 0..100 {
+            kv.set(
+                format!("key{}", index).into_bytes(),
+                format!("value{}", index).into_bytes(),
+            )
+            .unwrap();
+        }
+        kv.snapshot(temp_dir.path()).unwrap();
+
+        let restored = KvInMemoryStore::restore(temp_dir.path()).unwrap();
+        for index in 0..100 {
+            assert_eq!(
+                restored.get(format!("key{}", index).as_bytes()).unwrap(),
+                Some(format!("value{}", index).into_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn restore_without_snapshot_is_empty() {
+        let temp_dir = TempDir::new().unwrap();
+        let kv = KvInMemoryStore::restore(temp_dir.path()).unwrap();
+        assert_eq!(kv.stats().unwrap().keys, 0);
+        let kv = KvInMemoryStore::restore("").unwrap();
+        assert_eq!(kv.stats().unwrap().keys, 0);
+        assert!(kv.snapshot("").is_err());
+    }
 }
@@ -1,17 +1,87 @@
 //! This module provides various key value storage engines
 //!
 
-use std::path::PathBuf;
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+    sync::mpsc::Receiver,
+    time::Duration,
+};
 
-use crate::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::{read_whole_entry, write_entry},
+    datastructures::matcher::escape,
+    GenericError, KvError, MatchOptions, Result,
+};
+
+/// Number of bytes of entries [`migrate`] collects before handing them to
+/// the destination engine
+const MIGRATE_BATCH_SIZE: usize = 64 * 1024;
+
+/// Statistics describing how many keys a store holds and how much space it
+/// takes up on disk.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StoreStats {
+    /// Number of keys held by the store. Keys that were overwritten may be
+    /// counted more than once until they are compacted.
+    pub keys: usize,
+    /// Number of segments inside of each level, starting at the first level
+    pub segments_per_level: Vec<usize>,
+    /// Total number of bytes used by the store on disk
+    pub disk_size: u64,
+    /// Number of bytes used by the write-ahead-logs
+    pub wal_size: u64,
+}
+
+/// Result of checking every record of a store against its checksum,
+/// returned by [`KvsEngine::verify`]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    /// Number of records that match their checksum
+    pub good: usize,
+    /// Key of every record that doesn't match its checksum
+    pub corrupt: Vec<Vec<u8>>,
+}
+
+/// Details of the stored value of a key, returned by
+/// [`KvsEngine::get_with_metadata`]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordMeta {
+    /// Time in nanoseconds since the unix epoch the value was written at.
+    /// Engines that don't keep track of it report 0.
+    pub timestamp: u128,
+    /// Number of bytes in the value
+    pub value_size: usize,
+}
+
+/// Lazy stream of keys returned by [`KvsEngine::find_iter`]
+pub type KeyIter<'a> = Box<dyn Iterator<Item = Result<Vec<u8>>> + 'a>;
+
+/// Lazy stream of keys and their values returned by [`KvsEngine::iter`]
+pub type EntryIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
 
 /// Trait for a key value storage engine
 pub trait KvsEngine: Clone + Send + Sync {
     /// Build a Kvstore from a database folder
+    ///
+    /// `restore` is the name every engine implements. [`KvsEngine::open`]
+    /// is kept as an alias for code written against the older name.
     fn restore(folder: impl Into<PathBuf>) -> Result<Self>
     where
         Self: Sized;
 
+    /// Build a Kvstore from a database folder, the same as
+    /// [`KvsEngine::restore`]
+    #[deprecated(since = "0.1.0", note = "use `KvsEngine::restore` instead")]
+    fn open(folder: impl Into<PathBuf>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::restore(folder)
+    }
+
     /// Sets the value of a string key to a string.
     /// If the key already exists, the previous value will be overwritten.
     ///
@@ -20,6 +90,53 @@ pub trait KvsEngine: Clone + Send + Sync {
     /// Returns an error if the value is not written successfully
     fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
 
+    /// Sets the value of a key only if the key doesn't exist yet. Checking
+    /// the key and writing the value happen atomically, so when many callers
+    /// race to set the same key exactly one of them succeeds. Returns whether
+    /// the value was written.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key could not be read or the value is not
+    /// written successfully
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool>;
+
+    /// Sets the value of a key and returns the value it held before, or
+    /// `None` if it didn't exist. Reading the old value and writing the new
+    /// one happen atomically, so every caller sees the value written by the
+    /// caller before it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key could not be read or the value is not
+    /// written successfully
+    fn get_and_set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Adds `suffix` to the end of the value of a key, treating a missing key
+    /// as an empty value. The value is read and written atomically, so
+    /// concurrent appends never overwrite each other. Returns the length of
+    /// the new value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key could not be read or the value is not
+    /// written successfully
+    fn append(&self, key: Vec<u8>, suffix: &[u8]) -> Result<usize>;
+
+    /// Sets the value of a key that expires once `ttl` has passed. An
+    /// expired key reads as if it was never set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value is not written successfully or the
+    /// engine doesn't support expiring keys
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let _ = (key, value, ttl);
+        Err(KvError::StringError(GenericError::new(
+            "Engine does not support keys with a time to live",
+        )))
+    }
+
     /// Gets the string value of a given string key.
     /// Returns `None` if the given key does not exist.
     ///
@@ -28,6 +145,32 @@ pub trait KvsEngine: Clone + Send + Sync {
     /// Return an error if the value is not read successfullly
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
+    /// Gets the value of a key along with when it was written and its size.
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the value is not read successfullly
+    fn get_with_metadata(&self, key: &[u8]) -> Result<Option<(Vec<u8>, RecordMeta)>> {
+        Ok(self.get(key)?.map(|value| {
+            let meta = RecordMeta {
+                timestamp: 0,
+                value_size: value.len(),
+            };
+            (value, meta)
+        }))
+    }
+
+    /// Gets the value of a key, or `default` if the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the value is not read successfullly. A key that
+    /// can't be read is never mistaken for a missing one.
+    fn get_or(&self, key: &[u8], default: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
     /// Removes a given key.
     ///
     /// # Errors
@@ -35,12 +178,285 @@ pub trait KvsEngine: Clone + Send + Sync {
     /// Return an error if the key does not exist or value failed to be read
     fn remove(&self, key: Vec<u8>) -> Result<()>;
 
-    /// Find a collection of key values.
+    /// Remove every live key in `[start, end)`. Keys are found with a sorted
+    /// scan, so the scan stops at the first key past the range. Keys set
+    /// while the range is being deleted may be left behind. Returns the
+    /// number of keys that were removed.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the keys could not be read or removed
+    fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<usize> {
+        remove_keys(self, keys_in_range(self, start, Some(end))?)
+    }
+
+    /// Remove every live key starting with `prefix`. The keys are found with
+    /// the same sorted scan as [`KvsEngine::delete_range`], over
+    /// `[prefix, successor)` where the successor is the first key after every
+    /// key with the prefix. An empty prefix removes every key. Returns the
+    /// number of keys that were removed.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the keys could not be read or removed
+    fn delete_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        let end = prefix_successor(prefix);
+        remove_keys(self, keys_in_range(self, prefix, end.as_deref())?)
+    }
+
+    /// Find a collection of key values. Keys are returned once each, in
+    /// sorted byte order. Removed and expired keys aren't found.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if we failed to complete the read of the keys
+    fn find(&self, like: Vec<u8>) -> Result<Vec<Vec<u8>>> {
+        self.find_with(like, MatchOptions::default())
+    }
+
+    /// Find a collection of key values, matching the pattern using the given
+    /// options.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if we failed to complete the read of the keys
+    fn find_with(&self, like: Vec<u8>, options: MatchOptions) -> Result<Vec<Vec<u8>>> {
+        self.find_iter_with(like, options)?.collect()
+    }
+
+    /// Find one page of the keys matching a pattern. Keys are sorted, the
+    /// first `offset` of them are skipped and at most `limit` are returned,
+    /// so the same page is returned as long as the keys don't change. Keys
+    /// after the page are never read.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if we failed to complete the read of the keys
+    fn find_paged(
+        &self,
+        like: Vec<u8>,
+        options: MatchOptions,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.find_iter_with(like, options)?
+            .skip(offset)
+            .take(limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// Find the keys matching a pattern one at a time. Keys are read as the
+    /// iterator is advanced, so a broad pattern doesn't have to be held in
+    /// memory all at once.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the search could not be started. Errors reading a
+    /// key are returned by the iterator.
+    fn find_iter(&self, like: Vec<u8>) -> Result<KeyIter<'_>> {
+        self.find_iter_with(like, MatchOptions::default())
+    }
+
+    /// Find the keys matching a pattern one at a time, matching the pattern
+    /// using the given options.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the search could not be started. Errors reading a
+    /// key are returned by the iterator.
+    fn find_iter_with(&self, like: Vec<u8>, options: MatchOptions) -> Result<KeyIter<'_>>;
+
+    /// Count the keys matching a pattern. Every key is counted once and
+    /// removed or expired keys aren't counted, the same as `find`, but the
+    /// keys are never collected.
     ///
     /// # Errors
     ///
     /// Return an error if we failed to complete the read of the keys
-    fn find(&self, like: Vec<u8>) -> Result<Vec<Vec<u8>>>;
+    fn count(&self, like: Vec<u8>) -> Result<usize> {
+        self.find_iter(like)?
+            .try_fold(0, |count, key| key.map(|_| count + 1))
+    }
+
+    /// Walk every live key of the store along with its newest value, in key
+    /// order. Removed and expired keys are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the walk could not be started. Errors reading a
+    /// key or value are returned by the iterator.
+    fn iter(&self) -> Result<EntryIter<'_>> {
+        let keys = self.find_iter(b"*".to_vec())?;
+        Ok(Box::new(keys.filter_map(move |key| {
+            let key = match key {
+                Ok(key) => key,
+                Err(e) => return Some(Err(e)),
+            };
+            // the key may have been removed after it was found
+            self.get(&key)
+                .transpose()
+                .map(|value| value.map(|value| (key, value)))
+        })))
+    }
+
+    /// Write every live key and value of the store to `writer`. Each key and
+    /// value is preceded by its length as a 4 byte big endian integer.
+    /// [`KvsEngine::import`] reads the dump back.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the store could not be read or the dump could not
+    /// be written
+    fn export(&self, mut writer: impl Write) -> Result<()> {
+        for entry in self.iter()? {
+            let (key, value) = entry?;
+            write_entry(&mut writer, &key, &value)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Set every key and value of a dump written by [`KvsEngine::export`].
+    /// Returns the number of keys that were set. A truncated entry at the end
+    /// of the dump is skipped, so every whole entry before it is still set.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the dump could not be read or a value could not be
+    /// set
+    fn import(&self, mut reader: impl Read) -> Result<usize> {
+        let mut imported = 0;
+        while let Some((key, value)) = read_whole_entry(&mut reader)? {
+            self.set(key, value)?;
+            imported += 1;
+        }
+        Ok(imported)
+    }
+
+    /// Remove every key from the store, leaving it empty.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the stored data could not be removed
+    fn clear(&self) -> Result<()>;
+
+    /// Collect statistics about the keys stored and the space used on disk.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the size of the store could not be read
+    fn stats(&self) -> Result<StoreStats>;
+
+    /// Reclaim the space used by overwritten values and removed keys.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the stored data could not be rewritten
+    fn compact(&self) -> Result<()>;
+
+    /// Check every record the engine keeps on disk against its checksum, so
+    /// a damaged disk is found before the damaged values are read. Engines
+    /// that don't keep a checksum for every record report nothing.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the stored data could not be read
+    fn verify(&self) -> Result<VerifyReport> {
+        Ok(VerifyReport::default())
+    }
+
+    /// Watch every key matching a pattern. A [`Notification`] is sent on the
+    /// returned channel whenever a matching key is set or removed. Drop the
+    /// receiver to stop watching.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the engine doesn't support subscriptions
+    fn subscribe(&self, like: Vec<u8>) -> Result<Receiver<Notification>> {
+        let _ = like;
+        Err(KvError::StringError(GenericError::new(
+            "Engine does not support subscriptions",
+        )))
+    }
+}
+
+/// Copy every live key and value of `src` into `dst`, so data can be moved
+/// from one engine to another. The entries are handed to
+/// [`KvsEngine::import`] of `dst` in batches, letting engines that load a
+/// dump faster than they take single writes do so. Returns the number of keys
+/// that were copied.
+///
+/// # Errors
+///
+/// Return an error if `src` could not be read or `dst` could not be written.
+/// Batches written before the error stay in `dst`.
+pub fn migrate<S: KvsEngine, D: KvsEngine>(src: &S, dst: &D) -> Result<usize> {
+    let mut migrated = 0;
+    let mut batch = vec![];
+    let mut entries = src.iter()?;
+    loop {
+        let entry = entries.next().transpose()?;
+        let done = entry.is_none();
+        if let Some((key, value)) = entry {
+            write_entry(&mut batch, &key, &value)?;
+        }
+        if !batch.is_empty() && (done || batch.len() >= MIGRATE_BATCH_SIZE) {
+            migrated += dst.import(&std::mem::take(&mut batch)[..])?;
+        }
+        if done {
+            return Ok(migrated);
+        }
+    }
+}
+
+/// Collect every live key of `engine` in `[start, end)`, in sorted order. A
+/// missing `end` collects every key from `start` on. Only keys sharing the
+/// longest prefix of `start` and `end` are scanned.
+pub(crate) fn keys_in_range<E: KvsEngine>(
+    engine: &E,
+    start: &[u8],
+    end: Option<&[u8]>,
+) -> Result<Vec<Vec<u8>>> {
+    let shared = match end {
+        Some(end) if start >= end => return Ok(vec![]),
+        Some(end) => start
+            .iter()
+            .zip(end.iter())
+            .take_while(|(a, b)| a == b)
+            .count(),
+        None => 0,
+    };
+    let mut keys = vec![];
+    for key in engine.find_iter(escape(&start[..shared], b"*"))? {
+        let key = key?;
+        if end.map(|end| key.as_slice() >= end).unwrap_or(false) {
+            break;
+        }
+        if key.as_slice() >= start {
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+/// The smallest key that is larger than every key starting with `prefix`.
+/// Trailing `0xFF` bytes can't be incremented, so they are dropped before the
+/// last byte is incremented. Returns `None` if no such key exists, which is
+/// the case for an empty prefix or one made only of `0xFF` bytes.
+pub(crate) fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|byte| *byte != u8::MAX)?;
+    let mut successor = prefix[..=last].to_vec();
+    successor[last] += 1;
+    Some(successor)
+}
+
+/// Remove each key one at a time, returning the number of keys removed
+fn remove_keys<E: KvsEngine>(engine: &E, keys: Vec<Vec<u8>>) -> Result<usize> {
+    let removed = keys.len();
+    for key in keys {
+        engine.remove(key)?;
+    }
+    Ok(removed)
 }
 
 /// kvs is this libraries implementation of a key value store
@@ -49,9 +465,21 @@ pub mod kvs;
 /// kvs store that keeps all data in a library
 pub mod memory;
 
+/// change notifications sent to the subscribers of an engine
+mod notify;
+
 /// sled is a already implemented library in rust
 pub mod sled;
 
-pub use self::kvs::KvStore;
+/// named keyspaces that share a single engine
+pub mod tree;
+
+pub use self::kvs::{
+    Compression, Config, ConfigBuilder, FsyncPolicy, KvStore, SerializationFormat, Snapshot,
+    StoreIter,
+};
 pub use self::memory::KvInMemoryStore;
+pub use self::notify::Notification;
+pub(crate) use self::notify::Subscribers;
 pub use self::sled::SledKvsEngine;
+pub use self::tree::Tree;
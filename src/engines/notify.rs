@@ -0,0 +1,69 @@
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc, Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    datastructures::matcher::{prepare_with, PreparedPattern},
+    MatchOptions,
+};
+
+/// A change made to a key watched by [`KvsEngine::subscribe`]
+///
+/// [`KvsEngine::subscribe`]: crate::KvsEngine::subscribe
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    /// Key that was changed
+    #[serde(with = "crate::common::base64_bytes")]
+    pub key: Vec<u8>,
+    /// New value of the key, or `None` if the key was removed
+    #[serde(with = "crate::common::base64_bytes::option")]
+    pub value: Option<Vec<u8>>,
+}
+
+/// Channel of a subscriber along with the pattern of the keys it watches
+type Subscriber = (PreparedPattern, Sender<Notification>);
+
+/// Channels of everyone watching the keys of a store. A subscriber is
+/// forgotten the next time a change is sent after it drops its receiver.
+#[derive(Clone, Default)]
+pub(crate) struct Subscribers {
+    inner: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Subscribers {
+    /// Watch every key matching the pattern `like`
+    pub fn subscribe(&self, like: Vec<u8>) -> Receiver<Notification> {
+        let (sender, receiver) = mpsc::channel();
+        self.inner
+            .lock()
+            .unwrap()
+            .push((prepare_with(like, MatchOptions::default()), sender));
+        receiver
+    }
+
+    /// Whether nobody is watching the store
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().unwrap().is_empty()
+    }
+
+    /// Tell every subscriber watching `key` about its new value
+    pub fn notify(&self, key: &[u8], value: Option<&[u8]>) {
+        let mut subscribers = self.inner.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        subscribers.retain(|(pattern, sender)| {
+            if !pattern.test(key) {
+                return true;
+            }
+            let notification = Notification {
+                key: key.to_vec(),
+                value: value.map(<[u8]>::to_vec),
+            };
+            sender.send(notification).is_ok()
+        });
+    }
+}
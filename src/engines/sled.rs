@@ -1,7 +1,7 @@
 use std::path::PathBuf;
 
-use super::KvsEngine;
-use crate::{GenericError, KvError, Result};
+use super::{KeyIter, KvsEngine, StoreStats};
+use crate::{datastructures::matcher::prepare_with, GenericError, KvError, MatchOptions, Result};
 use sled::{open, Db, Tree};
 
 /// Implementation of Sled Key Value Store
@@ -20,6 +20,35 @@ impl KvsEngine for SledKvsEngine {
         Ok(())
     }
 
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        let tree: &Tree = &self.0;
+        let swapped = tree.compare_and_swap(key, None as Option<&[u8]>, Some(value))?;
+        if swapped.is_err() {
+            return Ok(false);
+        }
+        tree.flush()?;
+        Ok(true)
+    }
+
+    fn get_and_set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let tree: &Tree = &self.0;
+        let previous = tree.insert(key, value)?;
+        tree.flush()?;
+        Ok(previous.map(|inner| inner.to_vec()))
+    }
+
+    fn append(&self, key: Vec<u8>, suffix: &[u8]) -> Result<usize> {
+        let tree: &Tree = &self.0;
+        // sled retries the update if the key changes under it
+        let value = tree.update_and_fetch(key, |previous| {
+            let mut value = previous.map(<[u8]>::to_vec).unwrap_or_default();
+            value.extend_from_slice(suffix);
+            Some(value)
+        })?;
+        tree.flush()?;
+        Ok(value.map_or(0, |value| value.len()))
+    }
+
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         let tree: &Tree = &self.0;
         let value = tree.get(key)?;
@@ -29,8 +58,15 @@ impl KvsEngine for SledKvsEngine {
         // .transpose()
     }
 
-    fn find(&self, _like: Vec<u8>) -> Result<Vec<Vec<u8>>> {
-        todo!()
+    fn find_iter_with(&self, like: Vec<u8>, options: MatchOptions) -> Result<KeyIter<'_>> {
+        let pattern = prepare_with(like, options);
+        Ok(Box::new(self.0.iter().keys().filter_map(
+            move |key| match key {
+                Ok(key) if pattern.test(&key) => Some(Ok(key.to_vec())),
+                Ok(_) => None,
+                Err(e) => Some(Err(e.into())),
+            },
+        )))
     }
 
     fn remove(&self, key: Vec<u8>) -> Result<()> {
@@ -42,4 +78,53 @@ impl KvsEngine for SledKvsEngine {
         tree.flush()?;
         Ok(())
     }
+
+    fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<usize> {
+        if start >= end {
+            return Ok(0);
+        }
+        let tree: &Tree = &self.0;
+        let mut removed = 0;
+        for key in tree.range(start..end).keys() {
+            if tree.remove(key?)?.is_some() {
+                removed += 1;
+            }
+        }
+        tree.flush()?;
+        Ok(removed)
+    }
+
+    fn delete_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        let tree: &Tree = &self.0;
+        let mut removed = 0;
+        for key in tree.scan_prefix(prefix).keys() {
+            if tree.remove(key?)?.is_some() {
+                removed += 1;
+            }
+        }
+        tree.flush()?;
+        Ok(removed)
+    }
+
+    fn clear(&self) -> Result<()> {
+        let tree: &Tree = &self.0;
+        tree.clear()?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats {
+            keys: self.0.len(),
+            segments_per_level: vec![],
+            disk_size: self.0.size_on_disk()?,
+            wal_size: 0,
+        })
+    }
+
+    fn compact(&self) -> Result<()> {
+        // sled reclaims space on its own, flushing lets it write out its logs
+        self.0.flush()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,263 @@
+use std::convert::TryInto;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{
+    datastructures::matcher::escape, GenericError, KeyIter, KvError, KvsEngine, MatchOptions,
+    RecordMeta, Result, StoreStats, VerifyReport,
+};
+
+/// Prefix of the keys that record the index given to every tree
+const SCHEMA_PREFIX: &[u8] = b"__schema.";
+
+/// Name of the tree opened by `KvsEngine::restore`
+pub const DEFAULT_TREE: &str = "default";
+
+/// A named keyspace stored inside of another engine. Every key of the tree is
+/// prefixed with the tree's index as 8 big endian bytes, so trees sharing the
+/// same engine never see each other's keys. The index given to each name is
+/// kept in the engine under `__schema.<name>`.
+#[derive(Clone)]
+pub struct Tree<Kvs: KvsEngine> {
+    inner: Kvs,
+    name: String,
+    name_index: u64,
+}
+
+impl<Kvs: KvsEngine> Tree<Kvs> {
+    /// Open the tree called `name` inside of `inner`, creating it if it
+    /// doesn't exist yet.
+    pub fn open(inner: Kvs, name: &str) -> Result<Self> {
+        let schema_key = schema_key(name);
+        let name_index = match inner.get(&schema_key)? {
+            Some(index) => decode_index(&index)?,
+            None => {
+                let index = Self::next_index(&inner)?;
+                inner.set(schema_key, index.to_be_bytes().to_vec())?;
+                index
+            }
+        };
+        Ok(Self {
+            inner,
+            name: name.to_owned(),
+            name_index,
+        })
+    }
+
+    /// Name of the tree
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// List the names of every tree stored inside of `inner`
+    pub fn list(inner: &Kvs) -> Result<Vec<String>> {
+        inner
+            .find(escape(SCHEMA_PREFIX, b"*"))?
+            .into_iter()
+            .map(|key| Ok(String::from_utf8(key[SCHEMA_PREFIX.len()..].to_vec())?))
+            .collect()
+    }
+
+    /// Delete the tree, removing every key it holds and its schema record.
+    /// Other trees stored in the same engine are left untouched.
+    pub fn drop(self) -> Result<()> {
+        self.clear()?;
+        self.inner.remove(schema_key(&self.name))
+    }
+
+    /// The index after the largest one given to a tree so far
+    fn next_index(inner: &Kvs) -> Result<u64> {
+        let mut next = 0;
+        for key in inner.find(escape(SCHEMA_PREFIX, b"*"))? {
+            if let Some(index) = inner.get(&key)? {
+                next = next.max(decode_index(&index)? + 1);
+            }
+        }
+        Ok(next)
+    }
+
+    fn prefix(&self) -> [u8; 8] {
+        self.name_index.to_be_bytes()
+    }
+
+    fn prefixed(&self, key: &[u8]) -> Vec<u8> {
+        let mut prefixed = self.prefix().to_vec();
+        prefixed.extend_from_slice(key);
+        prefixed
+    }
+}
+
+impl<Kvs: KvsEngine> KvsEngine for Tree<Kvs> {
+    fn restore(folder: impl Into<PathBuf>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::open(Kvs::restore(folder)?, DEFAULT_TREE)
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.inner.set(self.prefixed(&key), value)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.inner.set_with_ttl(self.prefixed(&key), value, ttl)
+    }
+
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.inner.set_if_absent(self.prefixed(&key), value)
+    }
+
+    fn get_and_set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.inner.get_and_set(self.prefixed(&key), value)
+    }
+
+    fn append(&self, key: Vec<u8>, suffix: &[u8]) -> Result<usize> {
+        self.inner.append(self.prefixed(&key), suffix)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(&self.prefixed(key))
+    }
+
+    fn get_with_metadata(&self, key: &[u8]) -> Result<Option<(Vec<u8>, RecordMeta)>> {
+        self.inner.get_with_metadata(&self.prefixed(key))
+    }
+
+    fn find_iter_with(&self, like: Vec<u8>, options: MatchOptions) -> Result<KeyIter<'_>> {
+        let pattern = escape(&self.prefix(), &like);
+        let prefix_len = self.prefix().len();
+        let keys = self.inner.find_iter_with(pattern, options)?;
+        Ok(Box::new(
+            keys.map(move |key| key.map(|key| key[prefix_len..].to_vec())),
+        ))
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        self.inner.remove(self.prefixed(&key))
+    }
+
+    fn clear(&self) -> Result<()> {
+        for key in self.find(b"*".to_vec())? {
+            self.remove(key)?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats {
+            keys: self.count(b"*".to_vec())?,
+            ..self.inner.stats()?
+        })
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.inner.compact()
+    }
+
+    /// Verify the whole engine the tree is stored in. Corrupt keys are
+    /// reported as they are stored, with the prefix of their tree.
+    fn verify(&self) -> Result<VerifyReport> {
+        self.inner.verify()
+    }
+}
+
+fn schema_key(name: &str) -> Vec<u8> {
+    let mut key = SCHEMA_PREFIX.to_vec();
+    key.extend_from_slice(name.as_bytes());
+    key
+}
+
+fn decode_index(bytes: &[u8]) -> Result<u64> {
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| KvError::Parse(GenericError::new("Tree index must be 8 bytes long")))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tree;
+    use crate::{KvInMemoryStore, KvStore, KvsEngine};
+    use tempfile::TempDir;
+
+    #[test]
+    fn trees_do_not_collide() {
+        let inner = KvInMemoryStore::new();
+        let users = Tree::open(inner.clone(), "users").unwrap();
+        let posts = Tree::open(inner, "posts").unwrap();
+
+        users.set(b"1".to_vec(), b"alec".to_vec()).unwrap();
+        posts.set(b"1".to_vec(), b"hello world".to_vec()).unwrap();
+        assert_eq!(users.get(b"1").unwrap(), Some(b"alec".to_vec()));
+        assert_eq!(posts.get(b"1").unwrap(), Some(b"hello world".to_vec()));
+
+        users.remove(b"1".to_vec()).unwrap();
+        assert_eq!(users.get(b"1").unwrap(), None);
+        assert_eq!(posts.get(b"1").unwrap(), Some(b"hello world".to_vec()));
+    }
+
+    #[test]
+    fn find_is_scoped_to_one_tree() {
+        let inner = KvInMemoryStore::new();
+        let users = Tree::open(inner.clone(), "users").unwrap();
+        let posts = Tree::open(inner, "posts").unwrap();
+
+        users.set(b"user1".to_vec(), b"a".to_vec()).unwrap();
+        users.set(b"user2".to_vec(), b"b".to_vec()).unwrap();
+        posts.set(b"user3".to_vec(), b"c".to_vec()).unwrap();
+
+        let keys = users.find(b"user*".to_vec()).unwrap();
+        assert_eq!(keys, vec![b"user1".to_vec(), b"user2".to_vec()]);
+        assert_eq!(posts.find(b"*".to_vec()).unwrap(), vec![b"user3".to_vec()]);
+        assert_eq!(users.stats().unwrap().keys, 2);
+    }
+
+    #[test]
+    fn reopen_tree_after_restore() {
+        let temp_dir = TempDir::new().unwrap();
+        {
+            let inner = KvStore::restore(temp_dir.path()).unwrap();
+            Tree::open(inner.clone(), "users").unwrap();
+            let posts = Tree::open(inner, "posts").unwrap();
+            posts.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+        }
+
+        let inner = KvStore::restore(temp_dir.path()).unwrap();
+        let posts = Tree::open(inner.clone(), "posts").unwrap();
+        assert_eq!(posts.get(b"key").unwrap(), Some(b"value".to_vec()));
+        let users = Tree::open(inner, "users").unwrap();
+        assert_eq!(users.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn list_and_drop_trees() {
+        let inner = KvInMemoryStore::new();
+        let names = ["users", "posts", "comments"];
+        for name in names.iter() {
+            let tree = Tree::open(inner.clone(), name).unwrap();
+            for i in 0..10 {
+                tree.set(format!("key{}", i).into_bytes(), name.as_bytes().to_vec())
+                    .unwrap();
+            }
+        }
+        let mut listed = Tree::list(&inner).unwrap();
+        listed.sort();
+        assert_eq!(listed, vec!["comments", "posts", "users"]);
+
+        Tree::open(inner.clone(), "posts").unwrap().drop().unwrap();
+        let mut listed = Tree::list(&inner).unwrap();
+        listed.sort();
+        assert_eq!(listed, vec!["comments", "users"]);
+
+        for name in ["users", "comments"].iter() {
+            let tree = Tree::open(inner.clone(), name).unwrap();
+            assert_eq!(tree.find(b"*".to_vec()).unwrap().len(), 10);
+            for i in 0..10 {
+                let value = tree.get(format!("key{}", i).as_bytes()).unwrap();
+                assert_eq!(value, Some(name.as_bytes().to_vec()));
+            }
+        }
+        let posts = Tree::open(inner, "posts").unwrap();
+        assert!(posts.find(b"*".to_vec()).unwrap().is_empty());
+    }
+}
@@ -4,7 +4,7 @@ use std::{error, string::FromUtf8Error};
 
 /// Generic Error because right now i'm to lazy to implement an actually good
 /// error class
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct GenericError {
     details: String,
 }
@@ -53,6 +53,9 @@ pub enum KvError {
     Serialize(bincode::ErrorKind),
     /// The `Json` error is used to capture any issues had with dealing with json
     Json(serde_json::Error),
+    /// The `MessagePack` error is used to capture an error triggered by
+    /// rmp_serde
+    MessagePack(GenericError),
     /// The `KeyNotFound` is used when searching for a key in the database can't be found
     KeyNotFound(GenericError),
     /// The `UnexpectedCommandType` is used when the user issues a command we don't understand
@@ -69,17 +72,131 @@ pub enum KvError {
     Lock(GenericError),
     /// Error with a string message
     StringError(GenericError),
+    /// The `Corruption` error is used when data read from disk doesn't match
+    /// its checksum or can't be right
+    Corruption {
+        /// What was found to be corrupt
+        context: String,
+        /// Key of the corrupted record, when it is known
+        key: Option<Vec<u8>>,
+    },
+    /// The `CorruptLog` error is used when a record in the middle of a
+    /// write-ahead-log can't be read, so the records after it are lost
+    CorruptLog(GenericError),
+    /// The `ValueTooLarge` error is used when a value is bigger than the store
+    /// is configured to accept
+    ValueTooLarge {
+        /// Size of the value in bytes
+        size: usize,
+        /// Largest value size accepted in bytes
+        limit: usize,
+    },
+    /// The `Timeout` error is used when the server didn't accept a connection
+    /// or answer a request in the configured time
+    Timeout(io::Error),
+    /// The `ConnectionRefused` error is used when nothing is listening at the
+    /// address of the server
+    ConnectionRefused(io::Error),
+    /// The `ConnectionReset` error is used when the server dropped an open
+    /// connection
+    ConnectionReset(io::Error),
+    /// The `EngineMismatch` error is used when a data directory was written by
+    /// a different engine than the one asked to open it
+    EngineMismatch {
+        /// Engine that wrote the data directory
+        found: String,
+        /// Engine that was asked to open the data directory
+        requested: String,
+    },
+    /// The `ManifestMismatch` error is used when a store is opened with a
+    /// setting that doesn't match the one recorded in its manifest
+    ManifestMismatch {
+        /// Name of the setting that doesn't match
+        setting: String,
+        /// Value recorded in the manifest of the store
+        found: String,
+        /// Value the store was asked to be opened with
+        requested: String,
+    },
+    /// The `ReadOnly` error is used when a store opened in read-only mode is
+    /// asked to change its data
+    ReadOnly,
+    /// The `RateLimited` error is used when a connection sends requests
+    /// faster than the server allows
+    RateLimited,
 }
 
 /// `Result` is a error helper for `KvError`
 pub type Result<T> = std::result::Result<T, KvError>;
 
+impl KvError {
+    /// Build a copy of the error with the same variant, for handing one
+    /// failure to several callers. Wrapped errors that can't be cloned are
+    /// rebuilt from their message.
+    pub(crate) fn duplicate(&self) -> KvError {
+        match self {
+            KvError::Io(err) => KvError::Io(copy_io_error(err)),
+            KvError::Serialize(err) => {
+                KvError::Serialize(bincode::ErrorKind::Custom(err.to_string()))
+            }
+            KvError::Json(err) => {
+                KvError::Json(<serde_json::Error as serde::de::Error>::custom(err))
+            }
+            KvError::MessagePack(err) => KvError::MessagePack(err.clone()),
+            KvError::KeyNotFound(err) => KvError::KeyNotFound(err.clone()),
+            KvError::UnexpectedCommandType(err) => KvError::UnexpectedCommandType(err.clone()),
+            KvError::Parse(err) => KvError::Parse(err.clone()),
+            KvError::Utf8(err) => KvError::Utf8(err.clone()),
+            KvError::Compact(err) => KvError::Compact(err.clone()),
+            KvError::Sled(err) => KvError::Sled(err.clone()),
+            KvError::Lock(err) => KvError::Lock(err.clone()),
+            KvError::StringError(err) => KvError::StringError(err.clone()),
+            KvError::Corruption { context, key } => KvError::Corruption {
+                context: context.clone(),
+                key: key.clone(),
+            },
+            KvError::CorruptLog(err) => KvError::CorruptLog(err.clone()),
+            KvError::ValueTooLarge { size, limit } => KvError::ValueTooLarge {
+                size: *size,
+                limit: *limit,
+            },
+            KvError::Timeout(err) => KvError::Timeout(copy_io_error(err)),
+            KvError::ConnectionRefused(err) => KvError::ConnectionRefused(copy_io_error(err)),
+            KvError::ConnectionReset(err) => KvError::ConnectionReset(copy_io_error(err)),
+            KvError::EngineMismatch { found, requested } => KvError::EngineMismatch {
+                found: found.clone(),
+                requested: requested.clone(),
+            },
+            KvError::ManifestMismatch {
+                setting,
+                found,
+                requested,
+            } => KvError::ManifestMismatch {
+                setting: setting.clone(),
+                found: found.clone(),
+                requested: requested.clone(),
+            },
+            KvError::ReadOnly => KvError::ReadOnly,
+            KvError::RateLimited => KvError::RateLimited,
+        }
+    }
+}
+
+/// Copy an io error, keeping the os error code when there is one
+fn copy_io_error(err: &io::Error) -> io::Error {
+    match err.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(err.kind(), err.to_string()),
+    }
+}
+
 impl fmt::Display for KvError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             KvError::Io(ref err) => write!(f, "File Not Found: {}", err),
             KvError::Serialize(ref err) => write!(f, "Bincode Err: {}", err),
             KvError::Json(ref err) => write!(f, "Json Err: {}", err),
+            KvError::MessagePack(ref err) => write!(f, "MessagePack Err: {}", err),
             KvError::KeyNotFound(ref err) => write!(f, "KeyNotFound Err: {}", err),
             KvError::UnexpectedCommandType(ref err) => write!(f, "Command type Err: {}", err),
             KvError::Parse(ref err) => write!(f, "Prase Err: {}", err),
@@ -88,16 +205,61 @@ impl fmt::Display for KvError {
             KvError::Sled(ref err) => write!(f, "Sled Err: {}", err),
             KvError::StringError(ref err) => write!(f, "String Error: {}", err),
             KvError::Lock(ref err) => write!(f, "Lock Error: {}", err),
+            KvError::Corruption {
+                ref context,
+                key: Some(ref key),
+            } => write!(
+                f,
+                "Corruption Err: {} (key {})",
+                context,
+                String::from_utf8_lossy(key)
+            ),
+            KvError::Corruption {
+                ref context,
+                key: None,
+            } => write!(f, "Corruption Err: {}", context),
+            KvError::CorruptLog(ref err) => write!(f, "Corrupt Log Err: {}", err),
+            KvError::ValueTooLarge { size, limit } => write!(
+                f,
+                "Value Too Large Err: {} bytes is over the limit of {} bytes",
+                size, limit
+            ),
+            KvError::Timeout(ref err) => write!(f, "Timeout Err: {}", err),
+            KvError::ConnectionRefused(ref err) => write!(f, "Connection Refused Err: {}", err),
+            KvError::ConnectionReset(ref err) => write!(f, "Connection Reset Err: {}", err),
+            KvError::EngineMismatch {
+                ref found,
+                ref requested,
+            } => write!(
+                f,
+                "Engine Mismatch Err: data was written by the {} engine but {} was requested",
+                found, requested
+            ),
+            KvError::ManifestMismatch {
+                ref setting,
+                ref found,
+                ref requested,
+            } => write!(
+                f,
+                "Manifest Mismatch Err: store was created with {} {} but {} was requested",
+                setting, found, requested
+            ),
+            KvError::ReadOnly => write!(f, "Read Only Err: store was opened in read-only mode"),
+            KvError::RateLimited => write!(
+                f,
+                "Rate Limited Err: too many requests were sent on this connection"
+            ),
         }
     }
 }
 
 impl error::Error for KvError {
-    fn cause(&self) -> Option<&dyn error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
             KvError::Io(ref err) => Some(err),
             KvError::Serialize(ref err) => Some(err),
             KvError::Json(ref err) => Some(err),
+            KvError::MessagePack(ref err) => Some(err),
             KvError::KeyNotFound(ref err) => Some(err),
             KvError::UnexpectedCommandType(ref err) => Some(err),
             KvError::Parse(ref err) => Some(err),
@@ -106,6 +268,16 @@ impl error::Error for KvError {
             KvError::Sled(ref err) => Some(err),
             KvError::StringError(ref err) => Some(err),
             KvError::Lock(ref err) => Some(err),
+            KvError::Corruption { .. } => None,
+            KvError::CorruptLog(ref err) => Some(err),
+            KvError::ValueTooLarge { .. } => None,
+            KvError::Timeout(ref err) => Some(err),
+            KvError::ConnectionRefused(ref err) => Some(err),
+            KvError::ConnectionReset(ref err) => Some(err),
+            KvError::EngineMismatch { .. } => None,
+            KvError::ManifestMismatch { .. } => None,
+            KvError::ReadOnly => None,
+            KvError::RateLimited => None,
         }
     }
 }
@@ -128,6 +300,18 @@ impl From<serde_json::Error> for KvError {
     }
 }
 
+impl From<rmp_serde::encode::Error> for KvError {
+    fn from(err: rmp_serde::encode::Error) -> Self {
+        KvError::MessagePack(err.to_string().into())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for KvError {
+    fn from(err: rmp_serde::decode::Error) -> Self {
+        KvError::MessagePack(err.to_string().into())
+    }
+}
+
 impl From<std::boxed::Box<bincode::ErrorKind>> for KvError {
     fn from(err: std::boxed::Box<bincode::ErrorKind>) -> KvError {
         KvError::Serialize(*err)
@@ -145,3 +329,43 @@ impl From<sled::Error> for KvError {
         KvError::Sled(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, io};
+
+    use super::KvError;
+
+    #[test]
+    fn source_is_the_wrapped_error() {
+        let err = KvError::from(io::Error::new(io::ErrorKind::NotFound, "missing log"));
+        let source = err.source().expect("io error has a source");
+        let io_err = source.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io_err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(io_err.to_string(), "missing log");
+        assert!(KvError::ReadOnly.source().is_none());
+    }
+
+    #[test]
+    fn duplicate_keeps_the_variant() {
+        let err = KvError::from(io::Error::new(io::ErrorKind::NotFound, "missing log"));
+        match err.duplicate() {
+            KvError::Io(copy) => {
+                assert_eq!(copy.kind(), io::ErrorKind::NotFound);
+                assert_eq!(copy.to_string(), "missing log");
+            }
+            other => panic!("expected an io error, got {}", other),
+        }
+        let err = KvError::Corruption {
+            context: "bad crc".into(),
+            key: Some(b"key".to_vec()),
+        };
+        match err.duplicate() {
+            KvError::Corruption { context, key } => {
+                assert_eq!(context, "bad crc");
+                assert_eq!(key, Some(b"key".to_vec()));
+            }
+            other => panic!("expected a corruption error, got {}", other),
+        }
+    }
+}
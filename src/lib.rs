@@ -5,11 +5,19 @@
 #[macro_use]
 extern crate log;
 
-pub use client::KvClient;
-pub use engines::{KvInMemoryStore, KvStore, KvsEngine, SledKvsEngine};
+pub use async_client::AsyncKvClient;
+pub use client::{KvClient, RetryPolicy, Subscription};
+pub use common::WireCodec;
+pub use datastructures::matcher::MatchOptions;
+pub use engines::{
+    migrate, Compression, Config, ConfigBuilder, EntryIter, FsyncPolicy, KeyIter, KvInMemoryStore,
+    KvStore, KvsEngine, Notification, RecordMeta, SerializationFormat, SledKvsEngine, Snapshot,
+    StoreIter, StoreStats, Tree, VerifyReport,
+};
 pub use error::{GenericError, KvError, Result};
 pub use server::KvServer;
 
+mod async_client;
 mod client;
 mod common;
 mod datastructures;
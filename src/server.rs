@@ -1,25 +1,166 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
+    io::{self, BufReader, BufWriter, Read, Write},
     net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
 };
 
 use serde_json::Deserializer;
 
-use crate::{common::FindResponse, error::Result};
 use crate::{
-    common::{GetResponse, RemoveResponse, Request, SetResponse},
-    KvsEngine,
+    common::{
+        read_entry, read_frame, AppendResponse, AuthResponse, CountResponse, DumpResponse,
+        FindResponse, LoadResponse, SubscribeResponse,
+    },
+    error::Result,
 };
+use crate::{
+    common::{
+        CompactResponse, GetResponse, RemoveResponse, Request, SetResponse, StatsResponse,
+        VerifyResponse,
+    },
+    KvError, KvsEngine, MatchOptions, Notification, WireCodec,
+};
+
+/// How often the thread of a subscribed client with no notifications to
+/// send checks if the client is still connected
+const SUBSCRIBER_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Wrapper class to hold the current context of the key value server
 pub struct KvServer<E: KvsEngine> {
     engine: E,
+    max_value_size: Option<usize>,
+    codec: WireCodec,
+    password: Option<String>,
+    idle_timeout: Option<Duration>,
+    max_requests_per_sec: Option<u32>,
 }
 
 impl<E: KvsEngine> KvServer<E> {
     /// Create a `KvServer` with a given storage engine
     pub fn new(engine: E) -> Self {
-        KvServer { engine }
+        KvServer {
+            engine,
+            max_value_size: None,
+            codec: WireCodec::default(),
+            password: None,
+            idle_timeout: None,
+            max_requests_per_sec: None,
+        }
+    }
+
+    /// Reject `set` requests whose value is larger than `limit` bytes before
+    /// they reach the storage engine
+    pub fn with_max_value_size(mut self, limit: usize) -> Self {
+        self.max_value_size = Some(limit);
+        self
+    }
+
+    /// Encode requests and responses with `codec` instead of JSON
+    pub fn with_codec(mut self, codec: WireCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Require every connection to send `password` in an `Auth` request
+    /// before any other request. Other requests are answered with an error
+    /// until then, and a connection sending the wrong password is closed.
+    pub fn with_password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Close a connection once no request has arrived on it for `timeout`,
+    /// so an idle client doesn't keep the server from serving others
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Answer requests on a connection that sends more than `rate` of them a
+    /// second with a rate limited error, without handing them to the
+    /// engine. A connection may send a burst of up to `rate` requests at
+    /// once. Every connection is limited on its own.
+    pub fn with_max_requests_per_sec(mut self, rate: u32) -> Self {
+        self.max_requests_per_sec = Some(rate);
+        self
+    }
+
+    /// Whether `token` matches the password of the server. Any token is
+    /// accepted when no password is set.
+    fn check_token(&self, token: &str) -> bool {
+        let password = match &self.password {
+            Some(password) => password.as_bytes(),
+            None => return true,
+        };
+        // every byte is compared so the time taken doesn't reveal how much
+        // of the token was right
+        password.len() == token.len()
+            && password
+                .iter()
+                .zip(token.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Make sure no value of a dump is larger than the server accepts
+    fn check_dump(&self, dump: &[u8]) -> Result<()> {
+        let limit = match self.max_value_size {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let mut reader = dump;
+        while let Some((_, value)) = read_entry(&mut reader)? {
+            if value.len() > limit {
+                return Err(KvError::ValueTooLarge {
+                    size: value.len(),
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Stream notifications to a subscribed client from a thread of its
+    /// own, so the server can go on serving other connections. The thread
+    /// ends once a notification can't be written to the client, or once the
+    /// client closes the connection while there is nothing to send, which
+    /// drops the subscription.
+    fn stream_notifications(
+        tcp: TcpStream,
+        codec: WireCodec,
+        notifications: Receiver<Notification>,
+    ) -> Result<()> {
+        let peer_addr = tcp.peer_addr()?;
+        thread::Builder::new()
+            .name("kvs-subscriber".into())
+            .spawn(move || {
+                let mut writer = BufWriter::new(tcp);
+                loop {
+                    let Notification { key, value } =
+                        match notifications.recv_timeout(SUBSCRIBER_POLL_INTERVAL) {
+                            Ok(notification) => notification,
+                            Err(RecvTimeoutError::Timeout) => {
+                                if has_disconnected(writer.get_ref()) {
+                                    info!("Subscriber {} disconnected", peer_addr);
+                                    break;
+                                }
+                                continue;
+                            }
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        };
+                    let response = SubscribeResponse::Notification { key, value };
+                    let sent = codec
+                        .write(&mut writer, &response)
+                        .and_then(|_| writer.flush().map_err(KvError::from));
+                    if let Err(e) = sent {
+                        info!("Subscriber {} went away: {}", peer_addr, e);
+                        break;
+                    }
+                }
+            })?;
+        Ok(())
     }
 
     /// Run the server listening on the given address
@@ -40,52 +181,307 @@ impl<E: KvsEngine> KvServer<E> {
 
     fn serve(&mut self, tcp: TcpStream) -> Result<()> {
         let peer_addr = tcp.peer_addr()?;
-        let reader = BufReader::new(&tcp);
+        tcp.set_read_timeout(self.idle_timeout)?;
+        let mut reader = BufReader::new(&tcp);
         let mut writer = BufWriter::new(&tcp);
-        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+        let codec = self.codec;
+        let req_reader: Box<dyn Iterator<Item = Result<Request>>> = match codec {
+            WireCodec::Json => Box::new(
+                Deserializer::from_reader(reader)
+                    .into_iter::<Request>()
+                    // IO errors are unwrapped so idle timeouts can be spotted
+                    .map(|req| {
+                        req.map_err(|e| {
+                            if e.is_io() {
+                                KvError::Io(e.into())
+                            } else {
+                                e.into()
+                            }
+                        })
+                    }),
+            ),
+            WireCodec::Bincode => Box::new(std::iter::from_fn(move || {
+                read_frame(&mut reader).transpose()
+            })),
+        };
         macro_rules! send_response {
             ($resp:expr) => {{
                 let response = $resp;
-                serde_json::to_writer(&mut writer, &response)?;
+                codec.write(&mut writer, &response)?;
                 writer.flush()?;
                 info!("Response sent to {}: {:?}", peer_addr, response);
             }};
         }
 
+        let mut authenticated = self.password.is_none();
+        let mut limiter = self.max_requests_per_sec.map(TokenBucket::new);
         for req in req_reader {
-            let req = req?;
-            info!("Receive request from {}: {:?}", peer_addr, req);
+            let req = match req {
+                Ok(req) => req,
+                Err(KvError::Io(e)) if is_timeout(&e) => {
+                    info!("Closing idle connection from {}", peer_addr);
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            match &req {
+                Request::Auth { .. } => info!("Receive auth request from {}", peer_addr),
+                req => info!("Receive request from {}: {:?}", peer_addr, req),
+            }
+            if limiter.as_mut().map(TokenBucket::take) == Some(false) {
+                warn!("Rate limited request from {}", peer_addr);
+                reject(
+                    codec,
+                    &mut writer,
+                    &req,
+                    format!("{}", KvError::RateLimited),
+                )?;
+                continue;
+            }
+            if !authenticated && !matches!(req, Request::Auth { .. }) {
+                warn!("Rejected request from unauthenticated client {}", peer_addr);
+                reject(
+                    codec,
+                    &mut writer,
+                    &req,
+                    "Authentication required".to_owned(),
+                )?;
+                continue;
+            }
             match req {
-                Request::Get { key } => send_response!(match self.engine.get(key.as_bytes()) {
-                    Ok(Some(v)) => match String::from_utf8(v) {
-                        Ok(v) => GetResponse::Ok(Some(v)),
-                        Err(e) => GetResponse::Err(format!("{}", e)),
-                    },
-                    Ok(None) => GetResponse::Ok(None),
+                Request::Get { key } => send_response!(match self.engine.get(&key) {
+                    Ok(value) => GetResponse::Ok(value),
                     Err(e) => GetResponse::Err(format!("{}", e)),
                 }),
-                Request::Find { pattern } => {
-                    send_response!(match self.engine.find(pattern.as_bytes().to_vec()) {
-                        Ok(list) => FindResponse::Ok(list),
-                        Err(e) => FindResponse::Err(format!("{}", e)),
-                    })
+                Request::Find {
+                    pattern,
+                    ignore_case,
+                    offset,
+                    limit,
+                } => {
+                    let options = MatchOptions { ignore_case };
+                    send_response!(
+                        match self.engine.find_paged(pattern, options, offset, limit) {
+                            Ok(list) => FindResponse::Ok(list),
+                            Err(e) => FindResponse::Err(format!("{}", e)),
+                        }
+                    )
                 }
-                Request::Set { key, value } => send_response!(match self
-                    .engine
-                    .set(key.as_bytes().to_vec(), value.as_bytes().to_vec())
-                {
-                    Ok(_) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(format!("{}", e)),
+                Request::Set { key, value } => send_response!(match self.max_value_size {
+                    Some(limit) if value.len() > limit => SetResponse::Err(format!(
+                        "{}",
+                        KvError::ValueTooLarge {
+                            size: value.len(),
+                            limit
+                        }
+                    )),
+                    _ => match self.engine.set(key, value) {
+                        Ok(_) => SetResponse::Ok(()),
+                        Err(e) => SetResponse::Err(format!("{}", e)),
+                    },
                 }),
                 Request::Remove { key } => {
-                    send_response!(match self.engine.remove(key.as_bytes().to_vec()) {
+                    send_response!(match self.engine.remove(key) {
                         Ok(_) => RemoveResponse::Ok(()),
                         Err(e) => RemoveResponse::Err(format!("{}", e)),
                     })
                 }
+                Request::Append { key, suffix } => {
+                    send_response!(match self.engine.append(key, &suffix) {
+                        Ok(len) => AppendResponse::Ok(len),
+                        Err(e) => AppendResponse::Err(format!("{}", e)),
+                    })
+                }
+                Request::Count { pattern } => send_response!(match self.engine.count(pattern) {
+                    Ok(count) => CountResponse::Ok(count),
+                    Err(e) => CountResponse::Err(format!("{}", e)),
+                }),
+                Request::Stats => send_response!(match self.engine.stats() {
+                    Ok(stats) => StatsResponse::Ok(stats),
+                    Err(e) => StatsResponse::Err(format!("{}", e)),
+                }),
+                Request::Compact => send_response!(match self.engine.compact() {
+                    Ok(()) => CompactResponse::Ok(()),
+                    Err(e) => CompactResponse::Err(format!("{}", e)),
+                }),
+                Request::Verify => send_response!(match self.engine.verify() {
+                    Ok(report) => VerifyResponse::Ok(report),
+                    Err(e) => VerifyResponse::Err(format!("{}", e)),
+                }),
+                Request::Dump => {
+                    // entries are written without flushing, so the dump
+                    // leaves in large writes instead of one per key
+                    let mut sent = 0;
+                    let done = match self.engine.iter() {
+                        Ok(entries) => {
+                            let mut done = DumpResponse::Done;
+                            for entry in entries {
+                                match entry {
+                                    Ok((key, value)) => {
+                                        codec.write(
+                                            &mut writer,
+                                            &DumpResponse::Entry { key, value },
+                                        )?;
+                                        sent += 1;
+                                    }
+                                    Err(e) => {
+                                        done = DumpResponse::Err(format!("{}", e));
+                                        break;
+                                    }
+                                }
+                            }
+                            done
+                        }
+                        Err(e) => DumpResponse::Err(format!("{}", e)),
+                    };
+                    info!("Dumped {} keys to {}", sent, peer_addr);
+                    send_response!(done)
+                }
+                Request::Load { dump } => send_response!(match self
+                    .check_dump(&dump)
+                    .and_then(|_| self.engine.import(&dump[..]))
+                {
+                    Ok(loaded) => LoadResponse::Ok(loaded),
+                    Err(e) => LoadResponse::Err(format!("{}", e)),
+                }),
+                Request::Subscribe { pattern } => match self.engine.subscribe(pattern) {
+                    Ok(notifications) => {
+                        send_response!(SubscribeResponse::Ok(()));
+                        return Self::stream_notifications(tcp.try_clone()?, codec, notifications);
+                    }
+                    Err(e) => send_response!(SubscribeResponse::Err(format!("{}", e))),
+                },
+                Request::Auth { token } => {
+                    if self.check_token(&token) {
+                        authenticated = true;
+                        send_response!(AuthResponse::Ok(()));
+                    } else {
+                        send_response!(AuthResponse::Err("Invalid token".to_owned()));
+                        warn!(
+                            "Closing connection from {} after an invalid token",
+                            peer_addr
+                        );
+                        return Ok(());
+                    }
+                }
             }
         }
 
         Ok(())
     }
 }
+
+/// TokenBucket limits how many requests a single connection may send. It
+/// holds up to a second's worth of tokens and refills at `rate` tokens a
+/// second, every request taking one.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take a token for a request, returning `false` if none is left
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Check without blocking if the peer closed its end of the connection.
+/// A subscribed client has nothing more to send, so anything it did send is
+/// thrown away.
+fn has_disconnected(mut tcp: &TcpStream) -> bool {
+    if tcp.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let mut buffer = [0; 64];
+    let closed = loop {
+        match tcp.read(&mut buffer) {
+            Ok(0) => break true,
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break !is_timeout(&e),
+        }
+    };
+    closed || tcp.set_nonblocking(false).is_err()
+}
+
+/// Whether a read failed because the read timeout of the socket passed
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Answer a request with the error response of its kind, without handing
+/// the request to the engine
+fn reject<W: Write>(codec: WireCodec, writer: &mut W, req: &Request, msg: String) -> Result<()> {
+    match req {
+        Request::Get { .. } => codec.write(writer, &GetResponse::Err(msg)),
+        Request::Find { .. } => codec.write(writer, &FindResponse::Err(msg)),
+        Request::Set { .. } => codec.write(writer, &SetResponse::Err(msg)),
+        Request::Remove { .. } => codec.write(writer, &RemoveResponse::Err(msg)),
+        Request::Append { .. } => codec.write(writer, &AppendResponse::Err(msg)),
+        Request::Count { .. } => codec.write(writer, &CountResponse::Err(msg)),
+        Request::Stats => codec.write(writer, &StatsResponse::Err(msg)),
+        Request::Compact => codec.write(writer, &CompactResponse::Err(msg)),
+        Request::Verify => codec.write(writer, &VerifyResponse::Err(msg)),
+        Request::Dump => codec.write(writer, &DumpResponse::Err(msg)),
+        Request::Load { .. } => codec.write(writer, &LoadResponse::Err(msg)),
+        Request::Subscribe { .. } => codec.write(writer, &SubscribeResponse::Err(msg)),
+        Request::Auth { .. } => codec.write(writer, &AuthResponse::Err(msg)),
+    }?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::has_disconnected;
+
+    #[test]
+    fn closed_connection_is_spotted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        assert!(!has_disconnected(&server));
+
+        // whatever the client sends is thrown away
+        client.write_all(b"ignored").unwrap();
+        client.flush().unwrap();
+        assert!(!has_disconnected(&server));
+
+        drop(client);
+        let mut closed = false;
+        for _ in 0..50 {
+            if has_disconnected(&server) {
+                closed = true;
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(closed);
+    }
+}
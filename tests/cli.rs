@@ -1,12 +1,24 @@
 use assert_cmd::prelude::*;
+use kvs::{KvClient, RetryPolicy};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::process::{Child, Command};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
 
+/// Kills the spawned server when dropped, so a failed assert never leaves
+/// it running on its port
+struct ServerGuard(Child);
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -212,6 +224,41 @@ fn cli_wrong_engine() {
     }
 }
 
+#[test]
+fn cli_engine_mismatch_with_marker() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["-e", "kvs", "-p", "4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "Engine Mismatch Err: data was written by the sled engine but kvs was requested",
+        ));
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "sled"
+    );
+
+    // --force starts the server anyway and takes over the directory
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut server = ServerGuard(
+        cmd.args(["-e", "kvs", "-p", "4009", "--force"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+    assert!(server.0.try_wait().unwrap().is_none());
+    drop(server);
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "kvs"
+    );
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -335,3 +382,138 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_multiple_engines() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = ServerGuard(
+        server
+            .args(["--bind", "kvs:4006", "--bind", "sled:4007"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["-p", "4006", "set", "key1", "kvs-value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["-p", "4007", "set", "key1", "sled-value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["-p", "4006", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("kvs-value\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["-p", "4007", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("sled-value\n");
+}
+
+#[test]
+fn cli_multiple_engines_report_a_failed_binding() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut server = ServerGuard(
+        server
+            .args(["--bind", "kvs:4011", "--bind", "sled:4011"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    // one binding keeps serving the port, the other can't bind it
+    let status = server.0.try_wait().unwrap();
+    assert!(matches!(status, Some(status) if !status.success()));
+}
+
+#[test]
+fn cli_access_server_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "kvs").unwrap();
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.arg("--help")
+        .assert()
+        .success()
+        .stdout(contains("memory"));
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let server = ServerGuard(
+        server
+            .args(["-e", "memory", "-p", "4010"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["-p", "4010", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["-p", "4010", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    drop(server);
+    // the marker still belongs to the engine whose data is in the directory
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "kvs"
+    );
+}
+
+#[test]
+fn client_reconnects_after_server_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let start_server = || {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["-e", "kvs", "-a", "127.0.0.1", "-p", "4008"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        ServerGuard(child)
+    };
+
+    let server = start_server();
+    let retry = RetryPolicy::new(10, Duration::from_millis(100));
+    let mut client = KvClient::connect_with_retry(addr, retry).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    drop(server);
+    let _server = start_server();
+
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
@@ -0,0 +1,423 @@
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::{
+    AsyncKvClient, Config, KvClient, KvError, KvInMemoryStore, KvServer, KvStore, KvsEngine,
+    Notification, Result, WireCodec,
+};
+use tempfile::TempDir;
+
+/// Start a server backed by the in memory engine on a free port and wait
+/// until it accepts connections.
+fn spawn_server(codec: WireCodec) -> SocketAddr {
+    spawn(KvServer::new(KvInMemoryStore::new()).with_codec(codec))
+}
+
+/// Run `server` on a free port and wait until it accepts connections.
+fn spawn<E: KvsEngine + 'static>(server: KvServer<E>) -> SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    thread::spawn(move || server.run(addr));
+    for _ in 0..50 {
+        if std::net::TcpStream::connect(addr).is_ok() {
+            return addr;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("server at {} never started", addr);
+}
+
+#[tokio::test]
+async fn async_client_set_then_get() -> Result<()> {
+    let addr = spawn_server(WireCodec::Json);
+    let mut client = AsyncKvClient::connect(addr).await?;
+
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned()).await?, None);
+
+    client.set("key2".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(client.find("KEY*".to_owned(), true).await?.len(), 2);
+    client.remove("key1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    Ok(())
+}
+
+#[test]
+fn connect_to_closed_port_fails_fast() {
+    // nothing listens on the port once the listener is dropped
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let start = Instant::now();
+    let result = KvClient::connect_with_timeout(
+        addr,
+        Duration::from_millis(200),
+        Duration::from_millis(200),
+    );
+    assert!(result.is_err());
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn connect_to_closed_port_is_refused() -> Result<()> {
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let result = KvClient::connect(addr);
+    assert!(matches!(result, Err(KvError::ConnectionRefused(_))));
+    Ok(())
+}
+
+#[test]
+fn request_times_out() -> Result<()> {
+    // the listener accepts connections but never answers a request
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let mut client = KvClient::connect_with_timeout(
+        listener.local_addr()?,
+        Duration::from_millis(200),
+        Duration::from_millis(200),
+    )?;
+    let start = Instant::now();
+    let result = client.get("key1".to_owned());
+    assert!(matches!(result, Err(KvError::Timeout(_))));
+    assert!(start.elapsed() < Duration::from_secs(2));
+    Ok(())
+}
+
+fn set_get_and_find(client: &mut KvClient) -> Result<()> {
+    for i in 0..20 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..20 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(client.find("key1*".to_owned(), false)?.len(), 11);
+    assert_eq!(client.count("key1*".to_owned())?, 11);
+    let page = client.find_page("key1*".to_owned(), false, 9, Some(5))?;
+    assert_eq!(page, vec!["key18".to_owned(), "key19".to_owned()]);
+    assert!(client
+        .find_page("key1*".to_owned(), false, 11, Some(5))?
+        .is_empty());
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn json_and_bincode_codecs() -> Result<()> {
+    let addr = spawn_server(WireCodec::Json);
+    set_get_and_find(&mut KvClient::connect(addr)?)?;
+
+    let addr = spawn_server(WireCodec::Bincode);
+    set_get_and_find(&mut KvClient::connect(addr)?.with_codec(WireCodec::Bincode))?;
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let key = b"bin\x00\xffkey".to_vec();
+    let value = b"\x00\x9f\x92\x96\xc3\x28\x00".to_vec();
+    for codec in [WireCodec::Json, WireCodec::Bincode].iter() {
+        let addr = spawn_server(*codec);
+        let mut client = KvClient::connect(addr)?.with_codec(*codec);
+        client.set_bytes(key.clone(), value.clone())?;
+        assert_eq!(client.get_bytes(key.clone())?, Some(value.clone()));
+        assert_eq!(
+            client.find_bytes(b"bin*".to_vec(), false)?,
+            vec![key.clone()]
+        );
+        // the string methods refuse values that aren't UTF-8
+        client.set_bytes(b"text".to_vec(), value.clone())?;
+        assert!(matches!(
+            client.get("text".to_owned()),
+            Err(KvError::Utf8(_))
+        ));
+
+        client.remove_bytes(key.clone())?;
+        assert_eq!(client.get_bytes(key.clone())?, None);
+        // an empty value is still a value
+        client.set_bytes(key.clone(), vec![])?;
+        assert_eq!(client.get_bytes(key.clone())?, Some(vec![]));
+    }
+    Ok(())
+}
+
+#[test]
+fn subscriber_is_notified_of_matching_changes() -> Result<()> {
+    for codec in [WireCodec::Json, WireCodec::Bincode].iter() {
+        let addr = spawn_server(*codec);
+        let mut notifications = KvClient::connect(addr)?
+            .with_codec(*codec)
+            .subscribe(b"user*".to_vec())?;
+
+        let mut client = KvClient::connect(addr)?.with_codec(*codec);
+        client.set("post1".to_owned(), "hello".to_owned())?;
+        client.set("user1".to_owned(), "alec".to_owned())?;
+        client.remove("user1".to_owned())?;
+
+        let set = notifications.next().unwrap()?;
+        assert_eq!(
+            set,
+            Notification {
+                key: b"user1".to_vec(),
+                value: Some(b"alec".to_vec()),
+            }
+        );
+        let removed = notifications.next().unwrap()?;
+        assert_eq!((removed.key, removed.value), (b"user1".to_vec(), None));
+    }
+    Ok(())
+}
+
+#[test]
+fn stats_and_compact_over_the_network() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .max_wal_size(200)
+        .compaction_fanout(1000)
+        .build();
+    let addr = spawn(KvServer::new(KvStore::with_config(
+        temp_dir.path(),
+        config,
+    )?));
+    let mut client = KvClient::connect(addr)?;
+    assert_eq!(client.stats()?.keys, 0);
+
+    for round in 0..3 {
+        for i in 0..50 {
+            client.set(format!("key{}", i), format!("value{}", round))?;
+        }
+    }
+    let segments = |client: &mut KvClient| -> Result<usize> {
+        Ok(client.stats()?.segments_per_level.iter().sum())
+    };
+    let before = segments(&mut client)?;
+    assert!(before > 1);
+
+    client.compact()?;
+    let stats = client.stats()?;
+    assert!(segments(&mut client)? < before);
+    assert_eq!(stats.keys, 50);
+    assert_eq!(client.get("key7".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn auth_accepts_the_right_token() -> Result<()> {
+    for codec in [WireCodec::Json, WireCodec::Bincode].iter() {
+        let server = KvServer::new(KvInMemoryStore::new())
+            .with_codec(*codec)
+            .with_password("hunter2");
+        let addr = spawn(server);
+        let mut client = KvClient::connect_with_auth(addr, "hunter2")?.with_codec(*codec);
+        set_get_and_find(&mut client)?;
+    }
+
+    // a token sent to a server without a password is ignored
+    let addr = spawn_server(WireCodec::Json);
+    let mut client = KvClient::connect_with_auth(addr, "anything")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn auth_rejects_a_wrong_token() -> Result<()> {
+    let addr = spawn(KvServer::new(KvInMemoryStore::new()).with_password("hunter2"));
+    let mut client = KvClient::connect_with_auth(addr, "hunter3")?;
+    let err = client.set("key1".to_owned(), "value1".to_owned());
+    assert!(matches!(err, Err(KvError::StringError(_))));
+    // the server closed the connection
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let mut client = KvClient::connect_with_auth(addr, "hunter2")?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn requests_before_auth_are_rejected() -> Result<()> {
+    let addr = spawn(KvServer::new(KvInMemoryStore::new()).with_password("hunter2"));
+    let mut client = KvClient::connect(addr)?;
+    for _ in 0..2 {
+        let err = client.set("key1".to_owned(), "value1".to_owned());
+        let msg = format!("{}", err.unwrap_err());
+        assert!(msg.contains("Authentication required"));
+    }
+    assert!(client.stats().is_err());
+    // the server handles one connection at a time
+    drop(client);
+
+    let mut client = KvClient::connect_with_auth(addr, "hunter2")?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn idle_connection_is_closed() -> Result<()> {
+    let server =
+        KvServer::new(KvInMemoryStore::new()).with_idle_timeout(Duration::from_millis(100));
+    let addr = spawn(server);
+    let mut idle = std::net::TcpStream::connect(addr)?;
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let start = Instant::now();
+    assert_eq!(idle.read(&mut [0; 16])?, 0);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // the server moved on to the next client
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn dump_streams_every_key() -> Result<()> {
+    for codec in [WireCodec::Json, WireCodec::Bincode].iter() {
+        let addr = spawn_server(*codec);
+        let mut client = KvClient::connect(addr)?.with_codec(*codec);
+        set_get_and_find(&mut client)?;
+
+        let mut dump = vec![];
+        assert_eq!(client.dump(&mut dump)?, 19);
+        let store = KvInMemoryStore::new();
+        assert_eq!(store.import(&dump[..])?, 19);
+        assert_eq!(store.get(b"key1")?, None);
+        for i in 2..20 {
+            let value = store.get(format!("key{}", i).as_bytes())?;
+            assert_eq!(value, Some(format!("value{}", i).into_bytes()));
+        }
+        // the connection is still usable once the dump is done
+        assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+#[test]
+fn load_sends_dump_to_server() -> Result<()> {
+    let store = KvInMemoryStore::new();
+    for i in 0..5000 {
+        store.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+    }
+    let mut dump = vec![];
+    store.export(&mut dump)?;
+
+    let addr = spawn_server(WireCodec::Bincode);
+    let mut client = KvClient::connect(addr)?.with_codec(WireCodec::Bincode);
+    assert_eq!(client.load(&dump[..])?, 5000);
+    assert_eq!(client.stats()?.keys, 5000);
+    assert_eq!(client.get("key4999".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn async_client_with_bincode() -> Result<()> {
+    let addr = spawn_server(WireCodec::Bincode);
+    let mut client = AsyncKvClient::connect(addr)
+        .await?
+        .with_codec(WireCodec::Bincode);
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn append_over_the_network() -> Result<()> {
+    let addr = spawn_server(WireCodec::Json);
+    let mut client = KvClient::connect(addr)?;
+    assert_eq!(client.append("greeting".to_owned(), "hello".to_owned())?, 5);
+    assert_eq!(
+        client.append("greeting".to_owned(), " world".to_owned())?,
+        11
+    );
+    assert_eq!(
+        client.get("greeting".to_owned())?,
+        Some("hello world".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn get_or_over_the_network() -> Result<()> {
+    let addr = spawn_server(WireCodec::Json);
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let get_or =
+        |client: &mut KvClient, key: &str| client.get_or(key.to_owned(), "none".to_owned());
+    assert_eq!(get_or(&mut client, "key1")?, "value1");
+    assert_eq!(get_or(&mut client, "key2")?, "none");
+
+    // a server that never answers is an error, not a missing key
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let mut client = KvClient::connect_with_timeout(
+        listener.local_addr()?,
+        Duration::from_millis(200),
+        Duration::from_millis(200),
+    )?;
+    assert!(matches!(
+        get_or(&mut client, "key1"),
+        Err(KvError::Timeout(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn verify_over_the_network() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn(KvServer::new(KvStore::new(temp_dir.path())?));
+    let mut client = KvClient::connect(addr)?;
+    for i in 0..3 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    let report = client.verify()?;
+    assert_eq!(report.good, 3);
+    assert!(report.corrupt.is_empty());
+    Ok(())
+}
+
+#[test]
+fn fast_client_is_rate_limited() -> Result<()> {
+    let server = KvServer::new(KvInMemoryStore::new()).with_max_requests_per_sec(10);
+    let addr = spawn(server);
+
+    // a burst well over the limit has its extra requests turned away
+    let mut fast = KvClient::connect(addr)?;
+    let mut limited = 0;
+    for i in 0..30 {
+        match fast.set(format!("key{}", i), "value".to_owned()) {
+            Ok(()) => {}
+            Err(e) => {
+                assert!(e.to_string().contains("Rate Limited"), "{}", e);
+                limited += 1;
+            }
+        }
+    }
+    assert!(limited >= 10, "only {} requests were rate limited", limited);
+    // the connection stays usable once it slows down
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(fast.get("key0".to_owned())?, Some("value".to_owned()));
+    drop(fast);
+
+    // a client staying under the limit is never turned away
+    let mut slow = KvClient::connect(addr)?;
+    for i in 0..15 {
+        slow.set(format!("slow{}", i), "value".to_owned())?;
+        thread::sleep(Duration::from_millis(150));
+    }
+    assert_eq!(slow.get("slow14".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
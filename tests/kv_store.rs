@@ -1,6 +1,10 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    migrate, Compression, Config, FsyncPolicy, KvError, KvInMemoryStore, KvStore, KvsEngine,
+    MatchOptions, Result, SerializationFormat, SledKvsEngine, VerifyReport,
+};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -221,3 +225,2333 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn stats_count_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+    }
+
+    let stats = store.stats()?;
+    assert!(stats.keys >= 100);
+    assert!(stats.wal_size > 0);
+    assert!(stats.disk_size >= stats.wal_size);
+    Ok(())
+}
+
+#[test]
+fn clear_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+    }
+
+    store.clear()?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i).as_bytes())?, None);
+    }
+
+    // Open from disk again and check the data is still gone
+    drop(store);
+    let store = KvStore::restore(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i).as_bytes())?, None);
+    }
+    Ok(())
+}
+
+// Clearing the store while segments are merged in the background should
+// neither break the merge nor leave any of its output behind
+#[test]
+fn clear_while_merging() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(100).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+
+    for round in 0..20 {
+        for i in 0..50 {
+            store.set(format!("key{:03}", i).into_bytes(), b"value".to_vec())?;
+        }
+        store.clear()?;
+        for i in 0..50 {
+            let key = format!("key{:03}", i).into_bytes();
+            assert_eq!(store.get(&key)?, None, "round {}", round);
+        }
+    }
+
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    drop(store);
+    let store = KvStore::restore(temp_dir.path())?;
+    assert_eq!(store.get(b"key")?, Some(b"value".to_vec()));
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{:03}", i).as_bytes())?, None);
+    }
+    Ok(())
+}
+
+// A WAL size set through the builder should rotate the memory table as soon
+// as it is crossed
+#[test]
+fn config_builder_wal_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(1000).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+
+    // every key and value pair is 11 bytes in size
+    for i in 0..90 {
+        store.set(format!("key{:03}", i).into_bytes(), b"value".to_vec())?;
+    }
+    assert_eq!(store.stats()?.segments_per_level, vec![0]);
+
+    store.set(b"key090".to_vec(), b"value".to_vec())?;
+    assert_eq!(store.stats()?.segments_per_level, vec![1]);
+
+    for i in 0..91 {
+        let key = format!("key{:03}", i).into_bytes();
+        assert_eq!(store.get(&key)?, Some(b"value".to_vec()));
+    }
+    Ok(())
+}
+
+// The write buffer bounds the memory table on its own, even when the WAL
+// may grow much larger
+#[test]
+fn config_builder_write_buffer_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .max_wal_size(1_000_000)
+        .write_buffer_size(100)
+        .build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+
+    // every key and value pair is 11 bytes in size
+    for i in 0..9 {
+        store.set(format!("key{:03}", i).into_bytes(), b"value".to_vec())?;
+    }
+    assert_eq!(store.stats()?.segments_per_level, vec![0]);
+
+    store.set(b"key009".to_vec(), b"value".to_vec())?;
+    assert_eq!(store.stats()?.segments_per_level, vec![1]);
+
+    // overwriting a key grows the WAL but not the memory table
+    let config = Config::builder()
+        .max_wal_size(100)
+        .write_buffer_size(1_000_000)
+        .build();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    for _ in 0..9 {
+        store.set(b"key000".to_vec(), b"value".to_vec())?;
+    }
+    assert_eq!(store.stats()?.segments_per_level, vec![0]);
+    store.set(b"key000".to_vec(), b"value".to_vec())?;
+    assert_eq!(store.stats()?.segments_per_level, vec![1]);
+    assert_eq!(store.get(b"key000")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn flush_memtable_writes_a_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::new(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+    }
+    let segments = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    assert_eq!(segments(), 0);
+
+    store.flush_memtable()?;
+    assert_eq!(segments(), 1);
+    assert_eq!(store.stats()?.segments_per_level, vec![1]);
+    for i in 0..10 {
+        let key = format!("key{}", i).into_bytes();
+        assert_eq!(store.get(&key)?, Some(b"value".to_vec()));
+    }
+
+    // flushing an empty memory table writes nothing
+    store.flush_memtable()?;
+    assert_eq!(segments(), 1);
+    Ok(())
+}
+
+#[test]
+fn old_write_ahead_log_is_flushed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .wal_max_age(Duration::from_millis(100))
+        .build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    let segments = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    // a single key is nowhere near filling the write-ahead-log
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    assert_eq!(segments(), 0);
+    assert!(wait_for(|| if segments() > 0 { Some(()) } else { None }).is_some());
+    assert_eq!(store.get(b"key")?, Some(b"value".to_vec()));
+    drop(store);
+
+    let store = KvStore::new(temp_dir.path())?;
+    assert_eq!(store.get(b"key")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn unfinished_segments_are_removed_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let files_with = |extension: &str| {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some(extension.as_ref()))
+            .count()
+    };
+    {
+        let store = KvStore::new(temp_dir.path())?;
+        for i in 0..10 {
+            store.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+        }
+        store.flush_memtable()?;
+        assert_eq!(files_with("log"), 1);
+        assert_eq!(files_with("tmp"), 0);
+    }
+
+    // a merge that died part way through leaves its output behind
+    std::fs::write(temp_dir.path().join("1.log.tmp"), b"partial segment")?;
+    std::fs::create_dir_all(temp_dir.path().join("lv2"))?;
+    std::fs::write(temp_dir.path().join("lv2").join("2.log.tmp"), b"partial")?;
+
+    let store = KvStore::new(temp_dir.path())?;
+    assert_eq!(files_with("tmp"), 0);
+    assert_eq!(store.stats()?.segments_per_level.iter().sum::<usize>(), 1);
+    for i in 0..10 {
+        let key = format!("key{}", i).into_bytes();
+        assert_eq!(store.get(&key)?, Some(b"value".to_vec()));
+    }
+    Ok(())
+}
+
+#[test]
+fn stale_merge_inputs_are_removed_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let stale = temp_dir.path().join("stale");
+    std::fs::create_dir(&stale)?;
+    let stale_file = {
+        let store = KvStore::new(temp_dir.path())?;
+        for i in 0..10 {
+            store.set(format!("key{}", i).into_bytes(), b"old".to_vec())?;
+        }
+        store.flush_memtable()?;
+        // keep a copy of the segment as it was before being merged away
+        let segment = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.path().extension() == Some("log".as_ref()))
+            .unwrap()
+            .into_path();
+        let copy = stale.join(segment.file_name().unwrap());
+        std::fs::copy(&segment, &copy)?;
+
+        for i in 0..10 {
+            store.set(format!("key{}", i).into_bytes(), b"new".to_vec())?;
+        }
+        store.compact()?;
+        assert!(!segment.exists());
+        (copy, segment)
+    };
+
+    // a merge that stopped before removing its inputs leaves them behind
+    let (copy, segment) = stale_file;
+    std::fs::rename(&copy, &segment)?;
+    let store = KvStore::new(temp_dir.path())?;
+    for i in 0..10 {
+        let key = format!("key{}", i).into_bytes();
+        assert_eq!(store.get(&key)?, Some(b"new".to_vec()));
+    }
+    assert_eq!(store.stats()?.segments_per_level.iter().sum::<usize>(), 1);
+    drop(store);
+    assert!(!segment.exists());
+    Ok(())
+}
+
+#[test]
+fn get_or_falls_back_only_for_missing_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value".to_vec())?;
+    store.set(b"key2".to_vec(), b"value".to_vec())?;
+    store.flush_memtable()?;
+    assert_eq!(store.get_or(b"key1", b"default".to_vec())?, b"value");
+    assert_eq!(store.get_or(b"key3", b"default".to_vec())?, b"default");
+    store.remove(b"key1".to_vec())?;
+    assert_eq!(store.get_or(b"key1", b"default".to_vec())?, b"default");
+
+    // flip the last byte of the value of the last record in the segment,
+    // which sits right before the footer whose offset starts the trailer
+    let segment = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().extension() == Some("log".as_ref()))
+        .expect("segment was not written")
+        .into_path();
+    let mut bytes = std::fs::read(&segment)?;
+    let mut offset = [0; 8];
+    offset.copy_from_slice(&bytes[bytes.len() - 16..bytes.len() - 8]);
+    bytes[u64::from_be_bytes(offset) as usize - 1] ^= 0xff;
+    std::fs::write(&segment, bytes)?;
+
+    let store = KvStore::restore(temp_dir.path())?;
+    let result = store.get_or(b"key2", b"default".to_vec());
+    assert!(matches!(result, Err(KvError::Corruption { .. })));
+    Ok(())
+}
+
+#[test]
+fn corrupt_segment_is_detected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(100).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+
+    for i in 0..10 {
+        store.set(format!("key{:03}", i).into_bytes(), b"value".to_vec())?;
+    }
+
+    // the segment is written in the background, its bloom filter is the last
+    // file to be created
+    let segment = wait_for(|| {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.path().extension() == Some("bloom".as_ref()))
+            .map(|entry| entry.path().with_extension("log"))
+    })
+    .expect("segment was not written");
+
+    // flip the last byte before the segment's footer, which belongs to the
+    // value of the last record written to it. The trailer at the end of the
+    // file starts with the offset of the footer.
+    let mut bytes = std::fs::read(&segment)?;
+    let mut offset = [0; 8];
+    offset.copy_from_slice(&bytes[bytes.len() - 16..bytes.len() - 8]);
+    let footer_start = u64::from_be_bytes(offset) as usize;
+    bytes[footer_start - 1] ^= 0xff;
+    std::fs::write(&segment, bytes)?;
+
+    let corrupt_key = wait_for(|| {
+        (0..10)
+            .map(|i| format!("key{:03}", i).into_bytes())
+            .find(|key| matches!(store.get(key), Err(KvError::Corruption { .. })))
+    })
+    .expect("corruption was not detected");
+    assert_eq!(corrupt_key, b"key009".to_vec());
+
+    for i in 0..9 {
+        let key = format!("key{:03}", i).into_bytes();
+        assert_eq!(store.get(&key)?, Some(b"value".to_vec()));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn reads_of_different_keys_run_in_parallel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::new(temp_dir.path())?;
+    store.set(b"held".to_vec(), b"value".to_vec())?;
+    store.flush_memtable()?;
+    store.set(b"other".to_vec(), b"value".to_vec())?;
+    store.flush_memtable()?;
+
+    // segments are named after when they were written, so the first one
+    // holds the first key
+    let mut segments = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect::<Vec<_>>();
+    assert_eq!(segments.len(), 2);
+    segments.sort_by_key(|path| {
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        stem.parse::<u128>().unwrap()
+    });
+
+    // swap that segment for a named pipe, so a read of the key blocks inside
+    // the store until the pipe is opened for writing
+    let held = segments[0].clone();
+    let aside = held.with_extension("aside");
+    std::fs::rename(&held, &aside)?;
+    let status = std::process::Command::new("mkfifo").arg(&held).status()?;
+    assert!(status.success());
+
+    let read = |key: &'static [u8]| {
+        let store = store.clone();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let reader = thread::spawn(move || sender.send(store.get(key).ok()).unwrap());
+        (reader, receiver)
+    };
+    let (held_reader, held_read) = read(b"held");
+    thread::sleep(Duration::from_millis(200));
+    let (other_reader, other_read) = read(b"other");
+    let other_value = other_read.recv_timeout(Duration::from_secs(5));
+    let held_finished = held_read.try_recv().is_ok();
+
+    // release the held read. It fails, as a pipe can't seek to the block.
+    drop(std::fs::OpenOptions::new().write(true).open(&held)?);
+    assert_eq!(held_read.recv().unwrap(), None);
+    held_reader.join().unwrap();
+    other_reader.join().unwrap();
+
+    assert!(!held_finished, "the first read wasn't held");
+    assert_eq!(
+        other_value,
+        Ok(Some(Some(b"value".to_vec()))),
+        "the read of another key waited on the held read"
+    );
+
+    std::fs::remove_file(&held)?;
+    std::fs::rename(&aside, &held)?;
+    assert_eq!(store.get(b"held")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+fn wait_for<T>(f: impl Fn() -> Option<T>) -> Option<T> {
+    for _ in 0..100 {
+        if let Some(t) = f() {
+            return Some(t);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    None
+}
+
+#[test]
+fn find_skips_removed_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+
+    store.set(b"cat".to_vec(), b"meow".to_vec())?;
+    store.set(b"cow".to_vec(), b"moo".to_vec())?;
+    store.remove(b"cat".to_vec())?;
+    assert_eq!(store.find(b"c*".to_vec())?, vec![b"cow".to_vec()]);
+
+    // removing a key that was already written to a segment must hide it too
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(100).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    store.set(b"cat".to_vec(), b"meow".to_vec())?;
+    for i in 0..10 {
+        store.set(format!("key{:03}", i).into_bytes(), b"value".to_vec())?;
+    }
+    store.remove(b"cat".to_vec())?;
+    assert!(store.find(b"c*".to_vec())?.is_empty());
+    assert_eq!(store.find(b"key*".to_vec())?.len(), 10);
+    Ok(())
+}
+
+#[test]
+fn find_ignoring_case() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(100).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+
+    store.set(b"cat".to_vec(), b"meow".to_vec())?;
+    for i in 0..10 {
+        store.set(format!("key{:03}", i).into_bytes(), b"value".to_vec())?;
+    }
+    store.set(b"Cab".to_vec(), b"taxi".to_vec())?;
+
+    let options = MatchOptions { ignore_case: true };
+    assert!(store.find(b"CAT".to_vec())?.is_empty());
+    assert_eq!(
+        store.find_with(b"CAT".to_vec(), options)?,
+        vec![b"cat".to_vec()]
+    );
+    let mut keys = store.find_with(b"ca_".to_vec(), options)?;
+    keys.sort();
+    assert_eq!(keys, vec![b"Cab".to_vec(), b"cat".to_vec()]);
+    Ok(())
+}
+
+#[test]
+fn find_iter_merges_segments_and_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(100).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+
+    for i in (0..200).rev() {
+        store.set(format!("key{:03}", i).into_bytes(), b"old".to_vec())?;
+    }
+    for i in (0..200).step_by(2) {
+        store.set(format!("key{:03}", i).into_bytes(), b"new".to_vec())?;
+    }
+    store.remove(b"key001".to_vec())?;
+    store.remove(b"key004".to_vec())?;
+    assert!(store.stats()?.segments_per_level.iter().sum::<usize>() > 1);
+
+    let first = store
+        .find_iter(b"key*".to_vec())?
+        .take(5)
+        .collect::<Result<Vec<_>>>()?;
+    let expected = ["key000", "key002", "key003", "key005", "key006"]
+        .iter()
+        .map(|key| key.as_bytes().to_vec())
+        .collect::<Vec<_>>();
+    assert_eq!(first, expected);
+    assert_eq!(store.find(b"key*".to_vec())?.len(), 198);
+    Ok(())
+}
+
+#[test]
+fn empty_value_is_not_a_removed_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .max_wal_size(100)
+        .compression(Compression::Zstd { level: 3 })
+        .build();
+    let store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    store.set(b"empty".to_vec(), vec![])?;
+    assert_eq!(store.get(b"empty")?, Some(vec![]));
+    assert_eq!(store.find(b"empty".to_vec())?, vec![b"empty".to_vec()]);
+
+    // push the empty value out of memory and into the segments
+    for i in 0..100 {
+        store.set(format!("key{:03}", i).into_bytes(), vec![])?;
+    }
+    store.remove(b"key000".to_vec())?;
+    assert!(store.stats()?.segments_per_level.iter().sum::<usize>() > 0);
+    assert_eq!(store.get(b"empty")?, Some(vec![]));
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get(b"empty")?, Some(vec![]));
+    assert_eq!(store.get(b"key001")?, Some(vec![]));
+    assert_eq!(store.get(b"key000")?, None);
+    assert_eq!(store.find(b"key*".to_vec())?.len(), 99);
+
+    let mut dump = vec![];
+    store.export(&mut dump)?;
+    let copy = KvStore::restore(TempDir::new().unwrap().path())?;
+    assert_eq!(copy.import(&dump[..])?, 100);
+    assert_eq!(copy.get(b"empty")?, Some(vec![]));
+    Ok(())
+}
+
+#[test]
+fn find_returns_sorted_unique_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(100).build();
+    let store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    // every key is written more than once so it is held by several segments
+    for round in 0..3 {
+        for i in [7, 3, 12, 0, 9, 1, 15, 4].iter() {
+            let value = format!("value{}", round).into_bytes();
+            store.set(format!("key{}", i).into_bytes(), value)?;
+        }
+    }
+
+    let keys = store.find(b"key*".to_vec())?;
+    let mut expected = keys.clone();
+    expected.sort();
+    expected.dedup();
+    assert_eq!(keys, expected);
+    assert_eq!(keys.len(), 8);
+    for _ in 0..5 {
+        assert_eq!(store.find(b"key*".to_vec())?, keys);
+    }
+    drop(store);
+
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    assert_eq!(store.find(b"key*".to_vec())?, keys);
+    Ok(())
+}
+
+#[test]
+fn find_paged_returns_stable_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(100).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    for i in (0..25).rev() {
+        store.set(format!("key{:02}", i).into_bytes(), b"value".to_vec())?;
+    }
+    store.set(b"other".to_vec(), b"value".to_vec())?;
+
+    let page = |offset, limit| {
+        store
+            .find_paged(b"key*".to_vec(), MatchOptions::default(), offset, limit)
+            .unwrap()
+    };
+    let mut pages = vec![];
+    for offset in (0..25).step_by(10) {
+        let keys = page(offset, Some(10));
+        assert_eq!(keys, page(offset, Some(10)));
+        pages.extend(keys);
+    }
+    assert_eq!(pages, store.find(b"key*".to_vec())?);
+    assert_eq!(page(20, Some(10)).len(), 5);
+    assert_eq!(page(20, None), page(20, Some(5)));
+    assert_eq!(page(10, Some(1)), vec![b"key10".to_vec()]);
+    // pages past the last key are empty rather than an error
+    assert!(page(25, Some(10)).is_empty());
+    assert!(page(100, None).is_empty());
+    assert!(page(0, Some(0)).is_empty());
+    Ok(())
+}
+
+#[test]
+fn subscribe_to_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+    let users = store.subscribe(b"user*".to_vec())?;
+    let everything = store.subscribe(b"*".to_vec())?;
+
+    store.set(b"user1".to_vec(), b"alec".to_vec())?;
+    store.set(b"post1".to_vec(), b"hello".to_vec())?;
+    store.remove(b"user1".to_vec())?;
+
+    let keys = users
+        .try_iter()
+        .map(|n| (n.key, n.value))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        keys,
+        vec![
+            (b"user1".to_vec(), Some(b"alec".to_vec())),
+            (b"user1".to_vec(), None)
+        ]
+    );
+    assert_eq!(everything.try_iter().count(), 3);
+
+    // a dropped subscriber no longer receives changes
+    drop(users);
+    store.set(b"user2".to_vec(), b"bob".to_vec())?;
+    assert_eq!(everything.try_iter().count(), 1);
+    Ok(())
+}
+
+#[test]
+fn subscribers_are_notified_once_the_write_is_logged() -> Result<()> {
+    for single_writer in [false, true].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = Config::builder()
+            .fsync_policy(FsyncPolicy::Always)
+            .single_writer(*single_writer)
+            .build();
+        let store = KvStore::with_config(temp_dir.path(), config)?;
+        let changes = store.subscribe(b"a*".to_vec())?;
+        let logged = {
+            let folder = temp_dir.path().to_path_buf();
+            thread::spawn(move || {
+                let mut keys = vec![];
+                for change in changes.iter().take(2) {
+                    // the key is already in the write-ahead-log when the
+                    // notification is sent
+                    let log = WalkDir::new(&folder)
+                        .into_iter()
+                        .filter_map(|entry| entry.ok())
+                        .filter(|entry| entry.path().extension() == Some("redo".as_ref()))
+                        .flat_map(|entry| std::fs::read(entry.path()).unwrap())
+                        .collect::<Vec<_>>();
+                    assert!(log
+                        .windows(change.key.len())
+                        .any(|w| w == change.key.as_slice()));
+                    keys.push(change.key);
+                }
+                keys
+            })
+        };
+        store.set(b"apple".to_vec(), b"red".to_vec())?;
+        store.set(b"banana".to_vec(), b"yellow".to_vec())?;
+        store.set(b"apricot".to_vec(), b"orange".to_vec())?;
+        let keys = logged.join().unwrap();
+        assert_eq!(keys, vec![b"apple".to_vec(), b"apricot".to_vec()]);
+    }
+    Ok(())
+}
+
+#[test]
+fn get_with_metadata_reports_newest_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+    assert!(store.get_with_metadata(b"key1")?.is_none());
+
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    let (value, first) = store.get_with_metadata(b"key1")?.unwrap();
+    assert_eq!(value, b"value1".to_vec());
+    assert_eq!(first.value_size, 6);
+
+    store.set(b"key1".to_vec(), b"longer value".to_vec())?;
+    let (_, second) = store.get_with_metadata(b"key1")?.unwrap();
+    assert!(second.timestamp > first.timestamp);
+    assert_eq!(second.value_size, 12);
+
+    // the metadata is kept once the record is written to a segment
+    store.flush_memtable()?;
+    assert_eq!(store.get_with_metadata(b"key1")?.unwrap().1, second);
+    store.remove(b"key1".to_vec())?;
+    assert!(store.get_with_metadata(b"key1")?.is_none());
+    Ok(())
+}
+
+#[test]
+fn last_write_wins_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(64).build();
+    let store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    for round in 0..5 {
+        for i in 0..1000 {
+            store.set(b"key".to_vec(), format!("{}-{}", round, i).into_bytes())?;
+        }
+        let last = format!("{}-999", round).into_bytes();
+        assert_eq!(store.get(b"key")?, Some(last.clone()));
+        store.compact()?;
+        assert_eq!(store.get(b"key")?, Some(last));
+    }
+    drop(store);
+
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get(b"key")?, Some(b"4-999".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn export_then_import_into_fresh_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(100).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    for i in 0..50 {
+        store.set(format!("key{:03}", i).into_bytes(), b"old".to_vec())?;
+    }
+    for i in (0..50).step_by(3) {
+        store.set(format!("key{:03}", i).into_bytes(), b"new".to_vec())?;
+    }
+    for i in (0..50).step_by(5) {
+        store.remove(format!("key{:03}", i).into_bytes())?;
+    }
+
+    let mut dump = vec![];
+    store.export(&mut dump)?;
+
+    let fresh_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fresh = KvStore::new(fresh_dir.path())?;
+    let imported = fresh.subscribe(b"*".to_vec())?;
+    assert_eq!(fresh.import(&dump[..])?, 40);
+    assert_eq!(imported.try_iter().count(), 40);
+    for i in 0..50 {
+        let key = format!("key{:03}", i).into_bytes();
+        assert_eq!(fresh.get(&key)?, store.get(&key)?);
+    }
+    assert_eq!(fresh.find(b"*".to_vec())?, store.find(b"*".to_vec())?);
+    Ok(())
+}
+
+#[test]
+fn import_survives_restart_and_truncated_dump() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::new(source_dir.path())?;
+    for i in 0..2000 {
+        let value = format!("value{}", i).repeat(10).into_bytes();
+        source.set(format!("key{:04}", i).into_bytes(), value)?;
+    }
+    let mut dump = vec![];
+    source.export(&mut dump)?;
+
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let target = KvStore::new(target_dir.path())?;
+        assert_eq!(target.import(&dump[..])?, 2000);
+    }
+    let target = KvStore::new(target_dir.path())?;
+    assert_eq!(target.find(b"*".to_vec())?, source.find(b"*".to_vec())?);
+    for i in 0..2000 {
+        let key = format!("key{:04}", i).into_bytes();
+        assert_eq!(target.get(&key)?, source.get(&key)?);
+    }
+
+    // the last entry is cut short, every entry before it still loads
+    let truncated_dir = TempDir::new().expect("unable to create temporary working directory");
+    let truncated = KvStore::new(truncated_dir.path())?;
+    assert_eq!(truncated.import(&dump[..dump.len() - 3])?, 1999);
+    assert_eq!(truncated.get(b"key1998")?, source.get(b"key1998")?);
+    assert_eq!(truncated.get(b"key1999")?, None);
+    Ok(())
+}
+
+#[test]
+fn compact_reclaims_removed_keys() -> Result<()> {
+    let segment_bytes = |dir: &std::path::Path| -> u64 {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(200).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+
+    for i in 0..60 {
+        store.set(format!("key{:03}", i).into_bytes(), b"value".to_vec())?;
+    }
+    store.compact()?;
+    let full = segment_bytes(temp_dir.path());
+
+    for i in (0..60).step_by(2) {
+        store.remove(format!("key{:03}", i).into_bytes())?;
+    }
+    store.compact()?;
+    let half = segment_bytes(temp_dir.path());
+    assert!(half < full, "{} bytes before, {} bytes after", full, half);
+
+    for i in 0..60 {
+        let key = format!("key{:03}", i).into_bytes();
+        let expected = if i % 2 == 0 {
+            None
+        } else {
+            Some(b"value".to_vec())
+        };
+        assert_eq!(store.get(&key)?, expected);
+    }
+    assert_eq!(store.find(b"key*".to_vec())?.len(), 30);
+    Ok(())
+}
+
+#[test]
+fn zstd_compressed_segments() -> Result<()> {
+    let segment_bytes = |dir: &std::path::Path| -> u64 {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let write_store = |compression: Compression| -> Result<(TempDir, u64)> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = Config::builder().compression(compression).build();
+        let store = KvStore::with_config(temp_dir.path(), config)?;
+        for i in 0..20 {
+            store.set(format!("key{:03}", i).into_bytes(), vec![b'x'; 100])?;
+        }
+        store.compact()?;
+        let size = segment_bytes(temp_dir.path());
+        Ok((temp_dir, size))
+    };
+
+    let compression = Compression::Zstd { level: 3 };
+    let (_, plain_size) = write_store(Compression::None)?;
+    let (temp_dir, compressed_size) = write_store(compression)?;
+    assert!(
+        compressed_size < plain_size,
+        "compressed {} bytes, uncompressed {} bytes",
+        compressed_size,
+        plain_size
+    );
+
+    // compressed segments are read back after a restart and can be merged
+    let config = Config::builder().compression(compression).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    for i in 0..20 {
+        let key = format!("key{:03}", i).into_bytes();
+        assert_eq!(store.get(&key)?, Some(vec![b'x'; 100]));
+    }
+    store.set(b"key005".to_vec(), b"new".to_vec())?;
+    store.compact()?;
+    assert_eq!(store.get(b"key005")?, Some(b"new".to_vec()));
+    assert_eq!(store.get(b"key006")?, Some(vec![b'x'; 100]));
+    assert_eq!(store.find(b"key*".to_vec())?.len(), 20);
+    Ok(())
+}
+
+#[test]
+fn max_value_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_value_size(8).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+
+    store.set(b"key1".to_vec(), vec![b'x'; 8])?;
+    match store.set(b"key2".to_vec(), vec![b'x'; 9]) {
+        Err(KvError::ValueTooLarge { size, limit }) => assert_eq!((size, limit), (9, 8)),
+        result => panic!("expected the value to be rejected, got {:?}", result),
+    }
+    assert_eq!(store.get(b"key1")?, Some(vec![b'x'; 8]));
+    assert_eq!(store.get(b"key2")?, None);
+    Ok(())
+}
+
+#[test]
+fn restore_wal_with_torn_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::new(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+
+    // a crash in the middle of a write leaves part of a record at the end
+    let wal = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().extension() == Some("redo".as_ref()))
+        .expect("redo log was not written")
+        .into_path();
+    let mut bytes = std::fs::read(&wal)?;
+    let torn = bytes[..10].to_vec();
+    bytes.extend(torn);
+    std::fs::write(&wal, bytes)?;
+
+    let store = KvStore::new(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2")?, Some(b"value2".to_vec()));
+
+    // new writes land after the records that were recovered
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    assert_eq!(store.get(b"key3")?, Some(b"value3".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn strict_restore_reports_corrupt_wal_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::new(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+
+    // flip a byte of a value so the record no longer matches its checksum
+    let wal = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().extension() == Some("redo".as_ref()))
+        .expect("redo log was not written")
+        .into_path();
+    let mut bytes = std::fs::read(&wal)?;
+    let at = bytes
+        .windows(6)
+        .position(|window| window == b"value2")
+        .expect("value was not written to the redo log");
+    bytes[at] = b'V';
+    std::fs::write(&wal, bytes)?;
+
+    let strict = Config::builder().strict(true).build();
+    match KvStore::with_config(temp_dir.path(), strict) {
+        Err(KvError::Corruption { key, .. }) => assert_eq!(key, Some(b"key2".to_vec())),
+        Err(e) => panic!("expected a corruption error, got {}", e),
+        Ok(_) => panic!("corrupt record was restored in strict mode"),
+    }
+
+    // by default the corrupt record is skipped
+    let store = KvStore::new(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2")?, None);
+    Ok(())
+}
+
+#[test]
+fn corrupt_record_in_the_middle_of_a_wal_is_not_a_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::new(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+
+    // break the first record so it can't be read at all, the record after
+    // it is still whole
+    let wal = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().extension() == Some("redo".as_ref()))
+        .expect("redo log was not written")
+        .into_path();
+    let mut bytes = std::fs::read(&wal)?;
+    let at = bytes
+        .windows(4)
+        .position(|window| window == b"key1")
+        .expect("key was not written to the redo log");
+    bytes[at + 4] = 0xff;
+    std::fs::write(&wal, bytes)?;
+
+    let strict = Config::builder().strict(true).build();
+    match KvStore::with_config(temp_dir.path(), strict) {
+        Err(KvError::CorruptLog(_)) => {}
+        Err(e) => panic!("expected a corrupt log error, got {}", e),
+        Ok(_) => panic!("corrupt log was restored in strict mode"),
+    }
+    assert!(wal.exists());
+
+    // by default the store opens, but the damaged log is kept for recovery
+    let store = KvStore::new(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, None);
+    assert!(!wal.exists());
+    assert!(wal.with_extension("corrupt").exists());
+    drop(store);
+    Ok(())
+}
+
+#[test]
+fn restore_multiple_redo_logs() -> Result<()> {
+    let redo_logs = |dir: &std::path::Path| -> Vec<std::path::PathBuf> {
+        WalkDir::new(dir)
+            .max_depth(1)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("redo".as_ref()))
+            .map(|entry| entry.into_path())
+            .collect()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::new(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"old".to_vec())?;
+    store.set(b"key2".to_vec(), b"old".to_vec())?;
+    drop(store);
+
+    // plant a second, newer redo log as if a crash had left it behind
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::new(other_dir.path())?;
+    store.set(b"key2".to_vec(), b"new".to_vec())?;
+    store.set(b"key3".to_vec(), b"new".to_vec())?;
+    drop(store);
+    for log in redo_logs(other_dir.path()) {
+        std::fs::copy(&log, temp_dir.path().join(log.file_name().unwrap()))?;
+    }
+    assert_eq!(redo_logs(temp_dir.path()).len(), 2);
+
+    let store = KvStore::new(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"old".to_vec()));
+    assert_eq!(store.get(b"key2")?, Some(b"new".to_vec()));
+    assert_eq!(store.get(b"key3")?, Some(b"new".to_vec()));
+    assert_eq!(redo_logs(temp_dir.path()).len(), 1);
+    Ok(())
+}
+
+#[test]
+fn single_writer_acked_writes_survive_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .fsync_policy(FsyncPolicy::Always)
+        .single_writer(true)
+        .build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    let writers = (0..8)
+        .map(|thread| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", thread, i).into_bytes();
+                    store.set(key, format!("value{}", i).into_bytes())?;
+                }
+                store.remove(format!("key{}-0", thread).into_bytes())
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    drop(store);
+
+    let store = KvStore::new(temp_dir.path())?;
+    for thread in 0..8 {
+        assert_eq!(store.get(format!("key{}-0", thread).as_bytes())?, None);
+        for i in 1..50 {
+            let key = format!("key{}-{}", thread, i).into_bytes();
+            assert_eq!(store.get(&key)?, Some(format!("value{}", i).into_bytes()));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn fsync_policies_survive_restart() -> Result<()> {
+    let policies = [
+        FsyncPolicy::Always,
+        FsyncPolicy::EveryN(2),
+        FsyncPolicy::Interval(Duration::from_millis(10)),
+    ];
+    for policy in policies.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = Config::builder().fsync_policy(*policy).build();
+        let store = KvStore::with_config(temp_dir.path(), config)?;
+        for i in 0..5 {
+            store.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+        }
+        drop(store);
+
+        let store = KvStore::new(temp_dir.path())?;
+        for i in 0..5 {
+            let key = format!("key{}", i).into_bytes();
+            assert_eq!(store.get(&key)?, Some(b"value".to_vec()), "{:?}", policy);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn keys_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+
+    // a zero ttl expires right away
+    store.set_with_ttl(b"gone".to_vec(), b"value".to_vec(), Duration::from_secs(0))?;
+    assert_eq!(store.get(b"gone")?, None);
+
+    store.set_with_ttl(
+        b"kept".to_vec(),
+        b"value".to_vec(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get(b"kept")?, Some(b"value".to_vec()));
+
+    store.set_with_ttl(
+        b"short".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(50),
+    )?;
+    assert_eq!(store.find(b"short".to_vec())?, vec![b"short".to_vec()]);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get(b"short")?, None);
+    assert!(store.find(b"short".to_vec())?.is_empty());
+
+    // the expiry survives a restart
+    drop(store);
+    let store = KvStore::restore(temp_dir.path())?;
+    assert_eq!(store.get(b"kept")?, Some(b"value".to_vec()));
+    assert_eq!(store.get(b"short")?, None);
+    Ok(())
+}
+
+#[test]
+fn expired_keys_are_not_found_or_counted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+
+    store.set(b"session:1".to_vec(), b"value".to_vec())?;
+    store.set_with_ttl(
+        b"session:2".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(50),
+    )?;
+    assert_eq!(store.count(b"session:*".to_vec())?, 2);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        store.find(b"session:*".to_vec())?,
+        vec![b"session:1".to_vec()]
+    );
+    assert_eq!(store.count(b"session:*".to_vec())?, 1);
+
+    // an expired record moved into a segment is still skipped
+    store.compact()?;
+    assert_eq!(
+        store.find(b"session:*".to_vec())?,
+        vec![b"session:1".to_vec()]
+    );
+    assert_eq!(store.count(b"session:*".to_vec())?, 1);
+    Ok(())
+}
+
+#[test]
+fn sweeper_removes_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .expiration_sweep_interval(Duration::from_millis(50))
+        .build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+
+    store.set(b"kept".to_vec(), b"value".to_vec())?;
+    for i in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", i).into_bytes(),
+            vec![b'x'; 100],
+            Duration::from_millis(500),
+        )?;
+    }
+    // move every record out of the write-ahead-log so only the sweeper
+    // writes to it. The ttl leaves room for this to finish on a busy machine,
+    // otherwise the keys would expire before reaching the segment.
+    store.compact()?;
+    let before = store.stats()?;
+
+    thread::sleep(Duration::from_millis(1000));
+    assert!(store.stats()?.wal_size > before.wal_size);
+
+    store.compact()?;
+    let after = store.stats()?;
+    assert!(after.disk_size < before.disk_size);
+    assert_eq!(store.find(b"*".to_vec())?, vec![b"kept".to_vec()]);
+    assert_eq!(store.get(b"kept")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn expired_key_hides_older_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_wal_size(100).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+
+    // push the permanent value out of the write-ahead-log into a segment
+    store.set(b"key".to_vec(), b"forever".to_vec())?;
+    for i in 0..10 {
+        store.set(format!("filler{:03}", i).into_bytes(), b"value".to_vec())?;
+    }
+    store.set_with_ttl(b"key".to_vec(), b"brief".to_vec(), Duration::from_secs(0))?;
+    assert_eq!(store.get(b"key")?, None);
+    assert!(store.find(b"key".to_vec())?.is_empty());
+
+    store.remove(b"filler000".to_vec())?;
+    assert_eq!(store.get(b"filler000")?, None);
+    Ok(())
+}
+
+#[test]
+fn read_only_store_leaves_files_untouched() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let store = KvStore::restore(temp_dir.path())?;
+        store.set(b"key1".to_vec(), b"value1".to_vec())?;
+        store.flush_memtable()?;
+        // key2 is only held by the write-ahead-log
+        store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    }
+    let files = || -> Vec<(std::path::PathBuf, u64)> {
+        let mut files = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| (entry.path().to_owned(), entry.metadata().unwrap().len()))
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+    let before = files();
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2")?, Some(b"value2".to_vec()));
+    assert_eq!(
+        store.find(b"key*".to_vec())?,
+        vec![b"key1".to_vec(), b"key2".to_vec()]
+    );
+    assert!(matches!(
+        store.set(b"key3".to_vec(), b"value3".to_vec()),
+        Err(KvError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove(b"key1".to_vec()),
+        Err(KvError::ReadOnly)
+    ));
+    assert!(matches!(store.compact(), Err(KvError::ReadOnly)));
+    assert_eq!(files(), before);
+    drop(store);
+    assert_eq!(files(), before);
+    Ok(())
+}
+
+fn set_if_absent_inserts_once<E: KvsEngine + 'static>(store: E) -> Result<()> {
+    assert!(store.set_if_absent(b"key1".to_vec(), b"value1".to_vec())?);
+    assert!(!store.set_if_absent(b"key1".to_vec(), b"value2".to_vec())?);
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+
+    store.remove(b"key1".to_vec())?;
+    assert!(store.set_if_absent(b"key1".to_vec(), b"value3".to_vec())?);
+    assert_eq!(store.get(b"key1")?, Some(b"value3".to_vec()));
+
+    // exactly one of many threads racing on the same key wins
+    let barrier = Arc::new(Barrier::new(16));
+    let racers = (0..16)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store
+                    .set_if_absent(b"race".to_vec(), format!("{}", i).into_bytes())
+                    .unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+    let winners = racers
+        .into_iter()
+        .map(|racer| racer.join().unwrap())
+        .filter(|won| *won)
+        .count();
+    assert_eq!(winners, 1);
+    assert!(store.get(b"race")?.is_some());
+    Ok(())
+}
+
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+    set_if_absent_inserts_once(store.clone())?;
+
+    // a key only held by a segment is still present
+    store.flush_memtable()?;
+    assert!(!store.set_if_absent(b"key1".to_vec(), b"value4".to_vec())?);
+    store.set_with_ttl(b"gone".to_vec(), b"value".to_vec(), Duration::from_secs(0))?;
+    assert!(store.set_if_absent(b"gone".to_vec(), b"value".to_vec())?);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_if_absent_inserts_once(SledKvsEngine::restore(temp_dir.path())?)?;
+    set_if_absent_inserts_once(KvInMemoryStore::new())
+}
+
+fn delete_range_keeps_end<E: KvsEngine>(store: E) -> Result<()> {
+    for key in [&b"a"[..], b"ab", b"b", b"c", b"ca"].iter() {
+        store.set(key.to_vec(), b"value".to_vec())?;
+    }
+    assert_eq!(store.delete_range(b"a", b"c")?, 3);
+    assert_eq!(store.get(b"a")?, None);
+    assert_eq!(store.get(b"ab")?, None);
+    assert_eq!(store.get(b"b")?, None);
+    assert_eq!(store.get(b"c")?, Some(b"value".to_vec()));
+    assert_eq!(
+        store.find(b"*".to_vec())?,
+        vec![b"c".to_vec(), b"ca".to_vec()]
+    );
+    assert_eq!(store.delete_range(b"a", b"c")?, 0);
+    assert_eq!(store.delete_range(b"d", b"a")?, 0);
+    Ok(())
+}
+
+#[test]
+fn delete_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+    delete_range_keeps_end(store.clone())?;
+
+    // keys held by segments are removed as well
+    store.set(b"b1".to_vec(), b"value".to_vec())?;
+    store.set(b"b2".to_vec(), b"value".to_vec())?;
+    store.flush_memtable()?;
+    assert_eq!(store.delete_range(b"b", b"b2")?, 1);
+    drop(store);
+    let store = KvStore::restore(temp_dir.path())?;
+    assert_eq!(
+        store.find(b"*".to_vec())?,
+        vec![b"b2".to_vec(), b"c".to_vec(), b"ca".to_vec()]
+    );
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    delete_range_keeps_end(SledKvsEngine::restore(temp_dir.path())?)?;
+    delete_range_keeps_end(KvInMemoryStore::new())
+}
+
+fn delete_prefix_removes_matching_keys<E: KvsEngine>(store: E) -> Result<()> {
+    let keys = [
+        &b"user:1:name"[..],
+        b"user:1:email",
+        b"user:10:name",
+        b"user:2:name",
+        b"\xff",
+        b"\xff\xff",
+        b"a\xff",
+        b"a\xff\x01",
+        b"b",
+    ];
+    for key in keys.iter() {
+        store.set(key.to_vec(), b"value".to_vec())?;
+    }
+
+    assert_eq!(store.delete_prefix(b"user:1:")?, 2);
+    assert_eq!(store.get(b"user:1:name")?, None);
+    assert_eq!(store.get(b"user:10:name")?, Some(b"value".to_vec()));
+    assert_eq!(store.delete_prefix(b"nobody:")?, 0);
+
+    // prefixes ending in 0xFF can't have their last byte incremented
+    assert_eq!(store.delete_prefix(b"a\xff")?, 2);
+    assert_eq!(store.get(b"b")?, Some(b"value".to_vec()));
+    assert_eq!(store.delete_prefix(b"\xff")?, 2);
+    assert_eq!(
+        store.find(b"*".to_vec())?,
+        vec![
+            b"b".to_vec(),
+            b"user:10:name".to_vec(),
+            b"user:2:name".to_vec()
+        ]
+    );
+
+    // an empty prefix matches every key
+    assert_eq!(store.delete_prefix(b"")?, 3);
+    assert!(store.find(b"*".to_vec())?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn delete_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    delete_prefix_removes_matching_keys(KvStore::restore(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    delete_prefix_removes_matching_keys(SledKvsEngine::restore(temp_dir.path())?)?;
+    delete_prefix_removes_matching_keys(KvInMemoryStore::new())
+}
+
+fn get_and_set_loses_no_update<E: KvsEngine + 'static>(store: E) -> Result<()> {
+    assert_eq!(store.get_and_set(b"key".to_vec(), b"1".to_vec())?, None);
+    assert_eq!(
+        store.get_and_set(b"key".to_vec(), b"2".to_vec())?,
+        Some(b"1".to_vec())
+    );
+
+    // every value written is seen as the previous value by exactly one swap,
+    // apart from the value left in the store at the end
+    let barrier = Arc::new(Barrier::new(8));
+    let swappers = (0..8)
+        .map(|thread| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                (0..50)
+                    .map(|i| {
+                        let value = format!("{}-{}", thread, i).into_bytes();
+                        store.get_and_set(b"token".to_vec(), value).unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut seen = swappers
+        .into_iter()
+        .flat_map(|swapper| swapper.join().unwrap())
+        .collect::<Vec<_>>();
+    seen.push(store.get(b"token")?);
+    seen.sort();
+    let mut expected = vec![None];
+    for thread in 0..8 {
+        for i in 0..50 {
+            expected.push(Some(format!("{}-{}", thread, i).into_bytes()));
+        }
+    }
+    expected.sort();
+    assert_eq!(seen, expected);
+    Ok(())
+}
+
+#[test]
+fn get_and_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+    get_and_set_loses_no_update(store.clone())?;
+
+    // the previous value is found once it has been written to a segment
+    store.flush_memtable()?;
+    assert_eq!(
+        store.get_and_set(b"key".to_vec(), b"3".to_vec())?,
+        Some(b"2".to_vec())
+    );
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    get_and_set_loses_no_update(SledKvsEngine::restore(temp_dir.path())?)?;
+    get_and_set_loses_no_update(KvInMemoryStore::new())
+}
+
+fn append_keeps_every_chunk<E: KvsEngine + 'static>(store: E) -> Result<()> {
+    assert_eq!(store.append(b"key".to_vec(), b"one")?, 3);
+    assert_eq!(store.append(b"key".to_vec(), b",two")?, 7);
+    assert_eq!(store.append(b"key".to_vec(), b",three")?, 13);
+    assert_eq!(store.get(b"key")?, Some(b"one,two,three".to_vec()));
+
+    // every chunk is 8 bytes long and starts on a multiple of 8, so an
+    // interleaved append would leave a chunk that isn't one of the expected
+    let barrier = Arc::new(Barrier::new(8));
+    let appenders = (0..8)
+        .map(|thread| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..25 {
+                    let chunk = format!("<{}:{:04}>", thread, i).into_bytes();
+                    store.append(b"log".to_vec(), &chunk).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for appender in appenders {
+        appender.join().unwrap();
+    }
+    let log = store.get(b"log")?.unwrap();
+    assert_eq!(log.len(), 8 * 25 * 8);
+    let mut chunks = log
+        .chunks(8)
+        .map(|chunk| String::from_utf8(chunk.to_vec()).unwrap())
+        .collect::<Vec<_>>();
+    chunks.sort();
+    let mut expected = (0..8)
+        .flat_map(|thread| (0..25).map(move |i| format!("<{}:{:04}>", thread, i)))
+        .collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(chunks, expected);
+    Ok(())
+}
+
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+    append_keeps_every_chunk(store.clone())?;
+
+    // appending to a value held in a segment keeps the value after a restart
+    store.flush_memtable()?;
+    assert_eq!(store.append(b"key".to_vec(), b",four")?, 18);
+    drop(store);
+    let store = KvStore::restore(temp_dir.path())?;
+    assert_eq!(store.get(b"key")?, Some(b"one,two,three,four".to_vec()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    append_keeps_every_chunk(SledKvsEngine::restore(temp_dir.path())?)?;
+    append_keeps_every_chunk(KvInMemoryStore::new())
+}
+
+#[test]
+fn value_larger_than_a_block() -> Result<()> {
+    for compression in [Compression::None, Compression::Zstd { level: 3 }].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = Config::builder()
+            .block_size(256)
+            .compression(*compression)
+            .build();
+        let large = (0..256 * 6).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let store = KvStore::with_config(temp_dir.path(), config.clone())?;
+        store.set(b"a".to_vec(), b"small".to_vec())?;
+        store.set(b"b".to_vec(), large.clone())?;
+        store.set(b"c".to_vec(), b"small".to_vec())?;
+        store.flush_memtable()?;
+        assert_eq!(store.get(b"b")?, Some(large.clone()));
+        drop(store);
+
+        let store = KvStore::with_config(temp_dir.path(), config)?;
+        assert_eq!(store.get(b"a")?, Some(b"small".to_vec()));
+        assert_eq!(store.get(b"b")?, Some(large));
+        assert_eq!(store.get(b"c")?, Some(b"small".to_vec()));
+    }
+    Ok(())
+}
+
+/// Store with keys `key00` to `key29` spread over a deeper level, a segment
+/// of the first level and the memory table
+fn layered_store(temp_dir: &TempDir) -> Result<KvStore> {
+    let config = Config::builder().block_size(128).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    for i in 0..30 {
+        store.set(format!("key{:02}", i).into_bytes(), b"v1".to_vec())?;
+    }
+    store.flush_memtable()?;
+    store.compact()?;
+    for i in 10..20 {
+        store.set(format!("key{:02}", i).into_bytes(), b"v2".to_vec())?;
+    }
+    store.remove(b"key05".to_vec())?;
+    store.flush_memtable()?;
+    store.set(b"key15".to_vec(), b"v3".to_vec())?;
+    store.remove(b"key25".to_vec())?;
+    Ok(store)
+}
+
+#[test]
+fn iter_walks_every_key_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = layered_store(&temp_dir)?;
+    let entries = store.iter().collect::<Result<Vec<_>>>()?;
+    let keys = entries
+        .iter()
+        .map(|(key, _)| String::from_utf8(key.clone()).unwrap())
+        .collect::<Vec<_>>();
+    let expected = (0..30)
+        .filter(|i| *i != 5 && *i != 25)
+        .map(|i| format!("key{:02}", i))
+        .collect::<Vec<_>>();
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+#[test]
+fn iter_keeps_the_newest_value_across_levels() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = layered_store(&temp_dir)?;
+    for entry in store.iter() {
+        let (key, value) = entry?;
+        let i = String::from_utf8(key[3..].to_vec())
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        let expected: &[u8] = match i {
+            15 => b"v3",
+            10..=19 => b"v2",
+            _ => b"v1",
+        };
+        assert_eq!(value, expected, "key{:02}", i);
+    }
+    Ok(())
+}
+
+#[test]
+fn iter_seeks_to_the_first_key_at_or_after() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = layered_store(&temp_dir)?;
+    let mut iter = store.iter();
+    assert_eq!(iter.next().transpose()?.unwrap().0, b"key00");
+
+    iter.seek(b"key12");
+    assert_eq!(
+        iter.next().transpose()?,
+        Some((b"key12".to_vec(), b"v2".to_vec()))
+    );
+    assert_eq!(iter.next().transpose()?.unwrap().0, b"key13");
+
+    // a key between two keys, and a removed key, move to the next one
+    iter.seek(b"key14a");
+    assert_eq!(
+        iter.next().transpose()?,
+        Some((b"key15".to_vec(), b"v3".to_vec()))
+    );
+    iter.seek(b"key05");
+    assert_eq!(iter.next().transpose()?.unwrap().0, b"key06");
+
+    // seeking backwards works as well
+    iter.seek(b"");
+    assert_eq!(iter.next().transpose()?.unwrap().0, b"key00");
+    iter.seek(b"key29a");
+    assert!(iter.next().is_none());
+    Ok(())
+}
+
+#[test]
+fn reverse_scan_is_the_forward_scan_reversed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = layered_store(&temp_dir)?;
+    let ranges: &[(&[u8], &[u8])] = &[
+        (b"key03", b"key27"),
+        (b"key04a", b"key25"),
+        (b"", b"key99"),
+        (b"key10", b"key10"),
+    ];
+    for (start, end) in ranges.iter() {
+        let mut forward = store.iter();
+        forward.seek(start);
+        let mut forward = forward
+            .take_while(|entry| !matches!(entry, Ok((key, _)) if key.as_slice() >= *end))
+            .collect::<Result<Vec<_>>>()?;
+        let reverse = store.scan_rev(start, end).collect::<Result<Vec<_>>>()?;
+        forward.reverse();
+        assert_eq!(forward, reverse);
+    }
+    let reverse = store
+        .scan_rev(b"key03", b"key27")
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(reverse.len(), 22);
+    assert_eq!(reverse[0], (b"key26".to_vec(), b"v1".to_vec()));
+    assert_eq!(reverse[10], (b"key15".to_vec(), b"v3".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn reverse_scan_seeks_to_the_last_key_at_or_before() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = layered_store(&temp_dir)?;
+    let mut iter = store.scan_rev(b"key02", b"key99");
+    assert_eq!(iter.next().transpose()?.unwrap().0, b"key29");
+
+    iter.seek(b"key15");
+    assert_eq!(
+        iter.next().transpose()?,
+        Some((b"key15".to_vec(), b"v3".to_vec()))
+    );
+    iter.seek(b"key25");
+    assert_eq!(iter.next().transpose()?.unwrap().0, b"key24");
+    iter.seek(b"key06a");
+    assert_eq!(iter.next().transpose()?.unwrap().0, b"key06");
+    assert_eq!(iter.next().transpose()?.unwrap().0, b"key04");
+    assert_eq!(iter.next().transpose()?.unwrap().0, b"key03");
+    assert_eq!(iter.next().transpose()?.unwrap().0, b"key02");
+    assert!(iter.next().is_none());
+    Ok(())
+}
+
+#[test]
+fn iter_ignores_later_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = layered_store(&temp_dir)?;
+    let iter = store.iter();
+    store.set(b"key05".to_vec(), b"new".to_vec())?;
+    store.remove(b"key00".to_vec())?;
+    let keys = iter
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys.len(), 28);
+    assert_eq!(keys[0], b"key00");
+    assert!(!keys.contains(&b"key05".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn large_values_are_compressed_on_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let small = b"small value".to_vec();
+    let large = b"a large value that repeats itself ".repeat(1024);
+    {
+        let config = Config::builder().compress_min_bytes(64).build();
+        let store = KvStore::with_config(temp_dir.path(), config)?;
+        store.set(b"small".to_vec(), small.clone())?;
+        store.set(b"large".to_vec(), large.clone())?;
+        assert_eq!(store.get(b"small")?, Some(small.clone()));
+        assert_eq!(store.get(b"large")?, Some(large.clone()));
+        store.flush_memtable()?;
+        assert_eq!(store.get(b"small")?, Some(small.clone()));
+        assert_eq!(store.get(b"large")?, Some(large.clone()));
+    }
+
+    // every file of the store together is smaller than the large value
+    let stored = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum::<u64>();
+    assert!(stored < large.len() as u64, "{} bytes stored", stored);
+
+    // values are read back the same without the setting
+    let store = KvStore::restore(temp_dir.path())?;
+    assert_eq!(store.get(b"small")?, Some(small));
+    assert_eq!(store.get(b"large")?, Some(large));
+    Ok(())
+}
+
+#[test]
+fn durable_set_survives_a_crash() -> Result<()> {
+    for single_writer in [false, true].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = || {
+            Config::builder()
+                .fsync_policy(FsyncPolicy::Never)
+                .single_writer(*single_writer)
+                .build()
+        };
+        let store = KvStore::with_config(temp_dir.path(), config())?;
+        store.set(b"best effort".to_vec(), b"value".to_vec())?;
+        store.set_durable(b"critical".to_vec(), b"value".to_vec())?;
+        // the store goes away without being dropped, like a crashed process
+        std::mem::forget(store);
+
+        let store = KvStore::with_config(temp_dir.path(), config())?;
+        assert_eq!(store.get(b"critical")?, Some(b"value".to_vec()));
+    }
+    Ok(())
+}
+
+#[test]
+fn snapshot_ignores_later_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{:02}", i).into_bytes(), b"old".to_vec())?;
+    }
+    // half of the keys are held by a segment and half by the memory table
+    store.flush_memtable()?;
+    for i in 10..20 {
+        store.set(format!("key{:02}", i).into_bytes(), b"recent".to_vec())?;
+    }
+    let snapshot = store.snapshot()?;
+
+    store.set(b"key00".to_vec(), b"new".to_vec())?;
+    store.set(b"key15".to_vec(), b"new".to_vec())?;
+    store.remove(b"key01".to_vec())?;
+    store.set(b"key20".to_vec(), b"new".to_vec())?;
+    // the segments read by the snapshot are merged away and deleted
+    store.compact()?;
+
+    assert_eq!(store.get(b"key00")?, Some(b"new".to_vec()));
+    assert_eq!(store.get(b"key01")?, None);
+    assert_eq!(store.get(b"key15")?, Some(b"new".to_vec()));
+    assert_eq!(store.get(b"key20")?, Some(b"new".to_vec()));
+
+    assert_eq!(snapshot.get(b"key00")?, Some(b"old".to_vec()));
+    assert_eq!(snapshot.get(b"key01")?, Some(b"old".to_vec()));
+    assert_eq!(snapshot.get(b"key15")?, Some(b"recent".to_vec()));
+    assert_eq!(snapshot.get(b"key20")?, None);
+    let keys = snapshot.find(b"key*".to_vec())?;
+    assert_eq!(keys.len(), 20);
+    assert!(!keys.contains(&b"key20".to_vec()));
+    let entries = snapshot
+        .scan(b"key1*".to_vec())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 10);
+    assert!(entries.iter().all(|(_, value)| value == b"recent"));
+
+    // the old segments are deleted once the snapshot is dropped
+    let files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    let pinned = files();
+    drop(snapshot);
+    assert!(files() < pinned);
+    Ok(())
+}
+
+#[test]
+fn restore_from_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .max_wal_size(200)
+        .compaction_fanout(1)
+        .build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    // enough writes to be merged into a second level, plus some left in
+    // memory
+    for i in 0..300 {
+        store.set(format!("key{:03}", i).into_bytes(), b"before".to_vec())?;
+    }
+    store.remove(b"key007".to_vec())?;
+    // wait for the background merges to move segments into deeper levels
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.stats()?.segments_per_level.len() < 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(store.stats()?.segments_per_level.len() > 1);
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = backup_dir.path().join("backup");
+    store.backup(&backup)?;
+    for i in 0..10 {
+        store.set(format!("key{:03}", i).into_bytes(), b"after".to_vec())?;
+    }
+    store.set(b"later".to_vec(), b"after".to_vec())?;
+    store.compact()?;
+
+    // a backup is never written over an existing one
+    assert!(store.backup(&backup).is_err());
+
+    let restored = KvStore::new(&backup)?;
+    assert_eq!(restored.get(b"later")?, None);
+    assert_eq!(restored.get(b"key007")?, None);
+    for i in (0..300).filter(|i| *i != 7) {
+        let value = restored.get(format!("key{:03}", i).as_bytes())?;
+        assert_eq!(value, Some(b"before".to_vec()));
+    }
+    assert_eq!(restored.find(b"*".to_vec())?.len(), 299);
+    assert_eq!(store.get(b"key000")?, Some(b"after".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn verify_reports_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::new(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{:03}", i).into_bytes(), b"value".to_vec())?;
+    }
+    store.flush_memtable()?;
+    store.set(b"recent1".to_vec(), b"value".to_vec())?;
+    store.set(b"recent2".to_vec(), b"value".to_vec())?;
+    assert_eq!(
+        store.verify()?,
+        VerifyReport {
+            good: 12,
+            corrupt: vec![]
+        }
+    );
+
+    // flip the last byte before the segment's footer, which belongs to the
+    // value of the last record written to it
+    let segment = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().extension() == Some("log".as_ref()))
+        .unwrap()
+        .into_path();
+    let mut bytes = std::fs::read(&segment)?;
+    let mut offset = [0; 8];
+    offset.copy_from_slice(&bytes[bytes.len() - 16..bytes.len() - 8]);
+    let footer_start = u64::from_be_bytes(offset) as usize;
+    bytes[footer_start - 1] ^= 0xff;
+    std::fs::write(&segment, bytes)?;
+
+    assert_eq!(
+        store.verify()?,
+        VerifyReport {
+            good: 11,
+            corrupt: vec![b"key009".to_vec()]
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn count_matches_find() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = layered_store(&temp_dir)?;
+    for pattern in ["*", "key1*", "key2?", "key0*", "*5", "nothing*"].iter() {
+        let pattern = pattern.as_bytes().to_vec();
+        assert_eq!(
+            store.count(pattern.clone())?,
+            store.find(pattern.clone())?.len(),
+            "{}",
+            String::from_utf8_lossy(&pattern)
+        );
+    }
+    // keys rewritten in later levels are counted once, removed keys not at all
+    assert_eq!(store.count(b"*".to_vec())?, 28);
+    assert_eq!(store.count(b"key1*".to_vec())?, 10);
+    Ok(())
+}
+
+#[test]
+fn stores_read_back_in_every_serialization_format() -> Result<()> {
+    for format in [
+        SerializationFormat::Bincode,
+        SerializationFormat::MessagePack,
+    ]
+    .iter()
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        {
+            let config = Config::builder()
+                .block_size(128)
+                .serialization_format(*format)
+                .build();
+            let store = KvStore::with_config(temp_dir.path(), config)?;
+            for i in 0..30 {
+                store.set(format!("key{:02}", i).into_bytes(), b"v1".to_vec())?;
+            }
+            store.flush_memtable()?;
+            store.compact()?;
+            store.set(b"key10".to_vec(), b"v2".to_vec())?;
+            store.set_with_ttl(b"key11".to_vec(), b"v2".to_vec(), Duration::from_secs(3600))?;
+            store.remove(b"key05".to_vec())?;
+            store.flush_memtable()?;
+            store.set(b"key15".to_vec(), b"v3".to_vec())?;
+        }
+        assert!(temp_dir.path().join("MANIFEST").exists());
+
+        // settings that aren't asked for are taken from the manifest
+        let store = KvStore::new(temp_dir.path())?;
+        assert_eq!(store.get(b"key00")?, Some(b"v1".to_vec()));
+        assert_eq!(store.get(b"key05")?, None);
+        assert_eq!(store.get(b"key10")?, Some(b"v2".to_vec()));
+        assert_eq!(store.get(b"key11")?, Some(b"v2".to_vec()));
+        assert_eq!(store.get(b"key15")?, Some(b"v3".to_vec()));
+        assert_eq!(store.find(b"key*".to_vec())?.len(), 29);
+        assert!(store.verify()?.corrupt.is_empty(), "{:?}", format);
+
+        let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+        store.backup(backup_dir.path().join("copy"))?;
+        let copy = KvStore::new(backup_dir.path().join("copy"))?;
+        assert_eq!(copy.get(b"key15")?, Some(b"v3".to_vec()));
+        assert_eq!(copy.find(b"key*".to_vec())?.len(), 29);
+    }
+    Ok(())
+}
+
+#[test]
+fn new_store_writes_its_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .block_size(256)
+        .compression(Compression::Zstd { level: 3 })
+        .serialization_format(SerializationFormat::MessagePack)
+        .build();
+    KvStore::with_config(temp_dir.path(), config)?;
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(temp_dir.path().join("MANIFEST"))?)?;
+    assert_eq!(manifest["version"], 2);
+    assert_eq!(manifest["block_size"], 256);
+    assert_eq!(manifest["compression"]["Zstd"]["level"], 3);
+    assert_eq!(manifest["bloom_fp_rate"], 0.001);
+    assert_eq!(manifest["serialization_format"], "MessagePack");
+    // the logs and segments are kept in the store's folder
+    assert!(manifest["wal_dir"].is_null());
+    assert!(manifest["segment_dir"].is_null());
+
+    // a store opened without asking for any of them takes them all
+    let store = KvStore::new(temp_dir.path())?;
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    store.flush_memtable()?;
+    assert_eq!(store.get(b"key")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn reopening_with_other_settings_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let config = Config::builder().block_size(256).build();
+        let store = KvStore::with_config(temp_dir.path(), config)?;
+        store.set(b"key".to_vec(), b"value".to_vec())?;
+    }
+
+    let mismatched = [
+        ("block_size", Config::builder().block_size(128).build()),
+        (
+            "compression",
+            Config::builder()
+                .compression(Compression::Zstd { level: 3 })
+                .build(),
+        ),
+        (
+            "serialization_format",
+            Config::builder()
+                .serialization_format(SerializationFormat::MessagePack)
+                .build(),
+        ),
+        (
+            "bloom_fp_rate",
+            Config::builder().bloom_fp_rate(0.01).build(),
+        ),
+    ];
+    for (setting, config) in mismatched.iter() {
+        match KvStore::with_config(temp_dir.path(), config.clone()) {
+            Err(KvError::ManifestMismatch { setting: found, .. }) => assert_eq!(&found, setting),
+            Err(e) => panic!("unexpected error {} for {}", e, setting),
+            Ok(_) => panic!("opened the store with a different {}", setting),
+        }
+    }
+
+    // the settings the store was created with are still accepted
+    let config = Config::builder().block_size(256).build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get(b"key")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn out_of_range_bloom_fp_rate_is_an_error() {
+    for rate in [0.0, 1.0, 1.5, -0.1, f64::NAN] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = Config::builder().bloom_fp_rate(rate).build();
+        match KvStore::with_config(temp_dir.path(), config) {
+            Err(KvError::Parse(_)) => {}
+            Err(e) => panic!("unexpected error {} for {}", e, rate),
+            Ok(_) => panic!("opened a store with a bloom_fp_rate of {}", rate),
+        }
+        // nothing is written for a store that was refused
+        assert!(!temp_dir.path().join("MANIFEST").exists());
+    }
+}
+
+/// Encode a record the way stores did before records could expire or have
+/// their value compressed
+fn legacy_record(timestamp: u128, key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+    let mut digest = crc.digest();
+    digest.update(&timestamp.to_be_bytes());
+    digest.update(key);
+    digest.update(value.unwrap_or_default());
+    bincode::serialize(&(digest.finalize(), timestamp, key, value)).unwrap()
+}
+
+#[test]
+fn legacy_store_opens_and_migrates() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let start = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+
+    // a segment in the legacy layout only has its element count as a header
+    let mut segment = 20_usize.to_be_bytes().to_vec();
+    for i in 0..20 {
+        let key = format!("key{:02}", i);
+        segment.extend(legacy_record(start + i, key.as_bytes(), Some(b"v1")));
+    }
+    std::fs::write(temp_dir.path().join("1.log"), segment)?;
+    // and a legacy write-ahead-log starts straight with its first record
+    let mut log = legacy_record(start + 100, b"key03", Some(b"v2"));
+    log.extend(legacy_record(start + 101, b"key04", None));
+    std::fs::write(temp_dir.path().join("legacy.redo"), log)?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get(b"key00")?, Some(b"v1".to_vec()));
+        assert_eq!(store.get(b"key03")?, Some(b"v2".to_vec()));
+        assert_eq!(store.get(b"key04")?, None);
+        assert_eq!(store.get(b"key19")?, Some(b"v1".to_vec()));
+        assert_eq!(store.find(b"key*".to_vec())?.len(), 19);
+        assert!(store.verify()?.corrupt.is_empty());
+        Ok(())
+    };
+    let store = KvStore::new(temp_dir.path())?;
+    check(&store)?;
+    store.migrate()?;
+    check(&store)?;
+    drop(store);
+
+    // every segment and log is now written in the newest layout
+    let files = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .collect::<Vec<_>>();
+    for entry in files {
+        let magic: &[u8] = match entry.path().extension().and_then(|e| e.to_str()) {
+            Some("log") => b"KVSG",
+            Some("redo") => b"KVWL",
+            _ => continue,
+        };
+        let bytes = std::fs::read(entry.path())?;
+        assert_eq!(&bytes[..4], magic, "{:?}", entry.path());
+    }
+
+    let store = KvStore::new(temp_dir.path())?;
+    check(&store)?;
+    Ok(())
+}
+
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = layered_store(&temp_dir)?;
+    // enough data to be copied over in more than one batch
+    for i in 0..100 {
+        store.set(format!("large{:03}", i).into_bytes(), vec![i as u8; 1024])?;
+    }
+    let expected = KvsEngine::iter(&store)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(expected.len(), 128);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::restore(sled_dir.path())?;
+    assert_eq!(migrate(&store, &sled)?, 128);
+    for (key, value) in expected.iter() {
+        assert_eq!(sled.get(key)?.as_ref(), Some(value));
+    }
+    assert_eq!(sled.get(b"key05")?, None);
+    assert_eq!(sled.get(b"key25")?, None);
+
+    // and back again into a fresh store
+    let back_dir = TempDir::new().expect("unable to create temporary working directory");
+    let back = KvStore::new(back_dir.path())?;
+    assert_eq!(migrate(&sled, &back)?, 128);
+    assert_eq!(
+        KvsEngine::iter(&back)?.collect::<Result<Vec<_>>>()?,
+        expected
+    );
+    Ok(())
+}
+
+#[test]
+#[allow(deprecated)]
+fn open_is_an_alias_of_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let store = KvStore::restore(temp_dir.path())?;
+        store.set(b"key".to_vec(), b"value".to_vec())?;
+    }
+    let store = <KvStore as KvsEngine>::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn mmap_reads_match_file_reads() -> Result<()> {
+    for compression in [Compression::None, Compression::Zstd { level: 3 }].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = || Config::builder().block_size(128).compression(*compression);
+        {
+            let store = KvStore::with_config(temp_dir.path(), config().build())?;
+            for i in 0..200 {
+                let value = format!("value{}", i).repeat(i % 7 + 1).into_bytes();
+                store.set(format!("key{:03}", i).into_bytes(), value)?;
+            }
+            store.flush_memtable()?;
+            store.compact()?;
+            for i in (0..200).step_by(3) {
+                store.set(format!("key{:03}", i).into_bytes(), b"new".to_vec())?;
+            }
+            store.remove(b"key100".to_vec())?;
+            store.flush_memtable()?;
+        }
+
+        let read_everything = |store: &KvStore| -> Result<_> {
+            let values = (0..201)
+                .map(|i| store.get(format!("key{:03}", i).as_bytes()))
+                .collect::<Result<Vec<_>>>()?;
+            Ok((values, store.find(b"key1*".to_vec())?))
+        };
+        let file_reads = {
+            let store = KvStore::with_config(temp_dir.path(), config().build())?;
+            read_everything(&store)?
+        };
+        let store = KvStore::with_config(temp_dir.path(), config().mmap(true).build())?;
+        let mmap_reads = read_everything(&store)?;
+        assert_eq!(mmap_reads, file_reads, "{:?}", compression);
+        assert_eq!(mmap_reads.0[100], None);
+        assert_eq!(mmap_reads.0[3], Some(b"new".to_vec()));
+
+        // mapped segments can still be merged away and read afterwards
+        store.compact()?;
+        assert_eq!(read_everything(&store)?, file_reads, "{:?}", compression);
+    }
+    Ok(())
+}
+
+#[test]
+fn wal_and_segments_in_separate_dirs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wal_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segment_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || {
+        Config::builder()
+            .max_wal_size(1000)
+            .compaction_fanout(2)
+            .wal_dir(wal_dir.path())
+            .segment_dir(segment_dir.path())
+            .build()
+    };
+    let files = |dir: &std::path::Path, extension: &str| {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some(extension.as_ref()))
+            .count()
+    };
+
+    let store = KvStore::with_config(temp_dir.path(), config())?;
+    for i in 0..200 {
+        store.set(format!("key{:03}", i).into_bytes(), vec![b'x'; 50])?;
+    }
+    store.remove(b"key000".to_vec())?;
+    store.flush_memtable()?;
+    assert!(files(segment_dir.path(), "log") > 0);
+    assert_eq!(files(segment_dir.path(), "redo"), 0);
+    assert!(files(wal_dir.path(), "redo") > 0);
+    assert_eq!(files(wal_dir.path(), "log"), 0);
+    // only the manifest is left in the store's folder
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+    let stats = store.stats()?;
+    assert!(stats.wal_size > 0 && stats.disk_size > stats.wal_size);
+
+    store.compact()?;
+    assert_eq!(files(segment_dir.path(), "log"), 1);
+    assert_eq!(store.count(b"key*".to_vec())?, 199);
+
+    // the writes still in the write-ahead-log are restored from its dir
+    store.set(b"last".to_vec(), b"value".to_vec())?;
+    drop(store);
+    let store = KvStore::with_config(temp_dir.path(), config())?;
+    assert_eq!(store.get(b"key000")?, None);
+    assert_eq!(store.get(b"key199")?, Some(vec![b'x'; 50]));
+    assert_eq!(store.get(b"last")?, Some(b"value".to_vec()));
+
+    // a backup is a store in a single folder
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.backup(backup_dir.path().join("backup"))?;
+    let backup = KvStore::new(backup_dir.path().join("backup"))?;
+    assert_eq!(backup.count(b"*".to_vec())?, 200);
+    drop(store);
+
+    // the directories are kept in the manifest, so the store finds its logs
+    // without being given them again, and refuses to be given other ones
+    let store = KvStore::new(temp_dir.path())?;
+    assert_eq!(store.get(b"last")?, Some(b"value".to_vec()));
+    drop(store);
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mismatched = [
+        (
+            "wal_dir",
+            Config::builder().wal_dir(other_dir.path()).build(),
+        ),
+        (
+            "segment_dir",
+            Config::builder().segment_dir(other_dir.path()).build(),
+        ),
+    ];
+    for (setting, config) in mismatched.iter() {
+        match KvStore::with_config(temp_dir.path(), config.clone()) {
+            Err(KvError::ManifestMismatch { setting: found, .. }) => assert_eq!(&found, setting),
+            Err(e) => panic!("unexpected error {} for {}", e, setting),
+            Ok(_) => panic!("opened the store with a different {}", setting),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn manifest_without_dirs_records_the_ones_it_is_opened_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wal_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || Config::builder().wal_dir(wal_dir.path()).build();
+    let store = KvStore::with_config(temp_dir.path(), config())?;
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    drop(store);
+
+    // a version 1 manifest doesn't know where the store keeps its files
+    let path = temp_dir.path().join("MANIFEST");
+    let mut manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+    manifest["version"] = 1.into();
+    let fields = manifest.as_object_mut().unwrap();
+    fields.remove("wal_dir");
+    fields.remove("segment_dir");
+    std::fs::write(&path, serde_json::to_vec(&manifest)?)?;
+
+    let store = KvStore::with_config(temp_dir.path(), config())?;
+    assert_eq!(store.get(b"key")?, Some(b"value".to_vec()));
+    drop(store);
+    let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+    assert_eq!(manifest["version"], 2);
+    assert_eq!(manifest["wal_dir"], wal_dir.path().to_str().unwrap());
+    Ok(())
+}
+
+#[test]
+fn store_without_dirs_moves_its_files_into_new_dirs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::with_config(temp_dir.path(), Config::builder().build())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i).into_bytes(), vec![b'x'; 50])?;
+        if i % 25 == 24 {
+            store.flush_memtable()?;
+        }
+        if i == 49 {
+            // the segments written so far move to a deeper level
+            store.compact()?;
+        }
+    }
+    store.set(b"last".to_vec(), b"value".to_vec())?;
+    drop(store);
+
+    // a version 1 manifest belongs to a store that kept every file in its
+    // folder
+    let path = temp_dir.path().join("MANIFEST");
+    let mut manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+    manifest["version"] = 1.into();
+    let fields = manifest.as_object_mut().unwrap();
+    fields.remove("wal_dir");
+    fields.remove("segment_dir");
+    std::fs::write(&path, serde_json::to_vec(&manifest)?)?;
+
+    let wal_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segment_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .wal_dir(wal_dir.path())
+        .segment_dir(segment_dir.path())
+        .build();
+    let store = KvStore::with_config(temp_dir.path(), config)?;
+    assert_eq!(store.count(b"key*".to_vec())?, 100);
+    assert_eq!(store.get(b"key099")?, Some(vec![b'x'; 50]));
+    assert_eq!(store.get(b"last")?, Some(b"value".to_vec()));
+    // only the manifest is left in the store's folder
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+    assert!(segment_dir.path().join("lv2").is_dir());
+    drop(store);
+
+    let store = KvStore::new(temp_dir.path())?;
+    assert_eq!(store.count(b"*".to_vec())?, 101);
+    Ok(())
+}